@@ -0,0 +1,47 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Benchmarks for converting a full frame of Raw pixels between pixel formats.
+//!
+//! Run with `cargo bench --features bench`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rfb::encodings::{Encoding, RawEncoding};
+use rfb::pixel_formats::fourcc;
+use rfb::rfb::PixelFormat;
+
+const WIDTH: usize = 1024;
+const HEIGHT: usize = 768;
+
+fn transform(c: &mut Criterion) {
+    let xrgb = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+    let xbgr = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XB24).unwrap();
+    let mut xrgb_be = xrgb.clone();
+    xrgb_be.big_endian = true;
+    let rgb565 = PixelFormat::new_colorformat(16, 16, false, 11, 31, 5, 63, 0, 31);
+
+    let pixels: Vec<u8> = (0..WIDTH * HEIGHT * 4).map(|i| i as u8).collect();
+    let raw = RawEncoding::new(pixels);
+
+    let mut group = c.benchmark_group("RawEncoding::transform");
+    group.throughput(Throughput::Elements((WIDTH * HEIGHT) as u64));
+
+    for (name, output) in [
+        ("identity", &xrgb),
+        ("endian swap", &xrgb_be),
+        ("RGB888 to RGB565", &rgb565),
+        ("RGB888 to BGR888", &xbgr),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| raw.transform(black_box(&xrgb), black_box(output)))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, transform);
+criterion_main!(benches);
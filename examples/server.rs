@@ -4,26 +4,38 @@
 //
 // Copyright 2022 Oxide Computer Company
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 use clap::{Parser, ValueEnum};
-use env_logger;
 use image::io::Reader as ImageReader;
 use image::GenericImageView;
-use log::info;
-use rfb::encodings::RawEncoding;
+use log::{error, info};
+use rfb::encodings::{EncodingType, RawEncoding};
 use rfb::rfb::{
-    FramebufferUpdate, PixelFormat, ProtoVersion, Rectangle, SecurityType, SecurityTypes,
+    FramebufferUpdate, NameEncoding, ParseConfig, PixelFormat, ProtoVersion, Rect, Rectangle,
+    SecurityType, SecurityTypes,
 };
 use rfb::{
     pixel_formats::rgb_888,
-    server::{Server, VncServer, VncServerConfig, VncServerData},
+    server::{
+        AcceptedSession, Acceptor, DisconnectReason, Server, ServerError, VncServer,
+        VncServerConfig, VncServerData, DEFAULT_IDLE_TIMEOUT,
+    },
 };
+use std::cell::Cell;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpListener;
 
 const WIDTH: usize = 1024;
 const HEIGHT: usize = 768;
 
+/// Number of rows the image moves up by on each update when scrolling.
+const SCROLL_STEP: usize = 8;
+
+/// Number of clients that may be connected at once.
+const MAX_CONNECTIONS: usize = 16;
+
 #[derive(Parser, Debug)]
 /// A simple VNC server that displays a single image or color, in a given pixel format
 ///
@@ -38,6 +50,9 @@ const HEIGHT: usize = 768;
 /// For example, to use big-endian xRGB:
 /// ./example-server --big-endian true -r 1 -g 2 -b 3
 ///
+/// To animate the image by scrolling it upwards, use the `--scroll` flag. Clients that support the
+/// CopyRect encoding will only be sent the newly exposed rows of each frame.
+///
 struct Args {
     /// Image/color to display from the server
     #[clap(value_enum, short, long, default_value_t = Image::Oxide)]
@@ -58,6 +73,10 @@ struct Args {
     /// Byte mapping to blue (4-byte RGB pixel, endian-agnostic)
     #[clap(short, long, default_value_t = 2)]
     blue_order: u8,
+
+    /// Scroll the image upwards on each update
+    #[clap(long)]
+    scroll: bool,
 }
 
 #[derive(ValueEnum, Debug, Copy, Clone)]
@@ -76,6 +95,15 @@ struct ExampleServer {
     display: Image,
     rgb_order: (u8, u8, u8),
     big_endian: bool,
+    pixel_format: PixelFormat,
+    scroll: bool,
+}
+
+tokio::task_local! {
+    /// Number of rows the image has been scrolled by in the current session, or `None` before
+    /// the session's first frame. Each client is sent copies of rows it has already received, so
+    /// this can't be shared between sessions.
+    static SCROLL_OFFSET: Cell<Option<usize>>;
 }
 
 #[tokio::main]
@@ -101,12 +129,7 @@ async fn main() -> Result<()> {
         args.image, pf
     );
 
-    let config = VncServerConfig {
-        addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 9000),
-        version: ProtoVersion::Rfb38,
-        sec_types: SecurityTypes(vec![SecurityType::None, SecurityType::VncAuthentication]),
-        name: "rfb-example-server".to_string(),
-    };
+    let config = example_config(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 9000))?;
     let data = VncServerData {
         width: WIDTH as u16,
         height: HEIGHT as u16,
@@ -116,13 +139,58 @@ async fn main() -> Result<()> {
         display: args.image,
         rgb_order: (args.red_order, args.green_order, args.blue_order),
         big_endian: args.big_endian,
+        pixel_format: pf.clone(),
+        scroll: args.scroll,
     };
-    let s = VncServer::new(server, config, data);
-    s.start().await;
+    let addr = config.addr;
+    let vnc = VncServer::new(server, config, data);
+
+    // This is like `vnc.start()`, but limits the number of clients and shows how to drive each
+    // session directly.
+    let listener = TcpListener::bind(addr).await?;
+    let mut acceptor = Acceptor::new(vnc, listener, MAX_CONNECTIONS);
+    while let Some(session) = acceptor.accept().await {
+        tokio::spawn(async move {
+            let addr = session.addr();
+            match serve(session).await {
+                Ok(reason) => info!("[{:?}] session ended: {:?}", addr, reason),
+                Err(e) => error!("[{:?}] session failed: {}", addr, e),
+            }
+        });
+    }
 
     Ok(())
 }
 
+/// Runs a session with its own scroll state.
+async fn serve(session: AcceptedSession<ExampleServer>) -> Result<DisconnectReason, ServerError> {
+    SCROLL_OFFSET
+        .scope(Cell::new(None), session.process(None))
+        .await
+}
+
+fn example_config(addr: SocketAddr) -> Result<VncServerConfig> {
+    Ok(VncServerConfig {
+        addr,
+        version: ProtoVersion::Rfb38,
+        sec_types: SecurityTypes::builder().offer(SecurityType::None).build()?,
+        name: "rfb-example-server".to_string(),
+        name_encoding: NameEncoding::Utf8,
+        empty_name_replacement: None,
+        max_desktop_size: None,
+        handshake_timeout: Duration::from_secs(10),
+        idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        input_coalesce_interval: None,
+        relative_pointer: false,
+        suppress_key_repeat: false,
+        parse_config: ParseConfig::default(),
+        supported_encodings: vec![EncodingType::Raw, EncodingType::CopyRect, EncodingType::RRE],
+        count_bytes: false,
+        dirty_tracking: false,
+        security_policy: None,
+    })
+}
+
 fn validate_order(r: u8, g: u8, b: u8) -> Result<()> {
     if r > 3 || g > 3 || b > 3 {
         bail!("r/g/b must have ordering of 0, 1, 2, or 3");
@@ -157,9 +225,9 @@ fn generate_color(index: u8, big_endian: bool) -> Vec<u8> {
     let idx = order_to_index(index, big_endian);
 
     let mut x = 0;
-    for i in 0..pixels.len() {
+    for pixel in pixels.iter_mut() {
         if x == idx {
-            pixels[i] = 0xff;
+            *pixel = 0xff;
         }
 
         if x == 3 {
@@ -201,6 +269,15 @@ fn generate_image(name: &str, big_endian: bool, rgb_order: (u8, u8, u8)) -> Vec<
     pixels
 }
 
+/// Returns the pixel that fills the display, if it's a single color whatever the pixel format.
+fn solid_pixel(img: Image) -> Option<Vec<u8>> {
+    match img {
+        Image::White => Some(vec![0xffu8; rgb_888::BYTES_PER_PIXEL]),
+        Image::Black => Some(vec![0x0u8; rgb_888::BYTES_PER_PIXEL]),
+        _ => None,
+    }
+}
+
 fn generate_pixels(img: Image, big_endian: bool, rgb_order: (u8, u8, u8)) -> Vec<u8> {
     const LEN: usize = WIDTH * HEIGHT * rgb_888::BYTES_PER_PIXEL;
 
@@ -217,11 +294,301 @@ fn generate_pixels(img: Image, big_endian: bool, rgb_order: (u8, u8, u8)) -> Vec
     }
 }
 
+/// Returns the image with its rows rotated upwards by `offset` rows.
+fn scroll_pixels(pixels: &[u8], offset: usize) -> Vec<u8> {
+    let split = (offset % HEIGHT) * WIDTH * rgb_888::BYTES_PER_PIXEL;
+    let mut scrolled = Vec::with_capacity(pixels.len());
+    scrolled.extend_from_slice(&pixels[split..]);
+    scrolled.extend_from_slice(&pixels[..split]);
+    scrolled
+}
+
+fn full_update(pixels: Vec<u8>) -> FramebufferUpdate {
+    let r = Rectangle::new(
+        0,
+        0,
+        WIDTH as u16,
+        HEIGHT as u16,
+        Box::new(RawEncoding::new(pixels)),
+    );
+    FramebufferUpdate::new(vec![r])
+}
+
+impl ExampleServer {
+    /// Generates the next frame of the display.
+    ///
+    /// A white or black display is sent as a single RRE rectangle to clients that support it.
+    ///
+    /// When scrolling, a session's first frame, and any frame the client asks for in `full`, is
+    /// sent whole. Otherwise, clients that support CopyRect are sent a copy of the rows that
+    /// remain visible plus the newly exposed rows, and other clients are sent the whole frame as
+    /// Raw. This must run within `serve`, which holds the session's scroll state.
+    fn generate(&self, encodings: &[EncodingType], full: bool) -> FramebufferUpdate {
+        // Scrolling doesn't change a solid color.
+        if let Some(pixel) = solid_pixel(self.display) {
+            return FramebufferUpdate::solid_color(
+                WIDTH as u16,
+                HEIGHT as u16,
+                &pixel,
+                &self.pixel_format,
+                encodings,
+            );
+        }
+
+        let pixels = generate_pixels(self.display, self.big_endian, self.rgb_order);
+        if !self.scroll {
+            return full_update(pixels);
+        }
+
+        let (previous, offset) = SCROLL_OFFSET.with(|o| {
+            let previous = o.get();
+            let offset = previous.map_or(0, |p| (p + SCROLL_STEP) % HEIGHT);
+            o.set(Some(offset));
+            (previous, offset)
+        });
+        let frame = scroll_pixels(&pixels, offset);
+        if full || previous.is_none() {
+            return full_update(frame);
+        }
+
+        let exposed_start = (HEIGHT - SCROLL_STEP) * WIDTH * rgb_888::BYTES_PER_PIXEL;
+        let exposed = RawEncoding::new(frame[exposed_start..].to_vec());
+        let region = Rect::new(0, 0, WIDTH as u16, HEIGHT as u16);
+
+        FramebufferUpdate::scroll(region, 0, -(SCROLL_STEP as i16), exposed, encodings, || {
+            RawEncoding::new(frame)
+        })
+    }
+}
+
 #[async_trait]
 impl Server for ExampleServer {
-    async fn get_framebuffer_update(&self) -> FramebufferUpdate {
-        let pixels = generate_pixels(self.display, self.big_endian, self.rgb_order);
-        let r = Rectangle::new(0, 0, 1024, 768, Box::new(RawEncoding::new(pixels)));
-        FramebufferUpdate::new(vec![r])
+    async fn get_framebuffer_update(
+        &self,
+        _region: Rect,
+        encodings: &[EncodingType],
+    ) -> FramebufferUpdate {
+        self.generate(encodings, false)
+    }
+
+    async fn get_full_framebuffer_update(
+        &self,
+        _region: Rect,
+        encodings: &[EncodingType],
+    ) -> FramebufferUpdate {
+        self.generate(encodings, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rfb::encodings::EncodingRegistry;
+    use rfb::rfb::{
+        ClientInit, ClientMessage, FramebufferUpdateRequest, ReadMessage, SecurityResult,
+        ServerInit, WriteMessage,
+    };
+    use tokio::io::{AsyncReadExt, BufReader};
+    use tokio::net::TcpStream;
+
+    fn example_server(display: Image, scroll: bool) -> ExampleServer {
+        ExampleServer {
+            display,
+            rgb_order: (0, 1, 2),
+            big_endian: false,
+            pixel_format: PixelFormat::new_colorformat(
+                rgb_888::BITS_PER_PIXEL,
+                rgb_888::DEPTH,
+                false,
+                order_to_shift(0),
+                rgb_888::MAX_VALUE,
+                order_to_shift(1),
+                rgb_888::MAX_VALUE,
+                order_to_shift(2),
+                rgb_888::MAX_VALUE,
+            ),
+            scroll,
+        }
+    }
+
+    fn encoding_types(fbu: &FramebufferUpdate) -> Vec<EncodingType> {
+        fbu.rectangles().iter().map(|r| r.encoding_type()).collect()
+    }
+
+    #[test]
+    fn test_scroll_encodings() {
+        // CopyRect-capable client: a full frame, then a copy plus the exposed strip.
+        let server = example_server(Image::Red, true);
+        let encodings = [EncodingType::CopyRect, EncodingType::Raw];
+        SCROLL_OFFSET.sync_scope(Cell::new(None), || {
+            let first = server.generate(&encodings, false);
+            assert_eq!(encoding_types(&first), vec![EncodingType::Raw]);
+            let next = server.generate(&encodings, false);
+            assert_eq!(
+                encoding_types(&next),
+                vec![EncodingType::CopyRect, EncodingType::Raw]
+            );
+
+            // A full frame doesn't rely on what the client has.
+            let full = server.generate(&encodings, true);
+            assert_eq!(encoding_types(&full), vec![EncodingType::Raw]);
+        });
+
+        // Raw-only client: every frame is sent in full.
+        let encodings = [EncodingType::Raw];
+        SCROLL_OFFSET.sync_scope(Cell::new(None), || {
+            server.generate(&encodings, false);
+            let next = server.generate(&encodings, false);
+            assert_eq!(encoding_types(&next), vec![EncodingType::Raw]);
+        });
+    }
+
+    /// Starts the example server on a loopback port.
+    async fn start(server: ExampleServer) -> (Acceptor<ExampleServer>, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = VncServerData {
+            width: WIDTH as u16,
+            height: HEIGHT as u16,
+            input_pixel_format: server.pixel_format.clone(),
+        };
+        let vnc = VncServer::new(server, example_config(addr).unwrap(), data);
+        (Acceptor::new(vnc, listener, 2), addr)
+    }
+
+    /// A client connected to the example server over the wire.
+    struct TestClient {
+        stream: BufReader<TcpStream>,
+        pixel_format: PixelFormat,
+    }
+
+    impl TestClient {
+        /// Completes the handshake and sends SetEncodings.
+        async fn connect(addr: SocketAddr, encodings: Vec<EncodingType>) -> Self {
+            let mut c = BufReader::new(TcpStream::connect(addr).await.unwrap());
+
+            let version = ProtoVersion::read_from(&mut c).await.unwrap();
+            assert_eq!(version, ProtoVersion::Rfb38);
+            version.write_to(c.get_mut()).await.unwrap();
+
+            let n_types = c.read_u8().await.unwrap();
+            let mut types = vec![0u8; n_types as usize];
+            c.read_exact(&mut types).await.unwrap();
+            assert!(types.contains(&u8::from(SecurityType::None)));
+            SecurityType::None.write_to(c.get_mut()).await.unwrap();
+            let result = SecurityResult::read_from(&mut c, version).await.unwrap();
+            assert_eq!(result, SecurityResult::Success);
+
+            ClientInit::new(true).write_to(c.get_mut()).await.unwrap();
+            let server_init = ServerInit::read_from(&mut c).await.unwrap();
+            assert_eq!(server_init.name(), "rfb-example-server");
+            assert_eq!(server_init.resolution().width(), WIDTH as u16);
+            assert_eq!(server_init.resolution().height(), HEIGHT as u16);
+            let pixel_format = server_init.pixel_format().clone();
+
+            ClientMessage::SetEncodings(encodings)
+                .write_to(c.get_mut())
+                .await
+                .unwrap();
+
+            TestClient {
+                stream: c,
+                pixel_format,
+            }
+        }
+
+        /// Requests an update of the whole screen and reads it.
+        async fn request(&mut self, incremental: bool) -> FramebufferUpdate {
+            let req = FramebufferUpdateRequest::new(incremental, 0, 0, WIDTH as u16, HEIGHT as u16);
+            ClientMessage::FramebufferUpdateRequest(req)
+                .write_to(self.stream.get_mut())
+                .await
+                .unwrap();
+
+            FramebufferUpdate::read_from(
+                &mut self.stream,
+                &self.pixel_format,
+                &EncodingRegistry::default(),
+            )
+            .await
+            .unwrap()
+        }
+    }
+
+    /// Runs the example server on a loopback port and drives it as a client would, over the
+    /// wire: the handshake, SetEncodings, and a full Raw update, which must match the image.
+    #[tokio::test]
+    async fn test_interop() {
+        let (mut acceptor, addr) = start(example_server(Image::Oxide, false)).await;
+
+        let client = tokio::spawn(async move {
+            let mut c = TestClient::connect(addr, vec![EncodingType::Raw]).await;
+            let fbu = c.request(false).await;
+            assert_eq!(encoding_types(&fbu), vec![EncodingType::Raw]);
+            let mut framebuffer = vec![0u8; WIDTH * HEIGHT * rgb_888::BYTES_PER_PIXEL];
+            fbu.apply_to(&mut framebuffer, WIDTH, &c.pixel_format)
+                .unwrap();
+            framebuffer
+        });
+
+        let session = acceptor.accept().await.unwrap();
+        let session = tokio::spawn(serve(session));
+
+        let framebuffer = client.await.unwrap();
+        assert!(framebuffer == generate_pixels(Image::Oxide, false, (0, 1, 2)));
+
+        // The client hung up.
+        session.await.unwrap().unwrap();
+    }
+
+    /// Each session scrolls on its own, and is sent a whole frame whenever it asks for one.
+    #[tokio::test]
+    async fn test_scroll_sessions() {
+        let (mut acceptor, addr) = start(example_server(Image::Red, true)).await;
+        let encodings = vec![EncodingType::CopyRect, EncodingType::Raw];
+        let scrolled = vec![EncodingType::CopyRect, EncodingType::Raw];
+
+        let mut a = TestClient::connect(addr, encodings.clone()).await;
+        tokio::spawn(serve(acceptor.accept().await.unwrap()));
+        assert_eq!(
+            encoding_types(&a.request(false).await),
+            vec![EncodingType::Raw]
+        );
+        assert_eq!(encoding_types(&a.request(true).await), scrolled);
+
+        // A second client hasn't been sent anything to copy from, even though the first has.
+        let mut b = TestClient::connect(addr, encodings).await;
+        tokio::spawn(serve(acceptor.accept().await.unwrap()));
+        assert_eq!(
+            encoding_types(&b.request(true).await),
+            vec![EncodingType::Raw]
+        );
+        assert_eq!(encoding_types(&b.request(true).await), scrolled);
+
+        // A non-incremental request after scrolling gets the whole frame.
+        assert_eq!(
+            encoding_types(&a.request(false).await),
+            vec![EncodingType::Raw]
+        );
+        assert_eq!(encoding_types(&a.request(true).await), scrolled);
+    }
+
+    #[test]
+    fn test_solid_color_encodings() {
+        // A blank screen is tiny for clients that support RRE.
+        let server = example_server(Image::White, false);
+        let fbu = server.generate(&[EncodingType::RRE, EncodingType::Raw], false);
+        assert_eq!(encoding_types(&fbu), vec![EncodingType::RRE]);
+        assert_eq!(
+            fbu.rectangles()[0].data().encode(),
+            &[0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]
+        );
+
+        let fbu = server.generate(&[EncodingType::Raw], false);
+        assert_eq!(encoding_types(&fbu), vec![EncodingType::Raw]);
+        assert_eq!(
+            fbu.rectangles()[0].data().encode().len(),
+            WIDTH * HEIGHT * rgb_888::BYTES_PER_PIXEL
+        );
     }
 }
@@ -0,0 +1,56 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! A VNC server that listens on a Unix domain socket, for deployments where clients reach it
+//! through a local proxy. It displays a solid color.
+//!
+//! ./unix-server /tmp/rfb.sock
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use rfb::encodings::{EncodingType, RawEncoding};
+use rfb::pixel_formats::fourcc;
+use rfb::rfb::{FramebufferUpdate, Rect, Rectangle};
+use rfb::server::{accept_unix, Server, VncServer};
+use std::net::{Ipv4Addr, SocketAddr};
+
+const WIDTH: u16 = 640;
+const HEIGHT: u16 = 480;
+
+#[derive(Clone)]
+struct SolidServer;
+
+#[async_trait]
+impl Server for SolidServer {
+    async fn get_framebuffer_update(
+        &self,
+        _region: Rect,
+        _encodings: &[EncodingType],
+    ) -> FramebufferUpdate {
+        // XRGB8888, little-endian: a dark blue.
+        let pixels = [0x80u8, 0x20, 0x10, 0x00].repeat(WIDTH as usize * HEIGHT as usize);
+        let r = Rectangle::new(0, 0, WIDTH, HEIGHT, Box::new(RawEncoding::new(pixels)));
+        FramebufferUpdate::new(vec![r])
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => bail!("usage: unix-server <socket path>"),
+    };
+
+    // The TCP address in the configuration isn't used.
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24)?;
+    let vnc = VncServer::minimal(SolidServer, addr, WIDTH, HEIGHT, pf);
+
+    accept_unix(&vnc, &path).await?;
+    Ok(())
+}
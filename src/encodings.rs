@@ -5,14 +5,22 @@
 // Copyright 2022 Oxide Computer Company
 
 use crate::{
-    pixel_formats::rgb_888,
-    rfb::{PixelFormat, Position, Resolution},
+    pixel_formats::{rgb_888, PixelConverter},
+    rfb::{ParseConfig, PixelFormat, Position, ReadMessage, Rect, Resolution, Screen},
 };
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use flate2::{write::ZlibEncoder, Compression};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use EncodingType::*;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[allow(unused)]
 pub enum EncodingType {
     Raw,
@@ -27,21 +35,74 @@ pub enum EncodingType {
     ZRLE2,
     JPEG,
     Zlib,
+    Tight,
     CursorWithAlpha,
+    ExtendedDesktopSizePseudo,
+    LastRectPseudo,
+    ContinuousUpdatesPseudo,
+    CursorPosPseudo,
+
+    /// QEMU Pointer Motion Change: the server tells the client whether to send absolute pointer
+    /// positions or relative motion.
+    PointerMotionChangePseudo,
+
+    /// The JPEG quality the client would like, from 0 (lowest) to 9 (highest).
+    JpegQualityLevelPseudo(u8),
+
+    /// VMware pseudo-encodings, numbered from 0x574d5600, for viewers derived from VMware's.
+    /// Of these, only the cursor is implemented, by [`VMwareCursorEncoding`]; the others are
+    /// recognized so that backends can tell whether the client supports them.
+    VMwareCursorPseudo,
+    VMwareCursorStatePseudo,
+    VMwareCursorPositionPseudo,
+    VMwareKeyRepeatPseudo,
+    VMwareLedStatePseudo,
+    VMwareDisplayModeChangePseudo,
+    VMwareVirtualMachineStatePseudo,
+
     Other(i32),
 }
 
+/// The numbers reserved for VMware's pseudo-encodings. Unlike other pseudo-encodings, they're
+/// positive.
+const VMWARE_ENCODINGS: std::ops::RangeInclusive<i32> = 0x574d5600..=0x574d56ff;
+
 pub trait Encoding
 where
-    Self: Send,
+    Self: Send + Sync,
 {
     fn get_type(&self) -> EncodingType;
 
     /// Transform this encoding from its representation into a byte vector that can be passed to the client.
-    fn encode(&self) -> &Vec<u8>;
+    fn encode(&self) -> &[u8];
 
     /// Translates this encoding type from an input pixel format to an output format.
     fn transform(&self, input: &PixelFormat, output: &PixelFormat) -> Box<dyn Encoding>;
+
+    /// Translates this encoding using a prebuilt converter. Encodings that carry pixel data
+    /// should override this to reuse the converter rather than building their own.
+    fn convert(&self, converter: &PixelConverter) -> Box<dyn Encoding> {
+        self.transform(converter.input(), converter.output())
+    }
+
+    /// Returns the part of this encoding covering `region`, which is relative to the top-left
+    /// corner of the enclosing rectangle, of width `width`. Returns None if the encoding can't be
+    /// cropped, in which case it's sent whole.
+    fn crop(
+        &self,
+        _width: u16,
+        _region: Rect,
+        _bytes_per_pixel: usize,
+    ) -> Option<Box<dyn Encoding>> {
+        None
+    }
+
+    /// Returns the pixels of this encoding, for a rectangle of `width` x `height`, so that it can
+    /// be sent as Raw to a client that doesn't support the encoding. Returns None if the pixels
+    /// can't be recovered, as for encodings that refer to the client's framebuffer.
+    fn raw_pixels(&self, _width: u16, _height: u16) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 impl From<EncodingType> for i32 {
@@ -59,12 +120,41 @@ impl From<EncodingType> for i32 {
             ZRLE2 => 24,
             JPEG => 21,
             Zlib => 6,
+            Tight => 7,
             CursorWithAlpha => -314,
+            ExtendedDesktopSizePseudo => -308,
+            LastRectPseudo => -224,
+            ContinuousUpdatesPseudo => -313,
+            CursorPosPseudo => -232,
+            PointerMotionChangePseudo => -257,
+            JpegQualityLevelPseudo(level) => -32 + i32::from(level),
+            VMwareCursorPseudo => 0x574d5664,
+            VMwareCursorStatePseudo => 0x574d5665,
+            VMwareCursorPositionPseudo => 0x574d5666,
+            VMwareKeyRepeatPseudo => 0x574d5667,
+            VMwareLedStatePseudo => 0x574d5668,
+            VMwareDisplayModeChangePseudo => 0x574d5669,
+            VMwareVirtualMachineStatePseudo => 0x574d566a,
             Other(n) => n,
         }
     }
 }
 
+impl EncodingType {
+    /// Returns the encoding type number sent on the wire.
+    pub fn as_i32(&self) -> i32 {
+        i32::from(*self)
+    }
+
+    /// Returns whether this is a pseudo-encoding, which declares support for a protocol extension
+    /// rather than describing how pixel data is encoded. Pseudo-encodings have negative numbers,
+    /// apart from VMware's.
+    pub fn is_pseudo(&self) -> bool {
+        let n = self.as_i32();
+        n < 0 || VMWARE_ENCODINGS.contains(&n)
+    }
+}
+
 impl TryFrom<i32> for EncodingType {
     type Error = anyhow::Error;
 
@@ -82,21 +172,88 @@ impl TryFrom<i32> for EncodingType {
             24 => Ok(ZRLE2),
             21 => Ok(JPEG),
             6 => Ok(Zlib),
+            7 => Ok(Tight),
             -314 => Ok(CursorWithAlpha),
+            -308 => Ok(ExtendedDesktopSizePseudo),
+            -224 => Ok(LastRectPseudo),
+            -313 => Ok(ContinuousUpdatesPseudo),
+            -232 => Ok(CursorPosPseudo),
+            -257 => Ok(PointerMotionChangePseudo),
+            -32..=-23 => Ok(JpegQualityLevelPseudo((value + 32) as u8)),
+            0x574d5664 => Ok(VMwareCursorPseudo),
+            0x574d5665 => Ok(VMwareCursorStatePseudo),
+            0x574d5666 => Ok(VMwareCursorPositionPseudo),
+            0x574d5667 => Ok(VMwareKeyRepeatPseudo),
+            0x574d5668 => Ok(VMwareLedStatePseudo),
+            0x574d5669 => Ok(VMwareDisplayModeChangePseudo),
+            0x574d566a => Ok(VMwareVirtualMachineStatePseudo),
             v => Ok(EncodingType::Other(v)),
         }
     }
 }
 
+/// Chooses the encoding to use for a rectangle: the first encoding in the client's list (which is
+/// in order of preference) that the server supports. Raw is used if there's no such encoding, as
+/// every client must support it. Pseudo-encodings in the list are ignored.
+pub fn select_encoding(client: &[EncodingType], server: &[EncodingType]) -> EncodingType {
+    client
+        .iter()
+        .filter(|e| !e.is_pseudo())
+        .find(|e| server.contains(e))
+        .copied()
+        .unwrap_or(EncodingType::Raw)
+}
+
+/// Returns the JPEG quality level in the client's encodings, if it asked for one.
+pub fn jpeg_quality_level(client: &[EncodingType]) -> Option<u8> {
+    client.iter().find_map(|e| match e {
+        JpegQualityLevelPseudo(level) => Some(*level),
+        _ => None,
+    })
+}
+
+/// A client's SetEncodings list, split into real encodings, which are used to encode rectangles,
+/// and pseudo-encodings, which declare support for protocol extensions. The preference order of
+/// the real encodings is independent of the pseudo-encodings, so they're kept apart; each keeps
+/// the client's order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientEncodings {
+    pub real: Vec<EncodingType>,
+    pub pseudo: Vec<EncodingType>,
+}
+
+impl ClientEncodings {
+    pub fn new(encodings: &[EncodingType]) -> Self {
+        let (pseudo, real) = encodings.iter().partition(|e| e.is_pseudo());
+        ClientEncodings { real, pseudo }
+    }
+
+    /// Returns whether the client declared support for the pseudo-encoding `e`.
+    pub fn supports(&self, e: EncodingType) -> bool {
+        self.pseudo.contains(&e)
+    }
+}
+
 /// Section 7.7.1
+///
+/// The pixels are held in a reference-counted buffer, so a backend can share its framebuffer with
+/// the encoding without copying it.
 pub struct RawEncoding {
-    pixels: Vec<u8>,
+    pixels: Bytes,
 }
 
 impl RawEncoding {
     pub fn new(pixels: Vec<u8>) -> Self {
+        Self::from_bytes(Bytes::from(pixels))
+    }
+
+    pub fn from_bytes(pixels: Bytes) -> Self {
         Self { pixels }
     }
+
+    pub fn from_arc(pixels: Arc<[u8]>) -> Self {
+        Self::from_bytes(Bytes::from_owner(pixels))
+    }
 }
 
 impl Encoding for RawEncoding {
@@ -104,26 +261,767 @@ impl Encoding for RawEncoding {
         EncodingType::Raw
     }
 
-    fn encode(&self) -> &Vec<u8> {
+    fn encode(&self) -> &[u8] {
         &self.pixels
     }
 
     fn transform(&self, input: &PixelFormat, output: &PixelFormat) -> Box<dyn Encoding> {
-        // XXX: This assumes the pixel formats are both rgb888. The server code verifies this
-        // before calling.
-        assert!(input.is_rgb_888());
-        assert!(output.is_rgb_888());
+        // The pixels are already in the client's format, so share them rather than copying.
+        if input.canonical() == output.canonical() {
+            return Box::new(Self::from_bytes(self.pixels.clone()));
+        }
+
+        Box::new(Self::new(transform_pixels(&self.pixels, input, output)))
+    }
+
+    fn convert(&self, converter: &PixelConverter) -> Box<dyn Encoding> {
+        if converter.is_identity() {
+            return Box::new(Self::from_bytes(self.pixels.clone()));
+        }
 
+        Box::new(Self::new(converter.convert(&self.pixels)))
+    }
+
+    fn crop(&self, width: u16, region: Rect, bytes_per_pixel: usize) -> Option<Box<dyn Encoding>> {
+        let pixels = crop_pixels(&self.pixels, width, region, bytes_per_pixel)?;
+        Some(Box::new(Self::new(pixels)))
+    }
+
+    fn raw_pixels(&self, _width: u16, _height: u16) -> Option<Vec<u8>> {
+        Some(self.pixels.to_vec())
+    }
+}
+
+/// Returns the pixels of `region` within an image of the given width, or None if `pixels` is too
+/// short to hold the region.
+pub(crate) fn crop_pixels(
+    pixels: &[u8],
+    width: u16,
+    region: Rect,
+    bytes_per_pixel: usize,
+) -> Option<Vec<u8>> {
+    let stride = width as usize * bytes_per_pixel;
+    let row_len = region.width as usize * bytes_per_pixel;
+    let mut buf = Vec::with_capacity(row_len * region.height as usize);
+
+    let top = region.y as usize;
+    for y in top..top + region.height as usize {
+        let start = y * stride + region.x as usize * bytes_per_pixel;
+        buf.extend_from_slice(pixels.get(start..start + row_len)?);
+    }
+
+    Some(buf)
+}
+
+/// Converts raw pixel data between pixel formats.
+fn transform_pixels(pixels: &[u8], input: &PixelFormat, output: &PixelFormat) -> Vec<u8> {
+    // XXX: This assumes the conversion is supported, which requires both pixel formats to be
+    // true color. The server code verifies this before calling.
+    PixelConverter::new(input, output).convert(pixels)
+}
+
+/// Section 7.7.2
+///
+/// The rectangle's contents are copied from the given position in the client's framebuffer.
+pub struct CopyRectEncoding {
+    data: Vec<u8>,
+}
+
+impl CopyRectEncoding {
+    pub fn new(src_x: u16, src_y: u16) -> Self {
+        let mut data = Vec::with_capacity(4);
+        data.extend_from_slice(&src_x.to_be_bytes());
+        data.extend_from_slice(&src_y.to_be_bytes());
+
+        Self { data }
+    }
+}
+
+impl Encoding for CopyRectEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::CopyRect
+    }
+
+    fn encode(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+        // The source position is independent of the pixel format.
         Box::new(Self {
-            pixels: rgb_888::transform(&self.pixels, &input, &output),
+            data: self.data.clone(),
         })
     }
+
+    fn crop(
+        &self,
+        _width: u16,
+        region: Rect,
+        _bytes_per_pixel: usize,
+    ) -> Option<Box<dyn Encoding>> {
+        // Cropping the destination moves the source by the same amount.
+        let src_x = u16::from_be_bytes([self.data[0], self.data[1]]);
+        let src_y = u16::from_be_bytes([self.data[2], self.data[3]]);
+        Some(Box::new(Self::new(
+            src_x.checked_add(region.x)?,
+            src_y.checked_add(region.y)?,
+        )))
+    }
 }
 
-#[allow(dead_code)]
-struct RREncoding {
+/// RRE (rise-and-run-length) encoding
+///
+/// Only the background is supported, which makes this the cheapest way to fill a rectangle with a
+/// single color: the encoded size depends only on the pixel size, not on the rectangle's.
+pub struct RREncoding {
     background_pixel: Pixel,
-    sub_rectangles: Vec<RRESubrectangle>,
+    data: Vec<u8>,
+}
+
+impl RREncoding {
+    /// Creates a rectangle filled with `background`, a single pixel in the rectangle's format.
+    pub fn new(background: Vec<u8>) -> Self {
+        // No subrectangles, followed by the background pixel.
+        let mut data = Vec::with_capacity(4 + background.len());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&background);
+
+        Self {
+            background_pixel: Pixel { bytes: background },
+            data,
+        }
+    }
+
+    pub fn background(&self) -> &[u8] {
+        &self.background_pixel.bytes
+    }
+}
+
+impl Encoding for RREncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::RRE
+    }
+
+    fn encode(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn transform(&self, input: &PixelFormat, output: &PixelFormat) -> Box<dyn Encoding> {
+        Box::new(Self::new(transform_pixels(
+            self.background(),
+            input,
+            output,
+        )))
+    }
+
+    fn convert(&self, converter: &PixelConverter) -> Box<dyn Encoding> {
+        Box::new(Self::new(converter.convert(self.background())))
+    }
+
+    fn crop(
+        &self,
+        _width: u16,
+        _region: Rect,
+        _bytes_per_pixel: usize,
+    ) -> Option<Box<dyn Encoding>> {
+        // Any part of a solid rectangle is the same solid rectangle.
+        Some(Box::new(Self::new(self.background().to_vec())))
+    }
+
+    fn raw_pixels(&self, width: u16, height: u16) -> Option<Vec<u8>> {
+        Some(self.background().repeat(width as usize * height as usize))
+    }
+}
+
+/// Tight encoding
+///
+/// Single-color rectangles are sent using fill compression. Other rectangles use basic
+/// compression with the copy filter, compressing the pixels with zlib stream 0. The stream is
+/// reset for every rectangle, so rectangles don't depend on each other and may be sent in any
+/// order. Rectangles may be at most [`TIGHT_MAX_WIDTH`] pixels wide, and have at most
+/// [`TIGHT_MAX_PIXELS`] pixels; `FramebufferUpdateBuilder::tight` splits taller rectangles.
+///
+/// With the `jpeg` feature, rectangles created with a JPEG quality level use JPEG compression
+/// instead when they look photographic, if their pixel format is RGB888.
+pub struct TightEncoding {
+    pixels: Vec<u8>,
+    pixel_format: PixelFormat,
+
+    /// The rectangle's width and the client's JPEG quality level, if JPEG may be used.
+    jpeg: Option<(u16, u8)>,
+    data: Vec<u8>,
+}
+
+/// The widest rectangle that can be sent with Tight's basic compression.
+pub const TIGHT_MAX_WIDTH: u16 = 2048;
+
+/// The most pixels a Tight rectangle may have. Tight lengths have at most 22 bits, and at up to 4
+/// bytes per pixel, this keeps even incompressible data well within them.
+pub const TIGHT_MAX_PIXELS: usize = 1 << 19;
+
+/// The longest compressed data a Tight rectangle can carry.
+const TIGHT_MAX_DATA_LEN: usize = (1 << 22) - 1;
+
+// Compression control byte values.
+const TIGHT_RESET_STREAM_0: u8 = 0x01;
+const TIGHT_FILL: u8 = 0x80;
+#[cfg(feature = "jpeg")]
+const TIGHT_JPEG: u8 = 0x90;
+
+/// JPEG qualities for each of the client's quality levels, as used by other Tight servers.
+#[cfg(feature = "jpeg")]
+const TIGHT_JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 77, 79, 86, 92];
+
+/// Rectangles with fewer distinct colors than this compress better without JPEG.
+#[cfg(feature = "jpeg")]
+const TIGHT_JPEG_MIN_COLORS: usize = 64;
+
+// Data shorter than this is sent uncompressed.
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+
+impl TightEncoding {
+    /// Encodes `pixels`, which are in the given pixel format.
+    ///
+    /// Panics if there are more than [`TIGHT_MAX_PIXELS`] pixels.
+    pub fn new(pixels: Vec<u8>, pixel_format: &PixelFormat) -> Self {
+        Self::encode_with(pixels, pixel_format, None)
+    }
+
+    /// Encodes `pixels`, a rectangle `width` pixels wide, for a client that asked for the given
+    /// JPEG quality level (see [`jpeg_quality_level`]).
+    ///
+    /// Panics if there are more than [`TIGHT_MAX_PIXELS`] pixels.
+    pub fn with_quality(
+        pixels: Vec<u8>,
+        pixel_format: &PixelFormat,
+        width: u16,
+        quality_level: Option<u8>,
+    ) -> Self {
+        Self::encode_with(pixels, pixel_format, quality_level.map(|q| (width, q)))
+    }
+
+    /// Re-encodes the pixels with `converter`, which must convert from the rectangle's own pixel
+    /// format. If the conversion isn't supported, the pixels are left as they are.
+    fn convert_pixels(&self, converter: &PixelConverter) -> Box<dyn Encoding> {
+        if !converter.is_supported() {
+            return Box::new(Self::encode_with(
+                self.pixels.clone(),
+                &self.pixel_format,
+                self.jpeg,
+            ));
+        }
+
+        let pixels = converter.convert(&self.pixels);
+        Box::new(Self::encode_with(pixels, converter.output(), self.jpeg))
+    }
+
+    fn encode_with(pixels: Vec<u8>, pixel_format: &PixelFormat, jpeg: Option<(u16, u8)>) -> Self {
+        let bytes_per_pixel = (pixel_format.bits_per_pixel / 8).max(1) as usize;
+        assert!(
+            pixels.len() / bytes_per_pixel <= TIGHT_MAX_PIXELS,
+            "Tight rectangles may have at most {} pixels",
+            TIGHT_MAX_PIXELS
+        );
+
+        let data = tight_encode(&pixels, pixel_format, jpeg);
+        Self {
+            pixels,
+            pixel_format: pixel_format.clone(),
+            jpeg,
+            data,
+        }
+    }
+}
+
+fn tight_encode(pixels: &[u8], pf: &PixelFormat, jpeg: Option<(u16, u8)>) -> Vec<u8> {
+    let tpixels = tight_pixels(pixels, pf);
+    let tpixel_len = if pf.is_rgb_888() {
+        3
+    } else {
+        (pf.bits_per_pixel / 8) as usize
+    };
+
+    let mut chunks = tpixels.chunks_exact(tpixel_len);
+    if let Some(first) = chunks.next() {
+        if chunks.all(|p| p == first) {
+            let mut data = Vec::with_capacity(1 + tpixel_len);
+            data.push(TIGHT_FILL);
+            data.extend_from_slice(first);
+            return data;
+        }
+    }
+
+    #[cfg(feature = "jpeg")]
+    if let Some((width, quality_level)) = jpeg {
+        if pf.is_rgb_888() && width > 0 {
+            if let Some(data) = tight_jpeg(&tpixels, width, quality_level) {
+                return data;
+            }
+        }
+    }
+    #[cfg(not(feature = "jpeg"))]
+    let _ = jpeg;
+
+    let mut data = vec![TIGHT_RESET_STREAM_0];
+    if tpixels.len() < TIGHT_MIN_TO_COMPRESS {
+        data.extend_from_slice(&tpixels);
+        return data;
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&tpixels)
+        .expect("writing to a Vec cannot fail");
+    let compressed = encoder.finish().expect("writing to a Vec cannot fail");
+
+    tight_compact_len(compressed.len(), &mut data);
+    data.extend_from_slice(&compressed);
+    data
+}
+
+/// Converts pixels to Tight's TPIXEL representation, which packs RGB888 pixels into 3 bytes
+/// (red, green, blue) and leaves other formats unchanged.
+fn tight_pixels(pixels: &[u8], pf: &PixelFormat) -> Vec<u8> {
+    let cf = match pf.color_format() {
+        Some(cf) if pf.is_rgb_888() => cf,
+        _ => return pixels.to_vec(),
+    };
+
+    let (r, g, b, _) =
+        rgb_888::rgbx_index(cf.red_shift, cf.green_shift, cf.blue_shift, pf.big_endian);
+
+    let mut tpixels = Vec::with_capacity(pixels.len() / 4 * 3);
+    for p in pixels.chunks_exact(4) {
+        tpixels.extend_from_slice(&[p[r], p[g], p[b]]);
+    }
+    tpixels
+}
+
+/// Compresses 3-byte RGB pixels with JPEG, if they have enough colors to benefit from it.
+#[cfg(feature = "jpeg")]
+fn tight_jpeg(tpixels: &[u8], width: u16, quality_level: u8) -> Option<Vec<u8>> {
+    use image::codecs::jpeg::JpegEncoder;
+    use std::collections::HashSet;
+
+    let mut colors = HashSet::new();
+    for p in tpixels.chunks_exact(3) {
+        colors.insert(p);
+        if colors.len() >= TIGHT_JPEG_MIN_COLORS {
+            break;
+        }
+    }
+    if colors.len() < TIGHT_JPEG_MIN_COLORS {
+        return None;
+    }
+
+    let height = tpixels.len() / 3 / width as usize;
+    let quality = TIGHT_JPEG_QUALITY[usize::from(quality_level).min(9)];
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode(
+            tpixels,
+            u32::from(width),
+            height as u32,
+            image::ColorType::Rgb8,
+        )
+        .ok()?;
+    if jpeg.len() > TIGHT_MAX_DATA_LEN {
+        return None;
+    }
+
+    let mut data = vec![TIGHT_JPEG];
+    tight_compact_len(jpeg.len(), &mut data);
+    data.extend_from_slice(&jpeg);
+    Some(data)
+}
+
+/// Writes a length in Tight's compact representation: 7 bits per byte, with the high bit set if
+/// another byte follows, up to 3 bytes. The last byte holds 8 bits, so `len` may be at most
+/// [`TIGHT_MAX_DATA_LEN`].
+fn tight_compact_len(len: usize, out: &mut Vec<u8>) {
+    assert!(len <= TIGHT_MAX_DATA_LEN, "Tight data too long: {}", len);
+
+    let mut b = (len & 0x7f) as u8;
+    if len > 0x7f {
+        out.push(b | 0x80);
+        b = ((len >> 7) & 0x7f) as u8;
+        if len > 0x3fff {
+            out.push(b | 0x80);
+            b = ((len >> 14) & 0xff) as u8;
+        }
+    }
+    out.push(b);
+}
+
+impl Encoding for TightEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::Tight
+    }
+
+    fn encode(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn transform(&self, _input: &PixelFormat, output: &PixelFormat) -> Box<dyn Encoding> {
+        // The rectangle knows the format of its pixels, which may differ from the server's.
+        self.convert_pixels(&PixelConverter::new(&self.pixel_format, output))
+    }
+
+    fn convert(&self, converter: &PixelConverter) -> Box<dyn Encoding> {
+        if converter.input() == &self.pixel_format {
+            self.convert_pixels(converter)
+        } else {
+            self.convert_pixels(&PixelConverter::new(&self.pixel_format, converter.output()))
+        }
+    }
+
+    fn crop(&self, width: u16, region: Rect, bytes_per_pixel: usize) -> Option<Box<dyn Encoding>> {
+        let pixels = crop_pixels(&self.pixels, width, region, bytes_per_pixel)?;
+        let jpeg = self.jpeg.map(|(_, q)| (region.width, q));
+        Some(Box::new(Self::encode_with(
+            pixels,
+            &self.pixel_format,
+            jpeg,
+        )))
+    }
+
+    fn raw_pixels(&self, _width: u16, _height: u16) -> Option<Vec<u8>> {
+        Some(self.pixels.clone())
+    }
+}
+
+/// ExtendedDesktopSize pseudo-encoding
+///
+/// Carries the screen layout of the framebuffer. The position of the enclosing rectangle encodes
+/// the reason for the change (x) and its status (y), and its dimensions are the framebuffer size.
+pub struct ExtendedDesktopSizeEncoding {
+    data: Vec<u8>,
+}
+
+impl ExtendedDesktopSizeEncoding {
+    pub fn new(screens: &[Screen]) -> Self {
+        let mut data = Vec::with_capacity(4 + screens.len() * 16);
+
+        // TODO: handle cast
+        data.push(screens.len() as u8);
+        // 3 bytes of padding
+        data.extend_from_slice(&[0u8; 3]);
+
+        for screen in screens {
+            data.extend_from_slice(&screen.id.to_be_bytes());
+            data.extend_from_slice(&screen.x.to_be_bytes());
+            data.extend_from_slice(&screen.y.to_be_bytes());
+            data.extend_from_slice(&screen.width.to_be_bytes());
+            data.extend_from_slice(&screen.height.to_be_bytes());
+            data.extend_from_slice(&screen.flags.to_be_bytes());
+        }
+
+        Self { data }
+    }
+}
+
+impl Encoding for ExtendedDesktopSizeEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::ExtendedDesktopSizePseudo
+    }
+
+    fn encode(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+        // The screen layout contains no pixel data.
+        Box::new(Self {
+            data: self.data.clone(),
+        })
+    }
+}
+
+/// Cursor position pseudo-encoding
+///
+/// Moves the client's cursor to the position of the enclosing rectangle, which has no dimensions
+/// and no data.
+pub struct CursorPosEncoding;
+
+impl Encoding for CursorPosEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::CursorPosPseudo
+    }
+
+    fn encode(&self) -> &[u8] {
+        &[]
+    }
+
+    fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+        Box::new(CursorPosEncoding)
+    }
+}
+
+/// VMware cursor pseudo-encoding
+///
+/// Sets the shape of the client's cursor to an image the size of the enclosing rectangle, whose
+/// position is the cursor's hotspot. The cursor is either classic, a pair of AND and XOR masks
+/// in the client's pixel format that are combined with the framebuffer under it, or 32-bit RGBA
+/// with an alpha channel.
+pub struct VMwareCursorEncoding {
+    data: Vec<u8>,
+}
+
+const VMWARE_CURSOR_CLASSIC: u8 = 0;
+const VMWARE_CURSOR_ALPHA: u8 = 1;
+
+impl VMwareCursorEncoding {
+    /// Creates a classic cursor. `and_mask` and `xor_pixels` each hold one pixel per cursor
+    /// pixel; the client ANDs the framebuffer with the mask, then XORs the result with the pixels.
+    /// Panics if they aren't the same size.
+    pub fn classic(and_mask: &[u8], xor_pixels: &[u8]) -> Self {
+        assert_eq!(and_mask.len(), xor_pixels.len());
+        let mut data = Vec::with_capacity(2 + and_mask.len() * 2);
+        data.extend_from_slice(&[VMWARE_CURSOR_CLASSIC, 0]);
+        data.extend_from_slice(and_mask);
+        data.extend_from_slice(xor_pixels);
+        Self { data }
+    }
+
+    /// Creates a cursor with an alpha channel. `rgba` holds 4 bytes per cursor pixel: red, green,
+    /// blue and alpha, whatever the client's pixel format.
+    pub fn alpha(rgba: &[u8]) -> Self {
+        let mut data = Vec::with_capacity(2 + rgba.len());
+        data.extend_from_slice(&[VMWARE_CURSOR_ALPHA, 0]);
+        data.extend_from_slice(rgba);
+        Self { data }
+    }
+
+    /// Returns true for a cursor with an alpha channel.
+    pub fn is_alpha(&self) -> bool {
+        self.data[0] == VMWARE_CURSOR_ALPHA
+    }
+}
+
+impl Encoding for VMwareCursorEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::VMwareCursorPseudo
+    }
+
+    fn encode(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn transform(&self, input: &PixelFormat, output: &PixelFormat) -> Box<dyn Encoding> {
+        if self.is_alpha() {
+            return Box::new(Self {
+                data: self.data.clone(),
+            });
+        }
+
+        // A mask that's all zeros or all ones stays that way when converted like a color.
+        let (and_mask, xor_pixels) = self.data[2..].split_at((self.data.len() - 2) / 2);
+        Box::new(Self::classic(
+            &transform_pixels(and_mask, input, output),
+            &transform_pixels(xor_pixels, input, output),
+        ))
+    }
+}
+
+/// Pointer Motion Change pseudo-encoding
+///
+/// Switches the client between sending absolute pointer positions and relative motion. The x
+/// position of the enclosing rectangle is 1 for absolute and 0 for relative, and its dimensions
+/// are the framebuffer size. It has no data.
+pub struct PointerMotionChangeEncoding;
+
+impl Encoding for PointerMotionChangeEncoding {
+    fn get_type(&self) -> EncodingType {
+        EncodingType::PointerMotionChangePseudo
+    }
+
+    fn encode(&self) -> &[u8] {
+        &[]
+    }
+
+    fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+        Box::new(PointerMotionChangeEncoding)
+    }
+}
+
+/// Creates an encoding for a rectangle of the given dimensions from its pixels, which are in the
+/// given pixel format. The last argument is the client's encodings, for options it sets with
+/// pseudo-encodings, such as the JPEG quality level. Encoders may return a Raw rectangle for
+/// pixels they can't encode.
+pub type Encoder = fn(Vec<u8>, Resolution, &PixelFormat, &[EncodingType]) -> Box<dyn Encoding>;
+
+/// Reads the data for a rectangle of the given dimensions from a stream. Decoders should check the
+/// size of data they allocate for against the `ParseConfig` before reading it, since the
+/// dimensions come from the peer.
+pub type Decoder = for<'a> fn(
+    &'a mut (dyn AsyncRead + Unpin + Send),
+    Resolution,
+    &'a PixelFormat,
+    &'a ParseConfig,
+) -> BoxFuture<'a, Result<Box<dyn Encoding>>>;
+
+/// Maps encoding types to functions that produce and read rectangles in that encoding.
+///
+/// The default registry contains the encodings built into this crate. Embedders can register
+/// their own encoders and decoders, including for encoding types this crate doesn't know about
+/// (`EncodingType::Other`).
+#[derive(Clone)]
+pub struct EncodingRegistry {
+    encoders: HashMap<EncodingType, Encoder>,
+    decoders: HashMap<EncodingType, Decoder>,
+}
+
+impl EncodingRegistry {
+    /// Creates a registry with no encodings.
+    pub fn empty() -> Self {
+        Self {
+            encoders: HashMap::new(),
+            decoders: HashMap::new(),
+        }
+    }
+
+    pub fn register_encoder(&mut self, encoding: EncodingType, encoder: Encoder) {
+        self.encoders.insert(encoding, encoder);
+    }
+
+    pub fn register_decoder(&mut self, encoding: EncodingType, decoder: Decoder) {
+        self.decoders.insert(encoding, decoder);
+    }
+
+    /// Returns true if rectangles can be produced in `encoding`.
+    pub fn can_encode(&self, encoding: EncodingType) -> bool {
+        self.encoders.contains_key(&encoding)
+    }
+
+    /// Encodes a rectangle's pixels for a client that supports `client_encodings`, returning
+    /// `None` if there's no encoder for `encoding`.
+    pub fn encode(
+        &self,
+        encoding: EncodingType,
+        pixels: Vec<u8>,
+        dimensions: Resolution,
+        pixel_format: &PixelFormat,
+        client_encodings: &[EncodingType],
+    ) -> Option<Box<dyn Encoding>> {
+        let encoder = self.encoders.get(&encoding)?;
+        Some(encoder(pixels, dimensions, pixel_format, client_encodings))
+    }
+
+    /// Reads the data of a rectangle in `encoding` from `stream`. The built-in decoders reject
+    /// rectangles whose data is larger than `config.max_message_bytes`.
+    pub async fn decode(
+        &self,
+        encoding: EncodingType,
+        stream: &mut (dyn AsyncRead + Unpin + Send),
+        dimensions: Resolution,
+        pixel_format: &PixelFormat,
+        config: &ParseConfig,
+    ) -> Result<Box<dyn Encoding>> {
+        let decoder = self
+            .decoders
+            .get(&encoding)
+            .ok_or_else(|| anyhow!("no decoder for encoding {:?}", encoding))?;
+        decoder(stream, dimensions, pixel_format, config).await
+    }
+}
+
+impl Default for EncodingRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+
+        registry.register_encoder(Raw, |pixels, _, _, _| Box::new(RawEncoding::new(pixels)));
+        registry.register_encoder(Tight, |pixels, dimensions, pf, client| {
+            let (width, height) = (dimensions.width(), dimensions.height());
+            if width > TIGHT_MAX_WIDTH
+                || usize::from(width) * usize::from(height) > TIGHT_MAX_PIXELS
+            {
+                return Box::new(RawEncoding::new(pixels));
+            }
+            let quality = jpeg_quality_level(client);
+            Box::new(TightEncoding::with_quality(pixels, pf, width, quality))
+        });
+
+        registry.register_decoder(Raw, |stream, dimensions, pf, config| {
+            async move {
+                let len = u64::from(dimensions.width())
+                    * u64::from(dimensions.height())
+                    * u64::from(pf.bits_per_pixel / 8);
+                config.check_size(len, "Raw rectangle")?;
+                let mut pixels = vec![0u8; len as usize];
+                stream.read_exact(&mut pixels).await?;
+                Ok(Box::new(RawEncoding::new(pixels)) as Box<dyn Encoding>)
+            }
+            .boxed()
+        });
+        registry.register_decoder(RRE, |stream, _, pf, _| {
+            async move {
+                let n_subrects = stream.read_u32().await?;
+                if n_subrects != 0 {
+                    bail!("RRE subrectangles are not supported");
+                }
+                let mut background = vec![0u8; (pf.bits_per_pixel / 8) as usize];
+                stream.read_exact(&mut background).await?;
+                Ok(Box::new(RREncoding::new(background)) as Box<dyn Encoding>)
+            }
+            .boxed()
+        });
+        registry.register_decoder(CopyRect, |stream, _, _, _| {
+            async move {
+                let src_x = stream.read_u16().await?;
+                let src_y = stream.read_u16().await?;
+                Ok(Box::new(CopyRectEncoding::new(src_x, src_y)) as Box<dyn Encoding>)
+            }
+            .boxed()
+        });
+        registry.register_decoder(CursorPosPseudo, |_, _, _, _| {
+            async move { Ok(Box::new(CursorPosEncoding) as Box<dyn Encoding>) }.boxed()
+        });
+        registry.register_decoder(VMwareCursorPseudo, |stream, dimensions, pf, config| {
+            async move {
+                let mut header = [0u8; 2];
+                stream.read_exact(&mut header).await?;
+                let n_pixels = u64::from(dimensions.width()) * u64::from(dimensions.height());
+                let cursor = match header[0] {
+                    VMWARE_CURSOR_CLASSIC => {
+                        let len = n_pixels * u64::from(pf.bits_per_pixel / 8);
+                        config.check_size(2 + len * 2, "VMware cursor")?;
+                        let mut masks = vec![0u8; len as usize * 2];
+                        stream.read_exact(&mut masks).await?;
+                        let (and_mask, xor_pixels) = masks.split_at(len as usize);
+                        VMwareCursorEncoding::classic(and_mask, xor_pixels)
+                    }
+                    VMWARE_CURSOR_ALPHA => {
+                        config.check_size(2 + n_pixels * 4, "VMware cursor")?;
+                        let mut rgba = vec![0u8; n_pixels as usize * 4];
+                        stream.read_exact(&mut rgba).await?;
+                        VMwareCursorEncoding::alpha(&rgba)
+                    }
+                    t => bail!("unknown VMware cursor type {}", t),
+                };
+                Ok(Box::new(cursor) as Box<dyn Encoding>)
+            }
+            .boxed()
+        });
+        registry.register_decoder(PointerMotionChangePseudo, |_, _, _, _| {
+            async move { Ok(Box::new(PointerMotionChangeEncoding) as Box<dyn Encoding>) }.boxed()
+        });
+        registry.register_decoder(ExtendedDesktopSizePseudo, |mut stream, _, _, _| {
+            async move {
+                let n_screens = stream.read_u8().await?;
+                let mut padding = [0u8; 3];
+                stream.read_exact(&mut padding).await?;
+
+                let mut screens = Vec::with_capacity(n_screens as usize);
+                for _ in 0..n_screens {
+                    screens.push(Screen::read_from(&mut stream).await?);
+                }
+                Ok(Box::new(ExtendedDesktopSizeEncoding::new(&screens)) as Box<dyn Encoding>)
+            }
+            .boxed()
+        });
+
+        registry
+    }
 }
 
 #[allow(dead_code)]
@@ -155,3 +1053,281 @@ struct HextileTileEncoded {
     foreground: Option<Pixel>,
     // TODO: finish this
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        jpeg_quality_level, select_encoding, tight_compact_len, ClientEncodings, CopyRectEncoding,
+        Encoding, EncodingType, RawEncoding, TightEncoding, TIGHT_MAX_DATA_LEN,
+    };
+    use crate::pixel_formats::PixelConverter;
+    use crate::rfb::{PixelFormat, Rect};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn rgb565(big_endian: bool) -> PixelFormat {
+        PixelFormat::new_colorformat(16, 16, big_endian, 11, 31, 5, 63, 0, 31)
+    }
+
+    #[test]
+    fn test_raw_transform_endian_only() {
+        let raw = RawEncoding::new(vec![0x34, 0x12, 0x78, 0x56]);
+
+        // RGB565 isn't supported by the general (RGB888) transform, so this only succeeds by
+        // swapping bytes.
+        let swapped = raw.transform(&rgb565(false), &rgb565(true));
+        assert_eq!(swapped.encode(), &vec![0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn test_crop_out_of_bounds() {
+        // A 2x2 rectangle whose buffer is one pixel short.
+        let raw = RawEncoding::new(vec![0u8; 3 * 4]);
+        assert!(raw.crop(2, Rect::new(0, 0, 2, 1), 4).is_some());
+        assert!(raw.crop(2, Rect::new(0, 1, 2, 1), 4).is_none());
+
+        // Moving the source of a copy past the edge of the coordinate space.
+        let copy = CopyRectEncoding::new(0xfff0, 0);
+        assert!(copy.crop(32, Rect::new(0, 0, 16, 16), 4).is_some());
+        assert!(copy.crop(32, Rect::new(16, 0, 16, 16), 4).is_none());
+    }
+
+    #[test]
+    fn test_tight_fill() {
+        let pf = PixelFormat::new_colorformat(32, 24, false, 16, 255, 8, 255, 0, 255);
+        // Four little-endian xRGB pixels of the same color.
+        let pixels = [0x30, 0x20, 0x10, 0x00].repeat(4);
+
+        let tight = TightEncoding::new(pixels, &pf);
+        assert_eq!(tight.encode(), &vec![0x80, 0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn test_tight_transform_own_format() {
+        let xrgb = PixelFormat::new_colorformat(32, 24, false, 16, 255, 8, 255, 0, 255);
+        let xbgr = PixelFormat::new_colorformat(32, 24, false, 0, 255, 8, 255, 16, 255);
+        // A little-endian xBGR red pixel, in a rectangle that isn't in the server's xRGB format.
+        let tight = TightEncoding::new(vec![0xff, 0x00, 0x00, 0x00], &xbgr);
+
+        let converted = tight.transform(&xrgb, &xrgb);
+        assert_eq!(
+            converted.raw_pixels(1, 1).unwrap(),
+            vec![0x00, 0x00, 0xff, 0x00]
+        );
+        assert_eq!(converted.encode(), &vec![0x80, 0xff, 0x00, 0x00]);
+
+        // The same goes for a converter built for the server's format.
+        let converted = tight.convert(&PixelConverter::new(&xrgb, &xrgb));
+        assert_eq!(
+            converted.raw_pixels(1, 1).unwrap(),
+            vec![0x00, 0x00, 0xff, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_tight_basic() {
+        use flate2::read::ZlibDecoder;
+        use std::io::Read;
+
+        let pf = PixelFormat::new_colorformat(32, 24, false, 16, 255, 8, 255, 0, 255);
+        // Alternating black and white pixels, which compress well.
+        let pixels: Vec<u8> = (0..64u8).flat_map(|i| [i % 2 * 0xff; 4]).collect();
+
+        let tight = TightEncoding::new(pixels, &pf);
+        let data = tight.encode();
+        assert_eq!(data[0], 0x01);
+
+        // The compressed length fits in a single byte here.
+        let len = data[1] as usize;
+        assert!(len < 0x80);
+        assert_eq!(data.len(), 2 + len);
+
+        let mut tpixels = Vec::new();
+        ZlibDecoder::new(&data[2..])
+            .read_to_end(&mut tpixels)
+            .unwrap();
+        let expected: Vec<u8> = (0..64u8).flat_map(|i| [i % 2 * 0xff; 3]).collect();
+        assert_eq!(tpixels, expected);
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[test]
+    fn test_tight_jpeg() {
+        let pf = PixelFormat::new_colorformat(32, 24, false, 16, 255, 8, 255, 0, 255);
+        // A 32x32 gradient, which is too colorful for basic compression to do well.
+        let pixels: Vec<u8> = (0..32 * 32)
+            .flat_map(|i| {
+                let (x, y) = ((i % 32) as u8, (i / 32) as u8);
+                [x * 8, y * 8, x * 4 + y * 4, 0]
+            })
+            .collect();
+
+        let tight = TightEncoding::with_quality(pixels.clone(), &pf, 32, Some(8));
+        let data = tight.encode();
+        assert_eq!(data[0], 0x90);
+
+        // The length is in Tight's compact form, followed by the JPEG data.
+        let (len, start) = match (data[1], data[2]) {
+            (a, _) if a & 0x80 == 0 => (a as usize, 2),
+            (a, b) if b & 0x80 == 0 => ((a & 0x7f) as usize | (b as usize) << 7, 3),
+            (a, b) => (
+                (a & 0x7f) as usize | ((b & 0x7f) as usize) << 7 | (data[3] as usize) << 14,
+                4,
+            ),
+        };
+        assert_eq!(data.len(), start + len);
+
+        let img =
+            image::load_from_memory_with_format(&data[start..], image::ImageFormat::Jpeg).unwrap();
+        assert_eq!((img.width(), img.height()), (32, 32));
+
+        // Without a quality level, or with few colors, basic compression is used.
+        let tight = TightEncoding::with_quality(pixels, &pf, 32, None);
+        assert_eq!(tight.encode()[0], 0x01);
+        let stripes: Vec<u8> = (0..32 * 32u32)
+            .flat_map(|i| [(i % 2) as u8 * 0xff; 4])
+            .collect();
+        let tight = TightEncoding::with_quality(stripes, &pf, 32, Some(8));
+        assert_eq!(tight.encode()[0], 0x01);
+    }
+
+    #[test]
+    fn test_jpeg_quality_level() {
+        let encodings: Vec<EncodingType> = [7, -23, -314]
+            .into_iter()
+            .map(|e| EncodingType::try_from(e).unwrap())
+            .collect();
+        assert_eq!(encodings[1], EncodingType::JpegQualityLevelPseudo(9));
+        assert_eq!(i32::from(encodings[1]), -23);
+        assert_eq!(jpeg_quality_level(&encodings), Some(9));
+        assert_eq!(jpeg_quality_level(&encodings[..1]), None);
+    }
+
+    #[test]
+    fn test_tight_compact_len() {
+        let mut out = Vec::new();
+        tight_compact_len(10, &mut out);
+        tight_compact_len(0x80, &mut out);
+        tight_compact_len(0x4000, &mut out);
+        assert_eq!(out, vec![10, 0x80, 0x01, 0x80, 0x80, 0x01]);
+
+        // The longest length uses all 8 bits of the last byte.
+        let mut out = Vec::new();
+        tight_compact_len(TIGHT_MAX_DATA_LEN, &mut out);
+        assert_eq!(out, vec![0xff, 0xff, 0xff]);
+        assert_eq!(TIGHT_MAX_DATA_LEN, 0x3fffff);
+    }
+
+    #[test]
+    fn test_select_encoding() {
+        let all = [
+            EncodingType::Tight,
+            EncodingType::CopyRect,
+            EncodingType::ZRLE,
+            EncodingType::Raw,
+        ];
+        let servers: [&[EncodingType]; 3] = [
+            &[EncodingType::Raw],
+            &[EncodingType::Raw, EncodingType::CopyRect],
+            &[EncodingType::Raw, EncodingType::Tight],
+        ];
+
+        for server in servers {
+            for n in 0..=all.len() {
+                let e = select_encoding(&all[n..], server);
+                assert!(server.contains(&e), "{:?} not in {:?}", e, server);
+            }
+        }
+
+        // The client's order of preference wins.
+        let server = [
+            EncodingType::Raw,
+            EncodingType::CopyRect,
+            EncodingType::Tight,
+        ];
+        assert_eq!(select_encoding(&all, &server), EncodingType::Tight);
+        assert_eq!(select_encoding(&all[1..], &server), EncodingType::CopyRect);
+    }
+
+    #[test]
+    fn test_is_pseudo() {
+        assert!(!EncodingType::Raw.is_pseudo());
+        assert_eq!(EncodingType::Raw.as_i32(), 0);
+        assert!(EncodingType::CursorPseudo.is_pseudo());
+        assert_eq!(EncodingType::CursorPseudo.as_i32(), -239);
+        assert!(EncodingType::JpegQualityLevelPseudo(9).is_pseudo());
+        assert!(!EncodingType::Other(1000).is_pseudo());
+    }
+
+    #[test]
+    fn test_vmware_encodings() {
+        assert_eq!(
+            EncodingType::try_from(0x574d5664).unwrap(),
+            EncodingType::VMwareCursorPseudo
+        );
+        assert_eq!(EncodingType::VMwareCursorPseudo.as_i32(), 0x574d5664);
+        assert!(EncodingType::VMwareCursorPseudo.is_pseudo());
+        assert_eq!(
+            EncodingType::try_from(0x574d5669).unwrap(),
+            EncodingType::VMwareDisplayModeChangePseudo
+        );
+
+        // Other numbers in VMware's range are still pseudo-encodings.
+        let unnamed = EncodingType::try_from(0x574d56ff).unwrap();
+        assert_eq!(unnamed, EncodingType::Other(0x574d56ff));
+        assert!(unnamed.is_pseudo());
+        assert!(!EncodingType::Other(0x574d5700).is_pseudo());
+    }
+
+    #[test]
+    fn test_client_encodings_partition() {
+        let list = [
+            EncodingType::CursorPseudo,
+            EncodingType::Tight,
+            EncodingType::DesktopSizePseudo,
+            EncodingType::Raw,
+        ];
+        let encodings = ClientEncodings::new(&list);
+        assert_eq!(encodings.real, vec![EncodingType::Tight, EncodingType::Raw]);
+        assert_eq!(
+            encodings.pseudo,
+            vec![EncodingType::CursorPseudo, EncodingType::DesktopSizePseudo]
+        );
+        assert!(encodings.supports(EncodingType::DesktopSizePseudo));
+        assert!(!encodings.supports(EncodingType::Tight));
+
+        // A pseudo-encoding first in the list doesn't affect the choice of real encoding.
+        let server = [EncodingType::Raw, EncodingType::CursorPseudo];
+        assert_eq!(select_encoding(&list, &server), EncodingType::Raw);
+    }
+
+    #[test]
+    fn test_raw_shared_buffers() {
+        let pixels = Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let raw = RawEncoding::from_bytes(pixels.clone());
+        assert_eq!(raw.encode(), &pixels[..]);
+        assert_eq!(raw.encode().as_ptr(), pixels.as_ptr());
+
+        let pixels: Arc<[u8]> = Arc::from(vec![9, 10, 11, 12]);
+        let raw = RawEncoding::from_arc(pixels.clone());
+        assert_eq!(raw.encode(), &pixels[..]);
+        assert_eq!(raw.encode().as_ptr(), pixels.as_ptr());
+    }
+
+    #[test]
+    fn test_raw_identity_transform_shares_pixels() {
+        let pf = rgb565(false);
+        let raw = RawEncoding::new(vec![0x12, 0x34, 0x56, 0x78]);
+
+        let same = raw.transform(&pf, &pf);
+        assert_eq!(same.encode().as_ptr(), raw.encode().as_ptr());
+
+        let converter = crate::pixel_formats::PixelConverter::new(&pf, &pf);
+        let same = raw.convert(&converter);
+        assert_eq!(same.encode().as_ptr(), raw.encode().as_ptr());
+
+        // A real conversion still produces a new buffer.
+        let swapped = raw.transform(&pf, &rgb565(true));
+        assert_ne!(swapped.encode().as_ptr(), raw.encode().as_ptr());
+    }
+}
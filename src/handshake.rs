@@ -0,0 +1,526 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! The handshake phase of the protocol as a state machine
+//!
+//! [`HandshakeState`] holds no connection. Each call to [`HandshakeState::step`] takes the next
+//! thing the client sent and returns the messages to send in response; the new state says what
+//! to read from the client next. The server drives it over a stream, and it can also be stepped
+//! directly, to test a single transition or to interleave other logic between steps.
+
+use anyhow::Result;
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::rfb::{ProtoVersion, SecurityResult, SecurityType, SecurityTypes, WriteMessage};
+
+/// Errors that end the handshake. Any messages returned alongside the error should still be sent
+/// to the client before the connection is closed.
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum HandshakeError {
+    #[error("invalid protocol version: {0:?}")]
+    InvalidVersion([u8; 12]),
+
+    #[error("unsupported client version={client:?} (server version: {server:?})")]
+    UnsupportedVersion {
+        client: (u16, u16),
+        server: ProtoVersion,
+    },
+
+    #[error("no security types are configured")]
+    NoSecurityTypes,
+
+    #[error("invalid security choice={0:?}")]
+    UnsupportedSecurityType(SecurityType),
+
+    /// The client's response to the VNC authentication challenge was rejected. No password can
+    /// be configured yet, so every response is.
+    #[error("VNC authentication failed")]
+    AuthenticationFailed,
+
+    /// The input isn't what the current state is waiting for.
+    #[error("unexpected handshake input {input} in state {state}")]
+    UnexpectedInput {
+        state: &'static str,
+        input: &'static str,
+    },
+}
+
+/// Something received from the client (or, for `Start`, the connection being opened).
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeInput {
+    Start,
+    ClientVersion([u8; 12]),
+    SecurityChoice(SecurityType),
+    VncAuthResponse([u8; 16]),
+}
+
+impl HandshakeInput {
+    fn name(&self) -> &'static str {
+        match self {
+            HandshakeInput::Start => "Start",
+            HandshakeInput::ClientVersion(_) => "ClientVersion",
+            HandshakeInput::SecurityChoice(_) => "SecurityChoice",
+            HandshakeInput::VncAuthResponse(_) => "VncAuthResponse",
+        }
+    }
+}
+
+/// A message for the server to send to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeMessage {
+    Version(ProtoVersion),
+
+    /// The security types, in the form used by `version`.
+    SecurityTypes {
+        types: SecurityTypes,
+        version: ProtoVersion,
+    },
+
+    VncAuthChallenge([u8; 16]),
+
+    /// The security result, in the form used by `version`.
+    SecurityResult {
+        result: SecurityResult,
+        version: ProtoVersion,
+    },
+}
+
+impl HandshakeMessage {
+    pub async fn write_to<S: AsyncWrite + Unpin + Send>(self, stream: &mut S) -> Result<()> {
+        match self {
+            HandshakeMessage::Version(v) => v.write_to(stream).await,
+            HandshakeMessage::SecurityTypes { types, version } => {
+                types.write_for_version(stream, version).await
+            }
+            HandshakeMessage::VncAuthChallenge(challenge) => {
+                stream.write_all(&challenge).await?;
+                Ok(stream.flush().await?)
+            }
+            HandshakeMessage::SecurityResult { result, version } => {
+                result.write_for_version(stream, version).await
+            }
+        }
+    }
+}
+
+/// The result of a step: messages to send, in order, and whether the handshake failed.
+#[derive(Debug, Default, PartialEq)]
+pub struct HandshakeOutput {
+    pub send: Vec<HandshakeMessage>,
+    pub error: Option<HandshakeError>,
+}
+
+/// Section 7.1: the server's side of the handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeState {
+    /// Nothing has been sent yet.
+    Start {
+        server_version: ProtoVersion,
+        sec_types: SecurityTypes,
+    },
+
+    /// The server's version has been sent; waiting for the client's.
+    AwaitingVersion {
+        server_version: ProtoVersion,
+        sec_types: SecurityTypes,
+    },
+
+    /// The security types have been sent; waiting for the client to choose one.
+    AwaitingSecurityChoice {
+        version: ProtoVersion,
+        sec_types: SecurityTypes,
+    },
+
+    /// The VNC authentication challenge has been sent; waiting for the response. Responses can't
+    /// be verified without a configured password, which isn't supported yet, so every response is
+    /// rejected.
+    AwaitingVncAuthResponse {
+        version: ProtoVersion,
+        challenge: [u8; 16],
+    },
+
+    /// The handshake succeeded, and the session moves on to initialization.
+    Done {
+        version: ProtoVersion,
+        security_type: SecurityType,
+    },
+
+    Failed,
+}
+
+impl HandshakeState {
+    pub fn new(server_version: ProtoVersion, sec_types: SecurityTypes) -> Self {
+        HandshakeState::Start {
+            server_version,
+            sec_types,
+        }
+    }
+
+    /// The negotiated version, once the client's version has been received.
+    pub fn version(&self) -> Option<ProtoVersion> {
+        match self {
+            HandshakeState::AwaitingSecurityChoice { version, .. }
+            | HandshakeState::AwaitingVncAuthResponse { version, .. }
+            | HandshakeState::Done { version, .. } => Some(*version),
+            _ => None,
+        }
+    }
+
+    /// The security type, once it has been selected.
+    pub fn security_type(&self) -> Option<SecurityType> {
+        match self {
+            HandshakeState::AwaitingVncAuthResponse { .. } => Some(SecurityType::VncAuthentication),
+            HandshakeState::Done { security_type, .. } => Some(security_type.clone()),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HandshakeState::Start { .. } => "Start",
+            HandshakeState::AwaitingVersion { .. } => "AwaitingVersion",
+            HandshakeState::AwaitingSecurityChoice { .. } => "AwaitingSecurityChoice",
+            HandshakeState::AwaitingVncAuthResponse { .. } => "AwaitingVncAuthResponse",
+            HandshakeState::Done { .. } => "Done",
+            HandshakeState::Failed => "Failed",
+        }
+    }
+
+    /// Advances the handshake with `input`. On error, the state becomes `Failed`.
+    pub fn step(&mut self, input: HandshakeInput) -> HandshakeOutput {
+        let state = std::mem::replace(self, HandshakeState::Failed);
+        let mut send = Vec::new();
+
+        match (state, input) {
+            (
+                HandshakeState::Start {
+                    server_version,
+                    sec_types,
+                },
+                HandshakeInput::Start,
+            ) => {
+                send.push(HandshakeMessage::Version(server_version));
+                *self = HandshakeState::AwaitingVersion {
+                    server_version,
+                    sec_types,
+                };
+            }
+
+            (
+                HandshakeState::AwaitingVersion {
+                    server_version,
+                    sec_types,
+                },
+                HandshakeInput::ClientVersion(buf),
+            ) => {
+                let client = match ProtoVersion::parse(&buf) {
+                    Some(client) => client,
+                    None => return failed(send, HandshakeError::InvalidVersion(buf)),
+                };
+                let version = match ProtoVersion::negotiate(server_version, client) {
+                    Some(version) => version,
+                    None => {
+                        let err = HandshakeError::UnsupportedVersion {
+                            client,
+                            server: server_version,
+                        };
+                        return failed(send, err);
+                    }
+                };
+
+                if sec_types.0.is_empty() {
+                    send.push(HandshakeMessage::SecurityTypes {
+                        types: sec_types,
+                        version,
+                    });
+                    return failed(send, HandshakeError::NoSecurityTypes);
+                }
+
+                if version == ProtoVersion::Rfb33 {
+                    // Version 3.3 has no negotiation: the server decides on the security type.
+                    let choice = sec_types.0[0].clone();
+                    send.push(HandshakeMessage::SecurityTypes {
+                        types: SecurityTypes(vec![choice.clone()]),
+                        version,
+                    });
+                    *self = select(version, choice, &mut send);
+                } else {
+                    send.push(HandshakeMessage::SecurityTypes {
+                        types: sec_types.clone(),
+                        version,
+                    });
+                    *self = HandshakeState::AwaitingSecurityChoice { version, sec_types };
+                }
+            }
+
+            (
+                HandshakeState::AwaitingSecurityChoice { version, sec_types },
+                HandshakeInput::SecurityChoice(choice),
+            ) => {
+                if !sec_types.contains(&choice) {
+                    send.push(HandshakeMessage::SecurityResult {
+                        result: SecurityResult::Failure("unsupported security type".to_string()),
+                        version,
+                    });
+                    return failed(send, HandshakeError::UnsupportedSecurityType(choice));
+                }
+                *self = select(version, choice, &mut send);
+            }
+
+            (
+                HandshakeState::AwaitingVncAuthResponse { version, .. },
+                HandshakeInput::VncAuthResponse(_response),
+            ) => {
+                // TODO: verify the response against the challenge encrypted with a configured
+                // password. Until then, fail closed rather than accept any response.
+                send.push(HandshakeMessage::SecurityResult {
+                    result: SecurityResult::Failure("authentication failed".to_string()),
+                    version,
+                });
+                return failed(send, HandshakeError::AuthenticationFailed);
+            }
+
+            (state, input) => {
+                let err = HandshakeError::UnexpectedInput {
+                    state: state.name(),
+                    input: input.name(),
+                };
+                return failed(send, err);
+            }
+        }
+
+        HandshakeOutput { send, error: None }
+    }
+}
+
+fn failed(send: Vec<HandshakeMessage>, error: HandshakeError) -> HandshakeOutput {
+    HandshakeOutput {
+        send,
+        error: Some(error),
+    }
+}
+
+/// Moves on from a selected security type, returning the next state.
+fn select(
+    version: ProtoVersion,
+    choice: SecurityType,
+    send: &mut Vec<HandshakeMessage>,
+) -> HandshakeState {
+    match choice {
+        SecurityType::VncAuthentication => {
+            let challenge: [u8; 16] = rand::random();
+            send.push(HandshakeMessage::VncAuthChallenge(challenge));
+            HandshakeState::AwaitingVncAuthResponse { version, challenge }
+        }
+        SecurityType::None => {
+            // Section 7.2.1: with no authentication, only version 3.8 sends a SecurityResult.
+            // Versions 3.3 and 3.7 proceed directly to initialization.
+            if version >= ProtoVersion::Rfb38 {
+                send.push(HandshakeMessage::SecurityResult {
+                    result: SecurityResult::Success,
+                    version,
+                });
+            }
+            HandshakeState::Done {
+                version,
+                security_type: SecurityType::None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HandshakeError, HandshakeInput, HandshakeMessage, HandshakeState};
+    use crate::rfb::{ProtoVersion, SecurityResult, SecurityType, SecurityTypes};
+
+    /// Returns a handshake that has sent its version and is waiting for the client's.
+    fn started(server_version: ProtoVersion, sec_types: Vec<SecurityType>) -> HandshakeState {
+        let mut hs = HandshakeState::new(server_version, SecurityTypes(sec_types));
+        let out = hs.step(HandshakeInput::Start);
+        assert_eq!(out.send, vec![HandshakeMessage::Version(server_version)]);
+        assert_eq!(out.error, None);
+        hs
+    }
+
+    #[test]
+    fn test_version_transitions() {
+        let types = vec![SecurityType::None, SecurityType::VncAuthentication];
+
+        // 3.8 offers the whole list and waits for a choice.
+        let mut hs = started(ProtoVersion::Rfb38, types.clone());
+        let out = hs.step(HandshakeInput::ClientVersion(*b"RFB 003.008\n"));
+        assert_eq!(
+            out.send,
+            vec![HandshakeMessage::SecurityTypes {
+                types: SecurityTypes(types.clone()),
+                version: ProtoVersion::Rfb38,
+            }]
+        );
+        assert!(matches!(hs, HandshakeState::AwaitingSecurityChoice { .. }));
+
+        // 3.3 sends the server's choice, and with no authentication, nothing else.
+        let mut hs = started(ProtoVersion::Rfb38, types);
+        let out = hs.step(HandshakeInput::ClientVersion(*b"RFB 003.003\n"));
+        assert_eq!(
+            out.send,
+            vec![HandshakeMessage::SecurityTypes {
+                types: SecurityTypes(vec![SecurityType::None]),
+                version: ProtoVersion::Rfb33,
+            }]
+        );
+        assert_eq!(
+            hs,
+            HandshakeState::Done {
+                version: ProtoVersion::Rfb33,
+                security_type: SecurityType::None
+            }
+        );
+
+        let mut hs = started(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let out = hs.step(HandshakeInput::ClientVersion(*b"RFB 003.002\n"));
+        assert!(out.send.is_empty());
+        assert_eq!(
+            out.error,
+            Some(HandshakeError::UnsupportedVersion {
+                client: (3, 2),
+                server: ProtoVersion::Rfb38
+            })
+        );
+        assert_eq!(hs, HandshakeState::Failed);
+
+        let mut hs = started(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let out = hs.step(HandshakeInput::ClientVersion(*b"HTTP/1.1 200"));
+        assert!(matches!(out.error, Some(HandshakeError::InvalidVersion(_))));
+
+        let mut hs = started(ProtoVersion::Rfb38, vec![]);
+        let out = hs.step(HandshakeInput::ClientVersion(*b"RFB 003.008\n"));
+        assert_eq!(out.send.len(), 1);
+        assert_eq!(out.error, Some(HandshakeError::NoSecurityTypes));
+    }
+
+    #[test]
+    fn test_security_choice_transitions() {
+        let choose = |version: &[u8; 12], choice: SecurityType| {
+            let mut hs = started(ProtoVersion::Rfb38, vec![SecurityType::None]);
+            hs.step(HandshakeInput::ClientVersion(*version));
+            let out = hs.step(HandshakeInput::SecurityChoice(choice));
+            (hs, out)
+        };
+
+        // 3.8 confirms the choice of no authentication with a SecurityResult, but 3.7 doesn't.
+        let (hs, out) = choose(b"RFB 003.008\n", SecurityType::None);
+        assert_eq!(
+            out.send,
+            vec![HandshakeMessage::SecurityResult {
+                result: SecurityResult::Success,
+                version: ProtoVersion::Rfb38,
+            }]
+        );
+        assert_eq!(hs.security_type(), Some(SecurityType::None));
+
+        let (hs, out) = choose(b"RFB 003.007\n", SecurityType::None);
+        assert!(out.send.is_empty());
+        assert!(matches!(hs, HandshakeState::Done { .. }));
+
+        // A type that wasn't offered fails, and the client is told why.
+        let (hs, out) = choose(b"RFB 003.008\n", SecurityType::VncAuthentication);
+        assert_eq!(
+            out.send,
+            vec![HandshakeMessage::SecurityResult {
+                result: SecurityResult::Failure("unsupported security type".to_string()),
+                version: ProtoVersion::Rfb38,
+            }]
+        );
+        assert_eq!(
+            out.error,
+            Some(HandshakeError::UnsupportedSecurityType(
+                SecurityType::VncAuthentication
+            ))
+        );
+        assert_eq!(hs, HandshakeState::Failed);
+    }
+
+    #[test]
+    fn test_vnc_auth_transitions() {
+        let mut hs = started(ProtoVersion::Rfb38, vec![SecurityType::VncAuthentication]);
+        hs.step(HandshakeInput::ClientVersion(*b"RFB 003.008\n"));
+
+        let out = hs.step(HandshakeInput::SecurityChoice(
+            SecurityType::VncAuthentication,
+        ));
+        let challenge = match (&out.send[..], &hs) {
+            (
+                [HandshakeMessage::VncAuthChallenge(sent)],
+                HandshakeState::AwaitingVncAuthResponse { challenge, .. },
+            ) => {
+                assert_eq!(sent, challenge);
+                *challenge
+            }
+            other => panic!("unexpected transition: {:?}", other),
+        };
+
+        assert_eq!(hs.version(), Some(ProtoVersion::Rfb38));
+        assert_eq!(hs.security_type(), Some(SecurityType::VncAuthentication));
+
+        // Without a password to check against, a response is never accepted.
+        let out = hs.step(HandshakeInput::VncAuthResponse(challenge));
+        assert_eq!(
+            out.send,
+            vec![HandshakeMessage::SecurityResult {
+                result: SecurityResult::Failure("authentication failed".to_string()),
+                version: ProtoVersion::Rfb38,
+            }]
+        );
+        assert_eq!(out.error, Some(HandshakeError::AuthenticationFailed));
+        assert_eq!(hs, HandshakeState::Failed);
+    }
+
+    #[test]
+    fn test_vnc_auth_wrong_response() {
+        for version in [ProtoVersion::Rfb33, ProtoVersion::Rfb38] {
+            let mut hs = started(version, vec![SecurityType::VncAuthentication]);
+            let out = hs.step(HandshakeInput::ClientVersion(*version.as_bytes()));
+            if version == ProtoVersion::Rfb38 {
+                hs.step(HandshakeInput::SecurityChoice(
+                    SecurityType::VncAuthentication,
+                ));
+            } else {
+                // 3.3 picks the type itself and sends the challenge right away.
+                assert!(matches!(
+                    out.send[..],
+                    [_, HandshakeMessage::VncAuthChallenge(_)]
+                ));
+            }
+
+            let out = hs.step(HandshakeInput::VncAuthResponse([0u8; 16]));
+            assert_eq!(
+                out.send,
+                vec![HandshakeMessage::SecurityResult {
+                    result: SecurityResult::Failure("authentication failed".to_string()),
+                    version,
+                }]
+            );
+            assert_eq!(out.error, Some(HandshakeError::AuthenticationFailed));
+            assert_eq!(hs.security_type(), None);
+        }
+    }
+
+    #[test]
+    fn test_unexpected_input() {
+        let mut hs = started(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let out = hs.step(HandshakeInput::SecurityChoice(SecurityType::None));
+        assert_eq!(
+            out.error,
+            Some(HandshakeError::UnexpectedInput {
+                state: "AwaitingVersion",
+                input: "SecurityChoice"
+            })
+        );
+        assert_eq!(hs, HandshakeState::Failed);
+    }
+}
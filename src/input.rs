@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Input events
+//!
+//! Clients send pointer events for every movement of the mouse, which during a drag can be far
+//! more often than a backend can process them. The [`InputQueue`] coalesces pointer motion so
+//! that only the latest position is delivered, without losing button presses, button releases,
+//! or key events.
+//!
+//! Some clients also send a key-down for every auto-repeat of a held key. A [`KeyRepeatFilter`]
+//! drops those, for backends that generate their own repeats.
+//!
+//! For backends that want to type text, [`string_to_key_events`] produces the key events a client
+//! would send for it.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::keysym::Keysym;
+use crate::rfb::{KeyEvent, MouseButtons, PointerEvent};
+
+#[derive(Debug)]
+pub enum InputEvent {
+    Key(KeyEvent),
+    Pointer(PointerEvent),
+}
+
+#[derive(Debug)]
+struct QueuedEvent {
+    event: InputEvent,
+
+    /// Whether this is a pointer event that changed the button state. These are never merged, so
+    /// that a press or release is delivered at the position where it happened.
+    transition: bool,
+}
+
+/// A queue of input events that coalesces consecutive pointer motion.
+#[derive(Debug, Default)]
+pub struct InputQueue {
+    events: VecDeque<QueuedEvent>,
+
+    /// The button state as of the most recently queued pointer event.
+    buttons: MouseButtons,
+}
+
+impl InputQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an event to the queue. A pointer event that doesn't change the button state replaces
+    /// a queued pointer event immediately preceding it that also didn't.
+    pub fn push(&mut self, event: InputEvent) {
+        let transition = match &event {
+            InputEvent::Key(_) => false,
+            InputEvent::Pointer(pe) => {
+                let transition = pe.buttons() != self.buttons;
+                self.buttons = pe.buttons();
+
+                // Relative events are motion, so merging them would lose some of it.
+                if !transition && !pe.is_relative() {
+                    if let Some(last) = self.events.back_mut() {
+                        if matches!(last.event, InputEvent::Pointer(_)) && !last.transition {
+                            last.event = event;
+                            return;
+                        }
+                    }
+                }
+
+                transition
+            }
+        };
+
+        self.events.push_back(QueuedEvent { event, transition });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Removes all queued events, in the order they were received.
+    pub fn drain(&mut self) -> impl Iterator<Item = InputEvent> + '_ {
+        self.events.drain(..).map(|q| q.event)
+    }
+}
+
+/// Identifies a key by its scan code if the client sent one, as the keysym of a held key can
+/// change with the modifier state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum HeldKey {
+    Keycode(u32),
+    Keysym(Keysym),
+}
+
+impl HeldKey {
+    fn of(ke: &KeyEvent) -> Self {
+        match ke.keycode() {
+            Some(code) => HeldKey::Keycode(code),
+            None => HeldKey::Keysym(*ke.keysym()),
+        }
+    }
+}
+
+/// Tracks which keys are held down, to drop the repeated key-down events that clients send while
+/// a key is held.
+#[derive(Debug, Default)]
+pub struct KeyRepeatFilter {
+    held: HashSet<HeldKey>,
+}
+
+impl KeyRepeatFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether `ke` should be delivered: a key-down is dropped if the key is already
+    /// held, and a key-up is always delivered.
+    ///
+    /// Without a keycode, a key released after a modifier changed may come back up with a
+    /// different keysym than it went down with, such as `A` for `a`. The other case of a letter
+    /// is released along with it, and if neither was held, every held key is forgotten, so that
+    /// the key isn't treated as held forever.
+    pub fn accept(&mut self, ke: &KeyEvent) -> bool {
+        let key = HeldKey::of(ke);
+        if ke.is_pressed() {
+            return self.held.insert(key);
+        }
+
+        let mut released = self.held.remove(&key);
+        if let HeldKey::Keysym(Keysym::Utf32(c)) = key {
+            for other in c.to_lowercase().chain(c.to_uppercase()) {
+                released |= self.held.remove(&HeldKey::Keysym(Keysym::Utf32(other)));
+            }
+        }
+        if !released {
+            self.held.clear();
+        }
+        true
+    }
+}
+
+/// Returns whether typing `c` on a US keyboard layout needs shift held.
+fn needs_shift(c: char) -> bool {
+    c.is_ascii_uppercase() || "~!@#$%^&*()_+{}|:\"<>?".contains(c)
+}
+
+/// Converts text into the key events that type it, pressing and releasing each key in turn.
+/// Uppercase letters and shifted symbols are wrapped in a press and release of the left shift
+/// key, as on a US keyboard layout. Newlines and tabs become Return and Tab, and any other
+/// character is sent as its Unicode keysym.
+pub fn string_to_key_events(text: &str) -> Vec<KeyEvent> {
+    let mut events = Vec::new();
+
+    for c in text.chars() {
+        let key = match c {
+            '\n' => Keysym::ReturnOrEnter,
+            '\t' => Keysym::Tab,
+            c => Keysym::Utf32(c),
+        };
+        let shift = needs_shift(c);
+
+        if shift {
+            events.push(KeyEvent::new(Keysym::ShiftLeft, true));
+        }
+        events.push(KeyEvent::new(key, true));
+        events.push(KeyEvent::new(key, false));
+        if shift {
+            events.push(KeyEvent::new(Keysym::ShiftLeft, false));
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{string_to_key_events, InputEvent, InputQueue, KeyRepeatFilter};
+    use crate::keysym::Keysym;
+    use crate::rfb::{KeyEvent, MouseButtons, PointerEvent};
+
+    fn pointer(x: u16, buttons: MouseButtons) -> InputEvent {
+        InputEvent::Pointer(PointerEvent::new(x, 0, buttons))
+    }
+
+    #[test]
+    fn test_coalesce_keeps_button_transition() {
+        let mut queue = InputQueue::new();
+
+        for x in 0..50 {
+            queue.push(pointer(x, MouseButtons::empty()));
+        }
+        queue.push(pointer(50, MouseButtons::LEFT));
+        for x in 51..100 {
+            queue.push(pointer(x, MouseButtons::LEFT));
+        }
+
+        let events: Vec<(u16, MouseButtons)> = queue
+            .drain()
+            .map(|e| match e {
+                InputEvent::Pointer(pe) => (pe.position().x(), pe.buttons()),
+                InputEvent::Key(_) => panic!("unexpected key event"),
+            })
+            .collect();
+
+        // Motion is merged, but the press is delivered where it happened.
+        assert_eq!(
+            events,
+            vec![
+                (49, MouseButtons::empty()),
+                (50, MouseButtons::LEFT),
+                (99, MouseButtons::LEFT),
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_key_repeat_suppressed() {
+        let mut filter = KeyRepeatFilter::new();
+        let key = Keysym::Utf32('a');
+
+        let events = [
+            KeyEvent::new(key, true),
+            KeyEvent::new(key, true),
+            KeyEvent::new(key, false),
+        ];
+        let delivered: Vec<bool> = events
+            .iter()
+            .filter(|ke| filter.accept(ke))
+            .map(|ke| ke.is_pressed())
+            .collect();
+        assert_eq!(delivered, vec![true, false]);
+
+        // Once released, the next press is delivered again.
+        assert!(filter.accept(&KeyEvent::new(key, true)));
+    }
+
+    #[test]
+    fn test_key_repeat_shift_changed() {
+        let mut filter = KeyRepeatFilter::new();
+        let lower = Keysym::Utf32('a');
+        let upper = Keysym::Utf32('A');
+
+        // The key goes down as `a` and, with shift pressed meanwhile, comes up as `A`.
+        assert!(filter.accept(&KeyEvent::new(lower, true)));
+        assert!(filter.accept(&KeyEvent::new(Keysym::ShiftLeft, true)));
+        assert!(filter.accept(&KeyEvent::new(upper, false)));
+        assert!(filter.accept(&KeyEvent::new(Keysym::ShiftLeft, false)));
+        assert!(filter.accept(&KeyEvent::new(lower, true)));
+        assert!(filter.accept(&KeyEvent::new(lower, false)));
+
+        // A release that matches no held key, as `!` for `1`, forgets every held key.
+        assert!(filter.accept(&KeyEvent::new(Keysym::Utf32('1'), true)));
+        assert!(filter.accept(&KeyEvent::new(Keysym::Utf32('!'), false)));
+        assert!(filter.accept(&KeyEvent::new(Keysym::Utf32('1'), true)));
+    }
+
+    #[test]
+    fn test_string_to_key_events() {
+        let events: Vec<(Keysym, bool)> = string_to_key_events("Hi!")
+            .iter()
+            .map(|ke| (*ke.keysym(), ke.is_pressed()))
+            .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                (Keysym::ShiftLeft, true),
+                (Keysym::Utf32('H'), true),
+                (Keysym::Utf32('H'), false),
+                (Keysym::ShiftLeft, false),
+                (Keysym::Utf32('i'), true),
+                (Keysym::Utf32('i'), false),
+                (Keysym::ShiftLeft, true),
+                (Keysym::Utf32('!'), true),
+                (Keysym::Utf32('!'), false),
+                (Keysym::ShiftLeft, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_string_to_key_events_unicode() {
+        // Characters outside Latin-1 are sent as Unicode keysyms.
+        let keysyms: Vec<u32> = string_to_key_events("é€")
+            .iter()
+            .filter(|ke| ke.is_pressed())
+            .map(|ke| u32::from(*ke.keysym()))
+            .collect();
+        assert_eq!(keysyms, vec![0xe9, 0x0100_20ac]);
+    }
+}
@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Stream utilities
+//!
+//! Messages are parsed one field at a time (`read_u8`, `read_u16`, and so on), so reading directly
+//! from a socket issues a read syscall for every field. For example, parsing a SetPixelFormat
+//! message takes 13 reads from the underlying stream. Wrapping the stream in a buffered reader
+//! reduces that to a single read in the common case where the whole message has arrived.
+//!
+//! Writes pass through the buffered reader unchanged, so the wrapped stream can be used for the
+//! whole connection.
+//!
+//! [`Counted`] keeps running totals of the bytes read from and written to a stream, for
+//! embedders that want to track traffic per connection.
+//!
+//! With the `websocket` feature, [`WsWrap`] adapts a WebSocket connection, such as one from
+//! noVNC, into a byte stream that the server can use like a socket.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+/// The default size of the read buffer, which comfortably fits any fixed-size client message.
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 4096;
+
+/// Wraps a stream in a reader with a buffer of the default size.
+pub fn buffered_reader<S: AsyncRead>(stream: S) -> BufReader<S> {
+    buffered_reader_with_capacity(DEFAULT_READ_BUFFER_SIZE, stream)
+}
+
+/// Wraps a stream in a reader with a buffer of the given size.
+pub fn buffered_reader_with_capacity<S: AsyncRead>(capacity: usize, stream: S) -> BufReader<S> {
+    BufReader::with_capacity(capacity, stream)
+}
+
+/// Running totals of the bytes read from and written to a connection. Cloning the stats shares
+/// the same counters, so they can be read while the connection is in use.
+#[derive(Debug, Clone, Default)]
+pub struct SessionStats {
+    read: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl SessionStats {
+    pub fn read_bytes(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written_bytes(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+/// Wraps a stream, counting the bytes read from and written to it.
+pub struct Counted<S> {
+    stream: S,
+    stats: SessionStats,
+}
+
+impl<S> Counted<S> {
+    pub fn new(stream: S) -> Self {
+        Counted {
+            stream,
+            stats: SessionStats::default(),
+        }
+    }
+
+    pub fn read_bytes(&self) -> u64 {
+        self.stats.read_bytes()
+    }
+
+    pub fn written_bytes(&self) -> u64 {
+        self.stats.written_bytes()
+    }
+
+    /// Returns a handle to the counters that remains valid after the stream is moved.
+    pub fn stats(&self) -> SessionStats {
+        self.stats.clone()
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.stream).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        self.stats.read.fetch_add(n as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.stats.written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Wraps a WebSocket connection as a byte stream. The payloads of binary messages are read in
+/// order, and writes are sent as binary messages.
+///
+/// Pings are answered with pongs, and a close message is treated as the end of the stream.
+/// Text messages aren't part of the protocol, so reading one is an error.
+#[cfg(feature = "websocket")]
+pub struct WsWrap<S> {
+    ws: tokio_tungstenite::WebSocketStream<S>,
+
+    /// The unread part of the last binary message.
+    buf: Vec<u8>,
+    pos: usize,
+    closed: bool,
+}
+
+#[cfg(feature = "websocket")]
+impl<S> WsWrap<S> {
+    pub fn new(ws: tokio_tungstenite::WebSocketStream<S>) -> Self {
+        WsWrap {
+            ws,
+            buf: Vec::new(),
+            pos: 0,
+            closed: false,
+        }
+    }
+
+    pub fn into_inner(self) -> tokio_tungstenite::WebSocketStream<S> {
+        self.ws
+    }
+}
+
+#[cfg(feature = "websocket")]
+fn ws_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    match e {
+        tokio_tungstenite::tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsWrap<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        use futures::{SinkExt, StreamExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        loop {
+            if self.pos < self.buf.len() {
+                let n = buf.remaining().min(self.buf.len() - self.pos);
+                let start = self.pos;
+                buf.put_slice(&self.buf[start..start + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if self.closed {
+                return Poll::Ready(Ok(()));
+            }
+
+            let msg = match self.ws.poll_next_unpin(cx) {
+                Poll::Ready(Some(msg)) => msg.map_err(ws_error)?,
+                Poll::Ready(None) => {
+                    self.closed = true;
+                    continue;
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            match msg {
+                Message::Binary(data) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Message::Ping(_) => {
+                    // The pong is queued when the ping is read; flush it now rather than waiting
+                    // for the next write.
+                    if let Poll::Ready(Err(e)) = self.ws.poll_flush_unpin(cx) {
+                        return Poll::Ready(Err(ws_error(e)));
+                    }
+                }
+                Message::Close(_) => self.closed = true,
+                Message::Pong(_) | Message::Frame(_) => {}
+                Message::Text(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected WebSocket text message",
+                    )));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsWrap<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        match self.ws.poll_ready_unpin(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            Poll::Pending => return Poll::Pending,
+        }
+        self.ws
+            .start_send_unpin(Message::Binary(buf.to_vec()))
+            .map_err(ws_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Messages queued by `poll_write` may sit in the WebSocket's write buffer; flushing the sink
+    /// writes them out and flushes the underlying stream.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        use futures::SinkExt;
+        self.ws.poll_flush_unpin(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        use futures::SinkExt;
+        self.ws.poll_close_unpin(cx).map_err(ws_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::buffered_reader;
+    use crate::rfb::{ClientMessage, ReadMessage};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    /// A reader over a fixed buffer that counts how many times it is polled.
+    struct CountingReader {
+        data: Vec<u8>,
+        pos: usize,
+        polls: usize,
+    }
+
+    impl CountingReader {
+        fn new(data: Vec<u8>) -> Self {
+            Self {
+                data,
+                pos: 0,
+                polls: 0,
+            }
+        }
+    }
+
+    impl AsyncRead for CountingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            self.polls += 1;
+
+            let n = buf.remaining().min(self.data.len() - self.pos);
+            let start = self.pos;
+            buf.put_slice(&self.data[start..start + n]);
+            self.pos += n;
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn set_pixel_format_msg() -> Vec<u8> {
+        #[rustfmt::skip]
+        let msg = vec![
+            0, 0, 0, 0,         // type, padding
+            32, 24, 0, 1,       // bpp, depth, big endian, true color
+            0, 255, 0, 255,     // red max, green max
+            0, 255, 16, 8,      // blue max, red shift, green shift
+            0, 0, 0, 0,         // blue shift, padding
+        ];
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_unbuffered_reads() {
+        let mut reader = CountingReader::new(set_pixel_format_msg());
+
+        let msg = ClientMessage::read_from(&mut reader).await.unwrap();
+        assert!(matches!(msg, ClientMessage::SetPixelFormat(_)));
+        assert_eq!(reader.polls, 13);
+    }
+
+    #[tokio::test]
+    async fn test_buffered_reads() {
+        let mut reader = buffered_reader(CountingReader::new(set_pixel_format_msg()));
+
+        let msg = ClientMessage::read_from(&mut reader).await.unwrap();
+        assert!(matches!(msg, ClientMessage::SetPixelFormat(_)));
+        assert_eq!(reader.get_ref().polls, 1);
+    }
+
+    #[cfg(feature = "websocket")]
+    #[tokio::test]
+    async fn test_ws_wrap_ping_between_binary() {
+        use super::WsWrap;
+        use futures::{SinkExt, StreamExt};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_tungstenite::tungstenite::protocol::Role;
+        use tokio_tungstenite::tungstenite::Message;
+        use tokio_tungstenite::WebSocketStream;
+
+        let (server, client) = tokio::io::duplex(1024);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server = WsWrap::new(server);
+
+        client.send(Message::Binary(vec![1, 2])).await.unwrap();
+        client.send(Message::Ping(vec![9])).await.unwrap();
+        client.send(Message::Binary(vec![3])).await.unwrap();
+
+        // The ping doesn't appear in the data, and is answered.
+        let mut buf = [0u8; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Pong(vec![9])
+        );
+
+        server.write_all(&[4, 5]).await.unwrap();
+        server.flush().await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Binary(vec![4, 5])
+        );
+
+        // Text is rejected, and closing is the end of the stream.
+        client.send(Message::Text("hi".to_string())).await.unwrap();
+        let err = server.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        client.send(Message::Close(None)).await.unwrap();
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
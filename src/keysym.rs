@@ -6,7 +6,7 @@
 
 use Keysym::*;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Keysym {
     Unknown(u32),
     Utf32(char),
@@ -35,13 +35,17 @@ pub enum Keysym {
     AltRight,
 }
 
+const XK_F1: u32 = 0xffbe;
+const XK_F12: u32 = 0xffc9;
+
+/// Keysyms for Unicode characters outside Latin-1 are the code point with this bit set. Latin-1
+/// characters are their own keysyms.
+const UNICODE_KEYSYM: u32 = 0x0100_0000;
+
 impl TryFrom<u32> for Keysym {
     type Error = anyhow::Error;
 
     fn try_from(value: u32) -> Result<Self, Self::Error> {
-        const XK_F1: u32 = 0xffbe;
-        const XK_F12: u32 = 0xffc9;
-
         match value {
             0xff08 => Ok(Backspace),
             0xff09 => Ok(Tab),
@@ -57,7 +61,7 @@ impl TryFrom<u32> for Keysym {
             0xff52 => Ok(Up),
             0xff53 => Ok(Right),
             0xff54 => Ok(Down),
-            f if (f >= XK_F1 && f <= XK_F12) => {
+            f if (XK_F1..=XK_F12).contains(&f) => {
                 let n = f - XK_F1 + 1;
                 // TODO: handle cast
                 Ok(FunctionKey(n as u8))
@@ -71,6 +75,16 @@ impl TryFrom<u32> for Keysym {
             0xffe9 => Ok(AltLeft),
             0xffea => Ok(AltRight),
 
+            u if (UNICODE_KEYSYM + 0x100..=UNICODE_KEYSYM + 0x10_ffff).contains(&u) => {
+                match char::from_u32(u - UNICODE_KEYSYM) {
+                    Some(c) => Ok(Utf32(c)),
+                    None => Ok(Unknown(u)),
+                }
+            }
+
+            // Other keysyms above Latin-1 aren't code points.
+            other if other > 0xff => Ok(Unknown(other)),
+
             // TODO: figure out if there's a better way to map codes
             other => {
                 let c = char::from_u32(other);
@@ -83,3 +97,185 @@ impl TryFrom<u32> for Keysym {
         }
     }
 }
+
+impl From<Keysym> for u32 {
+    fn from(keysym: Keysym) -> Self {
+        match keysym {
+            Unknown(v) => v,
+            Utf32(c) if (c as u32) <= 0xff => c as u32,
+            Utf32(c) => UNICODE_KEYSYM | c as u32,
+            Backspace => 0xff08,
+            Tab => 0xff09,
+            ReturnOrEnter => 0xff0d,
+            Escape => 0xff1b,
+            Insert => 0xff63,
+            Delete => 0xffff,
+            Home => 0xff50,
+            End => 0xff57,
+            PageUp => 0xff55,
+            PageDown => 0xff56,
+            Left => 0xff51,
+            Up => 0xff52,
+            Right => 0xff53,
+            Down => 0xff54,
+            FunctionKey(n) => XK_F1 + u32::from(n) - 1,
+            ShiftLeft => 0xffe1,
+            ShiftRight => 0xffe2,
+            ControlLeft => 0xffe3,
+            ControlRight => 0xffe4,
+            MetaLeft => 0xffe7,
+            MetaRight => 0xffe8,
+            AltLeft => 0xffe9,
+            AltRight => 0xffea,
+        }
+    }
+}
+
+#[cfg(feature = "linux-input")]
+impl Keysym {
+    /// Returns the Linux input event code (`KEY_*` from `linux/input-event-codes.h`) for the
+    /// physical key that produces this keysym on a US layout, for feeding events to a `uinput`
+    /// device. Shifted characters map to the same key as their unshifted counterparts, so the
+    /// caller is responsible for the shift state.
+    pub fn to_evdev_code(&self) -> Option<u16> {
+        let code = match self {
+            Unknown(_) => return None,
+            Utf32(c) => return char_to_evdev_code(*c),
+            Backspace => 14,
+            Tab => 15,
+            ReturnOrEnter => 28,
+            Escape => 1,
+            Insert => 110,
+            Delete => 111,
+            Home => 102,
+            End => 107,
+            PageUp => 104,
+            PageDown => 109,
+            Left => 105,
+            Up => 103,
+            Right => 106,
+            Down => 108,
+            // KEY_F1 through KEY_F10 are contiguous, but KEY_F11 and KEY_F12 are not.
+            FunctionKey(n @ 1..=10) => 58 + *n as u16,
+            FunctionKey(11) => 87,
+            FunctionKey(12) => 88,
+            FunctionKey(_) => return None,
+            ShiftLeft => 42,
+            ShiftRight => 54,
+            ControlLeft => 29,
+            ControlRight => 97,
+            MetaLeft => 125,
+            MetaRight => 126,
+            AltLeft => 56,
+            AltRight => 100,
+        };
+
+        Some(code)
+    }
+}
+
+#[cfg(feature = "linux-input")]
+fn char_to_evdev_code(c: char) -> Option<u16> {
+    let code = match c.to_ascii_lowercase() {
+        '1' | '!' => 2,
+        '2' | '@' => 3,
+        '3' | '#' => 4,
+        '4' | '$' => 5,
+        '5' | '%' => 6,
+        '6' | '^' => 7,
+        '7' | '&' => 8,
+        '8' | '*' => 9,
+        '9' | '(' => 10,
+        '0' | ')' => 11,
+        '-' | '_' => 12,
+        '=' | '+' => 13,
+        'q' => 16,
+        'w' => 17,
+        'e' => 18,
+        'r' => 19,
+        't' => 20,
+        'y' => 21,
+        'u' => 22,
+        'i' => 23,
+        'o' => 24,
+        'p' => 25,
+        '[' | '{' => 26,
+        ']' | '}' => 27,
+        'a' => 30,
+        's' => 31,
+        'd' => 32,
+        'f' => 33,
+        'g' => 34,
+        'h' => 35,
+        'j' => 36,
+        'k' => 37,
+        'l' => 38,
+        ';' | ':' => 39,
+        '\'' | '"' => 40,
+        '`' | '~' => 41,
+        '\\' | '|' => 43,
+        'z' => 44,
+        'x' => 45,
+        'c' => 46,
+        'v' => 47,
+        'b' => 48,
+        'n' => 49,
+        'm' => 50,
+        ',' | '<' => 51,
+        '.' | '>' => 52,
+        '/' | '?' => 53,
+        ' ' => 57,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Keysym;
+
+    #[cfg(feature = "linux-input")]
+    fn evdev_code(keysym: u32) -> Option<u16> {
+        Keysym::try_from(keysym).unwrap().to_evdev_code()
+    }
+
+    #[cfg(feature = "linux-input")]
+    #[test]
+    fn test_to_evdev_code() {
+        // KEY_A, for both cases
+        assert_eq!(evdev_code('a' as u32), Some(30));
+        assert_eq!(evdev_code('A' as u32), Some(30));
+        // KEY_0
+        assert_eq!(evdev_code('0' as u32), Some(11));
+        // KEY_ENTER
+        assert_eq!(evdev_code(0xff0d), Some(28));
+        // KEY_F1, KEY_F10, KEY_F12
+        assert_eq!(evdev_code(0xffbe), Some(59));
+        assert_eq!(evdev_code(0xffc7), Some(68));
+        assert_eq!(evdev_code(0xffc9), Some(88));
+        // KEY_LEFTSHIFT, KEY_RIGHTALT
+        assert_eq!(evdev_code(0xffe1), Some(42));
+        assert_eq!(evdev_code(0xffea), Some(100));
+        // KEY_UP
+        assert_eq!(evdev_code(0xff52), Some(103));
+
+        assert_eq!(evdev_code('é' as u32), None);
+    }
+
+    #[test]
+    fn test_unicode_keysyms() {
+        // Latin-1 characters are their own keysyms.
+        assert_eq!(u32::from(Keysym::Utf32('a')), 0x61);
+        assert_eq!(u32::from(Keysym::Utf32('é')), 0xe9);
+        assert_eq!(Keysym::try_from(0xe9).unwrap(), Keysym::Utf32('é'));
+
+        // Others are in the Unicode keysym range.
+        assert_eq!(u32::from(Keysym::Utf32('€')), 0x0100_20ac);
+        assert_eq!(u32::from(Keysym::Utf32('→')), 0x0100_2192);
+        assert_eq!(Keysym::try_from(0x0100_2192).unwrap(), Keysym::Utf32('→'));
+
+        // Legacy keysyms above Latin-1 aren't code points.
+        assert_eq!(Keysym::try_from(0x1a1).unwrap(), Keysym::Unknown(0x1a1));
+    }
+}
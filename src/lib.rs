@@ -4,8 +4,24 @@
 //
 // Copyright 2022 Oxide Computer Company
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 pub mod encodings;
-mod keysym;
+#[cfg(feature = "std")]
+pub mod handshake;
+#[cfg(feature = "std")]
+pub mod input;
+#[cfg(feature = "std")]
+pub mod io;
+#[cfg(feature = "std")]
+pub mod keysym;
+#[cfg(feature = "std")]
 pub mod pixel_formats;
+#[cfg(feature = "std")]
+pub mod record;
+#[cfg(feature = "std")]
 pub mod rfb;
+#[cfg(feature = "std")]
 pub mod server;
+pub mod wire;
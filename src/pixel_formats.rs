@@ -73,6 +73,7 @@ pub mod fourcc {
     pub const FOURCC_BX24: u32 = 0x34325842; // little-endian BGRx, 8:8:8:8
     pub const FOURCC_XB24: u32 = 0x34324258; // little-endian xBGR, 8:8:8:8
 
+    #[allow(clippy::identity_op, clippy::erasing_op)]
     pub fn fourcc_to_pixel_format(fourcc: u32) -> Result<PixelFormat> {
         match fourcc {
             // little-endian xRGB
@@ -140,9 +141,220 @@ pub mod fourcc {
     }
 }
 
+use crate::rfb::PixelFormat;
+
+/// Reverses the byte order of each pixel, converting between the little-endian and big-endian
+/// variants of an otherwise identical pixel format.
+pub fn swap_bytes(pixels: &[u8], bytes_per_pixel: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(pixels.len());
+
+    match bytes_per_pixel {
+        4 => {
+            for p in pixels.chunks_exact(4) {
+                let v = u32::from_ne_bytes([p[0], p[1], p[2], p[3]]).swap_bytes();
+                buf.extend_from_slice(&v.to_ne_bytes());
+            }
+        }
+        2 => {
+            for p in pixels.chunks_exact(2) {
+                let v = u16::from_ne_bytes([p[0], p[1]]).swap_bytes();
+                buf.extend_from_slice(&v.to_ne_bytes());
+            }
+        }
+        _ => {
+            for p in pixels.chunks_exact(bytes_per_pixel) {
+                buf.extend(p.iter().rev());
+            }
+        }
+    }
+
+    buf
+}
+
+/// Converts pixels from one pixel format to another.
+///
+/// Building a converter works out up front how the bytes of each pixel map between the two
+/// formats, so a converter can be kept and reused for every frame until either format changes.
+#[derive(Debug, Clone)]
+pub struct PixelConverter {
+    input: PixelFormat,
+    output: PixelFormat,
+    plan: ConversionPlan,
+}
+
+#[derive(Debug, Clone)]
+enum ConversionPlan {
+    Identity,
+    SwapBytes(usize),
+    Rgb888 {
+        input: (usize, usize, usize, usize),
+        output: (usize, usize, usize, usize),
+    },
+    PackRgb888 {
+        input: (usize, usize, usize, usize),
+        output: (usize, usize, usize),
+    },
+    TrueColor {
+        input: PixelLayout,
+        output: PixelLayout,
+    },
+    Unsupported,
+}
+
+/// Where each color is in a true-color pixel, for converting between arbitrary formats.
+#[derive(Debug, Clone)]
+struct PixelLayout {
+    bytes_per_pixel: usize,
+    big_endian: bool,
+
+    /// (shift, max) of red, green and blue.
+    channels: [(u8, u32); 3],
+}
+
+impl PixelLayout {
+    fn new(pf: &PixelFormat) -> Option<Self> {
+        let cf = pf.color_format()?;
+        if !matches!(pf.bits_per_pixel, 8 | 16 | 24 | 32) {
+            return None;
+        }
+        let channels = [
+            (cf.red_shift, u32::from(cf.red_max)),
+            (cf.green_shift, u32::from(cf.green_max)),
+            (cf.blue_shift, u32::from(cf.blue_max)),
+        ];
+        if channels.iter().any(|&(shift, max)| max == 0 || shift >= 32) {
+            return None;
+        }
+
+        Some(PixelLayout {
+            bytes_per_pixel: (pf.bits_per_pixel / 8) as usize,
+            big_endian: pf.big_endian,
+            channels,
+        })
+    }
+
+    fn read(&self, bytes: &[u8]) -> u32 {
+        let mut buf = [0u8; 4];
+        if self.big_endian {
+            buf[4 - self.bytes_per_pixel..].copy_from_slice(bytes);
+            u32::from_be_bytes(buf)
+        } else {
+            buf[..self.bytes_per_pixel].copy_from_slice(bytes);
+            u32::from_le_bytes(buf)
+        }
+    }
+
+    fn write(&self, value: u32, out: &mut Vec<u8>) {
+        if self.big_endian {
+            out.extend_from_slice(&value.to_be_bytes()[4 - self.bytes_per_pixel..]);
+        } else {
+            out.extend_from_slice(&value.to_le_bytes()[..self.bytes_per_pixel]);
+        }
+    }
+}
+
+/// Converts between any two true-color formats by rescaling each color to the output's range.
+fn convert_true_color(pixels: &[u8], input: &PixelLayout, output: &PixelLayout) -> Vec<u8> {
+    let n = pixels.len() / input.bytes_per_pixel;
+    let mut buf = Vec::with_capacity(n * output.bytes_per_pixel);
+
+    for bytes in pixels.chunks_exact(input.bytes_per_pixel) {
+        let value = input.read(bytes);
+        let mut out = 0u32;
+        for (&(in_shift, in_max), &(out_shift, out_max)) in
+            input.channels.iter().zip(output.channels.iter())
+        {
+            let c = u64::from((value >> in_shift) & in_max);
+            let c = (c * u64::from(out_max) + u64::from(in_max) / 2) / u64::from(in_max);
+            out |= (c as u32) << out_shift;
+        }
+        output.write(out, &mut buf);
+    }
+
+    buf
+}
+
+impl PixelConverter {
+    pub fn new(input: &PixelFormat, output: &PixelFormat) -> Self {
+        let plan = if input.canonical() == output.canonical() {
+            ConversionPlan::Identity
+        } else if input.differs_only_in_endianness(output) {
+            ConversionPlan::SwapBytes((input.bits_per_pixel / 8) as usize)
+        } else if input.is_rgb_888() && output.is_rgb_888() {
+            ConversionPlan::Rgb888 {
+                input: rgb_888::format_index(input),
+                output: rgb_888::format_index(output),
+            }
+        } else if input.is_rgb_888() && rgb_888_packed::is_packed(output) {
+            ConversionPlan::PackRgb888 {
+                input: rgb_888::format_index(input),
+                output: rgb_888_packed::format_index(output),
+            }
+        } else if let (Some(input), Some(output)) =
+            (PixelLayout::new(input), PixelLayout::new(output))
+        {
+            ConversionPlan::TrueColor { input, output }
+        } else {
+            ConversionPlan::Unsupported
+        };
+
+        PixelConverter {
+            input: input.clone(),
+            output: output.clone(),
+            plan,
+        }
+    }
+
+    pub fn input(&self) -> &PixelFormat {
+        &self.input
+    }
+
+    pub fn output(&self) -> &PixelFormat {
+        &self.output
+    }
+
+    /// Returns true if the input and output formats lay out pixels the same way, so pixels can
+    /// be sent as is. The formats themselves may differ; see `PixelFormat::canonical`.
+    pub fn is_identity(&self) -> bool {
+        matches!(self.plan, ConversionPlan::Identity)
+    }
+
+    /// Returns true if pixels can be converted between the two formats.
+    ///
+    /// Conversions between true-color formats of 8, 16, 24 or 32 bits per pixel are supported,
+    /// with faster paths for formats that differ only in endianness, between 4-byte RGB formats,
+    /// and from 4-byte to 3-byte RGB formats. Color map formats aren't supported.
+    pub fn is_supported(&self) -> bool {
+        !matches!(self.plan, ConversionPlan::Unsupported)
+    }
+
+    /// Converts pixels from the input format to the output format.
+    ///
+    /// Panics if the conversion isn't supported; see `is_supported`.
+    pub fn convert(&self, pixels: &[u8]) -> Vec<u8> {
+        match &self.plan {
+            ConversionPlan::Identity => pixels.to_vec(),
+            ConversionPlan::SwapBytes(bytes_per_pixel) => swap_bytes(pixels, *bytes_per_pixel),
+            ConversionPlan::Rgb888 { input, output } => {
+                rgb_888::transform_indexed(pixels, *input, *output)
+            }
+            ConversionPlan::PackRgb888 { input, output } => {
+                rgb_888_packed::pack_indexed(pixels, *input, *output)
+            }
+            ConversionPlan::TrueColor { input, output } => {
+                convert_true_color(pixels, input, output)
+            }
+            ConversionPlan::Unsupported => panic!(
+                "unsupported pixel format conversion: input={:?}, output={:?}",
+                self.input, self.output
+            ),
+        }
+    }
+}
+
 /// Utility functions for 32-bit RGB pixel formats, with 8-bits used per color.
 pub mod rgb_888 {
-    use crate::rfb::{ColorSpecification, PixelFormat};
+    use crate::rfb::PixelFormat;
 
     pub const BYTES_PER_PIXEL: usize = 4;
     pub const BITS_PER_PIXEL: u8 = 32;
@@ -196,38 +408,28 @@ pub mod rgb_888 {
         (r, g, b, x)
     }
 
-    /// Translate between RGB888 formats. The input and output format must both be RGB888.
-    pub fn transform(pixels: &Vec<u8>, input: &PixelFormat, output: &PixelFormat) -> Vec<u8> {
-        assert!(input.is_rgb_888());
-        assert!(output.is_rgb_888());
+    /// Returns the byte indices of each color (and the unused byte) for an RGB888 pixel format.
+    pub fn format_index(pf: &PixelFormat) -> (usize, usize, usize, usize) {
+        assert!(pf.is_rgb_888());
 
-        //let mut buf = Vec::with_capacity(pixels.len());
-        //buf.resize(pixels.len(), 0x0u8);
-        let mut buf = vec![0; pixels.len()];
+        // RGB888 formats are true color.
+        let cf = pf.color_format().unwrap();
+        rgbx_index(cf.red_shift, cf.green_shift, cf.blue_shift, pf.big_endian)
+    }
 
-        let (ir, ig, ib, ix) = match &input.color_spec {
-            ColorSpecification::ColorFormat(cf) => rgbx_index(
-                cf.red_shift,
-                cf.green_shift,
-                cf.blue_shift,
-                input.big_endian,
-            ),
-            ColorSpecification::ColorMap(_) => {
-                unreachable!();
-            }
-        };
+    /// Translate between RGB888 formats. The input and output format must both be RGB888.
+    pub fn transform(pixels: &[u8], input: &PixelFormat, output: &PixelFormat) -> Vec<u8> {
+        transform_indexed(pixels, format_index(input), format_index(output))
+    }
 
-        let (or, og, ob, ox) = match &output.color_spec {
-            ColorSpecification::ColorFormat(cf) => rgbx_index(
-                cf.red_shift,
-                cf.green_shift,
-                cf.blue_shift,
-                output.big_endian,
-            ),
-            ColorSpecification::ColorMap(_) => {
-                unreachable!();
-            }
-        };
+    /// Translate between RGB888 formats whose byte indices have already been computed with
+    /// `format_index`.
+    pub fn transform_indexed(
+        pixels: &[u8],
+        (ir, ig, ib, ix): (usize, usize, usize, usize),
+        (or, og, ob, ox): (usize, usize, usize, usize),
+    ) -> Vec<u8> {
+        let mut buf = vec![0; pixels.len()];
 
         let mut i = 0;
         while i < pixels.len() {
@@ -250,11 +452,91 @@ pub mod rgb_888 {
     }
 }
 
+/// Utility functions for 24-bit RGB pixel formats, with 8 bits used per color and no unused byte.
+/// Some clients use these to save bandwidth over the 32-bit formats in [`rgb_888`].
+pub mod rgb_888_packed {
+    use crate::rfb::PixelFormat;
+
+    pub const BYTES_PER_PIXEL: usize = 3;
+    pub const BITS_PER_PIXEL: u8 = 24;
+
+    /// Number of bits used for color in a pixel
+    pub const DEPTH: u8 = 24;
+
+    /// Returns the pixel format with red, green and blue bytes in that order in memory.
+    pub fn rgb() -> PixelFormat {
+        PixelFormat::new_colorformat(BITS_PER_PIXEL, DEPTH, true, 16, 255, 8, 255, 0, 255)
+    }
+
+    /// Returns true if `pf` is a 24-bit format with each color in its own byte.
+    pub fn is_packed(pf: &PixelFormat) -> bool {
+        if pf.bits_per_pixel != BITS_PER_PIXEL || pf.depth != DEPTH {
+            return false;
+        }
+
+        match pf.color_format() {
+            Some(cf) => {
+                let shifts = [cf.red_shift, cf.green_shift, cf.blue_shift];
+                [cf.red_max, cf.green_max, cf.blue_max]
+                    .iter()
+                    .all(|&max| max == super::rgb_888::MAX_VALUE)
+                    && shifts.iter().all(|s| matches!(s, 0 | 8 | 16))
+                    && shifts[0] != shifts[1]
+                    && shifts[0] != shifts[2]
+                    && shifts[1] != shifts[2]
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the byte indices of red, green and blue in a pixel of a packed format.
+    pub fn format_index(pf: &PixelFormat) -> (usize, usize, usize) {
+        assert!(is_packed(pf));
+
+        let cf = pf.color_format().unwrap();
+        let index = |shift: u8| {
+            let i = (shift / 8) as usize;
+            if pf.big_endian {
+                BYTES_PER_PIXEL - 1 - i
+            } else {
+                i
+            }
+        };
+        (
+            index(cf.red_shift),
+            index(cf.green_shift),
+            index(cf.blue_shift),
+        )
+    }
+
+    /// Packs 32-bit RGB888 pixels into a 24-bit format, dropping the unused byte. The indices
+    /// are computed with `rgb_888::format_index` and `format_index`.
+    pub fn pack_indexed(
+        pixels: &[u8],
+        (ir, ig, ib, _): (usize, usize, usize, usize),
+        (or, og, ob): (usize, usize, usize),
+    ) -> Vec<u8> {
+        let mut buf = vec![0; pixels.len() / 4 * BYTES_PER_PIXEL];
+
+        for (p, out) in pixels
+            .chunks_exact(4)
+            .zip(buf.chunks_exact_mut(BYTES_PER_PIXEL))
+        {
+            out[or] = p[ir];
+            out[og] = p[ig];
+            out[ob] = p[ib];
+        }
+
+        buf
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::pixel_formats::rgb_888::{color_shift_to_index, rgbx_index};
 
-    use super::{fourcc, rgb_888::transform};
+    use super::{fourcc, rgb_888::transform, rgb_888_packed, swap_bytes, PixelConverter};
+    use crate::rfb::PixelFormat;
 
     #[test]
     fn test_color_shift_to_index() {
@@ -337,4 +619,109 @@ mod tests {
         let p5 = vec![1u8, 2u8, 3u8, 0u8];
         assert_eq!(transform(&pixels, &bgrx_le, &xbgr_le), p5);
     }
+
+    #[test]
+    fn test_swap_bytes() {
+        let pixels = vec![0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8];
+
+        assert_eq!(swap_bytes(&pixels, 4), vec![3, 2, 1, 0, 7, 6, 5, 4]);
+        assert_eq!(swap_bytes(&pixels, 2), vec![1, 0, 3, 2, 5, 4, 7, 6]);
+        assert_eq!(swap_bytes(&pixels, 1), pixels);
+    }
+
+    #[test]
+    fn test_pixel_converter() {
+        let xrgb = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let xbgr = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XB24).unwrap();
+        let mut xrgb_be = xrgb.clone();
+        xrgb_be.big_endian = true;
+
+        let pixels = vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+
+        let c = PixelConverter::new(&xrgb, &xrgb);
+        assert!(c.is_identity());
+        assert_eq!(c.convert(&pixels), pixels);
+
+        let c = PixelConverter::new(&xrgb, &xrgb_be);
+        assert_eq!(c.convert(&pixels), swap_bytes(&pixels, 4));
+
+        let c = PixelConverter::new(&xrgb, &xbgr);
+        assert!(c.is_supported());
+        assert_eq!(c.convert(&pixels), transform(&pixels, &xrgb, &xbgr));
+    }
+
+    #[test]
+    fn test_true_color_conversion() {
+        let xrgb = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let rgb565 = PixelFormat::new_colorformat(16, 16, false, 11, 31, 5, 63, 0, 31);
+
+        // Little-endian red, green, blue and white.
+        #[rustfmt::skip]
+        let pixels = [
+            0x00, 0x00, 0xff, 0x00,
+            0x00, 0xff, 0x00, 0x00,
+            0xff, 0x00, 0x00, 0x00,
+            0xff, 0xff, 0xff, 0x00,
+        ];
+
+        let c = PixelConverter::new(&xrgb, &rgb565);
+        assert!(c.is_supported());
+        let converted = c.convert(&pixels);
+        assert_eq!(converted, [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0xff, 0xff]);
+
+        let back = PixelConverter::new(&rgb565, &xrgb).convert(&converted);
+        assert_eq!(back, pixels);
+
+        // Color map formats can't be converted to or from.
+        let mut palette = rgb565.clone();
+        palette.color_spec = crate::rfb::ColorSpecification::ColorMap(crate::rfb::ColorMap {});
+        assert!(!PixelConverter::new(&xrgb, &palette).is_supported());
+        assert!(!PixelConverter::new(&palette, &xrgb).is_supported());
+    }
+
+    #[test]
+    fn test_equivalent_formats_identity() {
+        // xRGB, as little-endian shifts with a depth of 24, and as big-endian shifts with a depth
+        // of 32: the same bytes in memory.
+        let le = PixelFormat::new_colorformat(32, 24, false, 16, 255, 8, 255, 0, 255);
+        let be = PixelFormat::new_colorformat(32, 32, true, 8, 255, 16, 255, 24, 255);
+        assert_ne!(le, be);
+        assert_eq!(le.canonical(), be.canonical());
+
+        let c = PixelConverter::new(&le, &be);
+        assert!(c.is_identity());
+        assert_eq!(c.convert(&[1, 2, 3, 4]), [1, 2, 3, 4]);
+
+        // Swapping the bytes of RGB565 splits green across bytes, so its endianness matters.
+        let rgb565_le = PixelFormat::new_colorformat(16, 16, false, 11, 31, 5, 63, 0, 31);
+        let rgb565_be = PixelFormat::new_colorformat(16, 16, true, 11, 31, 5, 63, 0, 31);
+        assert_ne!(rgb565_le.canonical(), rgb565_be.canonical());
+        assert!(!PixelConverter::new(&rgb565_le, &rgb565_be).is_identity());
+    }
+
+    #[test]
+    fn test_pack_rgb888() {
+        let rgbx = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_RX24).unwrap();
+        let rgb = rgb_888_packed::rgb();
+        assert!(rgb_888_packed::is_packed(&rgb));
+        assert!(!rgb_888_packed::is_packed(&rgbx));
+
+        // Little-endian RGBx holds x, B, G, R in memory; packed RGB is R, G, B.
+        let pixels = [0u8, 1, 2, 3, 4, 5, 6, 7];
+        let c = PixelConverter::new(&rgbx, &rgb);
+        assert!(c.is_supported());
+        assert_eq!(c.convert(&pixels), [3, 2, 1, 7, 6, 5]);
+
+        // The same as the general conversion, and back again.
+        let mut rgb_slow = rgb.clone();
+        rgb_slow.depth = 23;
+        assert_eq!(
+            PixelConverter::new(&rgbx, &rgb_slow).convert(&pixels),
+            [3, 2, 1, 7, 6, 5]
+        );
+        assert_eq!(
+            PixelConverter::new(&rgb, &rgbx).convert(&[3, 2, 1, 7, 6, 5]),
+            [0, 1, 2, 3, 0, 5, 6, 7]
+        );
+    }
 }
@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Session recording
+//!
+//! A [`Recorder`] wraps a connection and copies everything the server writes to it into a sink,
+//! such as a file, for offline analysis. The recording is a sequence of frames:
+//!
+//! - data: the byte `D`, a big-endian u32 length, and that many bytes written to the client
+//! - checkpoint: the byte `C` and a big-endian u32 CRC32 of the data since the last checkpoint
+//!
+//! A checkpoint is written once at least the configured interval of data has been recorded since
+//! the last one, and when the recorder is finished. A [`Player`] verifies each checkpoint before
+//! handing back the data it covers, so a corrupted recording is detected rather than replayed.
+//!
+//! The recording format is specific to this crate and is never sent over the wire.
+
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use flate2::Crc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const FRAME_DATA: u8 = b'D';
+const FRAME_CHECKPOINT: u8 = b'C';
+
+/// The default amount of data recorded between checkpoints.
+pub const DEFAULT_CHECKPOINT_INTERVAL: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum RecordError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("checksum mismatch at offset {offset}: expected {expected:#010x}, got {actual:#010x}")]
+    ChecksumMismatch {
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("invalid frame type {0:#04x} at offset {1}")]
+    InvalidFrame(u8, u64),
+
+    #[error("recording ends without a checkpoint")]
+    Truncated,
+}
+
+/// Wraps a stream, recording every byte written to it into `sink`.
+///
+/// Reads pass through unchanged. Writes to the sink are synchronous, so it should be something
+/// cheap to write to, such as a buffered file or a `Vec<u8>`.
+pub struct Recorder<W, K = Vec<u8>> {
+    stream: W,
+    sink: K,
+    crc: Crc,
+    interval: usize,
+}
+
+impl<W, K: Write> Recorder<W, K> {
+    pub fn new(stream: W, sink: K) -> Self {
+        Self::with_interval(stream, sink, DEFAULT_CHECKPOINT_INTERVAL)
+    }
+
+    /// Creates a recorder that writes a checkpoint after every `interval` bytes of data.
+    pub fn with_interval(stream: W, sink: K, interval: usize) -> Self {
+        Recorder {
+            stream,
+            sink,
+            crc: Crc::new(),
+            interval,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.stream
+    }
+
+    fn record(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        self.sink.write_all(&[FRAME_DATA])?;
+        self.sink.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.sink.write_all(data)?;
+        self.crc.update(data);
+
+        if self.crc.amount() as usize >= self.interval {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        self.sink.write_all(&[FRAME_CHECKPOINT])?;
+        self.sink.write_all(&self.crc.sum().to_be_bytes())?;
+        self.crc.reset();
+        Ok(())
+    }
+
+    /// Writes a final checkpoint, returning the stream and the sink.
+    pub fn finish(mut self) -> io::Result<(W, K)> {
+        if self.crc.amount() > 0 {
+            self.checkpoint()?;
+        }
+        self.sink.flush()?;
+        Ok((self.stream, self.sink))
+    }
+}
+
+impl<W: AsyncRead + Unpin, K: Unpin> AsyncRead for Recorder<W, K> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<W: AsyncWrite + Unpin, K: Write + Unpin> AsyncWrite for Recorder<W, K> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = match Pin::new(&mut self.stream).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            other => return other,
+        };
+
+        // Only record what the stream actually accepted.
+        self.record(&buf[..n])?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sink.flush()?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Reads back a recording made by a [`Recorder`], verifying its checkpoints.
+pub struct Player<R> {
+    recording: R,
+
+    /// Offset into the recording, for error reporting.
+    offset: u64,
+}
+
+impl<R: Read> Player<R> {
+    pub fn new(recording: R) -> Self {
+        Player {
+            recording,
+            offset: 0,
+        }
+    }
+
+    /// Returns the data covered by the next checkpoint, once its checksum has been verified, or
+    /// None at the end of the recording.
+    pub fn next_segment(&mut self) -> Result<Option<Vec<u8>>, RecordError> {
+        let mut segment = Vec::new();
+        let mut crc = Crc::new();
+
+        loop {
+            let mut frame_type = [0u8; 1];
+            if self.recording.read(&mut frame_type)? == 0 {
+                return if segment.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(RecordError::Truncated)
+                };
+            }
+            let frame_offset = self.offset;
+            self.offset += 1;
+
+            match frame_type[0] {
+                FRAME_DATA => {
+                    let len = u32::from_be_bytes(self.read_array()?) as usize;
+                    let start = segment.len();
+                    segment.resize(start + len, 0);
+                    self.read_exact(&mut segment[start..])?;
+                    crc.update(&segment[start..]);
+                }
+                FRAME_CHECKPOINT => {
+                    let expected = u32::from_be_bytes(self.read_array()?);
+                    let actual = crc.sum();
+                    if expected != actual {
+                        return Err(RecordError::ChecksumMismatch {
+                            offset: frame_offset,
+                            expected,
+                            actual,
+                        });
+                    }
+                    return Ok(Some(segment));
+                }
+                t => return Err(RecordError::InvalidFrame(t, frame_offset)),
+            }
+        }
+    }
+
+    /// Verifies the whole recording, writing the recorded data to `stream` one verified segment
+    /// at a time.
+    pub async fn replay<T: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut T,
+    ) -> Result<(), RecordError> {
+        while let Some(segment) = self.next_segment()? {
+            stream.write_all(&segment).await?;
+        }
+        stream.flush().await?;
+        Ok(())
+    }
+
+    fn read_array(&mut self) -> Result<[u8; 4], RecordError> {
+        let mut buf = [0u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), RecordError> {
+        self.recording.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => RecordError::Truncated,
+            _ => RecordError::Io(e),
+        })?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Player, RecordError, Recorder};
+    use crate::rfb::{ProtoVersion, SecurityResult, SecurityType, SecurityTypes, WriteMessage};
+
+    /// Writes the server's side of a 3.8 handshake through a recorder, returning the bytes sent
+    /// to the client and the recording.
+    async fn record_handshake(interval: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut recorder = Recorder::with_interval(Vec::new(), Vec::new(), interval);
+
+        ProtoVersion::Rfb38.write_to(&mut recorder).await.unwrap();
+        SecurityTypes(vec![SecurityType::None])
+            .write_to(&mut recorder)
+            .await
+            .unwrap();
+        SecurityResult::Success
+            .write_to(&mut recorder)
+            .await
+            .unwrap();
+
+        recorder.finish().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let (wire, recording) = record_handshake(8).await;
+        assert_eq!(&wire[..12], b"RFB 003.008\n");
+
+        let mut replayed = Vec::new();
+        Player::new(recording.as_slice())
+            .replay(&mut replayed)
+            .await
+            .unwrap();
+        assert_eq!(replayed, wire);
+    }
+
+    #[tokio::test]
+    async fn test_replay_detects_corruption() {
+        let (_, mut recording) = record_handshake(8).await;
+
+        // Flip a bit in the protocol version, inside the first data frame.
+        recording[5] ^= 0x01;
+
+        let mut replayed = Vec::new();
+        let err = Player::new(recording.as_slice())
+            .replay(&mut replayed)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, RecordError::ChecksumMismatch { .. }));
+        assert!(replayed.is_empty());
+    }
+}
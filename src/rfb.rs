@@ -4,95 +4,485 @@
 //
 // Copyright 2022 Oxide Computer Company
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use bitflags::bitflags;
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::encodings::{Encoding, EncodingType};
+use crate::encodings::{
+    crop_pixels, jpeg_quality_level, ClientEncodings, CopyRectEncoding, CursorPosEncoding,
+    Encoding, EncodingRegistry, EncodingType, PointerMotionChangeEncoding, RREncoding, RawEncoding,
+    TightEncoding, VMwareCursorEncoding, TIGHT_MAX_PIXELS, TIGHT_MAX_WIDTH,
+};
 use crate::keysym::Keysym;
-use crate::pixel_formats::rgb_888;
+use crate::pixel_formats::{rgb_888, PixelConverter};
+use crate::wire::{self, FixedMessage};
 
 pub trait ReadMessage {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>>
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(stream: &'a mut S) -> BoxFuture<'a, Result<Self>>
     where
         Self: Sized;
 }
 
 pub trait WriteMessage {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>>;
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>>;
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+/// A message that can be both read and written, so that generic code, such as a proxy or a
+/// recorder, can handle any message the same way. Unlike `WriteMessage::write_to`, writing
+/// borrows the message, so it can be written more than once.
+///
+/// `write` clones the message and writes the copy, which for large messages, such as cut text or
+/// long encoding lists, means copying all of their contents each time. Code that doesn't need the
+/// message afterwards should use `write_to` instead.
+///
+/// Every message that implements both `ReadMessage` and `WriteMessage` implements this.
+pub trait Codec: Sized {
+    fn read<'a, R: AsyncRead + Unpin + Send>(stream: &'a mut R) -> BoxFuture<'a, Result<Self>>;
+
+    fn write<'a, W: AsyncWrite + Unpin + Send>(
+        &'a self,
+        stream: &'a mut W,
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+impl<T: ReadMessage + WriteMessage + Clone + Send + Sync> Codec for T {
+    fn read<'a, R: AsyncRead + Unpin + Send>(stream: &'a mut R) -> BoxFuture<'a, Result<Self>> {
+        T::read_from(stream)
+    }
+
+    fn write<'a, W: AsyncWrite + Unpin + Send>(
+        &'a self,
+        stream: &'a mut W,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.clone().write_to(stream)
+    }
+}
+
+/// Errors for messages that are well-framed but violate the protocol.
+#[derive(Debug, Error)]
+pub enum ProtoError {
+    #[error("nonzero padding in {message} message")]
+    NonZeroPadding { message: &'static str },
+
+    /// The ProtocolVersion message isn't of the form "RFB xxx.yyy\n", or is for a version older
+    /// than 3.3. Holds the message as received.
+    #[error("invalid protocol version {0:?}")]
+    InvalidProtocolVersion(String),
+
+    #[error("invalid big-endian flag: {0}")]
+    InvalidEndianFlag(u8),
+
+    #[error("invalid {field} flag: {value}")]
+    InvalidFlag { field: &'static str, value: u16 },
+
+    #[error("unknown vendor (type 255) sub-message: {0}")]
+    UnknownVendorSubmessage(u8),
+
+    #[error("unknown client message type: {0}")]
+    UnknownMessageType(u8),
+
+    #[error("framebuffer of {width}x{height} exceeds the maximum dimension of {max}")]
+    DimensionTooLarge { width: u16, height: u16, max: u16 },
+
+    #[error("too many encodings in SetEncodings: {count} (max {max})")]
+    TooManyEncodings { count: u16, max: u16 },
+
+    #[error("{message} message of {size} bytes exceeds the limit of {max} bytes")]
+    MessageTooLarge {
+        message: &'static str,
+        size: u64,
+        max: u64,
+    },
+
+    #[error("reading {field}: {source}")]
+    Read {
+        field: &'static str,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("rectangle at ({x}, {y}) of size {width}x{height} exceeds the {fb_width}x{fb_height} framebuffer")]
+    RectangleOutOfBounds {
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        fb_width: u16,
+        fb_height: u16,
+    },
+}
+
+impl ProtoError {
+    /// If this error was caused by a failed read, returns an I/O error of the same kind whose
+    /// message names the field being read.
+    pub fn io_error(&self) -> Option<std::io::Error> {
+        match self {
+            ProtoError::Read { source, .. } => {
+                Some(std::io::Error::new(source.kind(), self.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Adds the name of the field being read to I/O errors.
+trait ReadField<T> {
+    fn field(self, field: &'static str) -> Result<T, ProtoError>;
+}
+
+impl<T> ReadField<T> for std::io::Result<T> {
+    fn field(self, field: &'static str) -> Result<T, ProtoError> {
+        self.map_err(|source| ProtoError::Read { field, source })
+    }
+}
+
+/// Options controlling how messages are parsed. Most apply to client messages; `max_dimension`
+/// applies to ServerInit, which proxies and clients read from an upstream server.
+#[derive(Debug, Clone)]
+pub struct ParseConfig {
+    /// Reject messages whose padding bytes are nonzero. The protocol requires padding to be
+    /// ignored, so this is off by default, but it can help catch framing bugs in clients.
+    pub strict_padding: bool,
+
+    /// Reject boolean flags with values other than 0 or 1, rather than treating any nonzero value
+    /// as true. This covers the big-endian and true-colour flags of pixel formats, and the
+    /// incremental, down and enable flags of client messages.
+    pub strict_flags: bool,
+
+    /// The largest number of encodings accepted in a SetEncodings message. Clients only send a
+    /// handful, so a much larger count usually means a misbehaving client.
+    pub max_encodings: u16,
+
+    /// The largest message, in bytes, that will be read, or for a FramebufferUpdate, the largest
+    /// rectangle. This bounds how much a single message can cause to be read and allocated,
+    /// whatever lengths, counts or dimensions it claims.
+    pub max_message_bytes: u64,
+
+    /// What to do with client messages of a type this crate doesn't handle.
+    pub unknown_messages: UnknownMessagePolicy,
+
+    /// The largest framebuffer width or height accepted in a ServerInit. Code downstream of a
+    /// proxy usually allocates the whole framebuffer, so this stops an upstream server from
+    /// claiming one of 65535x65535.
+    pub max_dimension: u16,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        Self {
+            strict_padding: false,
+            strict_flags: false,
+            max_encodings: 4096,
+            max_message_bytes: 16 * 1024 * 1024,
+            unknown_messages: UnknownMessagePolicy::Disconnect,
+            max_dimension: 16384,
+        }
+    }
+}
+
+/// How to handle a client message of a type that isn't supported.
+///
+/// Client messages don't carry their length, so a message can only be skipped if its layout is
+/// known. These types can be skipped:
+///
+/// - 1: FixColourMapEntries
+/// - 248: ClientFence
+/// - 250: xvp
+///
+/// Any other unknown type ends the connection whatever the policy, as there's no way to find
+/// where the next message starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownMessagePolicy {
+    /// Fail with [`ProtoError::UnknownMessageType`], ending the connection.
+    Disconnect,
+
+    /// Skip messages of the types above, returning them as [`ClientMessage::Ignored`].
+    Ignore,
+}
+
+impl ParseConfig {
+    fn check_padding(&self, padding: &[u8], message: &'static str) -> Result<()> {
+        if self.strict_padding && padding.iter().any(|b| *b != 0) {
+            return Err(ProtoError::NonZeroPadding { message }.into());
+        }
+        Ok(())
+    }
+
+    /// Interprets a boolean flag, rejecting values other than 0 or 1 if `strict_flags` is set.
+    fn flag(&self, value: u16, field: &'static str) -> Result<bool> {
+        if self.strict_flags && value > 1 {
+            return Err(ProtoError::InvalidFlag { field, value }.into());
+        }
+        Ok(value != 0)
+    }
+
+    /// Checks the full size of a message, as given by its header, before reading the rest.
+    pub(crate) fn check_size(&self, size: u64, message: &'static str) -> Result<()> {
+        if size > self.max_message_bytes {
+            return Err(ProtoError::MessageTooLarge {
+                message,
+                size,
+                max: self.max_message_bytes,
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// A protocol version this crate implements. Versions are ordered by their version numbers, so a
+/// newer version compares greater than an older one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ProtoVersion {
     Rfb33,
     Rfb37,
     Rfb38,
 }
 
+impl Ord for ProtoVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.numeric().cmp(&other.numeric())
+    }
+}
+
+impl PartialOrd for ProtoVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl ProtoVersion {
+    /// Returns the (major, minor) version number.
+    pub fn numeric(&self) -> (u8, u8) {
+        match self {
+            ProtoVersion::Rfb33 => (3, 3),
+            ProtoVersion::Rfb37 => (3, 7),
+            ProtoVersion::Rfb38 => (3, 8),
+        }
+    }
+
+    /// Returns the ProtocolVersion message for this version.
+    pub fn as_bytes(&self) -> &'static [u8; 12] {
+        match self {
+            ProtoVersion::Rfb33 => b"RFB 003.003\n",
+            ProtoVersion::Rfb37 => b"RFB 003.007\n",
+            ProtoVersion::Rfb38 => b"RFB 003.008\n",
+        }
+    }
+
+    /// Parses a ProtocolVersion message ("RFB xxx.yyy\n") into its (major, minor) version
+    /// numbers, whether or not it's a version this crate implements.
+    pub fn parse(buf: &[u8; 12]) -> Option<(u16, u16)> {
+        if &buf[..4] != b"RFB " || buf[7] != b'.' || buf[11] != b'\n' {
+            return None;
+        }
+
+        let number = |digits: &[u8]| -> Option<u16> {
+            let s = std::str::from_utf8(digits).ok()?;
+            if !s.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            s.parse().ok()
+        };
+
+        Some((number(&buf[4..7])?, number(&buf[8..11])?))
+    }
+
+    /// Determines the version to use for a session, given the highest version the server offered
+    /// and the (major, minor) version the client replied with.
+    ///
+    /// The session uses the lower of the two. A client version between the ones this crate
+    /// implements is treated as the next lower version (e.g. 3.5 as 3.3), as the specification
+    /// requires. Returns `None` if the client's version is older than 3.3.
+    pub fn negotiate(server_max: ProtoVersion, client: (u16, u16)) -> Option<ProtoVersion> {
+        let client = match client {
+            (3, 0..=2) | (0..=2, _) => return None,
+            (3, 3..=6) => ProtoVersion::Rfb33,
+            (3, 7) => ProtoVersion::Rfb37,
+            _ => ProtoVersion::Rfb38,
+        };
+
+        if client < server_max {
+            Some(client)
+        } else {
+            Some(server_max)
+        }
+    }
+}
+
 impl ReadMessage for ProtoVersion {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
+    /// Reads a ProtocolVersion message, mapping the version to the one this crate implements that
+    /// a peer of that version uses, as `negotiate` does: for example, 3.5 is read as 3.3, and
+    /// 3.889 as 3.8. Versions older than 3.3 are rejected.
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
         async move {
             let mut buf = [0u8; 12];
-            stream.read_exact(&mut buf).await?;
+            stream.read_exact(&mut buf).await.field("ProtoVersion")?;
 
-            match &buf {
-                b"RFB 003.003\n" => Ok(ProtoVersion::Rfb33),
-                b"RFB 003.007\n" => Ok(ProtoVersion::Rfb37),
-                b"RFB 003.008\n" => Ok(ProtoVersion::Rfb38),
-                _ => Err(anyhow!("invalid protocol version")),
-            }
+            ProtoVersion::parse(&buf)
+                .and_then(|v| ProtoVersion::negotiate(ProtoVersion::Rfb38, v))
+                .ok_or_else(|| {
+                    let received = String::from_utf8_lossy(&buf).into_owned();
+                    ProtoError::InvalidProtocolVersion(received).into()
+                })
         }
         .boxed()
     }
 }
 
 impl WriteMessage for ProtoVersion {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
         async move {
-            let s = match self {
-                ProtoVersion::Rfb33 => b"RFB 003.003\n",
-                ProtoVersion::Rfb37 => b"RFB 003.007\n",
-                ProtoVersion::Rfb38 => b"RFB 003.008\n",
-            };
-
-            Ok(stream.write_all(s).await?)
+            stream.write_all(self.as_bytes()).await?;
+            Ok(stream.flush().await?)
         }
         .boxed()
     }
 }
 
 // Section 7.1.2
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct SecurityTypes(pub Vec<SecurityType>);
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum SecurityType {
     None,
+
+    /// VNC authentication, a DES challenge and response. Passwords aren't supported yet, so the
+    /// server can't verify a response and rejects every client that chooses this type.
     VncAuthentication,
 }
 
+/// The reason sent to a client when the server has no security types to offer.
+const NO_SECURITY_TYPES_REASON: &str = "no security types are available";
+
+/// A security type was offered more than once.
+#[derive(Debug, Error)]
+#[error("security type {0:?} is offered more than once")]
+pub struct DuplicateSecurityType(pub SecurityType);
+
+/// Builds a list of security types to offer, in order of preference. Clients generally choose
+/// the first type in the list that they support.
+///
+/// ```
+/// use rfb::rfb::{SecurityType, SecurityTypes};
+///
+/// let types = SecurityTypes::builder()
+///     .offer(SecurityType::VncAuthentication)
+///     .offer(SecurityType::None)
+///     .build()
+///     .unwrap();
+/// assert_eq!(types.0[0], SecurityType::VncAuthentication);
+/// ```
+#[derive(Debug, Default)]
+pub struct SecurityTypesBuilder {
+    types: Vec<SecurityType>,
+    duplicate: Option<SecurityType>,
+}
+
+impl SecurityTypesBuilder {
+    /// Offers `t`, in preference to any types offered after it.
+    pub fn offer(mut self, t: SecurityType) -> Self {
+        if self.types.contains(&t) {
+            self.duplicate.get_or_insert(t);
+        } else {
+            self.types.push(t);
+        }
+        self
+    }
+
+    /// Returns the list, or an error if any type was offered twice.
+    pub fn build(self) -> Result<SecurityTypes, DuplicateSecurityType> {
+        match self.duplicate {
+            Some(t) => Err(DuplicateSecurityType(t)),
+            None => Ok(SecurityTypes(self.types)),
+        }
+    }
+}
+
+impl SecurityTypes {
+    pub fn builder() -> SecurityTypesBuilder {
+        SecurityTypesBuilder::default()
+    }
+
+    /// Returns true if `t` is one of the types offered.
+    pub fn contains(&self, t: &SecurityType) -> bool {
+        self.0.contains(t)
+    }
+
+    /// Writes the security types as sent in a session using `version`. Version 3.3 has no
+    /// negotiation, so only the first type is sent, as a u32.
+    ///
+    /// If there are no security types, the client is told the connection failed: the type (or
+    /// count) is zero, followed by a reason string.
+    pub async fn write_for_version<S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &mut S,
+        version: ProtoVersion,
+    ) -> Result<()> {
+        if version != ProtoVersion::Rfb33 {
+            return self.write_to(stream).await;
+        }
+
+        match self.0.into_iter().next() {
+            Some(t) => stream.write_u32(u8::from(t) as u32).await?,
+            None => {
+                stream.write_u32(0).await?;
+                write_reason(stream, NO_SECURITY_TYPES_REASON).await?;
+            }
+        }
+        Ok(stream.flush().await?)
+    }
+}
+
+async fn write_reason<S: AsyncWrite + Unpin + Send>(stream: &mut S, reason: &str) -> Result<()> {
+    // TODO: cast properly
+    stream.write_u32(reason.len() as u32).await?;
+    stream.write_all(reason.as_bytes()).await?;
+    Ok(())
+}
+
 impl WriteMessage for SecurityTypes {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
         async move {
+            if self.0.is_empty() {
+                stream.write_u8(0).await?;
+                write_reason(stream, NO_SECURITY_TYPES_REASON).await?;
+                return Ok(stream.flush().await?);
+            }
+
             // TODO: fix cast
             stream.write_u8(self.0.len() as u8).await?;
             for t in self.0.into_iter() {
                 t.write_to(stream).await?;
             }
 
-            Ok(())
+            Ok(stream.flush().await?)
         }
         .boxed()
     }
 }
 
 impl ReadMessage for SecurityType {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
         async move {
-            let t = stream.read_u8().await?;
+            let t = stream.read_u8().await.field("SecurityType")?;
             match t {
                 1 => Ok(SecurityType::None),
                 2 => Ok(SecurityType::VncAuthentication),
@@ -103,14 +493,22 @@ impl ReadMessage for SecurityType {
     }
 }
 
+impl From<SecurityType> for u8 {
+    fn from(t: SecurityType) -> Self {
+        match t {
+            SecurityType::None => 1,
+            SecurityType::VncAuthentication => 2,
+        }
+    }
+}
+
 impl WriteMessage for SecurityType {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
         async move {
-            let val = match self {
-                SecurityType::None => 0,
-                SecurityType::VncAuthentication => 1,
-            };
-            stream.write_u8(val).await?;
+            stream.write_u8(self.into()).await?;
 
             Ok(())
         }
@@ -119,40 +517,118 @@ impl WriteMessage for SecurityType {
 }
 
 // Section 7.1.3
+#[derive(Debug, Clone, PartialEq)]
 pub enum SecurityResult {
     Success,
     Failure(String),
 }
 
-impl WriteMessage for SecurityResult {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
-        async move {
-            match self {
-                SecurityResult::Success => {
-                    stream.write_u32(0).await?;
-                }
-                SecurityResult::Failure(s) => {
-                    stream.write_u32(1).await?;
-                    stream.write_all(s.as_bytes()).await?;
-                }
-            };
+impl SecurityResult {
+    /// Reads the result as sent in a session using `version`. Only version 3.8 includes the
+    /// reason for a failure; for earlier versions, the reason is empty.
+    pub async fn read_from<S: AsyncRead + Unpin + Send>(
+        stream: &mut S,
+        version: ProtoVersion,
+    ) -> Result<Self> {
+        Self::read_with(stream, version, &ParseConfig::default()).await
+    }
 
-            Ok(())
+    /// Like `read_from`, but rejects a failure whose reason makes the message larger than
+    /// `config.max_message_bytes`.
+    pub async fn read_with<S: AsyncRead + Unpin + Send>(
+        stream: &mut S,
+        version: ProtoVersion,
+        config: &ParseConfig,
+    ) -> Result<Self> {
+        let status = stream.read_u32().await.field("SecurityResult.status")?;
+        if status == 0 {
+            return Ok(SecurityResult::Success);
         }
-        .boxed()
+
+        let mut reason = String::new();
+        if version >= ProtoVersion::Rfb38 {
+            let len = stream
+                .read_u32()
+                .await
+                .field("SecurityResult.reason_length")?;
+            config.check_size(8 + len as u64, "SecurityResult")?;
+            let mut buf = vec![0u8; len as usize];
+            stream
+                .read_exact(&mut buf)
+                .await
+                .field("SecurityResult.reason")?;
+            reason = String::from_utf8_lossy(&buf).into_owned();
+        }
+
+        Ok(SecurityResult::Failure(reason))
+    }
+
+    /// Writes the result as sent in a session using `version`. Only version 3.8 includes the
+    /// reason for a failure; earlier versions send just the status.
+    pub async fn write_for_version<S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &mut S,
+        version: ProtoVersion,
+    ) -> Result<()> {
+        match self {
+            SecurityResult::Success => {
+                stream.write_u32(0).await?;
+            }
+            SecurityResult::Failure(s) => {
+                stream.write_u32(1).await?;
+                if version >= ProtoVersion::Rfb38 {
+                    write_reason(stream, &s).await?;
+                }
+            }
+        };
+
+        Ok(stream.flush().await?)
+    }
+}
+
+impl WriteMessage for SecurityResult {
+    /// Writes the result in the version 3.8 format, in which a failure includes its reason.
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        self.write_for_version(stream, ProtoVersion::Rfb38).boxed()
     }
 }
 
 // Section 7.3.1
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct ClientInit {
+    /// Whether other clients may stay connected. If false, the client is asking for exclusive
+    /// access to the server.
     pub shared: bool,
 }
 
+impl ClientInit {
+    pub fn new(shared: bool) -> Self {
+        ClientInit { shared }
+    }
+}
+
+impl WriteMessage for ClientInit {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            stream.write_u8(self.shared as u8).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
 impl ReadMessage for ClientInit {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
         async {
-            let flag = stream.read_u8().await?;
+            let flag = stream.read_u8().await.field("ClientInit.shared")?;
             match flag {
                 0 => Ok(ClientInit { shared: false }),
                 _ => Ok(ClientInit { shared: true }),
@@ -162,12 +638,48 @@ impl ReadMessage for ClientInit {
     }
 }
 
+/// The encoding used for the desktop name in ServerInit.
+///
+/// The RFB specification says the name is Latin-1, but many clients (such as noVNC) decode it as
+/// UTF-8, which is what most servers send in practice.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum NameEncoding {
+    /// ISO 8859-1. Names containing characters outside of Latin-1 can't be sent.
+    Latin1,
+    #[default]
+    Utf8,
+}
+
+impl NameEncoding {
+    /// Encodes a name, returning an error if it can't be represented.
+    pub fn encode(&self, name: &str) -> Result<Vec<u8>> {
+        match self {
+            NameEncoding::Latin1 => name
+                .chars()
+                .map(|c| {
+                    u8::try_from(c).map_err(|_| anyhow!("name character {:?} is not Latin-1", c))
+                })
+                .collect(),
+            NameEncoding::Utf8 => Ok(name.as_bytes().to_vec()),
+        }
+    }
+
+    /// Decodes a name, returning an error if it isn't valid in this encoding.
+    pub fn decode(&self, name: &[u8]) -> Result<String> {
+        match self {
+            NameEncoding::Latin1 => Ok(name.iter().map(|b| char::from(*b)).collect()),
+            NameEncoding::Utf8 => Ok(String::from_utf8(name.to_vec())?),
+        }
+    }
+}
+
 // Section 7.3.2
 #[derive(Debug)]
 pub struct ServerInit {
     initial_res: Resolution,
     pixel_format: PixelFormat,
     name: String,
+    name_encoding: NameEncoding,
 }
 
 impl ServerInit {
@@ -176,26 +688,137 @@ impl ServerInit {
             initial_res: Resolution { width, height },
             pixel_format,
             name,
+            name_encoding: NameEncoding::default(),
+        }
+    }
+
+    /// Sets the encoding of the name (UTF-8 by default).
+    pub fn with_name_encoding(mut self, name_encoding: NameEncoding) -> Self {
+        self.name_encoding = name_encoding;
+        self
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.initial_res
+    }
+
+    pub fn pixel_format(&self) -> &PixelFormat {
+        &self.pixel_format
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks that neither dimension of the framebuffer exceeds `max_dimension`.
+    pub fn validate(&self, max_dimension: u16) -> Result<(), ProtoError> {
+        let Resolution { width, height } = self.initial_res;
+        if width > max_dimension || height > max_dimension {
+            return Err(ProtoError::DimensionTooLarge {
+                width,
+                height,
+                max: max_dimension,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads a ServerInit sent by an upstream server, rejecting one whose framebuffer is larger
+    /// than `config.max_dimension` in either direction. A name that isn't valid UTF-8 is read as
+    /// Latin-1.
+    pub fn read_with<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+        config: &'a ParseConfig,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async move {
+            let initial_res = Resolution::read_from(stream).await?;
+            let pixel_format = PixelFormat::read_with(stream, config).await?;
+            let server_init = ServerInit::new(
+                initial_res.width,
+                initial_res.height,
+                String::new(),
+                pixel_format,
+            );
+            server_init.validate(config.max_dimension)?;
+
+            let len = stream.read_u32().await.field("ServerInit.name_length")?;
+            config.check_size(24 + len as u64, "ServerInit")?;
+            let mut name = vec![0u8; len as usize];
+            stream
+                .read_exact(&mut name)
+                .await
+                .field("ServerInit.name")?;
+            let name_encoding = match std::str::from_utf8(&name) {
+                Ok(_) => NameEncoding::Utf8,
+                Err(_) => NameEncoding::Latin1,
+            };
+
+            Ok(ServerInit {
+                name: name_encoding.decode(&name)?,
+                name_encoding,
+                ..server_init
+            })
+        }
+        .boxed()
+    }
+}
+
+impl ReadMessage for ServerInit {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let config = ParseConfig::default();
+            Self::read_with(stream, &config).await
         }
+        .boxed()
     }
 }
 
 impl WriteMessage for ServerInit {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
         async move {
+            let name = self.name_encoding.encode(&self.name)?;
+
             self.initial_res.write_to(stream).await?;
             self.pixel_format.write_to(stream).await?;
 
             // TODO: cast properly
-            stream.write_u32(self.name.len() as u32).await?;
-            stream.write_all(self.name.as_bytes()).await?;
+            stream.write_u32(name.len() as u32).await?;
+            stream.write_all(&name).await?;
 
-            Ok(())
+            Ok(stream.flush().await?)
         }
         .boxed()
     }
 }
 
+// Section 7.6
+/// The message type sent as the first byte of each server-to-client message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ServerMessageType {
+    FramebufferUpdate,
+    SetColorMapEntries,
+    Bell,
+    ServerCutText,
+    EndOfContinuousUpdates,
+}
+
+impl ServerMessageType {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ServerMessageType::FramebufferUpdate => 0,
+            ServerMessageType::SetColorMapEntries => 1,
+            ServerMessageType::Bell => 2,
+            ServerMessageType::ServerCutText => 3,
+            ServerMessageType::EndOfContinuousUpdates => 150,
+        }
+    }
+}
+
 pub enum _ServerMessage {
     FramebufferUpdate(FramebufferUpdate),
     SetColorMapEntries(SetColorMapEntries),
@@ -208,10 +831,72 @@ pub struct FramebufferUpdate {
 }
 
 impl FramebufferUpdate {
+    /// Creates an update from a list of rectangles.
+    ///
+    /// An update with no rectangles is valid and is written as a header with a rectangle count of
+    /// zero. Some clients treat such updates as a no-op and others as an error, so the server does
+    /// not send empty updates in response to incremental requests; see [`Self::is_empty`].
     pub fn new(rectangles: Vec<Rectangle>) -> Self {
         FramebufferUpdate { rectangles }
     }
 
+    /// Creates an update that moves the client's cursor to (`x`, `y`), or returns None if the
+    /// client didn't declare support for the cursor position pseudo-encoding.
+    pub fn cursor_position(x: u16, y: u16, encodings: &ClientEncodings) -> Option<Self> {
+        if !encodings.supports(EncodingType::CursorPosPseudo) {
+            return None;
+        }
+
+        let r = Rectangle::new(x, y, 0, 0, Box::new(CursorPosEncoding));
+        Some(FramebufferUpdate::new(vec![r]))
+    }
+
+    /// Creates an update that sets the client's cursor to `cursor`, an image of `width` x
+    /// `height` with its hotspot at (`hotspot_x`, `hotspot_y`), or returns None if the client
+    /// didn't declare support for the VMware cursor pseudo-encoding.
+    pub fn vmware_cursor(
+        hotspot_x: u16,
+        hotspot_y: u16,
+        width: u16,
+        height: u16,
+        cursor: VMwareCursorEncoding,
+        encodings: &ClientEncodings,
+    ) -> Option<Self> {
+        if !encodings.supports(EncodingType::VMwareCursorPseudo) {
+            return None;
+        }
+
+        let r = Rectangle::new(hotspot_x, hotspot_y, width, height, Box::new(cursor));
+        Some(FramebufferUpdate::new(vec![r]))
+    }
+
+    /// Creates an update that tells the client to send relative pointer motion, or absolute
+    /// positions if `relative` is false, for a framebuffer of `width` x `height`. Only clients
+    /// that support the Pointer Motion Change pseudo-encoding understand it.
+    pub fn pointer_motion_mode(relative: bool, width: u16, height: u16) -> Self {
+        let r = Rectangle::new(
+            u16::from(!relative),
+            0,
+            width,
+            height,
+            Box::new(PointerMotionChangeEncoding),
+        );
+        FramebufferUpdate::new(vec![r])
+    }
+
+    /// Returns true if the update contains no rectangles.
+    pub fn is_empty(&self) -> bool {
+        self.rectangles.is_empty()
+    }
+
+    pub fn rectangles(&self) -> &[Rectangle] {
+        &self.rectangles
+    }
+
+    pub fn into_rectangles(self) -> Vec<Rectangle> {
+        self.rectangles
+    }
+
     pub fn transform(&self, input_pf: &PixelFormat, output_pf: &PixelFormat) -> Self {
         let mut rectangles = Vec::new();
 
@@ -221,470 +906,3758 @@ impl FramebufferUpdate {
 
         FramebufferUpdate { rectangles }
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct Position {
-    x: u16,
-    y: u16,
-}
+    /// Clips each rectangle to `region`, dropping those that lie entirely outside of it. See
+    /// `Rectangle::clip`.
+    pub fn clip(self, region: Rect, bytes_per_pixel: usize) -> Self {
+        let rectangles = self
+            .rectangles
+            .into_iter()
+            .filter_map(|r| r.clip(region, bytes_per_pixel))
+            .collect();
 
-impl ReadMessage for Position {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
-        async {
-            let x = stream.read_u16().await?;
-            let y = stream.read_u16().await?;
+        FramebufferUpdate { rectangles }
+    }
 
-            Ok(Position { x, y })
-        }
-        .boxed()
+    /// Like `transform`, but reuses a converter built for the input and output formats.
+    pub fn convert(&self, converter: &PixelConverter) -> Self {
+        let rectangles = self
+            .rectangles
+            .iter()
+            .map(|r| r.convert(converter))
+            .collect();
+
+        FramebufferUpdate { rectangles }
     }
-}
 
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct Resolution {
-    width: u16,
-    height: u16,
-}
+    /// Builds an update that scrolls the contents of `region` by (`dx`, `dy`) pixels, as is
+    /// common for terminal-style displays. Content that remains visible is moved with a CopyRect
+    /// rectangle, followed by a Raw rectangle containing the newly exposed strip, whose pixels are
+    /// given by `exposed`.
+    ///
+    /// If the client's encodings don't include CopyRect, or both `dx` and `dy` are nonzero, the
+    /// whole region is sent as a single Raw rectangle instead, with the pixels returned by `full`.
+    /// A scroll by (0, 0) changes nothing, so the update is empty.
+    pub fn scroll(
+        region: Rect,
+        dx: i16,
+        dy: i16,
+        exposed: RawEncoding,
+        encodings: &[EncodingType],
+        full: impl FnOnce() -> RawEncoding,
+    ) -> Self {
+        if dx == 0 && dy == 0 {
+            return FramebufferUpdate::new(vec![]);
+        }
 
-impl ReadMessage for Resolution {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
-        async {
-            let width = stream.read_u16().await?;
-            let height = stream.read_u16().await?;
+        if !encodings.contains(&EncodingType::CopyRect) || (dx != 0 && dy != 0) {
+            let r = Rectangle::new(
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+                Box::new(full()),
+            );
+            return FramebufferUpdate::new(vec![r]);
+        }
 
-            Ok(Resolution { width, height })
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = region;
+
+        // The (destination, source) of the copied content and the exposed strip.
+        let (copy, exposed_rect) = if dy != 0 {
+            let n = dy.unsigned_abs().min(height);
+            let kept = height - n;
+            if dy < 0 {
+                // Content moves up, exposing a strip at the bottom.
+                (
+                    (Rect::new(x, y, width, kept), Position::new(x, y + n)),
+                    Rect::new(x, y + kept, width, n),
+                )
+            } else {
+                // Content moves down, exposing a strip at the top.
+                (
+                    (Rect::new(x, y + n, width, kept), Position::new(x, y)),
+                    Rect::new(x, y, width, n),
+                )
+            }
+        } else {
+            let n = dx.unsigned_abs().min(width);
+            let kept = width - n;
+            if dx < 0 {
+                // Content moves left, exposing a strip on the right.
+                (
+                    (Rect::new(x, y, kept, height), Position::new(x + n, y)),
+                    Rect::new(x + kept, y, n, height),
+                )
+            } else {
+                // Content moves right, exposing a strip on the left.
+                (
+                    (Rect::new(x + n, y, kept, height), Position::new(x, y)),
+                    Rect::new(x, y, n, height),
+                )
+            }
+        };
+
+        let mut rectangles = Vec::with_capacity(2);
+
+        // The copy must come first, so that it reads content from before the scroll.
+        let (dst, src) = copy;
+        if dst.width > 0 && dst.height > 0 {
+            rectangles.push(Rectangle::new(
+                dst.x,
+                dst.y,
+                dst.width,
+                dst.height,
+                Box::new(CopyRectEncoding::new(src.x, src.y)),
+            ));
         }
-        .boxed()
+
+        rectangles.push(Rectangle::new(
+            exposed_rect.x,
+            exposed_rect.y,
+            exposed_rect.width,
+            exposed_rect.height,
+            Box::new(exposed),
+        ));
+
+        FramebufferUpdate { rectangles }
     }
-}
 
-impl WriteMessage for Resolution {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
-        async move {
-            stream.write_u16(self.width).await?;
-            stream.write_u16(self.height).await?;
-            Ok(())
+    /// Builds an update filling a `width` x `height` framebuffer with a single `pixel`, which is in
+    /// `pixel_format`. This is sent as an RRE rectangle with only a background, whose size doesn't
+    /// depend on the dimensions, or as Raw if the client's encodings don't include RRE.
+    pub fn solid_color(
+        width: u16,
+        height: u16,
+        pixel: &[u8],
+        pixel_format: &PixelFormat,
+        encodings: &[EncodingType],
+    ) -> Self {
+        assert_eq!(
+            pixel.len(),
+            (pixel_format.bits_per_pixel / 8) as usize,
+            "pixel doesn't match the pixel format"
+        );
+
+        let data: Box<dyn Encoding> = if encodings.contains(&EncodingType::RRE) {
+            Box::new(RREncoding::new(pixel.to_vec()))
+        } else {
+            Box::new(RawEncoding::new(
+                pixel.repeat(width as usize * height as usize),
+            ))
+        };
+
+        FramebufferUpdate {
+            rectangles: vec![Rectangle::new(0, 0, width, height, data)],
         }
-        .boxed()
     }
 }
 
-pub struct Rectangle {
-    position: Position,
-    dimensions: Resolution,
-    data: Box<dyn Encoding>,
+/// Assembles a FramebufferUpdate from rectangles in a mix of encodings. Each encoding is only used
+/// if the client advertised support for it; otherwise the rectangle is sent as Raw.
+pub struct FramebufferUpdateBuilder<'a> {
+    encodings: &'a [EncodingType],
+    rectangles: Vec<Rectangle>,
 }
 
-impl Rectangle {
-    pub fn new(x: u16, y: u16, width: u16, height: u16, data: Box<dyn Encoding>) -> Self {
-        Rectangle {
-            position: Position { x, y },
-            dimensions: Resolution { width, height },
-            data,
+impl<'a> FramebufferUpdateBuilder<'a> {
+    /// Creates a builder for a client that supports `encodings`.
+    pub fn new(encodings: &'a [EncodingType]) -> Self {
+        Self {
+            encodings,
+            rectangles: Vec::new(),
         }
     }
 
-    pub fn transform(&self, input_pf: &PixelFormat, output_pf: &PixelFormat) -> Self {
-        Rectangle {
-            position: self.position,
-            dimensions: self.dimensions,
-            data: self.data.transform(input_pf, output_pf),
+    fn supports(&self, encoding: EncodingType) -> bool {
+        self.encodings.contains(&encoding)
+    }
+
+    /// Adds a rectangle copied from `src` in the client's framebuffer. If the client doesn't
+    /// support CopyRect, the rectangle is sent as Raw using the pixels returned by `pixels`.
+    pub fn copy_rect(mut self, dst: Rect, src: Position, pixels: impl FnOnce() -> Vec<u8>) -> Self {
+        if self.supports(EncodingType::CopyRect) {
+            self.push(dst, Box::new(CopyRectEncoding::new(src.x, src.y)));
+            self
+        } else {
+            self.raw(dst, pixels())
         }
     }
-}
 
-impl WriteMessage for Rectangle {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
-        async move {
-            let encoding_type: i32 = self.data.get_type().into();
+    /// Adds a Raw rectangle.
+    pub fn raw(mut self, rect: Rect, pixels: Vec<u8>) -> Self {
+        self.push(rect, Box::new(RawEncoding::new(pixels)));
+        self
+    }
 
-            stream.write_u16(self.position.x).await?;
-            stream.write_u16(self.position.y).await?;
-            stream.write_u16(self.dimensions.width).await?;
-            stream.write_u16(self.dimensions.height).await?;
-            stream.write_i32(encoding_type).await?;
+    /// Adds a Tight rectangle, whose pixels are in `pixel_format`. If the client doesn't support
+    /// Tight, or the rectangle is too wide for it, the rectangle is sent as Raw. Rectangles with
+    /// more than [`TIGHT_MAX_PIXELS`] pixels are split into bands of rows. If the client asked
+    /// for a JPEG quality level, it's used for photo-like rectangles.
+    pub fn tight(mut self, rect: Rect, pixels: Vec<u8>, pixel_format: &PixelFormat) -> Self {
+        if !self.supports(EncodingType::Tight) || rect.width > TIGHT_MAX_WIDTH {
+            return self.raw(rect, pixels);
+        }
 
-            let data = self.data.encode();
-            stream.write_all(data).await?;
+        let quality = jpeg_quality_level(self.encodings);
+        let band_height =
+            (TIGHT_MAX_PIXELS / usize::from(rect.width.max(1))).min(usize::from(u16::MAX));
+        let row_len = usize::from(rect.width) * (pixel_format.bits_per_pixel / 8) as usize;
+        if usize::from(rect.height) <= band_height {
+            let tight = TightEncoding::with_quality(pixels, pixel_format, rect.width, quality);
+            self.push(rect, Box::new(tight));
+            return self;
+        }
 
-            Ok(())
+        for (i, band) in pixels.chunks(band_height * row_len).enumerate() {
+            let y = rect.y + (i * band_height) as u16;
+            let height = (band.len() / row_len) as u16;
+            let band_rect = Rect::new(rect.x, y, rect.width, height);
+            let tight =
+                TightEncoding::with_quality(band.to_vec(), pixel_format, rect.width, quality);
+            self.push(band_rect, Box::new(tight));
         }
-        .boxed()
+        self
+    }
+
+    /// Adds a rectangle in the encoding the client prefers most among those `registry` can
+    /// produce, or Raw if there are none.
+    pub fn encoded(
+        mut self,
+        rect: Rect,
+        pixels: Vec<u8>,
+        pixel_format: &PixelFormat,
+        registry: &EncodingRegistry,
+    ) -> Self {
+        let encoding = self
+            .encodings
+            .iter()
+            .copied()
+            .find(|e| registry.can_encode(*e));
+        let dimensions = Resolution::new(rect.width, rect.height);
+
+        match encoding {
+            Some(encoding) => {
+                let data = registry
+                    .encode(encoding, pixels, dimensions, pixel_format, self.encodings)
+                    .expect("registry has an encoder for the encoding");
+                self.push(rect, data);
+                self
+            }
+            None => self.raw(rect, pixels),
+        }
+    }
+
+    fn push(&mut self, rect: Rect, data: Box<dyn Encoding>) {
+        self.rectangles.push(Rectangle::new(
+            rect.x,
+            rect.y,
+            rect.width,
+            rect.height,
+            data,
+        ));
+    }
+
+    pub fn build(self) -> FramebufferUpdate {
+        FramebufferUpdate::new(self.rectangles)
     }
 }
 
-impl WriteMessage for FramebufferUpdate {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
-        async move {
-            // TODO: type function?
-            stream.write_u8(0).await?;
+/// A rectangular region of the framebuffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
 
-            // 1 byte of padding
-            stream.write_u8(0).await?;
+impl Rect {
+    pub fn new(x: u16, y: u16, width: u16, height: u16) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
 
-            // number of rectangles
-            let n_rect = self.rectangles.len() as u16;
-            stream.write_u16(n_rect).await?;
+    /// The x and y coordinates just past the right and bottom edges.
+    fn end(&self) -> (u32, u32) {
+        (
+            u32::from(self.x) + u32::from(self.width),
+            u32::from(self.y) + u32::from(self.height),
+        )
+    }
 
-            // rectangles
-            for r in self.rectangles.into_iter() {
-                r.write_to(stream).await?;
-            }
+    /// Returns whether `other` lies entirely within this rectangle.
+    pub fn contains(&self, other: &Rect) -> bool {
+        let ((r0, b0), (r1, b1)) = (self.end(), other.end());
+        other.x >= self.x && other.y >= self.y && r1 <= r0 && b1 <= b0
+    }
 
-            Ok(())
+    /// Returns the area covered by both rectangles, or None if they don't overlap.
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let (x0, y0) = (self.x.max(other.x), self.y.max(other.y));
+        let ((r0, b0), (r1, b1)) = (self.end(), other.end());
+        let (x1, y1) = (r0.min(r1), b0.min(b1));
+        if x1 <= u32::from(x0) || y1 <= u32::from(y0) {
+            return None;
         }
-        .boxed()
+
+        Some(Rect::new(
+            x0,
+            y0,
+            (x1 - u32::from(x0)) as u16,
+            (y1 - u32::from(y0)) as u16,
+        ))
+    }
+
+    /// Returns the smallest rectangle covering both rectangles.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let (x0, y0) = (self.x.min(other.x), self.y.min(other.y));
+        let ((r0, b0), (r1, b1)) = (self.end(), other.end());
+        let (x1, y1) = (r0.max(r1), b0.max(b1));
+
+        // Both rectangles fit in the u16 coordinate space, so their union's size does too,
+        // unless one extends past it.
+        Rect::new(
+            x0,
+            y0,
+            (x1 - u32::from(x0)).min(u16::MAX.into()) as u16,
+            (y1 - u32::from(y0)).min(u16::MAX.into()) as u16,
+        )
     }
 }
 
-#[derive(Debug)]
-pub struct SetColorMapEntries {
-    _colors: Vec<_ColorMapEntry>,
+/// The position of a point or the top-left corner of a rectangle, in pixels.
+///
+/// ```
+/// use rfb::rfb::Position;
+///
+/// let p = Position::new(10, 20);
+/// assert_eq!(p.x(), 10);
+/// assert_eq!(p.y(), 20);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Position {
+    x: u16,
+    y: u16,
 }
 
-#[derive(Debug)]
-pub struct _ColorMapEntry {
-    _color: u16,
-    _red: u16,
-    _blue: u16,
-    _green: u16,
+impl Position {
+    pub fn new(x: u16, y: u16) -> Self {
+        Position { x, y }
+    }
+
+    pub fn x(&self) -> u16 {
+        self.x
+    }
+
+    pub fn y(&self) -> u16 {
+        self.y
+    }
 }
 
-// TODO: only ISO 8859-1 (Latin-1) text supported
-// used for client and server
-#[derive(Debug)]
-pub struct CutText {
-    _text: String,
+impl ReadMessage for Position {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let x = stream.read_u16().await.field("Position.x")?;
+            let y = stream.read_u16().await.field("Position.y")?;
+
+            Ok(Position { x, y })
+        }
+        .boxed()
+    }
 }
 
-// Section 7.4
-#[derive(Debug, Clone, PartialEq)]
-pub struct PixelFormat {
-    pub bits_per_pixel: u8, // TODO: must be 8, 16, or 32
-    pub depth: u8,          // TODO: must be < bits_per_pixel
-    pub big_endian: bool,
-    pub color_spec: ColorSpecification,
+/// The dimensions of a framebuffer or rectangle, in pixels.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Resolution {
+    width: u16,
+    height: u16,
 }
 
-impl PixelFormat {
-    /// Constructor for a PixelFormat that uses a color format to specify colors.
-    pub fn new_colorformat(
-        bbp: u8,
-        depth: u8,
-        big_endian: bool,
-        red_shift: u8,
-        red_max: u16,
-        green_shift: u8,
-        green_max: u16,
-        blue_shift: u8,
-        blue_max: u16,
-    ) -> Self {
-        PixelFormat {
-            bits_per_pixel: bbp,
-            depth,
-            big_endian,
-            color_spec: ColorSpecification::ColorFormat(ColorFormat {
-                red_max,
-                green_max,
-                blue_max,
-                red_shift,
-                green_shift,
-                blue_shift,
-            }),
-        }
+impl Resolution {
+    pub fn new(width: u16, height: u16) -> Self {
+        Resolution { width, height }
     }
 
-    /// Returns true if the pixel format is RGB888 (8-bits per color and 32 bits per pixel).
-    pub fn is_rgb_888(&self) -> bool {
-        if self.bits_per_pixel != rgb_888::BITS_PER_PIXEL || self.depth != rgb_888::DEPTH {
-            return false;
-        }
+    pub fn width(&self) -> u16 {
+        self.width
+    }
 
-        match &self.color_spec {
-            ColorSpecification::ColorFormat(cf) => {
-                (cf.red_max == rgb_888::MAX_VALUE)
-                    && (cf.green_max == rgb_888::MAX_VALUE)
-                    && (cf.blue_max == rgb_888::MAX_VALUE)
-                    && (rgb_888::valid_shift(cf.red_shift))
-                    && (rgb_888::valid_shift(cf.green_shift))
-                    && (rgb_888::valid_shift(cf.blue_shift))
-            }
-            ColorSpecification::ColorMap(_) => false,
-        }
+    pub fn height(&self) -> u16 {
+        self.height
     }
 }
 
-impl ReadMessage for PixelFormat {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
+impl ReadMessage for Resolution {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
         async {
-            let bits_per_pixel = stream.read_u8().await?;
-            let depth = stream.read_u8().await?;
-            let be_flag = stream.read_u8().await?;
-            let big_endian = match be_flag {
-                0 => false,
-                _ => true,
-            };
-            let color_spec = ColorSpecification::read_from(stream).await?;
-
-            // 3 bytes of padding
-            let mut buf = [0u8; 3];
-            stream.read_exact(&mut buf).await?;
+            let width = stream.read_u16().await.field("Resolution.width")?;
+            let height = stream.read_u16().await.field("Resolution.height")?;
 
-            Ok(Self {
-                bits_per_pixel,
-                depth,
-                big_endian,
-                color_spec,
-            })
+            Ok(Resolution { width, height })
         }
         .boxed()
     }
 }
 
-impl WriteMessage for PixelFormat {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
+impl WriteMessage for Resolution {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
         async move {
-            stream.write_u8(self.bits_per_pixel).await?;
-            stream.write_u8(self.depth).await?;
-            stream.write_u8(if self.big_endian { 1 } else { 0 }).await?;
-            self.color_spec.write_to(stream).await?;
-
-            // 3 bytes of padding
-            let buf = [0u8; 3];
-            stream.write_all(&buf).await?;
-
+            stream.write_u16(self.width).await?;
+            stream.write_u16(self.height).await?;
             Ok(())
         }
         .boxed()
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-#[allow(dead_code)]
-pub enum ColorSpecification {
-    ColorFormat(ColorFormat),
-    ColorMap(ColorMap), // TODO: implement
+pub struct Rectangle {
+    position: Position,
+    dimensions: Resolution,
+    data: Box<dyn Encoding>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct ColorFormat {
-    // TODO: maxes must be 2^N - 1 for N bits per color
-    pub red_max: u16,
-    pub green_max: u16,
-    pub blue_max: u16,
-    pub red_shift: u8,
-    pub green_shift: u8,
-    pub blue_shift: u8,
-}
+impl Rectangle {
+    pub fn new(x: u16, y: u16, width: u16, height: u16, data: Box<dyn Encoding>) -> Self {
+        Rectangle {
+            position: Position { x, y },
+            dimensions: Resolution { width, height },
+            data,
+        }
+    }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct ColorMap {}
+    pub fn position(&self) -> Position {
+        self.position
+    }
 
-impl ReadMessage for ColorSpecification {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
-        async {
-            let tc_flag = stream.read_u8().await?;
-            match tc_flag {
-                0 => {
-                    // ColorMap
-                    unimplemented!()
-                }
-                _ => {
-                    // ColorFormat
-                    let red_max = stream.read_u16().await?;
-                    let green_max = stream.read_u16().await?;
-                    let blue_max = stream.read_u16().await?;
+    pub fn dimensions(&self) -> Resolution {
+        self.dimensions
+    }
 
-                    let red_shift = stream.read_u8().await?;
-                    let green_shift = stream.read_u8().await?;
-                    let blue_shift = stream.read_u8().await?;
+    pub fn data(&self) -> &dyn Encoding {
+        self.data.as_ref()
+    }
 
-                    Ok(ColorSpecification::ColorFormat(ColorFormat {
-                        red_max,
-                        green_max,
-                        blue_max,
-                        red_shift,
-                        green_shift,
-                        blue_shift,
-                    }))
-                }
-            }
+    pub fn encoding_type(&self) -> EncodingType {
+        self.data.get_type()
+    }
+
+    pub fn transform(&self, input_pf: &PixelFormat, output_pf: &PixelFormat) -> Self {
+        Rectangle {
+            position: self.position,
+            dimensions: self.dimensions,
+            data: self.data.transform(input_pf, output_pf),
         }
-        .boxed()
     }
-}
 
-impl WriteMessage for ColorSpecification {
-    fn write_to<'a>(self, stream: &'a mut TcpStream) -> BoxFuture<'a, Result<()>> {
-        async move {
-            match self {
-                ColorSpecification::ColorFormat(cf) => {
-                    stream.write_u8(1).await?; // true color
-                    stream.write_u16(cf.red_max).await?;
-                    stream.write_u16(cf.green_max).await?;
-                    stream.write_u16(cf.blue_max).await?;
+    /// Clips the rectangle to `region`, returning None if they don't overlap. Pseudo-encoded
+    /// rectangles, and rectangles whose encoding can't be cropped, are returned unchanged.
+    pub fn clip(self, region: Rect, bytes_per_pixel: usize) -> Option<Self> {
+        if self.data.get_type().is_pseudo() {
+            return Some(self);
+        }
 
-                    stream.write_u8(cf.red_shift).await?;
-                    stream.write_u8(cf.green_shift).await?;
-                    stream.write_u8(cf.blue_shift).await?;
-                }
-                ColorSpecification::ColorMap(_cm) => {
-                    unimplemented!()
-                }
-            };
+        let bounds = Rect::new(
+            self.position.x,
+            self.position.y,
+            self.dimensions.width,
+            self.dimensions.height,
+        );
+        if region.contains(&bounds) {
+            return Some(self);
+        }
+        let clipped = bounds.intersect(&region)?;
 
-            Ok(())
+        // The part to keep, relative to this rectangle.
+        let crop = Rect::new(
+            clipped.x - bounds.x,
+            clipped.y - bounds.y,
+            clipped.width,
+            clipped.height,
+        );
+        match self.data.crop(self.dimensions.width, crop, bytes_per_pixel) {
+            Some(data) => Some(Rectangle {
+                position: Position::new(clipped.x, clipped.y),
+                dimensions: Resolution::new(crop.width, crop.height),
+                data,
+            }),
+            None => Some(self),
         }
-        .boxed()
     }
-}
 
-// Section 7.5
-pub enum ClientMessage {
-    SetPixelFormat(PixelFormat),
-    SetEncodings(Vec<EncodingType>),
-    FramebufferUpdateRequest(FramebufferUpdateRequest),
-    KeyEvent(KeyEvent),
-    PointerEvent(PointerEvent),
-    ClientCutText(String),
+    pub fn convert(&self, converter: &PixelConverter) -> Self {
+        Rectangle {
+            position: self.position,
+            dimensions: self.dimensions,
+            data: self.data.convert(converter),
+        }
+    }
 }
 
-impl ReadMessage for ClientMessage {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<ClientMessage>> {
-        async {
-            let t = stream.read_u8().await?;
-            let res = match t {
-                0 => {
-                    // SetPixelFormat
-                    let mut padding = [0u8; 3];
-                    stream.read_exact(&mut padding).await?;
-                    let pixel_format = PixelFormat::read_from(stream).await?;
-                    Ok(ClientMessage::SetPixelFormat(pixel_format))
-                }
+impl Rectangle {
+    /// Writes the rectangle without consuming it.
+    async fn write_ref_to<S: AsyncWrite + Unpin + Send>(&self, stream: &mut S) -> Result<()> {
+        let header = wire::RectangleHeader {
+            x: self.position.x,
+            y: self.position.y,
+            width: self.dimensions.width,
+            height: self.dimensions.height,
+            encoding: self.data.get_type().into(),
+        };
+        let mut buf = [0u8; wire::RectangleHeader::LEN];
+        header.encode(&mut buf)?;
+        stream.write_all(&buf).await?;
 
-                2 => {
-                    // SetEncodings
-                    stream.read_u8().await?; // 1 byte of padding
-                    let num_encodings = stream.read_u16().await?;
+        let data = self.data.encode();
+        stream.write_all(data).await?;
 
-                    // TODO: what to do if num_encodings is 0
+        Ok(())
+    }
+}
 
-                    let mut encodings = Vec::new();
-                    for _ in 0..num_encodings {
-                        let e: EncodingType = EncodingType::try_from(stream.read_i32().await?)?;
-                        encodings.push(e);
-                    }
+/// The most rectangles a single FramebufferUpdate message can hold. A count of 0xffff is reserved
+/// for updates that end with a LastRect rectangle.
+pub const MAX_UPDATE_RECTANGLES: u16 = u16::MAX - 1;
 
-                    Ok(ClientMessage::SetEncodings(encodings))
-                }
-                3 => {
-                    // FramebufferUpdateRequest
-                    let incremental = match stream.read_u8().await? {
-                        0 => false,
-                        _ => true,
-                    };
-                    let position = Position::read_from(stream).await?;
-                    let resolution = Resolution::read_from(stream).await?;
+/// Writes the message type, padding and rectangle count that start a FramebufferUpdate.
+async fn write_update_header<S: AsyncWrite + Unpin + Send>(
+    stream: &mut S,
+    rectangles: u16,
+) -> Result<()> {
+    let mut buf = [0u8; wire::FramebufferUpdateHeader::LEN];
+    wire::FramebufferUpdateHeader { rectangles }.encode(&mut buf)?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
 
-                    let fbu_req = FramebufferUpdateRequest {
-                        incremental,
-                        position,
-                        resolution,
-                    };
+impl WriteMessage for Rectangle {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move { self.write_ref_to(stream).await }.boxed()
+    }
+}
 
-                    Ok(ClientMessage::FramebufferUpdateRequest(fbu_req))
-                }
-                4 => {
-                    // KeyEvent
-                    let is_pressed = match stream.read_u8().await? {
-                        0 => false,
-                        _ => true,
-                    };
+impl FramebufferUpdate {
+    /// Checks that every rectangle fits within a framebuffer of the given dimensions.
+    ///
+    /// Pseudo-encoded rectangles don't describe pixels on the framebuffer, so they are not
+    /// checked.
+    pub fn validate(&self, width: u16, height: u16) -> Result<(), ProtoError> {
+        for r in &self.rectangles {
+            if r.data.get_type().is_pseudo() {
+                continue;
+            }
 
-                    // 2 bytes of padding
-                    stream.read_u16().await?;
+            let right = u32::from(r.position.x) + u32::from(r.dimensions.width);
+            let bottom = u32::from(r.position.y) + u32::from(r.dimensions.height);
+            if right > u32::from(width) || bottom > u32::from(height) {
+                return Err(ProtoError::RectangleOutOfBounds {
+                    x: r.position.x,
+                    y: r.position.y,
+                    width: r.dimensions.width,
+                    height: r.dimensions.height,
+                    fb_width: width,
+                    fb_height: height,
+                });
+            }
+        }
 
-                    let key = Keysym::try_from(stream.read_u32().await?)?;
+        Ok(())
+    }
 
-                    let key_event = KeyEvent { is_pressed, key };
+    /// Validates the update against the framebuffer dimensions, then writes it.
+    pub async fn write_checked_to<S: AsyncWrite + Unpin + Send>(
+        &self,
+        stream: &mut S,
+        width: u16,
+        height: u16,
+    ) -> Result<()> {
+        self.validate(width, height)?;
+        self.write_ref_to(stream).await
+    }
 
-                    Ok(ClientMessage::KeyEvent(key_event))
+    /// Writes the update without consuming it, so that it can be sent more than once.
+    ///
+    /// An update with more rectangles than fit in the message's count is sent as several
+    /// consecutive updates of at most [`MAX_UPDATE_RECTANGLES`] rectangles each.
+    ///
+    /// Like every server message, the stream is flushed once the update is written, so that a
+    /// buffered stream doesn't hold it back.
+    pub async fn write_ref_to<S: AsyncWrite + Unpin + Send>(&self, stream: &mut S) -> Result<()> {
+        if self.rectangles.is_empty() {
+            write_update_header(stream, 0).await?;
+        }
+
+        for chunk in self.rectangles.chunks(MAX_UPDATE_RECTANGLES as usize) {
+            write_update_header(stream, chunk.len() as u16).await?;
+            for r in chunk {
+                r.write_ref_to(stream).await?;
+            }
+        }
+
+        Ok(stream.flush().await?)
+    }
+
+    /// Reads an update, including its message type, as sent by a server using `pixel_format`.
+    /// Each rectangle is decoded with the decoder for its encoding in `registry`.
+    pub async fn read_from<S: AsyncRead + Unpin + Send>(
+        stream: &mut S,
+        pixel_format: &PixelFormat,
+        registry: &EncodingRegistry,
+    ) -> Result<Self> {
+        Self::read_with(stream, pixel_format, registry, &ParseConfig::default()).await
+    }
+
+    /// Like `read_from`, but rejects rectangles whose data is larger than
+    /// `config.max_message_bytes`, before allocating for them.
+    pub async fn read_with<S: AsyncRead + Unpin + Send>(
+        stream: &mut S,
+        pixel_format: &PixelFormat,
+        registry: &EncodingRegistry,
+        config: &ParseConfig,
+    ) -> Result<Self> {
+        let message_type = stream
+            .read_u8()
+            .await
+            .field("FramebufferUpdate.message_type")?;
+        if message_type != ServerMessageType::FramebufferUpdate.to_u8() {
+            bail!(
+                "expected a FramebufferUpdate, got message type {}",
+                message_type
+            );
+        }
+
+        // 1 byte of padding
+        stream.read_u8().await.field("FramebufferUpdate.padding")?;
+
+        // A count of 0xffff means the update ends with a LastRect rectangle.
+        let n_rects = stream
+            .read_u16()
+            .await
+            .field("FramebufferUpdate.number_of_rectangles")?;
+
+        let mut rectangles = Vec::new();
+        while n_rects == u16::MAX || rectangles.len() < n_rects as usize {
+            let position = Position::read_from(stream).await?;
+            let dimensions = Resolution::read_from(stream).await?;
+            let encoding =
+                EncodingType::try_from(stream.read_i32().await.field("Rectangle.encoding_type")?)?;
+            if encoding == EncodingType::LastRectPseudo {
+                break;
+            }
+
+            let data = registry
+                .decode(encoding, stream, dimensions, pixel_format, config)
+                .await?;
+            rectangles.push(Rectangle {
+                position,
+                dimensions,
+                data,
+            });
+        }
+
+        Ok(FramebufferUpdate { rectangles })
+    }
+
+    /// Applies the update to a client's copy of the framebuffer: `framebuffer` holds rows of
+    /// `fb_width` pixels in `pf`, with no padding between them.
+    ///
+    /// Rectangles are applied in order, so a CopyRect rectangle copies from the framebuffer as
+    /// updated by the rectangles before it. Pseudo-encoded rectangles are skipped. It's an error
+    /// for a rectangle to lie outside the framebuffer, or to use an encoding whose pixels can't be
+    /// recovered; rectangles before it have already been applied.
+    pub fn apply_to(
+        &self,
+        framebuffer: &mut [u8],
+        fb_width: usize,
+        pf: &PixelFormat,
+    ) -> Result<()> {
+        let bytes_per_pixel = pf.bits_per_pixel as usize / 8;
+        let stride = fb_width * bytes_per_pixel;
+        let fb_height = framebuffer.len().checked_div(stride).unwrap_or(0);
+        let fits = |x: u16, y: u16, w: u16, h: u16| {
+            x as usize + w as usize <= fb_width && y as usize + h as usize <= fb_height
+        };
+
+        for r in &self.rectangles {
+            let encoding = r.data.get_type();
+            if encoding.is_pseudo() {
+                continue;
+            }
+
+            let Position { x, y } = r.position;
+            let Resolution { width, height } = r.dimensions;
+            if !fits(x, y, width, height) {
+                bail!(
+                    "{:?} rectangle at ({}, {}) of {}x{} is outside the {}x{} framebuffer",
+                    encoding,
+                    x,
+                    y,
+                    width,
+                    height,
+                    fb_width,
+                    fb_height
+                );
+            }
+
+            let row_len = width as usize * bytes_per_pixel;
+            if row_len == 0 || height == 0 {
+                continue;
+            }
+            let offset = |x: u16, y: u16| y as usize * stride + x as usize * bytes_per_pixel;
+
+            if encoding == EncodingType::CopyRect {
+                // The data is the source position.
+                let src = r.data.encode();
+                let src_x = u16::from_be_bytes([src[0], src[1]]);
+                let src_y = u16::from_be_bytes([src[2], src[3]]);
+                if !fits(src_x, src_y, width, height) {
+                    bail!(
+                        "CopyRect source at ({}, {}) is outside the framebuffer",
+                        src_x,
+                        src_y
+                    );
                 }
-                5 => {
-                    // PointerEvent
-                    let pointer_event = PointerEvent::read_from(stream).await?;
-                    Ok(ClientMessage::PointerEvent(pointer_event))
+
+                // Copy rows in the order that doesn't overwrite source rows before they're read.
+                let rows: Box<dyn Iterator<Item = u16>> = if src_y < y {
+                    Box::new((0..height).rev())
+                } else {
+                    Box::new(0..height)
+                };
+                for row in rows {
+                    let from = offset(src_x, src_y + row);
+                    framebuffer.copy_within(from..from + row_len, offset(x, y + row));
                 }
-                6 => {
-                    // ClientCutText
+                continue;
+            }
 
-                    // 3 bytes of padding
-                    let mut padding = [0u8; 3];
-                    stream.read_exact(&mut padding).await?;
+            let pixels = match r.data.raw_pixels(width, height) {
+                Some(pixels) => pixels,
+                None => bail!("can't apply a {:?} rectangle", encoding),
+            };
+            if pixels.len() != row_len * height as usize {
+                bail!(
+                    "{:?} rectangle has {} bytes of pixels, expected {}",
+                    encoding,
+                    pixels.len(),
+                    row_len * height as usize
+                );
+            }
+            for (row, src) in pixels.chunks_exact(row_len).enumerate() {
+                let start = offset(x, y + row as u16);
+                framebuffer[start..start + row_len].copy_from_slice(src);
+            }
+        }
 
-                    let len = stream.read_u32().await?;
-                    let mut buf: Vec<u8> = Vec::with_capacity(len as usize);
-                    stream.read_exact(&mut buf).await?;
+        Ok(())
+    }
 
-                    // TODO: The encoding RFB uses is ISO 8859-1 (Latin-1), which is a subset of
-                    // utf-8. Determine if this is the right approach.
-                    let text = String::from_utf8(buf)?;
+    /// Writes an update whose rectangles are produced one at a time by `rects`, so that a large
+    /// frame never has to be held in memory all at once.
+    ///
+    /// If the number of rectangles is known up front, pass it as `n_rects`; it's an error for the
+    /// iterator to produce a different number. Otherwise, pass `None` and the end of the update is
+    /// marked with a LastRect rectangle instead, which requires the client to support the LastRect
+    /// pseudo-encoding.
+    pub async fn write_from_iter<S, I>(stream: &mut S, n_rects: Option<u16>, rects: I) -> Result<()>
+    where
+        S: AsyncWrite + Unpin + Send,
+        I: IntoIterator<Item = Rectangle>,
+        I::IntoIter: Send,
+    {
+        // With LastRect, the number of rectangles is sent as 0xffff.
+        write_update_header(stream, n_rects.unwrap_or(u16::MAX)).await?;
 
-                    Ok(ClientMessage::ClientCutText(text))
+        let mut written = 0usize;
+        for r in rects {
+            if let Some(n) = n_rects {
+                if written == n as usize {
+                    bail!("update has more than the expected {} rectangles", n);
                 }
-                unknown => Err(anyhow!(format!("unknown client message type: {}", unknown))),
+            }
+            r.write_ref_to(stream).await?;
+            written += 1;
+        }
+
+        match n_rects {
+            Some(n) if written != n as usize => {
+                bail!("update has {} rectangles, expected {}", written, n);
+            }
+            Some(_) => {}
+            None => {
+                // An empty LastRect rectangle
+                stream.write_all(&[0u8; 8]).await?;
+                stream
+                    .write_i32(EncodingType::LastRectPseudo.into())
+                    .await?;
+            }
+        }
+
+        Ok(stream.flush().await?)
+    }
+}
+
+impl WriteMessage for FramebufferUpdate {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move { self.write_ref_to(stream).await }.boxed()
+    }
+}
+
+#[cfg(feature = "image")]
+impl FramebufferUpdate {
+    /// Builds an update containing `image` as a single Raw rectangle at the origin, with its
+    /// pixels in `pixel_format`. Any alpha channel is discarded.
+    ///
+    /// Panics if `pixel_format` isn't true color, or if the image is larger than the protocol
+    /// allows.
+    pub fn from_image(image: &image::DynamicImage, pixel_format: &PixelFormat) -> Self {
+        let cf = pixel_format
+            .color_format()
+            .expect("images can only be converted to true color pixel formats");
+        let image = image.to_rgb8();
+        let width = u16::try_from(image.width()).expect("image too wide");
+        let height = u16::try_from(image.height()).expect("image too tall");
+
+        let bytes_per_pixel = (pixel_format.bits_per_pixel / 8) as usize;
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * bytes_per_pixel);
+        for rgb in image.pixels() {
+            let value = pack_pixel(cf, rgb.0);
+            let bytes = if pixel_format.big_endian {
+                &value.to_be_bytes()[4 - bytes_per_pixel..]
+            } else {
+                &value.to_le_bytes()[..bytes_per_pixel]
             };
+            pixels.extend_from_slice(bytes);
+        }
 
-            res
+        FramebufferUpdate {
+            rectangles: vec![Rectangle::new(
+                0,
+                0,
+                width,
+                height,
+                Box::new(RawEncoding::new(pixels)),
+            )],
         }
-        .boxed()
+    }
+
+    /// Draws the update's Raw rectangles, whose pixels are in `pixel_format`, into an image large
+    /// enough to hold all of its rectangles. Other encodings are skipped, leaving black pixels.
+    ///
+    /// Panics if `pixel_format` isn't true color.
+    pub fn to_image(&self, pixel_format: &PixelFormat) -> image::DynamicImage {
+        let cf = pixel_format
+            .color_format()
+            .expect("images can only be converted from true color pixel formats");
+        let bytes_per_pixel = (pixel_format.bits_per_pixel / 8) as usize;
+
+        let (width, height) = self.rectangles.iter().fold((0, 0), |(w, h), r| {
+            (
+                w.max(u32::from(r.position.x) + u32::from(r.dimensions.width)),
+                h.max(u32::from(r.position.y) + u32::from(r.dimensions.height)),
+            )
+        });
+        let mut image = image::RgbImage::new(width, height);
+
+        for r in &self.rectangles {
+            if r.data.get_type() != EncodingType::Raw {
+                continue;
+            }
+
+            let pixels = r.data.encode().chunks_exact(bytes_per_pixel);
+            let rect_width = u32::from(r.dimensions.width);
+            for (i, bytes) in pixels.enumerate() {
+                let mut buf = [0u8; 4];
+                let value = if pixel_format.big_endian {
+                    buf[4 - bytes_per_pixel..].copy_from_slice(bytes);
+                    u32::from_be_bytes(buf)
+                } else {
+                    buf[..bytes_per_pixel].copy_from_slice(bytes);
+                    u32::from_le_bytes(buf)
+                };
+
+                let x = u32::from(r.position.x) + i as u32 % rect_width;
+                let y = u32::from(r.position.y) + i as u32 / rect_width;
+                image.put_pixel(x, y, image::Rgb(unpack_pixel(cf, value)));
+            }
+        }
+
+        image::DynamicImage::ImageRgb8(image)
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct FramebufferUpdateRequest {
-    incremental: bool,
-    position: Position,
-    resolution: Resolution,
+/// Packs 8-bit color values into a pixel value of the given format.
+#[cfg(feature = "image")]
+fn pack_pixel(cf: &ColorFormat, [r, g, b]: [u8; 3]) -> u32 {
+    let scale = |c: u8, max: u16| (u32::from(c) * u32::from(max) + 127) / 255;
+
+    (scale(r, cf.red_max) << cf.red_shift)
+        | (scale(g, cf.green_max) << cf.green_shift)
+        | (scale(b, cf.blue_max) << cf.blue_shift)
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct KeyEvent {
-    is_pressed: bool,
-    key: Keysym,
+/// Unpacks a pixel value of the given format into 8-bit color values.
+#[cfg(feature = "image")]
+fn unpack_pixel(cf: &ColorFormat, value: u32) -> [u8; 3] {
+    let scale = |shift: u8, max: u16| {
+        let max = u32::from(max);
+        let c = (value >> shift) & max;
+        ((c * 255 + max / 2) / max) as u8
+    };
+
+    [
+        scale(cf.red_shift, cf.red_max),
+        scale(cf.green_shift, cf.green_max),
+        scale(cf.blue_shift, cf.blue_max),
+    ]
 }
 
-bitflags! {
-    struct MouseButtons: u8 {
-        const LEFT = 1 << 0;
-        const MIDDLE = 1 << 1;
-        const RIGHT = 1 << 2;
-        const SCROLL_A = 1 << 3;
-        const SCROLL_B = 1 << 4;
-        const SCROLL_C = 1 << 5;
-        const SCROLL_D = 1 << 6;
+impl FramebufferUpdate {
+    /// Splits a frame of `width` x `height` pixels, in the pixel format `pf`, into Raw rectangles
+    /// of at most `tile_size` pixels on a side. Tiles are copied out of `pixels` only as they're
+    /// produced, so when passed to `write_from_iter`, just one tile is held in memory at a time.
+    ///
+    /// ```
+    /// use rfb::pixel_formats::fourcc;
+    /// use rfb::rfb::FramebufferUpdate;
+    ///
+    /// let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+    /// let pixels = vec![0u8; 100 * 100 * 4];
+    /// let tiles = FramebufferUpdate::tiled(&pixels, 100, 100, &pf, 64);
+    /// assert_eq!(tiles.len(), 4);
+    /// ```
+    pub fn tiled<'a>(
+        pixels: &'a [u8],
+        width: u16,
+        height: u16,
+        pf: &PixelFormat,
+        tile_size: u16,
+    ) -> Tiles<'a> {
+        assert!(tile_size > 0, "tile size must be nonzero");
+        assert_eq!(
+            pixels.len(),
+            width as usize * height as usize * (pf.bits_per_pixel as usize / 8)
+        );
+
+        Tiles {
+            pixels,
+            width,
+            height,
+            bytes_per_pixel: pf.bits_per_pixel as usize / 8,
+            tile_size,
+            x: 0,
+            y: 0,
+        }
+    }
+}
+
+/// An iterator over the tiles of a frame, in rows from the top left. See
+/// [`FramebufferUpdate::tiled`].
+pub struct Tiles<'a> {
+    pixels: &'a [u8],
+    width: u16,
+    height: u16,
+    bytes_per_pixel: usize,
+    tile_size: u16,
+
+    /// The position of the next tile.
+    x: u16,
+    y: u16,
+}
+
+impl Iterator for Tiles<'_> {
+    type Item = Rectangle;
+
+    fn next(&mut self) -> Option<Rectangle> {
+        if self.width == 0 || self.y >= self.height {
+            return None;
+        }
+
+        let w = self.tile_size.min(self.width - self.x);
+        let h = self.tile_size.min(self.height - self.y);
+        let tile = Rect::new(self.x, self.y, w, h);
+        let pixels = crop_pixels(self.pixels, self.width, tile, self.bytes_per_pixel)
+            .expect("frame size checked by tiled");
+        let r = Rectangle::new(tile.x, tile.y, w, h, Box::new(RawEncoding::new(pixels)));
+
+        self.x += w;
+        if self.x >= self.width {
+            self.x = 0;
+            self.y += h;
+        }
+
+        Some(r)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let tiles = |len: u16| (len as usize).div_ceil(self.tile_size as usize);
+        if self.width == 0 || self.y >= self.height {
+            return (0, Some(0));
+        }
+
+        // Tiles left in the current row, plus those in the rows below it.
+        let row = tiles(self.width - self.x);
+        let below = tiles(self.height - self.y) - 1;
+        let n = row + below * tiles(self.width);
+        (n, Some(n))
     }
 }
 
+impl ExactSizeIterator for Tiles<'_> {}
+
+// Section 7.6.2
 #[derive(Debug)]
-#[allow(dead_code)]
-pub struct PointerEvent {
-    position: Position,
-    pressed: MouseButtons,
+pub struct SetColorMapEntries {
+    first_color: u16,
+    colors: Vec<ColorMapEntry>,
 }
 
-impl ReadMessage for PointerEvent {
-    fn read_from<'a>(stream: &'a mut TcpStream) -> BoxFuture<'a, Result<Self>> {
-        async {
-            let button_mask = stream.read_u8().await?;
-            let pressed = MouseButtons::from_bits_truncate(button_mask);
-            let position = Position::read_from(stream).await?;
+impl SetColorMapEntries {
+    /// Sets the color map entries starting at index `first_color` to `colors`.
+    pub fn new(first_color: u16, colors: Vec<ColorMapEntry>) -> Self {
+        Self {
+            first_color,
+            colors,
+        }
+    }
+}
+
+impl WriteMessage for SetColorMapEntries {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let n_colors = u16::try_from(self.colors.len())
+                .map_err(|_| anyhow!("too many color map entries: {}", self.colors.len()))?;
+
+            stream
+                .write_u8(ServerMessageType::SetColorMapEntries.to_u8())
+                .await?;
+            // 1 byte of padding
+            stream.write_u8(0).await?;
+            stream.write_u16(self.first_color).await?;
+            stream.write_u16(n_colors).await?;
+
+            for c in self.colors {
+                stream.write_u16(c.red).await?;
+                stream.write_u16(c.green).await?;
+                stream.write_u16(c.blue).await?;
+            }
+
+            Ok(stream.flush().await?)
+        }
+        .boxed()
+    }
+}
+
+/// A color in a color map, with 16 bits per color.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ColorMapEntry {
+    pub red: u16,
+    pub green: u16,
+    pub blue: u16,
+}
+
+// TODO: only ISO 8859-1 (Latin-1) text supported
+// used for client and server
+#[derive(Debug, Clone, PartialEq)]
+pub struct CutText {
+    text: String,
+}
+
+impl CutText {
+    pub fn new(text: String) -> Self {
+        CutText { text }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+// Section 7.6.4
+impl WriteMessage for CutText {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            // Characters outside of Latin-1 can't be represented.
+            let buf: Vec<u8> = self
+                .text
+                .chars()
+                .map(|c| u8::try_from(c).unwrap_or(b'?'))
+                .collect();
+
+            stream
+                .write_u8(ServerMessageType::ServerCutText.to_u8())
+                .await?;
+            // 3 bytes of padding
+            stream.write_all(&[0u8; 3]).await?;
+            stream.write_u32(buf.len() as u32).await?;
+            stream.write_all(&buf).await?;
 
-            Ok(PointerEvent { position, pressed })
+            Ok(stream.flush().await?)
         }
         .boxed()
     }
 }
+
+// Section 7.4
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PixelFormat {
+    pub bits_per_pixel: u8, // TODO: must be 8, 16, or 32
+    pub depth: u8,          // TODO: must be < bits_per_pixel
+    pub big_endian: bool,
+    pub color_spec: ColorSpecification,
+}
+
+impl PixelFormat {
+    /// The size of a pixel format on the wire, such as in SetPixelFormat and ServerInit. A proxy
+    /// can read this many bytes before parsing, so that a short format is never forwarded.
+    pub const fn byte_len() -> usize {
+        16
+    }
+
+    /// Constructor for a PixelFormat that uses a color format to specify colors.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_colorformat(
+        bbp: u8,
+        depth: u8,
+        big_endian: bool,
+        red_shift: u8,
+        red_max: u16,
+        green_shift: u8,
+        green_max: u16,
+        blue_shift: u8,
+        blue_max: u16,
+    ) -> Self {
+        PixelFormat {
+            bits_per_pixel: bbp,
+            depth,
+            big_endian,
+            color_spec: ColorSpecification::ColorFormat(ColorFormat {
+                red_max,
+                green_max,
+                blue_max,
+                red_shift,
+                green_shift,
+                blue_shift,
+            }),
+        }
+    }
+
+    /// Returns true if pixel values are composed directly of red, green, and blue values (as
+    /// opposed to indexing into a color map).
+    pub fn is_true_color(&self) -> bool {
+        self.color_format().is_some()
+    }
+
+    /// Returns the color format of a true color pixel format.
+    pub fn color_format(&self) -> Option<&ColorFormat> {
+        match &self.color_spec {
+            ColorSpecification::ColorFormat(cf) => Some(cf),
+            ColorSpecification::ColorMap(_) => None,
+        }
+    }
+
+    /// Returns the color map of a pixel format that isn't true color.
+    pub fn color_map(&self) -> Option<&ColorMap> {
+        match &self.color_spec {
+            ColorSpecification::ColorFormat(_) => None,
+            ColorSpecification::ColorMap(cm) => Some(cm),
+        }
+    }
+
+    /// Returns true if the two pixel formats are the same except for their endianness, in which
+    /// case converting between them only requires swapping the bytes of each pixel.
+    pub fn differs_only_in_endianness(&self, other: &PixelFormat) -> bool {
+        self.big_endian != other.big_endian
+            && self.bits_per_pixel == other.bits_per_pixel
+            && self.depth == other.depth
+            && self.color_spec == other.color_spec
+    }
+
+    /// Returns true if the pixel format is RGB888 (8-bits per color and 32 bits per pixel).
+    pub fn is_rgb_888(&self) -> bool {
+        if self.bits_per_pixel != rgb_888::BITS_PER_PIXEL || self.depth != rgb_888::DEPTH {
+            return false;
+        }
+
+        match self.color_format() {
+            Some(cf) => {
+                (cf.red_max == rgb_888::MAX_VALUE)
+                    && (cf.green_max == rgb_888::MAX_VALUE)
+                    && (cf.blue_max == rgb_888::MAX_VALUE)
+                    && (rgb_888::valid_shift(cf.red_shift))
+                    && (rgb_888::valid_shift(cf.green_shift))
+                    && (rgb_888::valid_shift(cf.blue_shift))
+            }
+            None => false,
+        }
+    }
+
+    /// Describes whether converting pixels from the `from` format into this one preserves all of
+    /// the color information.
+    pub fn conversion_quality(&self, from: &PixelFormat) -> Quality {
+        if self.color_spec == from.color_spec {
+            return Quality::Lossless;
+        }
+
+        match (self.color_format(), from.color_format()) {
+            (Some(to), Some(from)) => {
+                if to.red_max >= from.red_max
+                    && to.green_max >= from.green_max
+                    && to.blue_max >= from.blue_max
+                {
+                    Quality::Lossless
+                } else {
+                    Quality::Lossy
+                }
+            }
+            // Color maps can only hold a limited number of colors, so assume some are lost.
+            _ => Quality::Lossy,
+        }
+    }
+
+    /// Returns the format reduced to how pixels are laid out in memory, so that formats that
+    /// describe the same bytes compare equal: the depth is ignored, the endianness of 8-bit
+    /// formats doesn't matter, and a big-endian format whose colors each fit within a byte is
+    /// described by the equivalent little-endian shifts.
+    pub fn canonical(&self) -> CanonicalFormat {
+        let bits_per_pixel = self.bits_per_pixel;
+        let mut big_endian = self.big_endian && bits_per_pixel > 8;
+        let mut channels = self.color_format().map(|cf| {
+            let bits = |max: u16| (16 - max.leading_zeros()) as u8;
+            [
+                (cf.red_shift, bits(cf.red_max)),
+                (cf.green_shift, bits(cf.green_max)),
+                (cf.blue_shift, bits(cf.blue_max)),
+            ]
+        });
+
+        // A color that's within a single byte stays at the same bits of its byte when the byte
+        // order is reversed.
+        if let Some(colors) = channels.as_mut().filter(|_| big_endian) {
+            let within_byte = |&(shift, bits): &(u8, u8)| {
+                bits > 0
+                    && u16::from(shift) + u16::from(bits) <= u16::from(bits_per_pixel)
+                    && shift / 8 == (shift + bits - 1) / 8
+            };
+            if bits_per_pixel.is_multiple_of(8) && colors.iter().all(within_byte) {
+                let last_byte = bits_per_pixel / 8 - 1;
+                for (shift, _) in colors.iter_mut() {
+                    *shift = (last_byte - *shift / 8) * 8 + *shift % 8;
+                }
+                big_endian = false;
+            }
+        }
+
+        CanonicalFormat {
+            bits_per_pixel,
+            big_endian,
+            channels,
+        }
+    }
+}
+
+/// A pixel format reduced to its memory layout; see [`PixelFormat::canonical`]. Pixels can be
+/// copied unchanged between two formats with the same canonical form.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CanonicalFormat {
+    pub bits_per_pixel: u8,
+
+    /// Always false if the byte order makes no difference to the layout.
+    pub big_endian: bool,
+
+    /// The (shift, number of bits) of red, green and blue, or None for a color map format.
+    pub channels: Option<[(u8, u8); 3]>,
+}
+
+/// How faithfully a pixel format conversion reproduces the source colors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quality {
+    /// Every color in the source format can be represented exactly.
+    Lossless,
+    /// Some color precision is lost in the conversion.
+    Lossy,
+}
+
+impl ReadMessage for PixelFormat {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let config = ParseConfig::default();
+            Self::read_with(stream, &config).await
+        }
+        .boxed()
+    }
+}
+
+impl PixelFormat {
+    /// Reads a pixel format, parsing it according to `config`.
+    pub fn read_with<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+        config: &'a ParseConfig,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async move {
+            let bits_per_pixel = stream.read_u8().await.field("PixelFormat.bits_per_pixel")?;
+            let depth = stream.read_u8().await.field("PixelFormat.depth")?;
+            let be_flag = stream.read_u8().await.field("PixelFormat.big_endian")?;
+            if config.strict_flags && be_flag > 1 {
+                return Err(ProtoError::InvalidEndianFlag(be_flag).into());
+            }
+            let big_endian = be_flag != 0;
+            let color_spec = ColorSpecification::read_with(stream, config).await?;
+
+            // 3 bytes of padding
+            let mut buf = [0u8; 3];
+            stream
+                .read_exact(&mut buf)
+                .await
+                .field("PixelFormat.padding")?;
+            config.check_padding(&buf, "PixelFormat")?;
+
+            Ok(Self {
+                bits_per_pixel,
+                depth,
+                big_endian,
+                color_spec,
+            })
+        }
+        .boxed()
+    }
+}
+
+impl WriteMessage for PixelFormat {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            stream.write_u8(self.bits_per_pixel).await?;
+            stream.write_u8(self.depth).await?;
+            stream.write_u8(if self.big_endian { 1 } else { 0 }).await?;
+            self.color_spec.write_to(stream).await?;
+
+            // 3 bytes of padding
+            let buf = [0u8; 3];
+            stream.write_all(&buf).await?;
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(dead_code)]
+pub enum ColorSpecification {
+    ColorFormat(ColorFormat),
+    ColorMap(ColorMap),
+}
+
+/// How the red, green and blue values of a true-color pixel are packed.
+///
+/// Each max must be `2^N - 1`, where N is the number of bits used for that color, and each
+/// color's bits (the max, shifted left by its shift) must fit within the pixel without
+/// overlapping the others. [`ColorFormat::new`] checks this; formats built from a struct literal
+/// are not checked, and conversions involving an invalid format produce garbage.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ColorFormat {
+    pub red_max: u16,
+    pub green_max: u16,
+    pub blue_max: u16,
+    pub red_shift: u8,
+    pub green_shift: u8,
+    pub blue_shift: u8,
+}
+
+/// Reasons a [`ColorFormat`] is invalid.
+#[derive(Debug, Error, PartialEq)]
+pub enum ColorFormatError {
+    #[error("{channel} max {max} is not one less than a power of two")]
+    InvalidMax { channel: &'static str, max: u16 },
+
+    #[error("{channel} bits at shift {shift} don't fit in {bits_per_pixel} bits per pixel")]
+    ShiftOutOfRange {
+        channel: &'static str,
+        shift: u8,
+        bits_per_pixel: u8,
+    },
+
+    #[error("{0} and {1} bits overlap")]
+    Overlap(&'static str, &'static str),
+}
+
+impl ColorFormat {
+    /// Creates a color format, checking that it's valid for a pixel of up to 32 bits.
+    pub fn new(
+        red_max: u16,
+        green_max: u16,
+        blue_max: u16,
+        red_shift: u8,
+        green_shift: u8,
+        blue_shift: u8,
+    ) -> Result<Self, ColorFormatError> {
+        let cf = ColorFormat {
+            red_max,
+            green_max,
+            blue_max,
+            red_shift,
+            green_shift,
+            blue_shift,
+        };
+        cf.validate(32)?;
+        Ok(cf)
+    }
+
+    /// Checks that this format is valid for pixels of `bits_per_pixel` bits.
+    pub fn validate(&self, bits_per_pixel: u8) -> Result<(), ColorFormatError> {
+        let channels = [
+            ("red", self.red_max, self.red_shift),
+            ("green", self.green_max, self.green_shift),
+            ("blue", self.blue_max, self.blue_shift),
+        ];
+
+        let mut masks = Vec::with_capacity(channels.len());
+        for (channel, max, shift) in channels {
+            if max == 0 || !(u32::from(max) + 1).is_power_of_two() {
+                return Err(ColorFormatError::InvalidMax { channel, max });
+            }
+
+            let bits = u16::BITS - max.leading_zeros();
+            if u32::from(shift) + bits > u32::from(bits_per_pixel) {
+                return Err(ColorFormatError::ShiftOutOfRange {
+                    channel,
+                    shift,
+                    bits_per_pixel,
+                });
+            }
+
+            let mask = u64::from(max) << shift;
+            for &(other, other_mask) in &masks {
+                if mask & other_mask != 0 {
+                    return Err(ColorFormatError::Overlap(other, channel));
+                }
+            }
+            masks.push((channel, mask));
+        }
+
+        Ok(())
+    }
+}
+
+/// Pixel values are indices into a color map, whose entries are set by the server with
+/// SetColorMapEntries.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ColorMap {}
+
+impl ReadMessage for ColorSpecification {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let config = ParseConfig::default();
+            Self::read_with(stream, &config).await
+        }
+        .boxed()
+    }
+}
+
+impl ColorSpecification {
+    /// Reads a color specification, parsing it according to `config`.
+    pub fn read_with<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+        config: &'a ParseConfig,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async move {
+            let tc_flag = stream.read_u8().await.field("PixelFormat.true_color")?;
+            match config.flag(tc_flag.into(), "true-colour")? {
+                false => {
+                    // ColorMap: the maxes and shifts are unused.
+                    let mut unused = [0u8; 9];
+                    stream
+                        .read_exact(&mut unused)
+                        .await
+                        .field("PixelFormat.color_map_padding")?;
+                    Ok(ColorSpecification::ColorMap(ColorMap {}))
+                }
+                true => {
+                    // ColorFormat
+                    let red_max = stream.read_u16().await.field("PixelFormat.red_max")?;
+                    let green_max = stream.read_u16().await.field("PixelFormat.green_max")?;
+                    let blue_max = stream.read_u16().await.field("PixelFormat.blue_max")?;
+
+                    let red_shift = stream.read_u8().await.field("PixelFormat.red_shift")?;
+                    let green_shift = stream.read_u8().await.field("PixelFormat.green_shift")?;
+                    let blue_shift = stream.read_u8().await.field("PixelFormat.blue_shift")?;
+
+                    Ok(ColorSpecification::ColorFormat(ColorFormat {
+                        red_max,
+                        green_max,
+                        blue_max,
+                        red_shift,
+                        green_shift,
+                        blue_shift,
+                    }))
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+impl WriteMessage for ColorSpecification {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match self {
+                ColorSpecification::ColorFormat(cf) => {
+                    stream.write_u8(1).await?; // true color
+                    stream.write_u16(cf.red_max).await?;
+                    stream.write_u16(cf.green_max).await?;
+                    stream.write_u16(cf.blue_max).await?;
+
+                    stream.write_u8(cf.red_shift).await?;
+                    stream.write_u8(cf.green_shift).await?;
+                    stream.write_u8(cf.blue_shift).await?;
+                }
+                ColorSpecification::ColorMap(_cm) => {
+                    stream.write_u8(0).await?; // not true color
+
+                    // The maxes and shifts are unused.
+                    stream.write_all(&[0u8; 9]).await?;
+                }
+            };
+
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+// Section 7.5
+#[derive(Debug, Clone)]
+pub enum ClientMessage {
+    SetPixelFormat(PixelFormat),
+
+    /// The client's encodings, in order of preference. An empty list resets the client to the
+    /// default it started with: Raw only, with no pseudo-encodings.
+    SetEncodings(Vec<EncodingType>),
+    FramebufferUpdateRequest(FramebufferUpdateRequest),
+    KeyEvent(KeyEvent),
+    PointerEvent(PointerEvent),
+    ClientCutText(String),
+    SetDesktopSize(SetDesktopSize),
+    EnableContinuousUpdates(EnableContinuousUpdates),
+
+    /// A message of the given type that was skipped under [`UnknownMessagePolicy::Ignore`].
+    Ignored(u8),
+}
+
+impl TryFrom<&[u8]> for ClientMessage {
+    type Error = anyhow::Error;
+
+    /// Parses a buffer holding exactly one client message.
+    fn try_from(buf: &[u8]) -> Result<Self> {
+        let (msg, len) = Self::from_bytes(buf)?;
+        if len != buf.len() {
+            bail!(
+                "{} trailing bytes after {} byte client message",
+                buf.len() - len,
+                len
+            );
+        }
+        Ok(msg)
+    }
+}
+
+impl ReadMessage for ClientMessage {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<ClientMessage>> {
+        async {
+            let config = ParseConfig::default();
+            Self::read_with(stream, &config).await
+        }
+        .boxed()
+    }
+}
+
+/// Writes the message as a client would send it. A key event with a keycode is written as a QEMU
+/// Extended Key Event. An `Ignored` message can't be written, since only its type is known, and
+/// nothing is written for a message with more encodings, screens or text than its length field
+/// can hold.
+impl WriteMessage for ClientMessage {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            match self {
+                ClientMessage::SetPixelFormat(pf) => {
+                    stream.write_all(&[0, 0, 0, 0]).await?;
+                    pf.write_to(stream).await?;
+                }
+                ClientMessage::SetEncodings(encodings) => {
+                    let n_encodings = u16::try_from(encodings.len())
+                        .map_err(|_| anyhow!("too many encodings: {}", encodings.len()))?;
+                    stream.write_all(&[2, 0]).await?;
+                    stream.write_u16(n_encodings).await?;
+                    for e in encodings {
+                        stream.write_i32(e.as_i32()).await?;
+                    }
+                }
+                ClientMessage::FramebufferUpdateRequest(req) => {
+                    stream.write_all(&[3, req.incremental as u8]).await?;
+                    stream.write_u16(req.position.x).await?;
+                    stream.write_u16(req.position.y).await?;
+                    req.resolution.write_to(stream).await?;
+                }
+                ClientMessage::KeyEvent(ke) => match ke.keycode {
+                    Some(keycode) => {
+                        stream.write_all(&[255, 0]).await?;
+                        stream.write_u16(ke.is_pressed as u16).await?;
+                        stream.write_u32(ke.key.into()).await?;
+                        stream.write_u32(keycode).await?;
+                    }
+                    None => {
+                        stream.write_all(&[4, ke.is_pressed as u8, 0, 0]).await?;
+                        stream.write_u32(ke.key.into()).await?;
+                    }
+                },
+                ClientMessage::PointerEvent(pe) => {
+                    stream.write_all(&[5, pe.pressed.bits()]).await?;
+                    stream.write_u16(pe.position.x).await?;
+                    stream.write_u16(pe.position.y).await?;
+                }
+                ClientMessage::ClientCutText(text) => {
+                    let len = u32::try_from(text.len())
+                        .map_err(|_| anyhow!("cut text too long: {} bytes", text.len()))?;
+                    stream.write_all(&[6, 0, 0, 0]).await?;
+                    stream.write_u32(len).await?;
+                    stream.write_all(text.as_bytes()).await?;
+                }
+                ClientMessage::SetDesktopSize(ds) => {
+                    let n_screens = u8::try_from(ds.screens.len())
+                        .map_err(|_| anyhow!("too many screens: {}", ds.screens.len()))?;
+                    stream.write_all(&[251, 0]).await?;
+                    stream.write_u16(ds.width).await?;
+                    stream.write_u16(ds.height).await?;
+                    stream.write_all(&[n_screens, 0]).await?;
+                    for screen in ds.screens {
+                        screen.write_to(stream).await?;
+                    }
+                }
+                ClientMessage::EnableContinuousUpdates(ecu) => {
+                    stream.write_all(&[150, ecu.enable as u8]).await?;
+                    stream.write_u16(ecu.region.x).await?;
+                    stream.write_u16(ecu.region.y).await?;
+                    stream.write_u16(ecu.region.width).await?;
+                    stream.write_u16(ecu.region.height).await?;
+                }
+                ClientMessage::Ignored(t) => {
+                    bail!("can't write ignored client message of type {}", t);
+                }
+            }
+
+            Ok(stream.flush().await?)
+        }
+        .boxed()
+    }
+}
+
+/// Reads and discards the rest of a message of type `t`, which must be one of the types that
+/// [`UnknownMessagePolicy`] lists as skippable.
+async fn skip_message<S: AsyncRead + Unpin + Send>(
+    stream: &mut S,
+    t: u8,
+    config: &ParseConfig,
+) -> Result<()> {
+    let (message, len) = match t {
+        1 => {
+            // FixColourMapEntries: padding, first color, then the number of 6 byte colors.
+            let mut header = [0u8; 5];
+            stream
+                .read_exact(&mut header)
+                .await
+                .field("FixColourMapEntries.header")?;
+            let colors = u16::from_be_bytes([header[3], header[4]]);
+            ("FixColourMapEntries", 6 * colors as u64)
+        }
+        248 => {
+            // ClientFence: padding and flags, then the length of the payload.
+            let mut header = [0u8; 8];
+            stream
+                .read_exact(&mut header)
+                .await
+                .field("ClientFence.header")?;
+            ("ClientFence", header[7] as u64)
+        }
+        // xvp: padding, version and message code.
+        250 => ("xvp", 3),
+        _ => unreachable!("message type {} can't be skipped", t),
+    };
+    config.check_size(len, message)?;
+
+    let mut data = vec![0u8; len as usize];
+    stream
+        .read_exact(&mut data)
+        .await
+        .field("ClientMessage.ignored")?;
+    Ok(())
+}
+
+impl ClientMessage {
+    /// Parses a client message from the start of `buf`, returning the message and the number of
+    /// bytes it took up. This is for callers that receive whole messages as buffers rather than
+    /// as a stream. A buffer that ends partway through a message is an error.
+    pub fn from_bytes(buf: &[u8]) -> Result<(ClientMessage, usize)> {
+        Self::from_bytes_with(buf, &ParseConfig::default())
+    }
+
+    /// Like `from_bytes`, but parses the message according to `config`.
+    pub fn from_bytes_with(buf: &[u8], config: &ParseConfig) -> Result<(ClientMessage, usize)> {
+        let mut rest = buf;
+
+        // Reading from a slice never waits, so the parse completes on its first poll.
+        let msg = Self::read_with(&mut rest, config)
+            .now_or_never()
+            .expect("parsing from a buffer does not block")?;
+
+        Ok((msg, buf.len() - rest.len()))
+    }
+
+    /// Reads a client message, parsing it according to `config`.
+    pub fn read_with<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+        config: &'a ParseConfig,
+    ) -> BoxFuture<'a, Result<ClientMessage>> {
+        async move {
+            let t = stream.read_u8().await.field("ClientMessage.message_type")?;
+            let res = match t {
+                0 => {
+                    // SetPixelFormat
+                    let mut padding = [0u8; 3];
+                    stream
+                        .read_exact(&mut padding)
+                        .await
+                        .field("SetPixelFormat.padding")?;
+                    config.check_padding(&padding, "SetPixelFormat")?;
+                    let pixel_format = PixelFormat::read_with(stream, config).await?;
+                    Ok(ClientMessage::SetPixelFormat(pixel_format))
+                }
+
+                2 => {
+                    // SetEncodings
+                    let padding = stream.read_u8().await.field("SetEncodings.padding")?;
+                    config.check_padding(&[padding], "SetEncodings")?;
+                    let num_encodings = stream
+                        .read_u16()
+                        .await
+                        .field("SetEncodings.number_of_encodings")?;
+                    if num_encodings > config.max_encodings {
+                        return Err(ProtoError::TooManyEncodings {
+                            count: num_encodings,
+                            max: config.max_encodings,
+                        }
+                        .into());
+                    }
+                    config.check_size(4 + 4 * num_encodings as u64, "SetEncodings")?;
+
+                    // An empty list is allowed; see `ClientMessage::SetEncodings`.
+                    let mut encodings = Vec::new();
+                    for _ in 0..num_encodings {
+                        let e: EncodingType = EncodingType::try_from(
+                            stream
+                                .read_i32()
+                                .await
+                                .field("SetEncodings.encoding_type")?,
+                        )?;
+                        encodings.push(e);
+                    }
+
+                    Ok(ClientMessage::SetEncodings(encodings))
+                }
+                3 => {
+                    // FramebufferUpdateRequest
+                    let incremental = stream
+                        .read_u8()
+                        .await
+                        .field("FramebufferUpdateRequest.incremental")?;
+                    let incremental = config.flag(incremental.into(), "incremental")?;
+                    let position = Position::read_from(stream).await?;
+                    let resolution = Resolution::read_from(stream).await?;
+
+                    let fbu_req = FramebufferUpdateRequest {
+                        incremental,
+                        position,
+                        resolution,
+                    };
+
+                    Ok(ClientMessage::FramebufferUpdateRequest(fbu_req))
+                }
+                4 => {
+                    // KeyEvent
+                    let is_pressed = stream.read_u8().await.field("KeyEvent.down_flag")?;
+                    let is_pressed = config.flag(is_pressed.into(), "down")?;
+
+                    // 2 bytes of padding
+                    let padding = stream.read_u16().await.field("KeyEvent.padding")?;
+                    config.check_padding(&padding.to_be_bytes(), "KeyEvent")?;
+
+                    let key = Keysym::try_from(stream.read_u32().await.field("KeyEvent.key")?)?;
+
+                    let key_event = KeyEvent {
+                        is_pressed,
+                        key,
+                        keycode: None,
+                    };
+
+                    Ok(ClientMessage::KeyEvent(key_event))
+                }
+                5 => {
+                    // PointerEvent
+                    let pointer_event = PointerEvent::read_from(stream).await?;
+                    Ok(ClientMessage::PointerEvent(pointer_event))
+                }
+                6 => {
+                    // ClientCutText
+
+                    // 3 bytes of padding
+                    let mut padding = [0u8; 3];
+                    stream
+                        .read_exact(&mut padding)
+                        .await
+                        .field("ClientCutText.padding")?;
+                    config.check_padding(&padding, "ClientCutText")?;
+
+                    let len = stream.read_u32().await.field("ClientCutText.length")?;
+                    config.check_size(8 + len as u64, "ClientCutText")?;
+                    let mut buf = vec![0u8; len as usize];
+                    stream
+                        .read_exact(&mut buf)
+                        .await
+                        .field("ClientCutText.text")?;
+
+                    // TODO: The encoding RFB uses is ISO 8859-1 (Latin-1), which is a subset of
+                    // utf-8. Determine if this is the right approach.
+                    let text = String::from_utf8(buf)?;
+
+                    Ok(ClientMessage::ClientCutText(text))
+                }
+                150 => {
+                    // EnableContinuousUpdates
+                    let ecu = EnableContinuousUpdates::read_with(stream, config).await?;
+                    Ok(ClientMessage::EnableContinuousUpdates(ecu))
+                }
+                251 => {
+                    // SetDesktopSize
+                    let set_desktop_size = SetDesktopSize::read_with(stream, config).await?;
+                    Ok(ClientMessage::SetDesktopSize(set_desktop_size))
+                }
+                255 => {
+                    // QEMU/vendor messages, multiplexed by a sub-type
+                    let sub_type = stream
+                        .read_u8()
+                        .await
+                        .field("QEMUClientMessage.submessage_type")?;
+                    match sub_type {
+                        0 => {
+                            // QEMU Extended Key Event
+                            let is_pressed = stream
+                                .read_u16()
+                                .await
+                                .field("QEMUExtendedKeyEvent.down_flag")?;
+                            let is_pressed = config.flag(is_pressed, "down")?;
+                            let key = Keysym::try_from(
+                                stream
+                                    .read_u32()
+                                    .await
+                                    .field("QEMUExtendedKeyEvent.keysym")?,
+                            )?;
+                            let keycode = stream
+                                .read_u32()
+                                .await
+                                .field("QEMUExtendedKeyEvent.keycode")?;
+
+                            let key_event = KeyEvent {
+                                is_pressed,
+                                key,
+                                keycode: Some(keycode),
+                            };
+
+                            Ok(ClientMessage::KeyEvent(key_event))
+                        }
+                        other => Err(ProtoError::UnknownVendorSubmessage(other).into()),
+                    }
+                }
+                t @ (1 | 248 | 250) if config.unknown_messages == UnknownMessagePolicy::Ignore => {
+                    skip_message(stream, t, config).await?;
+                    Ok(ClientMessage::Ignored(t))
+                }
+                unknown => Err(ProtoError::UnknownMessageType(unknown).into()),
+            };
+
+            res
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FramebufferUpdateRequest {
+    incremental: bool,
+    position: Position,
+    resolution: Resolution,
+}
+
+impl FramebufferUpdateRequest {
+    pub fn new(incremental: bool, x: u16, y: u16, width: u16, height: u16) -> Self {
+        FramebufferUpdateRequest {
+            incremental,
+            position: Position { x, y },
+            resolution: Resolution { width, height },
+        }
+    }
+
+    pub fn incremental(&self) -> bool {
+        self.incremental
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// The requested area of the framebuffer.
+    pub fn region(&self) -> Rect {
+        Rect::new(
+            self.position.x,
+            self.position.y,
+            self.resolution.width,
+            self.resolution.height,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyEvent {
+    is_pressed: bool,
+    key: Keysym,
+    keycode: Option<u32>,
+}
+
+impl KeyEvent {
+    pub fn new(key: Keysym, is_pressed: bool) -> Self {
+        KeyEvent {
+            is_pressed,
+            key,
+            keycode: None,
+        }
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.is_pressed
+    }
+
+    pub fn keysym(&self) -> &Keysym {
+        &self.key
+    }
+
+    /// Returns the XT scan code of the key, if the client sent the event as a QEMU Extended Key
+    /// Event. Unlike the keysym, this identifies the physical key regardless of keyboard layout.
+    pub fn keycode(&self) -> Option<u32> {
+        self.keycode
+    }
+}
+
+bitflags! {
+    #[derive(Default)]
+    pub struct MouseButtons: u8 {
+        const LEFT = 1 << 0;
+        const MIDDLE = 1 << 1;
+        const RIGHT = 1 << 2;
+        const SCROLL_A = 1 << 3;
+        const SCROLL_B = 1 << 4;
+        const SCROLL_C = 1 << 5;
+        const SCROLL_D = 1 << 6;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PointerEvent {
+    position: Position,
+    pressed: MouseButtons,
+    relative: bool,
+}
+
+/// In relative mode, motion is sent as a position offset by this much in each direction.
+const RELATIVE_POINTER_ORIGIN: i32 = 0x7fff;
+
+impl PointerEvent {
+    pub fn new(x: u16, y: u16, pressed: MouseButtons) -> Self {
+        PointerEvent {
+            position: Position { x, y },
+            pressed,
+            relative: false,
+        }
+    }
+
+    /// Marks whether the event was sent in relative mode, which depends on the session rather
+    /// than on the message.
+    pub fn with_relative(mut self, relative: bool) -> Self {
+        self.relative = relative;
+        self
+    }
+
+    /// Returns true if the client sent this event in relative mode, negotiated with the Pointer
+    /// Motion Change pseudo-encoding, in which case its position encodes motion; see `delta`.
+    pub fn is_relative(&self) -> bool {
+        self.relative
+    }
+
+    /// Returns the motion of a relative event, in pixels, or None for an absolute one.
+    pub fn delta(&self) -> Option<(i32, i32)> {
+        self.relative.then(|| {
+            (
+                i32::from(self.position.x) - RELATIVE_POINTER_ORIGIN,
+                i32::from(self.position.y) - RELATIVE_POINTER_ORIGIN,
+            )
+        })
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Returns the buttons that are currently pressed.
+    pub fn buttons(&self) -> MouseButtons {
+        self.pressed
+    }
+}
+
+/// Formats the event for logs as the position and the names of the pressed buttons, e.g.
+/// `(100, 200) LEFT|RIGHT`, or `(100, 200) none` if no buttons are pressed.
+impl std::fmt::Display for PointerEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {}) ", self.position.x, self.position.y)?;
+        if self.pressed.is_empty() {
+            return write!(f, "none");
+        }
+
+        let names = [
+            (MouseButtons::LEFT, "LEFT"),
+            (MouseButtons::MIDDLE, "MIDDLE"),
+            (MouseButtons::RIGHT, "RIGHT"),
+            (MouseButtons::SCROLL_A, "SCROLL_A"),
+            (MouseButtons::SCROLL_B, "SCROLL_B"),
+            (MouseButtons::SCROLL_C, "SCROLL_C"),
+            (MouseButtons::SCROLL_D, "SCROLL_D"),
+        ];
+        let mut first = true;
+        for (button, name) in names {
+            if self.pressed.contains(button) {
+                if !first {
+                    write!(f, "|")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReadMessage for PointerEvent {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let button_mask = stream.read_u8().await.field("PointerEvent.button_mask")?;
+            let pressed = MouseButtons::from_bits_truncate(button_mask);
+            let position = Position::read_from(stream).await?;
+
+            Ok(PointerEvent {
+                position,
+                pressed,
+                relative: false,
+            })
+        }
+        .boxed()
+    }
+}
+
+// ContinuousUpdates extension: EnableContinuousUpdates (client message type 150)
+//
+// Asks the server to send updates for a region as they happen, without waiting for
+// FramebufferUpdateRequests, or to stop doing so.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnableContinuousUpdates {
+    enable: bool,
+    region: Rect,
+}
+
+impl EnableContinuousUpdates {
+    pub fn new(enable: bool, x: u16, y: u16, width: u16, height: u16) -> Self {
+        EnableContinuousUpdates {
+            enable,
+            region: Rect::new(x, y, width, height),
+        }
+    }
+
+    pub fn enable(&self) -> bool {
+        self.enable
+    }
+
+    pub fn region(&self) -> Rect {
+        self.region
+    }
+}
+
+impl ReadMessage for EnableContinuousUpdates {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let config = ParseConfig::default();
+            Self::read_with(stream, &config).await
+        }
+        .boxed()
+    }
+}
+
+impl EnableContinuousUpdates {
+    /// Reads the message, without its message type, parsing it according to `config`.
+    pub fn read_with<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+        config: &'a ParseConfig,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async move {
+            let enable = stream
+                .read_u8()
+                .await
+                .field("EnableContinuousUpdates.enable")?;
+            let enable = config.flag(enable.into(), "enable")?;
+            let x = stream.read_u16().await.field("EnableContinuousUpdates.x")?;
+            let y = stream.read_u16().await.field("EnableContinuousUpdates.y")?;
+            let width = stream
+                .read_u16()
+                .await
+                .field("EnableContinuousUpdates.width")?;
+            let height = stream
+                .read_u16()
+                .await
+                .field("EnableContinuousUpdates.height")?;
+
+            Ok(EnableContinuousUpdates::new(enable, x, y, width, height))
+        }
+        .boxed()
+    }
+}
+
+// ContinuousUpdates extension: EndOfContinuousUpdates (server message type 150)
+//
+// Tells the client that the server supports continuous updates, in response to the
+// ContinuousUpdates pseudo-encoding, and that it has stopped sending them, in response to an
+// EnableContinuousUpdates that disables them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EndOfContinuousUpdates;
+
+impl WriteMessage for EndOfContinuousUpdates {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            stream
+                .write_u8(ServerMessageType::EndOfContinuousUpdates.to_u8())
+                .await?;
+            Ok(stream.flush().await?)
+        }
+        .boxed()
+    }
+}
+
+// ExtendedDesktopSize extension: SetDesktopSize (client message type 251)
+#[derive(Debug, Clone)]
+pub struct SetDesktopSize {
+    pub width: u16,
+    pub height: u16,
+    pub screens: Vec<Screen>,
+}
+
+impl ReadMessage for SetDesktopSize {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let config = ParseConfig::default();
+            Self::read_with(stream, &config).await
+        }
+        .boxed()
+    }
+}
+
+impl SetDesktopSize {
+    /// Reads the rest of a SetDesktopSize message, after the message type.
+    pub fn read_with<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+        config: &'a ParseConfig,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async move {
+            // 1 byte of padding
+            stream.read_u8().await.field("SetDesktopSize.padding")?;
+
+            let width = stream.read_u16().await.field("SetDesktopSize.width")?;
+            let height = stream.read_u16().await.field("SetDesktopSize.height")?;
+            let num_screens = stream
+                .read_u8()
+                .await
+                .field("SetDesktopSize.number_of_screens")?;
+
+            // 1 byte of padding
+            stream.read_u8().await.field("SetDesktopSize.padding")?;
+            config.check_size(8 + 16 * num_screens as u64, "SetDesktopSize")?;
+
+            let mut screens = Vec::with_capacity(num_screens as usize);
+            for _ in 0..num_screens {
+                screens.push(Screen::read_from(stream).await?);
+            }
+
+            Ok(SetDesktopSize {
+                width,
+                height,
+                screens,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// A single screen in the layout of the framebuffer, as used by the ExtendedDesktopSize
+/// extension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screen {
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub flags: u32,
+}
+
+impl ReadMessage for Screen {
+    fn read_from<'a, S: AsyncRead + Unpin + Send>(
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<Self>> {
+        async {
+            let id = stream.read_u32().await.field("Screen.id")?;
+            let x = stream.read_u16().await.field("Screen.x")?;
+            let y = stream.read_u16().await.field("Screen.y")?;
+            let width = stream.read_u16().await.field("Screen.width")?;
+            let height = stream.read_u16().await.field("Screen.height")?;
+            let flags = stream.read_u32().await.field("Screen.flags")?;
+
+            Ok(Screen {
+                id,
+                x,
+                y,
+                width,
+                height,
+                flags,
+            })
+        }
+        .boxed()
+    }
+}
+
+impl WriteMessage for Screen {
+    fn write_to<'a, S: AsyncWrite + Unpin + Send>(
+        self,
+        stream: &'a mut S,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            stream.write_u32(self.id).await?;
+            stream.write_u16(self.x).await?;
+            stream.write_u16(self.y).await?;
+            stream.write_u16(self.width).await?;
+            stream.write_u16(self.height).await?;
+            stream.write_u32(self.flags).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// The status of a desktop size change, sent as the y-position of an ExtendedDesktopSize
+/// rectangle.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DesktopSizeStatus {
+    Success,
+    Prohibited,
+    OutOfResources,
+    InvalidLayout,
+}
+
+impl From<DesktopSizeStatus> for u16 {
+    fn from(s: DesktopSizeStatus) -> Self {
+        match s {
+            DesktopSizeStatus::Success => 0,
+            DesktopSizeStatus::Prohibited => 1,
+            DesktopSizeStatus::OutOfResources => 2,
+            DesktopSizeStatus::InvalidLayout => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixel_formats::fourcc;
+
+    #[test]
+    fn test_pixel_format_color_format() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+
+        assert!(pf.is_true_color());
+        assert!(pf.color_map().is_none());
+        let cf = pf.color_format().unwrap();
+        assert_eq!(cf.red_shift, 16);
+        assert_eq!(cf.blue_max, 255);
+    }
+
+    #[test]
+    fn test_pixel_format_hash() {
+        use std::collections::HashMap;
+
+        let xr24 = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let bx24 = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_BX24).unwrap();
+
+        let mut formats = HashMap::new();
+        formats.insert((xr24.clone(), bx24.clone()), "xr24 to bx24");
+        formats.insert((bx24.clone(), xr24.clone()), "bx24 to xr24");
+        assert_eq!(formats.len(), 2);
+
+        let key = (
+            fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap(),
+            bx24,
+        );
+        assert_eq!(formats.get(&key), Some(&"xr24 to bx24"));
+        assert_eq!(formats.get(&(xr24.clone(), xr24)), None);
+    }
+
+    #[test]
+    fn test_pixel_format_color_map() {
+        let pf = PixelFormat {
+            bits_per_pixel: 8,
+            depth: 8,
+            big_endian: false,
+            color_spec: ColorSpecification::ColorMap(ColorMap {}),
+        };
+
+        assert!(!pf.is_true_color());
+        assert!(pf.color_format().is_none());
+        assert_eq!(pf.color_map(), Some(&ColorMap {}));
+    }
+
+    #[test]
+    fn test_scroll_one_line() {
+        let region = Rect::new(0, 0, 640, 480);
+        let exposed = RawEncoding::new(vec![0u8; 640 * 16 * 4]);
+        let encodings = [EncodingType::CopyRect, EncodingType::Raw];
+
+        let fbu = FramebufferUpdate::scroll(region, 0, -16, exposed, &encodings, || {
+            panic!("the whole region isn't needed")
+        });
+        assert_eq!(fbu.rectangles.len(), 2);
+
+        // The remaining lines move up by one line...
+        let copy = &fbu.rectangles[0];
+        assert_eq!(copy.data.get_type(), EncodingType::CopyRect);
+        assert_eq!(copy.position, Position::new(0, 0));
+        assert_eq!(copy.dimensions, Resolution::new(640, 464));
+        assert_eq!(copy.data.encode(), &vec![0, 0, 0, 16]);
+
+        // ... and the new line is drawn at the bottom.
+        let raw = &fbu.rectangles[1];
+        assert_eq!(raw.data.get_type(), EncodingType::Raw);
+        assert_eq!(raw.position, Position::new(0, 464));
+        assert_eq!(raw.dimensions, Resolution::new(640, 16));
+    }
+
+    #[test]
+    fn test_scroll_without_copy_rect() {
+        let region = Rect::new(0, 0, 640, 480);
+        let exposed = || RawEncoding::new(vec![0u8; 640 * 16 * 4]);
+        let full = || RawEncoding::new(vec![0u8; 640 * 480 * 4]);
+
+        // Without CopyRect, the whole region is sent as Raw.
+        let fbu = FramebufferUpdate::scroll(region, 0, -16, exposed(), &[EncodingType::Raw], full);
+        assert_eq!(fbu.rectangles.len(), 1);
+        let raw = &fbu.rectangles[0];
+        assert_eq!(raw.data.get_type(), EncodingType::Raw);
+        assert_eq!(raw.position, Position::new(0, 0));
+        assert_eq!(raw.dimensions, Resolution::new(640, 480));
+        assert_eq!(raw.data.encode().len(), 640 * 480 * 4);
+
+        // So is a diagonal scroll, which CopyRect and a single strip can't express.
+        let encodings = [EncodingType::CopyRect, EncodingType::Raw];
+        let fbu = FramebufferUpdate::scroll(region, 16, -16, exposed(), &encodings, full);
+        assert_eq!(fbu.rectangles.len(), 1);
+        assert_eq!(fbu.rectangles[0].dimensions, Resolution::new(640, 480));
+
+        // Not scrolling changes nothing.
+        let fbu = FramebufferUpdate::scroll(region, 0, 0, exposed(), &encodings, full);
+        assert!(fbu.is_empty());
+    }
+
+    /// A writer that records how much had been written each time it was flushed.
+    #[derive(Default)]
+    struct FlushRecorder {
+        data: Vec<u8>,
+        flushes: Vec<usize>,
+    }
+
+    impl AsyncWrite for FlushRecorder {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.data.extend_from_slice(buf);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let len = self.data.len();
+            self.flushes.push(len);
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_messages_flushed() {
+        let mut w = FlushRecorder::default();
+        let r = Rectangle::new(0, 0, 1, 1, Box::new(RawEncoding::new(vec![1, 2, 3, 4])));
+        FramebufferUpdate::new(vec![r])
+            .write_to(&mut w)
+            .await
+            .unwrap();
+        assert_eq!(w.data.len(), 4 + 12 + 4);
+        assert_eq!(w.flushes, vec![w.data.len()]);
+
+        CutText::new("hi".to_string())
+            .write_to(&mut w)
+            .await
+            .unwrap();
+        EndOfContinuousUpdates.write_to(&mut w).await.unwrap();
+        assert_eq!(w.flushes, vec![20, 30, 31]);
+    }
+
+    #[tokio::test]
+    async fn test_cursor_position() {
+        let unsupported = ClientEncodings::new(&[EncodingType::Raw]);
+        assert!(FramebufferUpdate::cursor_position(10, 20, &unsupported).is_none());
+
+        let encodings = ClientEncodings::new(&[EncodingType::Raw, EncodingType::CursorPosPseudo]);
+        let fbu = FramebufferUpdate::cursor_position(320, 240, &encodings).unwrap();
+        let r = &fbu.rectangles()[0];
+        assert_eq!(r.position(), Position::new(320, 240));
+        assert_eq!(r.dimensions(), Resolution::new(0, 0));
+        assert!(r.data().encode().is_empty());
+
+        let mut buf = Vec::new();
+        fbu.write_ref_to(&mut buf).await.unwrap();
+        assert_eq!(
+            buf,
+            [0, 0, 0, 1, 0x01, 0x40, 0, 0xf0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x18]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vmware_cursor() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let cursor = || VMwareCursorEncoding::classic(&[0xff; 8], &[0x11; 8]);
+        let unsupported = ClientEncodings::new(&[EncodingType::Raw]);
+        assert!(FramebufferUpdate::vmware_cursor(0, 0, 2, 1, cursor(), &unsupported).is_none());
+
+        // A 2x1 classic cursor with its hotspot at (1, 0).
+        let encodings = ClientEncodings::new(&[EncodingType::VMwareCursorPseudo]);
+        let fbu = FramebufferUpdate::vmware_cursor(1, 0, 2, 1, cursor(), &encodings).unwrap();
+        let mut buf = Vec::new();
+        fbu.write_ref_to(&mut buf).await.unwrap();
+        assert_eq!(&buf[12..18], &[0x57, 0x4d, 0x56, 0x64, 0, 0]);
+        assert_eq!(buf.len(), 4 + 12 + 2 + 16);
+
+        let read = FramebufferUpdate::read_from(&mut &buf[..], &pf, &EncodingRegistry::default())
+            .await
+            .unwrap();
+        let r = &read.rectangles()[0];
+        assert_eq!(r.data().get_type(), EncodingType::VMwareCursorPseudo);
+        assert_eq!(r.data().encode(), cursor().encode());
+
+        let alpha = VMwareCursorEncoding::alpha(&[1, 2, 3, 4]);
+        assert!(alpha.is_alpha());
+        assert_eq!(alpha.encode(), &[1, 0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn test_update_split_by_rectangle_count() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let rectangles = (0..70000u32)
+            .map(|i| {
+                let (x, y) = ((i % 256) as u16, (i / 256) as u16);
+                let pixel = i.to_le_bytes().to_vec();
+                Rectangle::new(x, y, 1, 1, Box::new(RawEncoding::new(pixel)))
+            })
+            .collect();
+        let fbu = FramebufferUpdate::new(rectangles);
+
+        let mut buf = Vec::new();
+        fbu.write_ref_to(&mut buf).await.unwrap();
+
+        // Two complete updates, together holding every rectangle in order.
+        let registry = EncodingRegistry::default();
+        let mut stream = &buf[..];
+        let first = FramebufferUpdate::read_from(&mut stream, &pf, &registry)
+            .await
+            .unwrap();
+        let second = FramebufferUpdate::read_from(&mut stream, &pf, &registry)
+            .await
+            .unwrap();
+        assert!(stream.is_empty());
+
+        assert_eq!(first.rectangles.len(), MAX_UPDATE_RECTANGLES as usize);
+        assert_eq!(
+            second.rectangles.len(),
+            70000 - MAX_UPDATE_RECTANGLES as usize
+        );
+        let last = second.rectangles.last().unwrap();
+        assert_eq!(last.position, Position::new(111, 273));
+        assert_eq!(last.data.encode(), &69999u32.to_le_bytes());
+
+        // An empty update is still sent.
+        let mut buf = Vec::new();
+        FramebufferUpdate::new(vec![])
+            .write_ref_to(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(buf, [0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_server_init_max_dimension() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let server_init = |width, height| {
+            let pf = pf.clone();
+            async move {
+                let mut buf = Vec::new();
+                ServerInit::new(width, height, "upstream".to_string(), pf)
+                    .write_to(&mut buf)
+                    .await
+                    .unwrap();
+                buf
+            }
+        };
+
+        let buf = server_init(640, 480).await;
+        let read = ServerInit::read_from(&mut &buf[..]).await.unwrap();
+        assert_eq!(read.resolution(), Resolution::new(640, 480));
+        assert_eq!(read.name(), "upstream");
+
+        let buf = server_init(65535, 65535).await;
+        let err = ServerInit::read_from(&mut &buf[..]).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::DimensionTooLarge {
+                width: 65535,
+                height: 65535,
+                max: 16384
+            })
+        ));
+
+        // The limit is configurable.
+        let config = ParseConfig {
+            max_dimension: 512,
+            ..Default::default()
+        };
+        let buf = server_init(640, 480).await;
+        assert!(ServerInit::read_with(&mut &buf[..], &config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_raw() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+
+        // A 2x2 rectangle at (1, 1) in a 4x3 framebuffer, sent and read back as a client would.
+        let pixels: Vec<u8> = (1..=4u8).flat_map(|p| [p; 4]).collect();
+        let fbu = FramebufferUpdate::new(vec![Rectangle::new(
+            1,
+            1,
+            2,
+            2,
+            Box::new(RawEncoding::new(pixels)),
+        )]);
+        let mut buf = Vec::new();
+        fbu.write_to(&mut buf).await.unwrap();
+        let fbu = FramebufferUpdate::read_from(&mut &buf[..], &pf, &EncodingRegistry::default())
+            .await
+            .unwrap();
+
+        let mut framebuffer = vec![0u8; 4 * 3 * 4];
+        fbu.apply_to(&mut framebuffer, 4, &pf).unwrap();
+        let rows: Vec<Vec<u8>> = framebuffer
+            .chunks(16)
+            .map(|row| row.chunks(4).map(|p| p[0]).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![vec![0, 0, 0, 0], vec![0, 1, 2, 0], vec![0, 3, 4, 0]]
+        );
+
+        // A rectangle that doesn't fit is an error.
+        let fbu = FramebufferUpdate::new(vec![Rectangle::new(
+            3,
+            0,
+            2,
+            1,
+            Box::new(RawEncoding::new(vec![0; 8])),
+        )]);
+        assert!(fbu.apply_to(&mut framebuffer, 4, &pf).is_err());
+    }
+
+    #[test]
+    fn test_apply_copy_rect() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let pixel_rows = |framebuffer: &[u8]| -> Vec<Vec<u8>> {
+            framebuffer
+                .chunks(12)
+                .map(|row| row.chunks(4).map(|p| p[0]).collect())
+                .collect()
+        };
+
+        // A 3x3 framebuffer, numbered 1 to 9. A Raw rectangle changes the middle, then the top
+        // two rows are copied down a row, overlapping their source; the copy sees the change.
+        let mut framebuffer: Vec<u8> = (1..=9u8).flat_map(|p| [p; 4]).collect();
+        let fbu = FramebufferUpdate::new(vec![
+            Rectangle::new(1, 1, 1, 1, Box::new(RawEncoding::new(vec![0; 4]))),
+            Rectangle::new(0, 1, 3, 2, Box::new(CopyRectEncoding::new(0, 0))),
+        ]);
+        fbu.apply_to(&mut framebuffer, 3, &pf).unwrap();
+        assert_eq!(
+            pixel_rows(&framebuffer),
+            vec![vec![1, 2, 3], vec![1, 2, 3], vec![4, 0, 6]]
+        );
+
+        // And back up, overlapping the other way.
+        let fbu = FramebufferUpdate::new(vec![Rectangle::new(
+            0,
+            0,
+            3,
+            2,
+            Box::new(CopyRectEncoding::new(0, 1)),
+        )]);
+        fbu.apply_to(&mut framebuffer, 3, &pf).unwrap();
+        assert_eq!(
+            pixel_rows(&framebuffer),
+            vec![vec![1, 2, 3], vec![4, 0, 6], vec![4, 0, 6]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_solid_color() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let pixel = [0x00, 0x80, 0xff, 0x00];
+        let encodings = [EncodingType::RRE, EncodingType::Raw];
+
+        // Header, rectangle header, subrectangle count and background, whatever the size.
+        for (w, h) in [(1, 1), (640, 480), (4096, 4096)] {
+            let fbu = FramebufferUpdate::solid_color(w, h, &pixel, &pf, &encodings);
+            let mut buf = Vec::new();
+            fbu.write_to(&mut buf).await.unwrap();
+            assert_eq!(buf.len(), 4 + 12 + 4 + 4);
+            assert_eq!(&buf[16..], &[0, 0, 0, 0, 0x00, 0x80, 0xff, 0x00]);
+        }
+
+        // It reads back as a solid rectangle of the full size.
+        let fbu = FramebufferUpdate::solid_color(640, 480, &pixel, &pf, &encodings);
+        let mut buf = Vec::new();
+        fbu.write_to(&mut buf).await.unwrap();
+        let fbu = FramebufferUpdate::read_from(&mut &buf[..], &pf, &EncodingRegistry::default())
+            .await
+            .unwrap();
+        let r = &fbu.rectangles()[0];
+        assert_eq!(r.dimensions(), Resolution::new(640, 480));
+        assert_eq!(
+            r.data().raw_pixels(640, 480).unwrap(),
+            pixel.repeat(640 * 480)
+        );
+
+        let fbu = FramebufferUpdate::solid_color(16, 8, &pixel, &pf, &[EncodingType::Raw]);
+        let r = &fbu.rectangles()[0];
+        assert_eq!(r.data().get_type(), EncodingType::Raw);
+        assert_eq!(r.data().encode(), pixel.repeat(16 * 8).as_slice());
+    }
+
+    fn build_mixed_update(encodings: &[EncodingType]) -> FramebufferUpdate {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+
+        FramebufferUpdateBuilder::new(encodings)
+            .copy_rect(Rect::new(0, 0, 8, 8), Position::new(0, 8), || {
+                vec![0u8; 8 * 8 * 4]
+            })
+            .tight(Rect::new(8, 0, 8, 8), vec![0xffu8; 8 * 8 * 4], &pf)
+            .raw(Rect::new(16, 0, 1, 1), vec![0u8; 4])
+            .build()
+    }
+
+    fn encoding_types(fbu: &FramebufferUpdate) -> Vec<EncodingType> {
+        fbu.rectangles().iter().map(|r| r.encoding_type()).collect()
+    }
+
+    #[test]
+    fn test_builder_mixed_encodings() {
+        let encodings = [
+            EncodingType::Tight,
+            EncodingType::CopyRect,
+            EncodingType::Raw,
+        ];
+        let fbu = build_mixed_update(&encodings);
+        assert_eq!(
+            encoding_types(&fbu),
+            vec![
+                EncodingType::CopyRect,
+                EncodingType::Tight,
+                EncodingType::Raw
+            ]
+        );
+
+        // A Raw-only client gets everything as Raw.
+        let fbu = build_mixed_update(&[EncodingType::Raw]);
+        assert_eq!(encoding_types(&fbu), vec![EncodingType::Raw; 3]);
+        assert_eq!(fbu.rectangles[0].data.encode().len(), 8 * 8 * 4);
+    }
+
+    #[test]
+    fn test_builder_tight_bands() {
+        // Noise doesn't compress, so the whole rectangle would need more than Tight's 22-bit
+        // length can describe.
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let mut seed = 1u32;
+        let pixels: Vec<u8> = (0..2048 * 700 * 4)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+
+        let fbu = FramebufferUpdateBuilder::new(&[EncodingType::Tight])
+            .tight(Rect::new(0, 10, 2048, 700), pixels, &pf)
+            .build();
+        let bands: Vec<(u16, u16)> = fbu
+            .rectangles()
+            .iter()
+            .map(|r| (r.position().y, r.dimensions().height()))
+            .collect();
+        assert_eq!(bands, vec![(10, 256), (266, 256), (522, 188)]);
+
+        for r in fbu.rectangles() {
+            assert_eq!(r.encoding_type(), EncodingType::Tight);
+            let data = r.data().encode();
+            let mut len = 0;
+            let mut i = 1;
+            for shift in [0, 7, 14] {
+                let b = data[i];
+                i += 1;
+                if shift == 14 {
+                    len |= usize::from(b) << shift;
+                    break;
+                }
+                len |= usize::from(b & 0x7f) << shift;
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            assert_eq!(len, data.len() - i);
+        }
+    }
+
+    fn server_init(name: &str, name_encoding: NameEncoding) -> ServerInit {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        ServerInit::new(8, 8, name.to_string(), pf).with_name_encoding(name_encoding)
+    }
+
+    #[tokio::test]
+    async fn test_server_init_name_utf8() {
+        let mut buf = Vec::new();
+        server_init("Café", NameEncoding::Utf8)
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+
+        assert_eq!(&buf[20..], &[0, 0, 0, 5, b'C', b'a', b'f', 0xc3, 0xa9]);
+    }
+
+    #[tokio::test]
+    async fn test_server_init_name_latin1() {
+        let mut buf = Vec::new();
+        server_init("Café", NameEncoding::Latin1)
+            .write_to(&mut buf)
+            .await
+            .unwrap();
+
+        assert_eq!(&buf[20..], &[0, 0, 0, 4, b'C', b'a', b'f', 0xe9]);
+
+        // Characters outside of Latin-1 can't be sent, and nothing is written.
+        let mut buf = Vec::new();
+        assert!(server_init("日本", NameEncoding::Latin1)
+            .write_to(&mut buf)
+            .await
+            .is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_conversion_quality() {
+        let rgb888 = PixelFormat::new_colorformat(32, 24, false, 0, 255, 8, 255, 16, 255);
+        let bgr888 = PixelFormat::new_colorformat(32, 24, true, 16, 255, 8, 255, 0, 255);
+        let rgb565 = PixelFormat::new_colorformat(16, 16, false, 11, 31, 5, 63, 0, 31);
+
+        assert_eq!(rgb888.conversion_quality(&rgb888), Quality::Lossless);
+        assert_eq!(bgr888.conversion_quality(&rgb888), Quality::Lossless);
+        assert_eq!(rgb565.conversion_quality(&rgb888), Quality::Lossy);
+        assert_eq!(rgb888.conversion_quality(&rgb565), Quality::Lossless);
+    }
+
+    #[tokio::test]
+    async fn test_strict_padding() {
+        // SetEncodings with a nonzero padding byte and a single Raw encoding.
+        let msg = [2u8, 0xff, 0, 1, 0, 0, 0, 0];
+
+        let lenient = ParseConfig::default();
+        let res = ClientMessage::read_with(&mut &msg[..], &lenient).await;
+        assert!(matches!(res, Ok(ClientMessage::SetEncodings(e)) if e == vec![EncodingType::Raw]));
+
+        let strict = ParseConfig {
+            strict_padding: true,
+            ..Default::default()
+        };
+        let err = ClientMessage::read_with(&mut &msg[..], &strict)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::NonZeroPadding {
+                message: "SetEncodings"
+            })
+        ));
+
+        // Zero padding is accepted in strict mode.
+        let msg = [2u8, 0, 0, 1, 0, 0, 0, 0];
+        assert!(ClientMessage::read_with(&mut &msg[..], &strict)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_version_negotiate() {
+        let client = |v: &[u8; 12]| ProtoVersion::parse(v).unwrap();
+
+        assert_eq!(
+            ProtoVersion::negotiate(ProtoVersion::Rfb38, client(b"RFB 003.003\n")),
+            Some(ProtoVersion::Rfb33)
+        );
+        assert_eq!(
+            ProtoVersion::negotiate(ProtoVersion::Rfb38, client(b"RFB 003.005\n")),
+            Some(ProtoVersion::Rfb33)
+        );
+        assert_eq!(
+            ProtoVersion::negotiate(ProtoVersion::Rfb37, client(b"RFB 003.008\n")),
+            Some(ProtoVersion::Rfb37)
+        );
+        assert_eq!(
+            ProtoVersion::negotiate(ProtoVersion::Rfb38, client(b"RFB 004.001\n")),
+            Some(ProtoVersion::Rfb38)
+        );
+        assert_eq!(
+            ProtoVersion::negotiate(ProtoVersion::Rfb38, client(b"RFB 003.002\n")),
+            None
+        );
+
+        assert_eq!(ProtoVersion::parse(b"RFB 003.0x8\n"), None);
+    }
+
+    #[test]
+    fn test_rect_geometry() {
+        let a = Rect::new(10, 10, 20, 20);
+        let b = Rect::new(20, 0, 20, 15);
+
+        // Overlapping
+        assert_eq!(a.intersect(&b), Some(Rect::new(20, 10, 10, 5)));
+        assert_eq!(b.intersect(&a), a.intersect(&b));
+        assert_eq!(a.union(&b), Rect::new(10, 0, 30, 30));
+
+        // Disjoint, including rectangles that only share an edge
+        let c = Rect::new(100, 100, 5, 5);
+        assert_eq!(a.intersect(&c), None);
+        assert_eq!(a.intersect(&Rect::new(30, 10, 5, 5)), None);
+        assert_eq!(a.union(&c), Rect::new(10, 10, 95, 95));
+
+        assert!(a.contains(&a));
+        assert!(a.contains(&Rect::new(15, 15, 15, 15)));
+        assert!(!a.contains(&b));
+        assert!(!a.contains(&Rect::new(15, 15, 16, 1)));
+        let edge = Rect::new(u16::MAX - 1, 0, 1, 1);
+        assert!(Rect::new(0, 0, u16::MAX, u16::MAX).contains(&edge));
+    }
+
+    #[test]
+    fn test_security_types_builder() {
+        let types = SecurityTypes::builder()
+            .offer(SecurityType::VncAuthentication)
+            .offer(SecurityType::None)
+            .build()
+            .unwrap();
+        assert_eq!(
+            types.0,
+            vec![SecurityType::VncAuthentication, SecurityType::None]
+        );
+        assert!(types.contains(&SecurityType::None));
+
+        let err = SecurityTypes::builder()
+            .offer(SecurityType::None)
+            .offer(SecurityType::VncAuthentication)
+            .offer(SecurityType::None)
+            .build()
+            .unwrap_err();
+        assert_eq!(err.0, SecurityType::None);
+    }
+
+    /// Writes `msg` and reads it back through `Codec`, returning what was read and the bytes.
+    async fn codec_round_trip<T: Codec>(msg: &T) -> (T, Vec<u8>) {
+        let mut buf = Vec::new();
+        msg.write(&mut buf).await.unwrap();
+        let read = T::read(&mut buf.as_slice()).await.unwrap();
+        (read, buf)
+    }
+
+    #[tokio::test]
+    async fn test_codec() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let (read, buf) = codec_round_trip(&pf).await;
+        assert_eq!(read, pf);
+        assert_eq!(buf.len(), 16);
+
+        let messages = vec![
+            ClientMessage::SetPixelFormat(pf),
+            ClientMessage::SetEncodings(vec![EncodingType::Raw, EncodingType::CursorPosPseudo]),
+            ClientMessage::FramebufferUpdateRequest(FramebufferUpdateRequest::new(
+                true, 1, 2, 640, 480,
+            )),
+            ClientMessage::KeyEvent(KeyEvent::new(Keysym::Utf32('a'), true)),
+            ClientMessage::KeyEvent(KeyEvent {
+                is_pressed: false,
+                key: Keysym::FunctionKey(5),
+                keycode: Some(0x3f),
+            }),
+            ClientMessage::PointerEvent(PointerEvent::new(300, 200, MouseButtons::LEFT)),
+            ClientMessage::ClientCutText("hello".to_string()),
+            ClientMessage::SetDesktopSize(SetDesktopSize {
+                width: 800,
+                height: 600,
+                screens: vec![Screen {
+                    id: 1,
+                    x: 0,
+                    y: 0,
+                    width: 800,
+                    height: 600,
+                    flags: 0,
+                }],
+            }),
+            ClientMessage::EnableContinuousUpdates(EnableContinuousUpdates::new(
+                true, 0, 0, 64, 48,
+            )),
+        ];
+
+        // Each message reads back as one that writes the same bytes.
+        for msg in &messages {
+            let (read, buf) = codec_round_trip(msg).await;
+            let mut again = Vec::new();
+            read.write(&mut again).await.unwrap();
+            assert_eq!(buf, again, "{:?}", msg);
+        }
+
+        let (_, buf) = codec_round_trip(&messages[1]).await;
+        assert_eq!(buf, [2, 0, 0, 2, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x18]);
+        let (read, _) = codec_round_trip(&messages[4]).await;
+        match read {
+            ClientMessage::KeyEvent(ke) => {
+                assert_eq!(ke.keysym(), &Keysym::FunctionKey(5));
+                assert_eq!(ke.keycode(), Some(0x3f));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        assert!(ClientMessage::Ignored(250)
+            .write(&mut Vec::new())
+            .await
+            .is_err());
+
+        // Lists too long for their count field aren't truncated; nothing is written.
+        let mut buf = Vec::new();
+        let encodings = vec![EncodingType::Raw; usize::from(u16::MAX) + 1];
+        let res = ClientMessage::SetEncodings(encodings)
+            .write_to(&mut buf)
+            .await;
+        assert!(res.is_err());
+        let screen = Screen {
+            id: 0,
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            flags: 0,
+        };
+        let ds = SetDesktopSize {
+            width: 1,
+            height: 1,
+            screens: vec![screen; 256],
+        };
+        let res = ClientMessage::SetDesktopSize(ds).write_to(&mut buf).await;
+        assert!(res.is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_version_order() {
+        assert!(ProtoVersion::Rfb33 < ProtoVersion::Rfb38);
+        assert!(ProtoVersion::Rfb37 > ProtoVersion::Rfb33);
+
+        // Versions compare as their version numbers do.
+        let all = [
+            ProtoVersion::Rfb38,
+            ProtoVersion::Rfb33,
+            ProtoVersion::Rfb37,
+        ];
+        for a in all {
+            for b in all {
+                assert_eq!(a.cmp(&b), a.numeric().cmp(&b.numeric()));
+            }
+        }
+
+        let mut sorted = all;
+        sorted.sort();
+        assert_eq!(sorted.map(|v| v.numeric()), [(3, 3), (3, 7), (3, 8)]);
+    }
+
+    #[tokio::test]
+    async fn test_version_read() {
+        let read = |v: &'static [u8; 12]| async move {
+            let mut stream = &v[..];
+            ProtoVersion::read_from(&mut stream).await
+        };
+
+        assert_eq!(read(b"RFB 003.008\n").await.unwrap(), ProtoVersion::Rfb38);
+        assert_eq!(read(b"RFB 003.889\n").await.unwrap(), ProtoVersion::Rfb38);
+        assert_eq!(read(b"RFB 003.005\n").await.unwrap(), ProtoVersion::Rfb33);
+
+        for v in [b"RFB 003.002\n", b"GET / HTTP/1"] {
+            let err = read(v).await.unwrap_err();
+            match err.downcast_ref::<ProtoError>() {
+                Some(ProtoError::InvalidProtocolVersion(received)) => {
+                    assert_eq!(received.as_bytes(), v)
+                }
+                _ => panic!("unexpected error: {:?}", err),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_security_result_failure() {
+        let failure = || SecurityResult::Failure("no".to_string());
+
+        let mut buf = Vec::new();
+        failure().write_to(&mut buf).await.unwrap();
+        assert_eq!(buf, vec![0, 0, 0, 1, 0, 0, 0, 2, b'n', b'o']);
+
+        let mut buf = Vec::new();
+        failure()
+            .write_for_version(&mut buf, ProtoVersion::Rfb37)
+            .await
+            .unwrap();
+        assert_eq!(buf, vec![0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_client_init_round_trip() {
+        for shared in [false, true] {
+            let mut buf = Vec::new();
+            ClientInit::new(shared).write_to(&mut buf).await.unwrap();
+            assert_eq!(buf, vec![shared as u8]);
+
+            let init = ClientInit::read_from(&mut buf.as_slice()).await.unwrap();
+            assert_eq!(init, ClientInit::new(shared));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_security_result_read() {
+        let buf = [0u8, 0, 0, 0];
+        let res = SecurityResult::read_from(&mut &buf[..], ProtoVersion::Rfb38).await;
+        assert_eq!(res.unwrap(), SecurityResult::Success);
+
+        // With 3.8, a failure is followed by its reason.
+        let buf = [0u8, 0, 0, 1, 0, 0, 0, 2, b'n', b'o'];
+        let res = SecurityResult::read_from(&mut &buf[..], ProtoVersion::Rfb38).await;
+        assert_eq!(res.unwrap(), SecurityResult::Failure("no".to_string()));
+
+        // Earlier versions send just the status, so nothing more is read.
+        let mut rest = &buf[..];
+        let res = SecurityResult::read_from(&mut rest, ProtoVersion::Rfb37).await;
+        assert_eq!(res.unwrap(), SecurityResult::Failure(String::new()));
+        assert_eq!(rest.len(), 6);
+
+        // A reason longer than the limit is rejected before it's read.
+        let config = ParseConfig {
+            max_message_bytes: 9,
+            ..ParseConfig::default()
+        };
+        let res = SecurityResult::read_with(&mut &buf[..], ProtoVersion::Rfb38, &config).await;
+        let err = res.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::MessageTooLarge {
+                message: "SecurityResult",
+                size: 10,
+                max: 9,
+            })
+        ));
+
+        // By default, a reason claiming to be 4 GiB isn't allocated.
+        let huge = [0u8, 0, 0, 1, 0xff, 0xff, 0xff, 0xff];
+        let res = SecurityResult::read_from(&mut &huge[..], ProtoVersion::Rfb38).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_strict_endian_flag() {
+        let strict = ParseConfig {
+            strict_flags: true,
+            ..Default::default()
+        };
+        let lenient = ParseConfig::default();
+
+        for flag in 0u8..=2 {
+            let pf = [32, 24, flag, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+
+            let res = PixelFormat::read_with(&mut &pf[..], &lenient).await;
+            assert_eq!(res.unwrap().big_endian, flag != 0);
+
+            let res = PixelFormat::read_with(&mut &pf[..], &strict).await;
+            if flag <= 1 {
+                assert_eq!(res.unwrap().big_endian, flag != 0);
+            } else {
+                let err = res.unwrap_err();
+                assert!(matches!(
+                    err.downcast_ref::<ProtoError>(),
+                    Some(ProtoError::InvalidEndianFlag(2))
+                ));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strict_flags() {
+        let strict = ParseConfig {
+            strict_flags: true,
+            ..Default::default()
+        };
+        let lenient = ParseConfig::default();
+
+        // Each message has a flag of 2.
+        let messages: [(&[u8], &str); 5] = [
+            (&[3, 2, 0, 0, 0, 0, 0, 1, 0, 1], "incremental"),
+            (&[4, 2, 0, 0, 0, 0, 0, 0x61], "down"),
+            (&[255, 0, 0, 2, 0, 0, 0, 0x61, 0, 0, 0, 0x1e], "down"),
+            (&[150, 2, 0, 0, 0, 0, 0, 1, 0, 1], "enable"),
+            (
+                &[
+                    0, 0, 0, 0, 32, 24, 0, 2, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0,
+                ],
+                "true-colour",
+            ),
+        ];
+        for (msg, flag) in messages {
+            assert!(ClientMessage::read_with(&mut &msg[..], &lenient)
+                .await
+                .is_ok());
+
+            let err = ClientMessage::read_with(&mut &msg[..], &strict)
+                .await
+                .unwrap_err();
+            match err.downcast_ref::<ProtoError>() {
+                Some(ProtoError::InvalidFlag { field, value: 2 }) => assert_eq!(*field, flag),
+                other => panic!("unexpected error for {} flag: {:?}", flag, other),
+            }
+        }
+
+        // Flags of 0 and 1 are accepted.
+        let msg = [3u8, 1, 0, 0, 0, 0, 0, 1, 0, 1];
+        match ClientMessage::read_with(&mut &msg[..], &strict).await {
+            Ok(ClientMessage::FramebufferUpdateRequest(req)) => assert!(req.incremental()),
+            other => panic!("unexpected result {:?}", other.map_err(|e| e.to_string())),
+        }
+    }
+
+    fn solid_rects(n: u8) -> impl Iterator<Item = Rectangle> {
+        (0..n).map(|i| Rectangle::new(i as u16, 0, 1, 1, Box::new(RawEncoding::new(vec![i; 4]))))
+    }
+
+    #[tokio::test]
+    async fn test_write_from_iter() {
+        let mut buf = Vec::new();
+        FramebufferUpdate::write_from_iter(&mut buf, Some(3), solid_rects(3))
+            .await
+            .unwrap();
+
+        // The same bytes as an update built up front.
+        let mut expected = Vec::new();
+        FramebufferUpdate::new(solid_rects(3).collect())
+            .write_to(&mut expected)
+            .await
+            .unwrap();
+        assert_eq!(buf, expected);
+
+        // A count that doesn't match is an error.
+        let mut buf = Vec::new();
+        assert!(
+            FramebufferUpdate::write_from_iter(&mut buf, Some(2), solid_rects(3))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_from_iter_last_rect() {
+        let mut buf = Vec::new();
+        FramebufferUpdate::write_from_iter(&mut buf, None, solid_rects(3))
+            .await
+            .unwrap();
+
+        assert_eq!(&buf[..4], &[0, 0, 0xff, 0xff]);
+        // Three 1x1 Raw rectangles of 16 bytes each, then the LastRect.
+        assert_eq!(buf.len(), 4 + 3 * 16 + 12);
+        assert_eq!(
+            &buf[52..],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x20]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_too_many_encodings() {
+        let config = ParseConfig {
+            max_encodings: 2,
+            ..Default::default()
+        };
+
+        // SetEncodings claiming 3 encodings; none of them need to be read.
+        let msg = [2u8, 0, 0, 3];
+        let err = ClientMessage::read_with(&mut &msg[..], &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::TooManyEncodings { count: 3, max: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_qemu_extended_key_event() {
+        // Pressing 'a' (keysym 0x61), which is XT scan code 0x1e.
+        let msg = [255u8, 0, 0, 1, 0, 0, 0, 0x61, 0, 0, 0, 0x1e];
+
+        let res = ClientMessage::read_from(&mut &msg[..]).await;
+        match res {
+            Ok(ClientMessage::KeyEvent(ke)) => {
+                assert!(ke.is_pressed());
+                assert!(matches!(ke.keysym(), Keysym::Utf32('a')));
+                assert_eq!(ke.keycode(), Some(0x1e));
+            }
+            _ => panic!("expected a key event"),
+        }
+
+        // Other sub-types aren't supported.
+        let msg = [255u8, 1, 0, 0];
+        let err = ClientMessage::read_from(&mut &msg[..]).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::UnknownVendorSubmessage(1))
+        ));
+    }
+
+    /// An encoding unknown to the crate, whose data is always 2 bytes.
+    struct DummyEncoding(Vec<u8>);
+
+    const DUMMY: EncodingType = EncodingType::Other(0x7fff_0001);
+
+    impl Encoding for DummyEncoding {
+        fn get_type(&self) -> EncodingType {
+            DUMMY
+        }
+
+        fn encode(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn transform(&self, _input: &PixelFormat, _output: &PixelFormat) -> Box<dyn Encoding> {
+            Box::new(DummyEncoding(self.0.clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registry_decode_custom() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+
+        let mut registry = EncodingRegistry::default();
+        registry.register_encoder(DUMMY, |pixels, _, _, _| {
+            Box::new(DummyEncoding(pixels[..2].to_vec()))
+        });
+        registry.register_decoder(DUMMY, |stream, _, _, _| {
+            async move {
+                let mut data = vec![0u8; 2];
+                stream.read_exact(&mut data).await?;
+                Ok(Box::new(DummyEncoding(data)) as Box<dyn Encoding>)
+            }
+            .boxed()
+        });
+
+        // The builder picks the client's preferred encoding that the registry can produce.
+        let encodings = [DUMMY, EncodingType::Raw];
+        let fbu = FramebufferUpdateBuilder::new(&encodings)
+            .encoded(Rect::new(1, 2, 1, 1), vec![7, 8, 9, 0], &pf, &registry)
+            .raw(Rect::new(0, 0, 1, 1), vec![1, 2, 3, 4])
+            .build();
+        let mut buf = Vec::new();
+        fbu.write_to(&mut buf).await.unwrap();
+
+        let fbu = FramebufferUpdate::read_from(&mut &buf[..], &pf, &registry)
+            .await
+            .unwrap();
+        assert_eq!(encoding_types(&fbu), vec![DUMMY, EncodingType::Raw]);
+        assert_eq!(fbu.rectangles[0].position, Position::new(1, 2));
+        assert_eq!(fbu.rectangles[0].data.encode(), &vec![7, 8]);
+        assert_eq!(fbu.rectangles[1].data.encode(), &vec![1, 2, 3, 4]);
+
+        // Without the custom decoder, the update can't be read.
+        let err =
+            FramebufferUpdate::read_from(&mut &buf[..], &pf, &EncodingRegistry::default()).await;
+        assert!(err.is_err());
+    }
+
+    /// A FramebufferUpdate header and one rectangle header.
+    fn update_header(width: u16, height: u16, encoding: EncodingType) -> Vec<u8> {
+        let mut buf = vec![0u8, 0, 0, 1, 0, 0, 0, 0];
+        buf.extend_from_slice(&width.to_be_bytes());
+        buf.extend_from_slice(&height.to_be_bytes());
+        buf.extend_from_slice(&i32::from(encoding).to_be_bytes());
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_decode_size_limit() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let registry = EncodingRegistry::default();
+        let config = ParseConfig {
+            max_message_bytes: 1024,
+            ..Default::default()
+        };
+
+        // A Raw rectangle claiming to be 65535x65535 is rejected before anything is allocated.
+        let buf = update_header(u16::MAX, u16::MAX, EncodingType::Raw);
+        let err = FramebufferUpdate::read_from(&mut &buf[..], &pf, &registry)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::MessageTooLarge {
+                message: "Raw rectangle",
+                ..
+            })
+        ));
+
+        // So is a large VMware cursor.
+        let mut buf = update_header(64, 64, EncodingType::VMwareCursorPseudo);
+        buf.extend_from_slice(&[0, 0]);
+        let err = FramebufferUpdate::read_with(&mut &buf[..], &pf, &registry, &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::MessageTooLarge {
+                message: "VMware cursor",
+                size: 32770,
+                max: 1024,
+            })
+        ));
+
+        // Rectangles within the limit are read.
+        let mut buf = update_header(16, 16, EncodingType::Raw);
+        buf.extend_from_slice(&[0u8; 16 * 16 * 4]);
+        let fbu = FramebufferUpdate::read_with(&mut &buf[..], &pf, &registry, &config)
+            .await
+            .unwrap();
+        assert_eq!(fbu.rectangles[0].data.encode().len(), 1024);
+    }
+
+    #[test]
+    fn test_registry_tight_encoder() {
+        let pf = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let registry = EncodingRegistry::default();
+        let encodings = [EncodingType::Tight, EncodingType::JpegQualityLevelPseudo(8)];
+
+        // The client's quality level is used.
+        let pixels: Vec<u8> = (0..32 * 32 * 4).map(|i| (i * 7 % 251) as u8).collect();
+        let fbu = FramebufferUpdateBuilder::new(&encodings)
+            .encoded(Rect::new(0, 0, 32, 32), pixels.clone(), &pf, &registry)
+            .build();
+        let expected = TightEncoding::with_quality(pixels, &pf, 32, Some(8));
+        assert_eq!(fbu.rectangles[0].data.encode(), expected.encode());
+
+        // Rectangles too wide for Tight are sent as Raw.
+        let width = TIGHT_MAX_WIDTH + 1;
+        let pixels = vec![0u8; usize::from(width) * 4];
+        let fbu = FramebufferUpdateBuilder::new(&encodings)
+            .encoded(Rect::new(0, 0, width, 1), pixels, &pf, &registry)
+            .build();
+        assert_eq!(encoding_types(&fbu), vec![EncodingType::Raw]);
+    }
+
+    #[test]
+    fn test_server_message_type() {
+        assert_eq!(ServerMessageType::FramebufferUpdate.to_u8(), 0);
+        assert_eq!(ServerMessageType::SetColorMapEntries.to_u8(), 1);
+        assert_eq!(ServerMessageType::Bell.to_u8(), 2);
+        assert_eq!(ServerMessageType::ServerCutText.to_u8(), 3);
+        assert_eq!(ServerMessageType::EndOfContinuousUpdates.to_u8(), 150);
+    }
+
+    #[test]
+    fn test_validate_bounds() {
+        let raw = |w: u16, h: u16| -> Box<dyn Encoding> {
+            Box::new(RawEncoding::new(vec![0; w as usize * h as usize * 4]))
+        };
+
+        let fbu = FramebufferUpdate::new(vec![Rectangle::new(8, 8, 8, 8, raw(8, 8))]);
+        assert!(fbu.validate(16, 16).is_ok());
+
+        let fbu = FramebufferUpdate::new(vec![Rectangle::new(10, 0, 8, 8, raw(8, 8))]);
+        let err = fbu.validate(16, 16).unwrap_err();
+        assert!(matches!(
+            err,
+            ProtoError::RectangleOutOfBounds {
+                x: 10,
+                width: 8,
+                fb_width: 16,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_tiled() {
+        let pf = crate::pixel_formats::fourcc::fourcc_to_pixel_format(
+            crate::pixel_formats::fourcc::FOURCC_XR24,
+        )
+        .unwrap();
+        let pixels = vec![0u8; 256 * 256 * 4];
+
+        let tiles = FramebufferUpdate::tiled(&pixels, 256, 256, &pf, 64);
+        assert_eq!(tiles.len(), 16);
+
+        // Every pixel is covered by exactly one tile.
+        let mut covered = vec![0u8; 256 * 256];
+        let mut n = 0;
+        for r in tiles {
+            assert_eq!(r.dimensions(), Resolution::new(64, 64));
+            assert_eq!(r.data().encode().len(), 64 * 64 * 4);
+
+            let p = r.position();
+            for y in p.y()..p.y() + 64 {
+                for x in p.x()..p.x() + 64 {
+                    covered[y as usize * 256 + x as usize] += 1;
+                }
+            }
+            n += 1;
+        }
+        assert_eq!(n, 16);
+        assert!(covered.iter().all(|&c| c == 1));
+
+        // Edge tiles are trimmed to the frame.
+        let pixels = vec![0u8; 100 * 70 * 4];
+        let tiles: Vec<_> = FramebufferUpdate::tiled(&pixels, 100, 70, &pf, 64).collect();
+        let sizes: Vec<_> = tiles.iter().map(|r| r.dimensions()).collect();
+        assert_eq!(
+            sizes,
+            vec![
+                Resolution::new(64, 64),
+                Resolution::new(36, 64),
+                Resolution::new(64, 6),
+                Resolution::new(36, 6),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_truncated_read_names_field() {
+        // bits-per-pixel and depth, then the stream ends.
+        let buf = vec![32u8, 24];
+        let err = PixelFormat::read_from(&mut buf.as_slice())
+            .await
+            .unwrap_err();
+
+        match err.downcast_ref::<ProtoError>() {
+            Some(ProtoError::Read { field, source }) => {
+                assert_eq!(*field, "PixelFormat.big_endian");
+                assert_eq!(source.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(err.to_string().contains("PixelFormat.big_endian"));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_pixel_format() {
+        let pf = [32u8, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0];
+        assert_eq!(pf.len(), PixelFormat::byte_len());
+
+        // Everything up to the shifts.
+        let err = PixelFormat::read_from(&mut &pf[..10]).await.unwrap_err();
+        match err.downcast_ref::<ProtoError>() {
+            Some(ProtoError::Read { field, source }) => {
+                assert_eq!(*field, "PixelFormat.red_shift");
+                assert_eq!(source.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let mut written = Vec::new();
+        PixelFormat::read_from(&mut &pf[..])
+            .await
+            .unwrap()
+            .write_to(&mut written)
+            .await
+            .unwrap();
+        assert_eq!(written.len(), PixelFormat::byte_len());
+    }
+
+    #[test]
+    fn test_pointer_event_display() {
+        let pe = PointerEvent::new(100, 200, MouseButtons::LEFT | MouseButtons::RIGHT);
+        assert_eq!(pe.to_string(), "(100, 200) LEFT|RIGHT");
+
+        let pe = PointerEvent::new(0, 1, MouseButtons::empty());
+        assert_eq!(pe.to_string(), "(0, 1) none");
+    }
+
+    #[test]
+    fn test_client_message_from_bytes() {
+        // A PointerEvent at (300, 200) with the left button down, followed by part of another
+        // message.
+        let buf = [5u8, 1, 0x01, 0x2c, 0, 200, 5, 0];
+        let (msg, len) = ClientMessage::from_bytes(&buf).unwrap();
+        assert_eq!(len, 6);
+        match msg {
+            ClientMessage::PointerEvent(pe) => {
+                assert_eq!(pe.position(), Position::new(300, 200));
+                assert_eq!(pe.buttons(), MouseButtons::LEFT);
+            }
+            _ => panic!("expected a pointer event"),
+        }
+
+        // A KeyEvent releasing 'a'.
+        let buf = [4u8, 0, 0, 0, 0, 0, 0, 0x61];
+        match ClientMessage::try_from(&buf[..]) {
+            Ok(ClientMessage::KeyEvent(ke)) => {
+                assert!(!ke.is_pressed());
+                assert!(matches!(ke.keysym(), Keysym::Utf32('a')));
+            }
+            _ => panic!("expected a key event"),
+        }
+
+        // Truncated messages and trailing bytes are errors.
+        assert!(ClientMessage::from_bytes(&buf[..5]).is_err());
+        let mut buf = buf.to_vec();
+        buf.push(0);
+        assert!(ClientMessage::try_from(&buf[..]).is_err());
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn test_image_round_trip() {
+        let mut img = image::RgbaImage::new(3, 2);
+        let colors = [
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [255, 255, 255, 255],
+            [0, 0, 0, 255],
+            [255, 0, 255, 0],
+        ];
+        for (i, p) in img.pixels_mut().enumerate() {
+            *p = image::Rgba(colors[i]);
+        }
+        let img = image::DynamicImage::ImageRgba8(img);
+
+        let xr24 = fourcc::fourcc_to_pixel_format(fourcc::FOURCC_XR24).unwrap();
+        let rgb565_be = PixelFormat::new_colorformat(16, 16, true, 11, 31, 5, 63, 0, 31);
+        for pf in [xr24.clone(), rgb565_be.clone()] {
+            let fbu = FramebufferUpdate::from_image(&img, &pf);
+            assert_eq!(fbu.rectangles().len(), 1);
+            assert_eq!(fbu.rectangles()[0].dimensions(), Resolution::new(3, 2));
+            assert_eq!(
+                fbu.to_image(&pf),
+                image::DynamicImage::ImageRgb8(img.to_rgb8())
+            );
+        }
+
+        // The first pixel is red: bytes B, G, R, x for little-endian xRGB, and 0xf800 for RGB565.
+        let fbu = FramebufferUpdate::from_image(&img, &xr24);
+        assert_eq!(&fbu.rectangles()[0].data().encode()[..4], &[0, 0, 255, 0]);
+        let fbu = FramebufferUpdate::from_image(&img, &rgb565_be);
+        assert_eq!(&fbu.rectangles()[0].data().encode()[..2], &[0xf8, 0x00]);
+    }
+
+    #[test]
+    fn test_color_format_new() {
+        // RGB565
+        let cf = ColorFormat::new(31, 63, 31, 11, 5, 0).unwrap();
+        assert!(cf.validate(16).is_ok());
+        assert!(matches!(
+            cf.validate(8),
+            Err(ColorFormatError::ShiftOutOfRange { channel: "red", .. })
+        ));
+
+        assert_eq!(
+            ColorFormat::new(1000, 255, 255, 16, 8, 0),
+            Err(ColorFormatError::InvalidMax {
+                channel: "red",
+                max: 1000
+            })
+        );
+        assert!(ColorFormat::new(0, 255, 255, 16, 8, 0).is_err());
+        assert!(ColorFormat::new(u16::MAX, 255, 255, 16, 8, 0).is_ok());
+
+        // Green overlaps the top of blue.
+        assert_eq!(
+            ColorFormat::new(255, 255, 255, 16, 4, 0),
+            Err(ColorFormatError::Overlap("green", "blue"))
+        );
+    }
+
+    #[test]
+    fn test_set_pixel_format_wire_layout() {
+        // SetPixelFormat as sent by a client asking for little-endian xRGB: the message type and
+        // 3 bytes of padding, then the 16-byte pixel format, which ends with its own 3 bytes of
+        // padding.
+        #[rustfmt::skip]
+        let buf = [
+            0u8, 0, 0, 0,
+            32, 24, 0, 1,
+            0, 255, 0, 255, 0, 255,
+            16, 8, 0,
+            0, 0, 0,
+        ];
+
+        let (msg, len) = ClientMessage::from_bytes(&buf).unwrap();
+        assert_eq!(len, 20);
+        match msg {
+            ClientMessage::SetPixelFormat(pf) => assert_eq!(
+                pf,
+                PixelFormat::new_colorformat(32, 24, false, 16, 255, 8, 255, 0, 255)
+            ),
+            _ => panic!("expected SetPixelFormat"),
+        }
+
+        // Neither a byte short nor a byte over is accepted as a complete message.
+        assert!(ClientMessage::from_bytes(&buf[..19]).is_err());
+        let mut long = buf.to_vec();
+        long.push(0);
+        assert!(ClientMessage::try_from(&long[..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_max_message_bytes() {
+        let config = ParseConfig {
+            max_message_bytes: 64,
+            ..Default::default()
+        };
+
+        // ClientCutText claiming 100 bytes of text, more than the budget allows.
+        let mut msg = vec![6u8, 0, 0, 0, 0, 0, 0, 100];
+        msg.extend_from_slice(&[b'a'; 100]);
+        let err = ClientMessage::read_with(&mut msg.as_slice(), &config)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::MessageTooLarge {
+                message: "ClientCutText",
+                size: 108,
+                max: 64,
+            })
+        ));
+
+        // Within the budget, the text is read in full.
+        let msg = [6u8, 0, 0, 0, 0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o'];
+        match ClientMessage::read_with(&mut &msg[..], &config).await {
+            Ok(ClientMessage::ClientCutText(text)) => assert_eq!(text, "hello"),
+            _ => panic!("expected cut text"),
+        }
+    }
+}
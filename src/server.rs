@@ -4,29 +4,169 @@
 //
 // Copyright 2022 Oxide Computer Company
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 use async_trait::async_trait;
-use log::{debug, error, info, trace};
+use futures::{Stream, StreamExt};
+use log::{debug, error, info, warn};
+use std::io::{self, ErrorKind};
 use std::marker::{Send, Sync};
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(unix)]
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::BufReader;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite};
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{broadcast, mpsc, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
+use crate::encodings::{ClientEncodings, EncodingType, ExtendedDesktopSizeEncoding, RawEncoding};
+use crate::handshake::{HandshakeError, HandshakeInput, HandshakeState};
+use crate::input::{InputEvent, InputQueue, KeyRepeatFilter};
+use crate::io::{buffered_reader, Counted, SessionStats};
+use crate::pixel_formats::PixelConverter;
 use crate::rfb::ClientMessage::{
-    ClientCutText, FramebufferUpdateRequest, KeyEvent, PointerEvent, SetEncodings, SetPixelFormat,
+    ClientCutText, EnableContinuousUpdates, FramebufferUpdateRequest, Ignored, KeyEvent,
+    PointerEvent, SetDesktopSize, SetEncodings, SetPixelFormat,
 };
 use crate::rfb::{
-    ClientInit, ClientMessage, FramebufferUpdate, PixelFormat, ProtoVersion, ReadMessage,
-    SecurityResult, SecurityType, SecurityTypes, ServerInit, WriteMessage,
+    ClientInit, ClientMessage, ColorMapEntry, CutText, DesktopSizeStatus, EndOfContinuousUpdates,
+    FramebufferUpdate, NameEncoding, ParseConfig, PixelFormat, ProtoError, ProtoVersion, Quality,
+    ReadMessage, Rect, Rectangle, Screen, SecurityType, SecurityTypes, ServerInit,
+    SetColorMapEntries, WriteMessage,
 };
 
 /// Immutable state
 pub struct VncServerConfig {
     pub addr: SocketAddr,
     pub version: ProtoVersion,
+
+    /// The security types offered to clients. Clients that choose
+    /// [`SecurityType::VncAuthentication`] are always refused, as there's no way to configure a
+    /// password yet.
     pub sec_types: SecurityTypes,
     pub name: String,
+
+    /// How the name is encoded in ServerInit. Sessions using a version older than 3.8 use
+    /// Latin-1 instead whenever the name can be represented in it.
+    pub name_encoding: NameEncoding,
+
+    /// If set, sent in ServerInit in place of an empty `name`. An empty name is valid, but some
+    /// older clients mishandle a zero-length name. If `None`, an empty name is sent as it is.
+    pub empty_name_replacement: Option<String>,
+
+    /// The largest framebuffer size (width, height) a client may request with SetDesktopSize. If
+    /// `None`, client-requested resizes are prohibited.
+    pub max_desktop_size: Option<(u16, u16)>,
+
+    /// How long to wait for the client during the handshake before giving up.
+    pub handshake_timeout: Duration,
+
+    /// How long a session may go without a message from the client before it's closed, to clean
+    /// up after clients that disappear without closing the connection. Clients that are viewing
+    /// the desktop request updates continually, so this can be generous. If `None`, sessions are
+    /// never closed for being idle.
+    pub idle_timeout: Option<Duration>,
+
+    /// If set, input events are queued and delivered to the backend at most once per interval,
+    /// with consecutive pointer motion coalesced. Key events and button changes are never
+    /// dropped. If `None`, every event is delivered as it arrives.
+    pub input_coalesce_interval: Option<Duration>,
+
+    /// Ask clients that support the Pointer Motion Change pseudo-encoding to send relative
+    /// pointer motion rather than absolute positions, for backends that emulate a mouse rather
+    /// than a tablet. Such events are marked with `PointerEvent::is_relative`.
+    pub relative_pointer: bool,
+
+    /// Drop key-down events for keys that are already held, which some clients send for each
+    /// auto-repeat. The key-up is still delivered. Useful for backends that repeat held keys
+    /// themselves.
+    pub suppress_key_repeat: bool,
+
+    /// Options for parsing client messages.
+    pub parse_config: ParseConfig,
+
+    /// The encodings the backend can produce. Encodings in a client's SetEncodings that aren't
+    /// in this list are not passed on to the backend. Raw is always usable, whether or not it's
+    /// listed.
+    pub supported_encodings: Vec<EncodingType>,
+
+    /// Count the bytes read from and written to each connection accepted by `start`, reporting
+    /// the counters to the backend with `Server::session_stats`.
+    pub count_bytes: bool,
+
+    /// Whether the backend reports the areas of the framebuffer that change with
+    /// `VncServer::mark_dirty`. If so, a session with continuous updates enabled only asks the
+    /// backend for the changed areas within the client's region, rather than polling the whole
+    /// region.
+    pub dirty_tracking: bool,
+
+    /// If set, chooses the security types offered to each client from its address, in place of
+    /// `sec_types`. For example, a policy might offer no authentication only to loopback clients.
+    pub security_policy: Option<fn(&SocketAddr) -> SecurityTypes>,
+}
+
+/// The idle timeout used by [`VncServerConfig::minimal`].
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The name used by [`VncServerConfig::minimal`], also in place of an empty name.
+pub const DEFAULT_NAME: &str = "rfb";
+
+impl VncServerConfig {
+    /// A configuration that legacy clients are known to handle: RFB 3.3, no security, and Raw
+    /// encoding only.
+    pub fn minimal(addr: SocketAddr) -> Self {
+        VncServerConfig {
+            addr,
+            version: ProtoVersion::Rfb33,
+            sec_types: SecurityTypes(vec![SecurityType::None]),
+            name: DEFAULT_NAME.to_string(),
+            name_encoding: NameEncoding::Latin1,
+            empty_name_replacement: Some(DEFAULT_NAME.to_string()),
+            max_desktop_size: None,
+            handshake_timeout: Duration::from_secs(10),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            input_coalesce_interval: None,
+            relative_pointer: false,
+            suppress_key_repeat: false,
+            parse_config: ParseConfig::default(),
+            supported_encodings: vec![EncodingType::Raw],
+            count_bytes: false,
+            dirty_tracking: false,
+            security_policy: None,
+        }
+    }
+}
+
+/// Errors that end a connection before initialization completes.
+#[derive(Debug, Error)]
+pub enum InitError {
+    #[error("timed out waiting for client")]
+    Timeout,
+
+    #[error("client disconnected")]
+    ClientDisconnected,
+
+    /// The server is configured without any security types, so no client can connect. The
+    /// client is sent the reason before the connection is closed.
+    #[error("no security types are configured")]
+    NoSecurityTypes,
+
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The handshake needs a client's response beyond its version, such as to a VNC
+    /// authentication challenge, so it can't be produced without one.
+    #[error("security type {0:?} requires a client")]
+    RequiresClient(SecurityType),
 }
 
 /// Mutable state
@@ -39,121 +179,1060 @@ pub struct VncServerData {
     pub input_pixel_format: PixelFormat,
 }
 
+/// Why a session ended without an error on the server's side.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisconnectReason {
+    /// The client closed the connection.
+    ClientClosed,
+
+    /// The server asked the session to stop.
+    BackendRequestedStop,
+
+    /// The client sent a message that couldn't be parsed.
+    ProtocolError(String),
+
+    /// The client sent nothing, or stalled partway through a message, for longer than the
+    /// configured idle timeout.
+    IdleTimeout,
+}
+
+/// Errors that end a session after initialization. Client messages that can't be parsed aren't
+/// errors on the server's side; they end the session with [`DisconnectReason::ProtocolError`].
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The server broke the protocol, other than by sending an invalid update.
+    #[error("protocol error: {0}")]
+    Protocol(ProtoError),
+
+    /// An update from the backend couldn't be sent, such as because a rectangle lies outside the
+    /// framebuffer.
+    #[error("encoding error: {0}")]
+    Encoding(ProtoError),
+
+    /// Any other error, such as from the backend.
+    #[error(transparent)]
+    Backend(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ServerError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<ProtoError>() {
+            Ok(e) => {
+                return match e.io_error() {
+                    Some(e) => ServerError::Io(e),
+                    None if matches!(e, ProtoError::RectangleOutOfBounds { .. }) => {
+                        ServerError::Encoding(e)
+                    }
+                    None => ServerError::Protocol(e),
+                };
+            }
+            Err(e) => e,
+        };
+
+        match e.downcast::<io::Error>() {
+            Ok(e) => ServerError::Io(e),
+            Err(e) => ServerError::Backend(e),
+        }
+    }
+}
+
+/// Parameters negotiated with a client during the handshake and initialization phases.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionParams {
+    pub version: ProtoVersion,
+    pub security_type: SecurityType,
+
+    /// Whether the client asked to share the desktop with other clients (ClientInit).
+    pub shared: bool,
+}
+
+/// The pixel converter used by a session, rebuilt only when the input or output pixel format
+/// changes rather than for every frame.
+#[derive(Default)]
+struct ConverterCache {
+    converter: Option<PixelConverter>,
+
+    /// Number of times a converter has been built, for tests.
+    builds: usize,
+}
+
+impl ConverterCache {
+    fn get(&mut self, input: &PixelFormat, output: &PixelFormat) -> &PixelConverter {
+        let stale = match &self.converter {
+            Some(c) => c.input() != input || c.output() != output,
+            None => true,
+        };
+        if stale {
+            self.converter = Some(PixelConverter::new(input, output));
+            self.builds += 1;
+        }
+
+        self.converter.as_ref().unwrap()
+    }
+}
+
+/// Returns whether the client can decode every rectangle in an update.
+fn client_supports(fbu: &FramebufferUpdate, encodings: &[EncodingType]) -> bool {
+    fbu.rectangles().iter().all(|r| {
+        let encoding = r.data().get_type();
+        encoding == EncodingType::Raw || encodings.contains(&encoding)
+    })
+}
+
+/// Replaces rectangles in an update from the backend whose encoding the client didn't ask for
+/// with Raw ones, which every client supports. Rectangles whose pixels can't be recovered, such
+/// as CopyRect, are dropped instead.
+fn fallback_to_raw(fbu: FramebufferUpdate, encodings: &[EncodingType]) -> FramebufferUpdate {
+    if client_supports(&fbu, encodings) {
+        return fbu;
+    }
+
+    let supported = |e: EncodingType| e == EncodingType::Raw || encodings.contains(&e);
+
+    let rectangles = fbu
+        .into_rectangles()
+        .into_iter()
+        .filter_map(|r| {
+            let encoding = r.data().get_type();
+            if supported(encoding) {
+                return Some(r);
+            }
+
+            let position = r.position();
+            let dimensions = r.dimensions();
+            match r.data().raw_pixels(dimensions.width(), dimensions.height()) {
+                Some(pixels) => {
+                    warn!(
+                        "backend sent a {:?} rectangle the client doesn't support; sending it as Raw",
+                        encoding
+                    );
+                    Some(Rectangle::new(
+                        position.x(),
+                        position.y(),
+                        dimensions.width(),
+                        dimensions.height(),
+                        Box::new(RawEncoding::new(pixels)),
+                    ))
+                }
+                None => {
+                    warn!(
+                        "backend sent a {:?} rectangle the client doesn't support; dropping it",
+                        encoding
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+
+    FramebufferUpdate::new(rectangles)
+}
+
 #[derive(Clone)]
 pub struct VncServer<S: Server> {
     config: Arc<VncServerConfig>,
     data: Arc<Mutex<VncServerData>>,
     pub server: Arc<S>,
+
+    /// The backend's most recent full frame, if it provided one with `set_full_frame`.
+    full_frame: Arc<Mutex<Option<Arc<FramebufferUpdate>>>>,
+
+    /// Clipboard contents published by the backend, sent to every session as ServerCutText.
+    cut_text: broadcast::Sender<CutText>,
+
+    /// Areas of the framebuffer the backend has reported as changed, with `mark_dirty`.
+    dirty: broadcast::Sender<Rect>,
 }
 
+/// How many clipboard updates may be queued for a session before older ones are dropped.
+const CUT_TEXT_QUEUE_LEN: usize = 8;
+
+/// How many dirty areas may be queued for a session. A session that falls behind treats its
+/// whole continuous updates region as dirty.
+const DIRTY_QUEUE_LEN: usize = 64;
+
+/// How often the backend is asked for an update while a client has continuous updates enabled,
+/// unless the session was started with `VncServer::run_with_refresh`.
+const CONTINUOUS_UPDATE_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long to wait before accepting again after an error. Errors such as running out of file
+/// descriptors persist for a while, and retrying at once would spin.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
 #[async_trait]
 pub trait Server: Sync + Send + Clone + 'static {
-    async fn get_framebuffer_update(&self) -> FramebufferUpdate;
+    /// Returns the next update for the client. `region` is the area the client asked for;
+    /// backends that can render part of the framebuffer may render only that area, and anything
+    /// outside of it is clipped before sending. `encodings` is the client's most recent
+    /// SetEncodings list, limited to the configured `supported_encodings`, so that rectangles can
+    /// use an encoding both sides support; before the client sends one, or if none of its
+    /// encodings are supported, it's just Raw. Rectangles in any other encoding are sent as Raw
+    /// instead, or dropped if their pixels can't be recovered, as for CopyRect.
+    async fn get_framebuffer_update(
+        &self,
+        region: Rect,
+        encodings: &[EncodingType],
+    ) -> FramebufferUpdate;
+
+    /// Returns an update for a non-incremental FramebufferUpdateRequest, which the client sends
+    /// when it needs all of `region` rather than just what changed, such as when it first
+    /// connects or has lost its framebuffer. The update mustn't depend on what the client already
+    /// has, as CopyRect does. By default, this is `get_framebuffer_update`, which suits backends
+    /// that always send whole contents.
+    async fn get_full_framebuffer_update(
+        &self,
+        region: Rect,
+        encodings: &[EncodingType],
+    ) -> FramebufferUpdate {
+        self.get_framebuffer_update(region, encodings).await
+    }
+
+    /// Called for each key event from the client.
+    async fn key_event(&self, _ke: crate::rfb::KeyEvent) {}
+
+    /// Called for each pointer event from the client. If input coalescing is enabled, consecutive
+    /// pointer motion may be merged into a single event.
+    async fn pointer_event(&self, _pe: crate::rfb::PointerEvent) {}
+
+    /// Returns an update to send as soon as initialization completes, without waiting for the
+    /// client's first FramebufferUpdateRequest. Some viewers don't request an update until they
+    /// receive input, so sending one avoids a blank screen. The client hasn't named its encodings
+    /// yet, so rectangles that aren't Raw are converted to Raw, or dropped if they can't be. By
+    /// default, nothing is sent.
+    async fn get_initial_framebuffer_update(&self) -> Option<FramebufferUpdate> {
+        None
+    }
+
+    /// Returns the color map, starting at index 0, if the server's pixel format uses one. It's
+    /// sent to the client right after initialization.
+    async fn get_color_map(&self) -> Vec<ColorMapEntry> {
+        Vec::new()
+    }
+
+    /// Called once per session, when the client's pixel format is settled: either when the
+    /// client first sends SetPixelFormat, or when it first requests an update without having
+    /// sent one, in which case it uses the server's format. Backends can use this to produce
+    /// frames in the client's format directly.
+    async fn format_negotiated(&self, _pf: &PixelFormat) {}
+
+    /// Called each time the client sends SetEncodings, with its list split into real encodings
+    /// and pseudo-encodings. Unlike the list passed to `get_framebuffer_update`, this isn't
+    /// limited to the configured `supported_encodings`.
+    async fn encodings_changed(&self, _encodings: &ClientEncodings) {}
+
+    /// Called when `start` accepts a connection, if `count_bytes` is enabled, with counters of
+    /// the bytes read from and written to it. The counters keep updating until the connection
+    /// closes.
+    async fn session_stats(&self, _addr: SocketAddr, _stats: SessionStats) {}
 }
 
 impl<S: Server> VncServer<S> {
     pub fn new(server: S, config: VncServerConfig, data: VncServerData) -> Self {
-        assert!(
-            config.sec_types.0.len() > 0,
-            "at least one security type must be defined"
-        );
         Self {
             config: Arc::new(config),
             data: Arc::new(Mutex::new(data)),
             server: Arc::new(server),
+            full_frame: Arc::new(Mutex::new(None)),
+            cut_text: broadcast::channel(CUT_TEXT_QUEUE_LEN).0,
+            dirty: broadcast::channel(DIRTY_QUEUE_LEN).0,
         }
     }
 
+    /// Creates a server using `VncServerConfig::minimal`, for a framebuffer of the given size and
+    /// pixel format.
+    pub fn minimal(
+        server: S,
+        addr: SocketAddr,
+        width: u16,
+        height: u16,
+        input_pixel_format: PixelFormat,
+    ) -> Self {
+        let data = VncServerData {
+            width,
+            height,
+            input_pixel_format,
+        };
+        Self::new(server, VncServerConfig::minimal(addr), data)
+    }
+
+    /// Returns a channel for publishing the backend's clipboard. Each session sends whatever is
+    /// published to its client as a ServerCutText message. Sessions that fall behind skip to
+    /// the most recent clipboard contents.
+    pub fn cut_text_sender(&self) -> broadcast::Sender<CutText> {
+        self.cut_text.clone()
+    }
+
+    /// Reports that `rect` of the framebuffer has changed. With `dirty_tracking` enabled,
+    /// sessions with continuous updates enabled send updates for the parts of it within their
+    /// client's region, and ignore the rest. Sessions waiting to answer an incremental request
+    /// that overlaps it ask the backend for an update right away, whether or not
+    /// `dirty_tracking` is enabled.
+    pub fn mark_dirty(&self, rect: Rect) {
+        // Sending only fails when there are no sessions, in which case there's nobody to tell.
+        let _ = self.dirty.send(rect);
+    }
+
+    /// Retains `fbu` as the current contents of the whole framebuffer. Non-incremental
+    /// FramebufferUpdateRequests, which ask for everything (for example, when a client resyncs),
+    /// are then answered with this frame without calling into the backend. Backends that keep
+    /// their last full frame should call this whenever it changes.
+    pub async fn set_full_frame(&self, fbu: FramebufferUpdate) {
+        *self.full_frame.lock().await = Some(Arc::new(fbu));
+    }
+
+    /// Discards the frame retained by `set_full_frame`, so that non-incremental requests go to
+    /// the backend again.
+    pub async fn clear_full_frame(&self) {
+        *self.full_frame.lock().await = None;
+    }
+
+    /// Changes the pixel format of the framebuffer data the backend provides. Each session
+    /// rebuilds its pixel converter before writing its next update.
+    ///
+    /// Nothing is sent to the client: clients choose their own pixel format with
+    /// SetPixelFormat, and updates continue to be converted to it. A frame retained by
+    /// `set_full_frame` is discarded if the format changed, since its pixels are in the old one.
     pub async fn set_pixel_format(&self, pixel_format: PixelFormat) {
         let mut locked = self.data.lock().await;
+        if locked.input_pixel_format != pixel_format {
+            self.clear_full_frame().await;
+        }
         locked.input_pixel_format = pixel_format;
     }
 
+    /// Changes the framebuffer dimensions. Subsequent requests are clipped to the new size, and
+    /// a frame retained by `set_full_frame` is discarded if the size changed, since it no longer
+    /// covers the framebuffer.
     pub async fn set_resolution(&self, width: u16, height: u16) {
         let mut locked = self.data.lock().await;
+        if (locked.width, locked.height) != (width, height) {
+            self.clear_full_frame().await;
+        }
         locked.width = width;
         locked.height = height;
     }
 
-    async fn rfb_handshake(&self, s: &mut TcpStream, addr: SocketAddr) -> Result<()> {
-        // ProtocolVersion handshake
-        info!("Tx [{:?}]: ProtoVersion={:?}", addr, self.config.version);
-        self.config.version.write_to(s).await?;
-        let client_version = ProtoVersion::read_from(s).await?;
-        info!("Rx [{:?}]: ClientVersion={:?}", addr, client_version);
+    /// Handles a client's SetDesktopSize request, updating the resolution if the request is
+    /// valid. Returns the update containing the ExtendedDesktopSize reply, which must be sent to
+    /// the client before any other framebuffer update.
+    pub async fn handle_set_desktop_size(
+        &self,
+        req: &crate::rfb::SetDesktopSize,
+    ) -> FramebufferUpdate {
+        let mut data = self.data.lock().await;
+
+        let status = match self.config.max_desktop_size {
+            None => DesktopSizeStatus::Prohibited,
+            Some((max_width, max_height)) => {
+                let screen_fits = |s: &Screen| {
+                    s.width > 0
+                        && s.height > 0
+                        && s.x as u32 + s.width as u32 <= req.width as u32
+                        && s.y as u32 + s.height as u32 <= req.height as u32
+                };
+
+                if req.width == 0
+                    || req.height == 0
+                    || req.width > max_width
+                    || req.height > max_height
+                    || req.screens.is_empty()
+                    || !req.screens.iter().all(screen_fits)
+                {
+                    DesktopSizeStatus::InvalidLayout
+                } else {
+                    DesktopSizeStatus::Success
+                }
+            }
+        };
+
+        let screens = if status == DesktopSizeStatus::Success {
+            if (data.width, data.height) != (req.width, req.height) {
+                self.clear_full_frame().await;
+            }
+            data.width = req.width;
+            data.height = req.height;
+            req.screens.clone()
+        } else {
+            vec![Screen {
+                id: 0,
+                x: 0,
+                y: 0,
+                width: data.width,
+                height: data.height,
+                flags: 0,
+            }]
+        };
+
+        // An x-position of 1 indicates the change was requested by this client.
+        let r = Rectangle::new(
+            1,
+            status.into(),
+            data.width,
+            data.height,
+            Box::new(ExtendedDesktopSizeEncoding::new(&screens)),
+        );
+        FramebufferUpdate::new(vec![r])
+    }
+
+    /// Sends a full framebuffer update to the client outside of the usual request/response flow.
+    ///
+    /// This is intended to be called immediately after initialization (ServerInit), before any
+    /// client messages are processed. At that point the client has not yet sent SetPixelFormat,
+    /// so the update must be in the server's native pixel format, as advertised in ServerInit.
+    pub async fn send_full_update<T: AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        fbu: &FramebufferUpdate,
+    ) -> Result<()> {
+        fbu.write_ref_to(s).await
+    }
+
+    /// Sends each update from `updates` to the client as soon as it's ready, returning how many
+    /// were sent. This is for short animations, such as a cursor trail, that a backend wants to
+    /// push after a single request.
+    ///
+    /// Clients expect one update in response to each FramebufferUpdateRequest, and a client that
+    /// falls behind can't ask the server to slow down, so this should only be used for a bounded
+    /// number of updates. Like `send_full_update`, the updates are sent as they are, so they must
+    /// be in the client's pixel format.
+    pub async fn send_updates<T, U>(&self, s: &mut T, updates: U) -> Result<usize>
+    where
+        T: AsyncWrite + Unpin + Send,
+        U: Stream<Item = FramebufferUpdate> + Send,
+    {
+        let mut updates = std::pin::pin!(updates);
+        let mut sent = 0;
+        while let Some(fbu) = updates.next().await {
+            fbu.write_ref_to(s).await?;
+            sent += 1;
+        }
+        Ok(sent)
+    }
 
-        if client_version < self.config.version {
-            let err_str = format!(
-                "[{:?}] unsupported client version={:?} (server version: {:?})",
-                addr, client_version, self.config.version
+    /// Sets the client's color map entries starting at index `first_color`. This only applies if
+    /// the server's pixel format uses a color map.
+    pub async fn send_color_map<T: AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        first_color: u16,
+        colors: &[ColorMapEntry],
+    ) -> Result<()> {
+        SetColorMapEntries::new(first_color, colors.to_vec())
+            .write_to(s)
+            .await
+    }
+
+    /// Sends a partial color map update during a session, changing only the `colors.len()`
+    /// entries starting at index `first_color`, for palettes that change over time. Returns an
+    /// error without sending anything if the server's pixel format doesn't use a color map, or
+    /// if the entries would run past the last index, 65535.
+    pub async fn update_color_map<T: AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        first_color: u16,
+        colors: &[ColorMapEntry],
+    ) -> Result<()> {
+        if self
+            .data
+            .lock()
+            .await
+            .input_pixel_format
+            .color_map()
+            .is_none()
+        {
+            bail!("server pixel format doesn't use a color map");
+        }
+        if usize::from(first_color) + colors.len() > usize::from(u16::MAX) + 1 {
+            bail!(
+                "{} color map entries starting at {} run past the end of the color map",
+                colors.len(),
+                first_color
             );
-            error!("{}", err_str);
-            bail!(err_str);
         }
 
-        // Security Handshake
-        let supported_types = self.config.sec_types.clone();
-        info!("Tx [{:?}]: SecurityTypes={:?}", addr, supported_types);
-        supported_types.write_to(s).await?;
-        let client_choice = SecurityType::read_from(s).await?;
-        info!("Rx [{:?}]: SecurityType Choice={:?}", addr, client_choice);
-        if !self.config.sec_types.0.contains(&client_choice) {
-            info!("Tx [{:?}]: SecurityResult=Failure", addr);
-            let failure = SecurityResult::Failure("unsupported security type".to_string());
-            failure.write_to(s).await?;
-            let err_str = format!("invalid security choice={:?}", client_choice);
-            error!("{}", err_str);
-            bail!(err_str);
+        self.send_color_map(s, first_color, colors).await
+    }
+
+    /// Responds to a client's FramebufferUpdateRequest with an update from the backend, converted
+    /// to the client's pixel format where possible. Returns whether an update was written.
+    ///
+    /// An empty update is not sent in response to an incremental request, since the client
+    /// already has the current contents and nothing would change; the session answers the
+    /// request later, once there's something to send. Non-incremental requests are
+    /// always answered, even if the update contains no rectangles, and use the frame retained by
+    /// `set_full_frame` if there is one, or otherwise `Server::get_full_framebuffer_update`.
+    ///
+    /// The request is clipped to the current framebuffer, so a client that hasn't caught up with
+    /// a resize isn't sent anything outside it.
+    async fn handle_update_request<T: AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        req: &crate::rfb::FramebufferUpdateRequest,
+        output_pixel_format: &PixelFormat,
+        converters: &mut ConverterCache,
+        encodings: &[EncodingType],
+    ) -> Result<bool> {
+        let (screen, bytes_per_pixel) = {
+            let data = self.data.lock().await;
+            let screen = Rect::new(0, 0, data.width, data.height);
+            (screen, data.input_pixel_format.bits_per_pixel as usize / 8)
+        };
+
+        if !req.incremental() {
+            let full_frame = self.full_frame.lock().await.clone();
+            match full_frame {
+                // A frame retained just before a resize may not fit the new size.
+                Some(fbu) if fbu.validate(screen.width, screen.height).is_err() => {
+                    debug!("retained full frame doesn't fit the framebuffer, ignoring it");
+                }
+                Some(fbu) => {
+                    self.write_update(s, &fbu, encodings, output_pixel_format, converters)
+                        .await?;
+                    return Ok(true);
+                }
+                None => {}
+            }
+        }
+
+        let fbu = match req.region().intersect(&screen) {
+            Some(region) if req.incremental() => self
+                .server
+                .get_framebuffer_update(region, encodings)
+                .await
+                .clip(region, bytes_per_pixel),
+            Some(region) => self
+                .server
+                .get_full_framebuffer_update(region, encodings)
+                .await
+                .clip(region, bytes_per_pixel),
+            None => FramebufferUpdate::new(vec![]),
+        };
+        let fbu = fallback_to_raw(fbu, encodings);
+        if fbu.is_empty() && req.incremental() {
+            return Ok(false);
+        }
+
+        self.write_update(s, &fbu, encodings, output_pixel_format, converters)
+            .await?;
+        Ok(true)
+    }
+
+    /// Writes an update from the backend, converting it to the client's pixel format. Rectangles
+    /// in encodings the client didn't ask for are replaced as by `fallback_to_raw`.
+    async fn write_update<T: AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        fbu: &FramebufferUpdate,
+        encodings: &[EncodingType],
+        output_pixel_format: &PixelFormat,
+        converters: &mut ConverterCache,
+    ) -> Result<()> {
+        let data = self.data.lock().await;
+
+        // Updates straight from the backend have already been checked, so a copy is only needed
+        // for shared ones, like the retained full frame.
+        let replaced;
+        let fbu = if client_supports(fbu, encodings) {
+            fbu
+        } else {
+            let pf = &data.input_pixel_format;
+            replaced = fallback_to_raw(fbu.convert(&PixelConverter::new(pf, pf)), encodings);
+            &replaced
+        };
+
+        // We only need to change pixel formats if the client requested a different
+        // one than what's specified in the input.
+        //
+        // Only true-color formats can be converted, so if either format uses a color
+        // map, we'll just leave the pixels as is.
+        let converter = converters.get(&data.input_pixel_format, output_pixel_format);
+        if !converter.is_identity() && converter.is_supported() {
+            debug!(
+                "transforming: input={:#?}, output={:#?}",
+                data.input_pixel_format, output_pixel_format
+            );
+            let fbu = fbu.convert(converter);
+            let (width, height) = (data.width, data.height);
+            drop(data);
+            return fbu.write_checked_to(s, width, height).await;
+        } else if !converter.is_supported() {
+            debug!(
+                "cannot transform between pixel formats: input={:?}, output={:?}",
+                data.input_pixel_format, output_pixel_format
+            );
         }
+        let (width, height) = (data.width, data.height);
+        drop(data);
+
+        fbu.write_checked_to(s, width, height).await
+    }
+
+    async fn rfb_handshake<T: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        addr: SocketAddr,
+    ) -> Result<(ProtoVersion, SecurityType)> {
+        let sec_types = match self.config.security_policy {
+            Some(policy) => policy(&addr),
+            None => self.config.sec_types.clone(),
+        };
+        let mut hs = HandshakeState::new(self.config.version, sec_types);
+        let mut input = HandshakeInput::Start;
+        let mut negotiated = None;
+        let mut selected = None;
+
+        loop {
+            let out = hs.step(input);
+            for msg in out.send {
+                info!("Tx [{:?}]: {:?}", addr, msg);
+                msg.write_to(s).await?;
+            }
+            if let Some(e) = out.error {
+                error!("[{:?}] {}", addr, e);
+                return Err(match e {
+                    HandshakeError::NoSecurityTypes => InitError::NoSecurityTypes.into(),
+                    e => e.into(),
+                });
+            }
 
-        let res = SecurityResult::Success;
-        info!("Tx: SecurityResult=Success");
-        res.write_to(s).await?;
+            if negotiated.is_none() {
+                negotiated = hs.version();
+                if let Some(version) = negotiated {
+                    debug!("[{:?}] handshake: version negotiated={:?}", addr, version);
+                }
+            }
+            if selected.is_none() {
+                selected = hs.security_type();
+                if let Some(t) = &selected {
+                    debug!("[{:?}] handshake: security type selected={:?}", addr, t);
+                }
+            }
+
+            input = match hs {
+                HandshakeState::AwaitingVersion { .. } => {
+                    let mut buf = [0u8; 12];
+                    s.read_exact(&mut buf).await?;
+                    info!(
+                        "Rx [{:?}]: ClientVersion={:?}",
+                        addr,
+                        ProtoVersion::parse(&buf)
+                    );
+                    HandshakeInput::ClientVersion(buf)
+                }
+                HandshakeState::AwaitingSecurityChoice { .. } => {
+                    let choice = SecurityType::read_from(s).await?;
+                    info!("Rx [{:?}]: SecurityType Choice={:?}", addr, choice);
+                    HandshakeInput::SecurityChoice(choice)
+                }
+                HandshakeState::AwaitingVncAuthResponse { .. } => {
+                    let response = self.vnc_auth_response(s).await?;
+                    info!("Rx [{:?}]: VncAuthentication response", addr);
+                    HandshakeInput::VncAuthResponse(response)
+                }
+                HandshakeState::Done {
+                    version,
+                    security_type,
+                } => return Ok((version, security_type)),
+                HandshakeState::Start { .. } | HandshakeState::Failed => {
+                    unreachable!("handshake stepped into {:?}", hs)
+                }
+            };
+        }
+    }
 
-        Ok(())
+    // Section 7.2.2
+    async fn vnc_auth_response<T: AsyncRead + Unpin + Send>(&self, s: &mut T) -> Result<[u8; 16]> {
+        // A client that stalls partway through its response must not hang the connection, so
+        // the read is bounded by the handshake timeout.
+        let mut response = [0u8; 16];
+        let timeout = self.config.handshake_timeout;
+        match tokio::time::timeout(timeout, s.read_exact(&mut response)).await {
+            Err(_) => Err(InitError::Timeout.into()),
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                Err(InitError::ClientDisconnected.into())
+            }
+            Ok(Err(e)) => Err(InitError::Io(e).into()),
+            Ok(Ok(_)) => Ok(response),
+        }
     }
 
-    async fn rfb_initialization(&self, s: &mut TcpStream, addr: SocketAddr) -> Result<()> {
+    async fn rfb_initialization<T: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        s: &mut T,
+        addr: SocketAddr,
+        version: ProtoVersion,
+    ) -> Result<bool> {
         let client_init = ClientInit::read_from(s).await?;
         info!("Rx [{:?}]: ClientInit={:?}", addr, client_init);
+        debug!(
+            "[{:?}] initialization: client init shared={}",
+            addr, client_init.shared
+        );
         // TODO: decide what to do in exclusive case
-        match client_init.shared {
-            true => {}
-            false => {}
-        }
 
         let data = self.data.lock().await;
-        let server_init = ServerInit::new(
+        let server_init = self.server_init(&data, version);
+        info!("Tx [{:?}]: ServerInit={:#?}", addr, server_init);
+        server_init.write_to(s).await?;
+
+        if data.input_pixel_format.color_map().is_some() {
+            drop(data);
+            let colors = self.server.get_color_map().await;
+            info!("Tx [{:?}]: SetColorMapEntries (n={})", addr, colors.len());
+            self.send_color_map(s, 0, &colors).await?;
+        }
+
+        Ok(client_init.shared)
+    }
+
+    fn server_init(&self, data: &VncServerData, version: ProtoVersion) -> ServerInit {
+        let name = match &self.config.empty_name_replacement {
+            Some(replacement) if self.config.name.is_empty() => replacement,
+            _ => &self.config.name,
+        };
+
+        // Clients older than 3.8 predate UTF-8 names and expect Latin-1, so use that whenever the
+        // name can be represented in it.
+        let name_encoding =
+            if version < ProtoVersion::Rfb38 && NameEncoding::Latin1.encode(name).is_ok() {
+                NameEncoding::Latin1
+            } else {
+                self.config.name_encoding
+            };
+
+        ServerInit::new(
             data.width,
             data.height,
-            self.config.name.clone(),
+            name.clone(),
             data.input_pixel_format.clone(),
-        );
-        info!("Tx [{:?}]: ServerInit={:#?}", addr, server_init);
-        server_init.write_to(s).await?;
+        )
+        .with_name_encoding(name_encoding)
+    }
+
+    /// Returns everything the server sends during the opening of a session, up to and including
+    /// ServerInit, as one buffer: the handshake with a client that replies with `client_version`
+    /// and chooses the None security type.
+    ///
+    /// This is useful for golden tests and for transports that prefer to batch the opening
+    /// writes. The configured security types are used, not `security_policy`. Fails if the
+    /// client couldn't connect or if it would have to do more than choose None, for example if
+    /// None isn't offered.
+    pub async fn handshake_bytes(&self, client_version: ProtoVersion) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut hs = HandshakeState::new(self.config.version, self.config.sec_types.clone());
+        let mut input = HandshakeInput::Start;
+
+        let version = loop {
+            let out = hs.step(input);
+            for msg in out.send {
+                msg.write_to(&mut buf).await?;
+            }
+            if let Some(e) = out.error {
+                return Err(e.into());
+            }
 
-        Ok(())
+            input = match hs {
+                HandshakeState::AwaitingVersion { .. } => {
+                    HandshakeInput::ClientVersion(*client_version.as_bytes())
+                }
+                HandshakeState::AwaitingSecurityChoice { .. } => {
+                    HandshakeInput::SecurityChoice(SecurityType::None)
+                }
+                HandshakeState::AwaitingVncAuthResponse { .. } => {
+                    return Err(InitError::RequiresClient(SecurityType::VncAuthentication).into());
+                }
+                HandshakeState::Done { version, .. } => break version,
+                HandshakeState::Start { .. } | HandshakeState::Failed => {
+                    unreachable!("handshake stepped into {:?}", hs)
+                }
+            };
+        };
+
+        let data = self.data.lock().await;
+        self.server_init(&data, version).write_to(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Delivers all queued input events to the backend.
+    async fn deliver_input(&self, input: &mut InputQueue) {
+        for event in input.drain() {
+            match event {
+                InputEvent::Key(ke) => self.server.key_event(ke).await,
+                InputEvent::Pointer(pe) => self.server.pointer_event(pe).await,
+            }
+        }
+    }
+
+    /// Performs the handshake and initialization phases of the protocol with a newly connected
+    /// client. The stream is taken by value and returned along with the negotiated parameters, so
+    /// that a security type which wraps the connection (such as TLS) can hand back the wrapped
+    /// stream; with the security types currently supported, the original stream is returned.
+    ///
+    /// ```no_run
+    /// # use rfb::server::{Server, VncServer};
+    /// # async fn serve<S: Server>(vnc: VncServer<S>) -> anyhow::Result<()> {
+    /// let listener = tokio::net::TcpListener::bind("127.0.0.1:5900").await?;
+    /// let (s, addr) = listener.accept().await?;
+    /// let s = rfb::io::buffered_reader(s);
+    ///
+    /// let (s, params) = vnc.initialize(s, addr).await?;
+    /// let reason = vnc.process(s, addr, params, None).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn initialize<T: AsyncRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut s: T,
+        addr: SocketAddr,
+    ) -> Result<(T, SessionParams)> {
+        let (version, security_type) = self.rfb_handshake(&mut s, addr).await?;
+        let shared = self.rfb_initialization(&mut s, addr, version).await?;
+
+        let params = SessionParams {
+            version,
+            security_type,
+            shared,
+        };
+        Ok((s, params))
     }
 
-    async fn handle_conn(&self, s: &mut TcpStream, addr: SocketAddr) {
+    async fn handle_conn<T: AsyncBufRead + AsyncWrite + Unpin + Send>(
+        &self,
+        s: T,
+        addr: SocketAddr,
+    ) {
         info!("[{:?}] new connection", addr);
 
-        if let Err(e) = self.rfb_handshake(s, addr).await {
-            error!("[{:?}] could not complete handshake: {:?}", addr, e);
-            return;
-        }
+        let (s, params) = match self.initialize(s, addr).await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("[{:?}] could not complete handshake: {:?}", addr, e);
+                return;
+            }
+        };
 
-        if let Err(e) = self.rfb_initialization(s, addr).await {
-            error!("[{:?}] could not complete handshake: {:?}", addr, e);
-            return;
+        match self.process(s, addr, params, None).await {
+            Ok(DisconnectReason::ClientClosed) => {
+                info!("[{:?}] client closed the connection", addr);
+            }
+            Ok(DisconnectReason::BackendRequestedStop) => {
+                info!("[{:?}] session stopped by the server", addr);
+            }
+            Ok(DisconnectReason::ProtocolError(e)) => {
+                error!("[{:?}] error reading client message: {}", addr, e);
+            }
+            Ok(DisconnectReason::IdleTimeout) => {
+                info!("[{:?}] closing idle session", addr);
+            }
+            Err(e) => {
+                error!("[{:?}] session failed: {}", addr, e);
+            }
         }
+    }
+
+    /// Handles messages from an initialized client until it disconnects, sending framebuffer
+    /// updates and delivering input to the backend. The stream is typically the one returned by
+    /// [`Self::initialize`].
+    ///
+    /// If `cancel` is given, cancelling it stops the session the next time it's waiting for the
+    /// client, returning [`DisconnectReason::BackendRequestedStop`]. This allows a server to shut
+    /// down its sessions gracefully.
+    pub async fn process<T: AsyncBufRead + AsyncWrite + Unpin + Send>(
+        &self,
+        s: T,
+        addr: SocketAddr,
+        params: SessionParams,
+        cancel: Option<CancellationToken>,
+    ) -> Result<DisconnectReason, ServerError> {
+        self.run_session(s, addr, params, cancel, CONTINUOUS_UPDATE_INTERVAL)
+            .await
+    }
+
+    /// Like [`Self::process`], but once the client enables continuous updates, the backend is
+    /// asked for an update every `interval` and it's pushed to the client without waiting for a
+    /// request. This suits backends that produce frames on their own schedule, such as a clock
+    /// or a video source. With `dirty_tracking`, only what was marked dirty since the last update
+    /// is sent. Input is delivered as usual in between.
+    pub async fn run_with_refresh<T: AsyncBufRead + AsyncWrite + Unpin + Send>(
+        &self,
+        s: T,
+        addr: SocketAddr,
+        params: SessionParams,
+        interval: Duration,
+        cancel: Option<CancellationToken>,
+    ) -> Result<DisconnectReason, ServerError> {
+        self.run_session(s, addr, params, cancel, interval).await
+    }
+
+    async fn run_session<T: AsyncBufRead + AsyncWrite + Unpin + Send>(
+        &self,
+        mut s: T,
+        addr: SocketAddr,
+        params: SessionParams,
+        cancel: Option<CancellationToken>,
+        refresh_interval: Duration,
+    ) -> Result<DisconnectReason, ServerError> {
+        debug!("[{:?}] session: {:?}", addr, params);
+        let s = &mut s;
+        let mut cut_text = self.cut_text.subscribe();
+        let mut dirty_rx = self.dirty.subscribe();
 
         let data = self.data.lock().await;
         let mut output_pixel_format = data.input_pixel_format.clone();
         drop(data);
 
+        let mut input = InputQueue::new();
+        let mut key_repeat = self.config.suppress_key_repeat.then(KeyRepeatFilter::new);
+        let mut next_input_delivery = Instant::now();
+        let mut warned_lossy = false;
+        // Until the client sends SetEncodings, it's only known to support Raw.
+        let mut encodings = vec![EncodingType::Raw];
+        let mut converters = ConverterCache::default();
+
+        if let Some(fbu) = self.server.get_initial_framebuffer_update().await {
+            self.write_update(s, &fbu, &encodings, &output_pixel_format, &mut converters)
+                .await?;
+            debug!("Tx [{:?}]: FramebufferUpdate (initial)", addr);
+        }
+
+        // Whether the client has been told to send relative pointer motion.
+        let mut relative_pointer = false;
+
+        // Whether the client's pixel format is known; see `Server::format_negotiated`.
+        let mut format_negotiated = false;
+
+        // Whether the client has told us it supports continuous updates, and if they're enabled,
+        // the region to send updates for and when to next send one.
+        let mut continuous_supported = false;
+        let mut continuous: Option<crate::rfb::FramebufferUpdateRequest> = None;
+        let mut next_continuous_update = Instant::now();
+
+        // With dirty tracking, the part of the continuous updates region that has changed since
+        // the last update was sent.
+        let mut dirty: Option<Rect> = None;
+
+        // An incremental request that was skipped because nothing had changed, and when to ask
+        // the backend again. Clients that don't use continuous updates wait for an answer, so
+        // it's retried on every refresh, or as soon as part of it is marked dirty.
+        let mut pending: Option<crate::rfb::FramebufferUpdateRequest> = None;
+        let mut next_pending_retry = Instant::now();
+
+        let idle_timeout = self.config.idle_timeout;
+        let mut idle_deadline = Instant::now() + idle_timeout.unwrap_or_default();
+
         loop {
-            let req = ClientMessage::read_from(s).await;
+            // Wait for the next message, delivering any input that was held back in the meantime.
+            let res = tokio::select! {
+                res = wait_for_data(s) => res,
+                _ = cancelled(&cancel) => {
+                    return Ok(DisconnectReason::BackendRequestedStop);
+                }
+                text = cut_text.recv() => {
+                    match text {
+                        Ok(text) => {
+                            debug!("Tx [{:?}]: ServerCutText={:?}", addr, text);
+                            text.write_to(s).await?;
+                        }
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            debug!("[{:?}] skipped {} clipboard updates", addr, n);
+                        }
+                        // The server holds a sender, so the channel is never closed.
+                        Err(broadcast::error::RecvError::Closed) => unreachable!(),
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep_until(next_input_delivery), if !input.is_empty() => {
+                    self.deliver_input(&mut input).await;
+                    if let Some(interval) = self.config.input_coalesce_interval {
+                        next_input_delivery = Instant::now() + interval;
+                    }
+                    continue;
+                }
+                rect = dirty_rx.recv() => {
+                    let rect = match rect {
+                        Ok(rect) => Some(rect),
+                        Err(broadcast::error::RecvError::Lagged(_)) => None,
+                        // The server holds a sender, so the channel is never closed.
+                        Err(broadcast::error::RecvError::Closed) => unreachable!(),
+                    };
+                    if let Some(req) = &pending {
+                        if rect.is_none_or(|r| r.intersect(&req.region()).is_some()) {
+                            next_pending_retry = Instant::now();
+                        }
+                    }
+
+                    let region = match &continuous {
+                        Some(req) => req.region(),
+                        None => continue,
+                    };
+                    if let Some(rect) = rect.unwrap_or(region).intersect(&region) {
+                        dirty = Some(dirty.map_or(rect, |d| d.union(&rect)));
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep_until(next_continuous_update), if continuous.is_some() => {
+                    let req = if !self.config.dirty_tracking {
+                        continuous.clone().unwrap()
+                    } else if let Some(r) = dirty.take() {
+                        crate::rfb::FramebufferUpdateRequest::new(true, r.x, r.y, r.width, r.height)
+                    } else {
+                        next_continuous_update = Instant::now() + refresh_interval;
+                        continue;
+                    };
+                    self.handle_update_request(
+                        s,
+                        &req,
+                        &output_pixel_format,
+                        &mut converters,
+                        &encodings,
+                    )
+                    .await?;
+                    next_continuous_update = Instant::now() + refresh_interval;
+                    continue;
+                }
+                _ = tokio::time::sleep_until(next_pending_retry), if pending.is_some() => {
+                    let req = pending.clone().unwrap();
+                    let sent = self
+                        .handle_update_request(
+                            s,
+                            &req,
+                            &output_pixel_format,
+                            &mut converters,
+                            &encodings,
+                        )
+                        .await?;
+                    if sent {
+                        debug!("Tx [{:?}]: FramebufferUpdate (pending request)", addr);
+                        pending = None;
+                    } else {
+                        next_pending_retry = Instant::now() + refresh_interval;
+                    }
+                    continue;
+                }
+                _ = tokio::time::sleep_until(idle_deadline), if idle_timeout.is_some() => {
+                    return Ok(DisconnectReason::IdleTimeout);
+                }
+            };
+            if !res? {
+                return Ok(DisconnectReason::ClientClosed);
+            }
+
+            // A client that stalls partway through a message is as idle as one that sends
+            // nothing at all.
+            let read = ClientMessage::read_with(s, &self.config.parse_config);
+            let req = match idle_timeout {
+                Some(_) => match tokio::time::timeout_at(idle_deadline, read).await {
+                    Ok(req) => req,
+                    Err(_) => return Ok(DisconnectReason::IdleTimeout),
+                },
+                None => read.await,
+            };
+            if let Some(timeout) = idle_timeout {
+                idle_deadline = Instant::now() + timeout;
+            }
 
             match req {
                 Ok(client_msg) => match client_msg {
@@ -162,59 +1241,194 @@ impl<S: Server> VncServer<S> {
 
                         // TODO: invalid pixel formats?
                         output_pixel_format = pf;
+                        if !format_negotiated {
+                            format_negotiated = true;
+                            self.server.format_negotiated(&output_pixel_format).await;
+                        }
+
+                        if !warned_lossy {
+                            let data = self.data.lock().await;
+                            if output_pixel_format.conversion_quality(&data.input_pixel_format)
+                                == Quality::Lossy
+                            {
+                                warn!(
+                                    "[{:?}] client pixel format loses color precision: input={:?}, output={:?}",
+                                    addr, data.input_pixel_format, output_pixel_format
+                                );
+                                warned_lossy = true;
+                            }
+                        }
                     }
                     SetEncodings(e) => {
                         debug!("Rx [{:?}]: SetEncodings={:?}", addr, e);
-                    }
-                    FramebufferUpdateRequest(f) => {
-                        debug!("Rx [{:?}]: FramebufferUpdateRequest={:?}", addr, f);
 
-                        let mut fbu = self.server.get_framebuffer_update().await;
-                        let data = self.data.lock().await;
-
-                        // We only need to change pixel formats if the client requested a different
-                        // one than what's specified in the input.
-                        //
-                        // For now, we only support transformations between 4-byte RGB formats, so
-                        // if the requested format isn't one of those, we'll just leave the pixels
-                        // as is.
-                        if data.input_pixel_format != output_pixel_format
-                            && data.input_pixel_format.is_rgb_888()
-                            && output_pixel_format.is_rgb_888()
+                        // An empty list resets the session to Raw only, which also turns off
+                        // every extension enabled by a pseudo-encoding.
+                        let e = if e.is_empty() {
+                            debug!("[{:?}] no encodings, resetting to Raw", addr);
+                            continuous_supported = false;
+                            continuous = None;
+                            dirty = None;
+                            vec![EncodingType::Raw]
+                        } else {
+                            e
+                        };
+                        let client_encodings = ClientEncodings::new(&e);
+                        self.server.encodings_changed(&client_encodings).await;
+
+                        // The server announces that it supports continuous updates the first
+                        // time the client says it does.
+                        if !continuous_supported
+                            && client_encodings.supports(EncodingType::ContinuousUpdatesPseudo)
                         {
+                            continuous_supported = true;
+                            EndOfContinuousUpdates.write_to(s).await?;
+                            debug!("Tx [{:?}]: EndOfContinuousUpdates", addr);
+                        }
+
+                        // A client that no longer lists the pseudo-encoding has gone back to
+                        // absolute positions on its own, so it's only told when it still lists it.
+                        let motion_change =
+                            client_encodings.supports(EncodingType::PointerMotionChangePseudo);
+                        let relative = self.config.relative_pointer && motion_change;
+                        if relative != relative_pointer && motion_change {
+                            let (width, height) = {
+                                let data = self.data.lock().await;
+                                (data.width, data.height)
+                            };
+                            FramebufferUpdate::pointer_motion_mode(relative, width, height)
+                                .write_to(s)
+                                .await?;
                             debug!(
-                                "transforming: input={:#?}, output={:#?}",
-                                data.input_pixel_format, output_pixel_format
+                                "Tx [{:?}]: FramebufferUpdate (PointerMotionChange, relative={})",
+                                addr, relative
                             );
-                            fbu = fbu.transform(&data.input_pixel_format, &output_pixel_format);
-                        } else if !(data.input_pixel_format.is_rgb_888()
-                            && output_pixel_format.is_rgb_888())
-                        {
-                            debug!("cannot transform between pixel formats (not rgb888): input.is_rgb_888()={}, output.is_rgb_888()={}", data.input_pixel_format.is_rgb_888(), output_pixel_format.is_rgb_888());
                         }
+                        relative_pointer = relative;
+
+                        // The JPEG quality level only affects Tight, so it's passed on whenever
+                        // Tight is supported.
+                        let tight = self
+                            .config
+                            .supported_encodings
+                            .contains(&EncodingType::Tight);
+                        encodings = e
+                            .into_iter()
+                            .filter(|e| {
+                                *e == EncodingType::Raw
+                                    || self.config.supported_encodings.contains(e)
+                                    || (tight
+                                        && matches!(e, EncodingType::JpegQualityLevelPseudo(_)))
+                            })
+                            .collect();
 
-                        if let Err(e) = fbu.write_to(s).await {
-                            error!(
-                                "[{:?}] could not write FramebufferUpdateRequest: {:?}",
-                                addr, e
+                        // Raw is implied even if the client didn't list it, so there's always
+                        // something to send.
+                        if encodings.iter().all(|e| e.is_pseudo()) {
+                            info!(
+                                "[{:?}] no supported encodings requested, falling back to Raw",
+                                addr
                             );
-                            return;
+                            encodings.push(EncodingType::Raw);
+                        }
+                    }
+                    FramebufferUpdateRequest(f) => {
+                        debug!("Rx [{:?}]: FramebufferUpdateRequest={:?}", addr, f);
+
+                        if !format_negotiated {
+                            format_negotiated = true;
+                            self.server.format_negotiated(&output_pixel_format).await;
+                        }
+
+                        let sent = self
+                            .handle_update_request(
+                                s,
+                                &f,
+                                &output_pixel_format,
+                                &mut converters,
+                                &encodings,
+                            )
+                            .await?;
+                        if sent {
+                            debug!("Tx [{:?}]: FramebufferUpdate", addr);
+                            pending = None;
+                        } else {
+                            debug!("[{:?}] skipping empty incremental update", addr);
+                            pending = Some(f);
+                            next_pending_retry = Instant::now() + refresh_interval;
                         }
-                        debug!("Tx [{:?}]: FramebufferUpdate", addr);
                     }
                     KeyEvent(ke) => {
-                        trace!("Rx [{:?}]: KeyEvent={:?}", addr, ke);
+                        debug!("Rx [{:?}]: KeyEvent={:?}", addr, ke);
+                        let repeat = key_repeat.as_mut().is_some_and(|f| !f.accept(&ke));
+                        if repeat {
+                            debug!("[{:?}] suppressing repeated key-down", addr);
+                        } else {
+                            input.push(InputEvent::Key(ke));
+                        }
                     }
                     PointerEvent(pe) => {
-                        trace!("Rx [{:?}: PointerEvent={:?}", addr, pe);
+                        debug!("Rx [{:?}]: PointerEvent {}", addr, pe);
+                        input.push(InputEvent::Pointer(pe.with_relative(relative_pointer)));
                     }
                     ClientCutText(t) => {
-                        trace!("Rx [{:?}: ClientCutText={:?}", addr, t);
+                        debug!("Rx [{:?}]: ClientCutText={:?}", addr, t);
+                    }
+                    SetDesktopSize(ds) => {
+                        debug!("Rx [{:?}]: SetDesktopSize={:?}", addr, ds);
+
+                        let fbu = self.handle_set_desktop_size(&ds).await;
+                        fbu.write_to(s).await?;
+                        debug!("Tx [{:?}]: FramebufferUpdate (ExtendedDesktopSize)", addr);
+                    }
+                    Ignored(t) => {
+                        debug!("Rx [{:?}]: ignored message of type {}", addr, t);
+                    }
+                    EnableContinuousUpdates(ecu) => {
+                        debug!("Rx [{:?}]: EnableContinuousUpdates={:?}", addr, ecu);
+
+                        if !continuous_supported {
+                            // Clients may only send this once we've acknowledged the
+                            // pseudo-encoding.
+                            warn!("[{:?}] unexpected EnableContinuousUpdates", addr);
+                        } else if ecu.enable() {
+                            let r = ecu.region();
+                            continuous = Some(crate::rfb::FramebufferUpdateRequest::new(
+                                true, r.x, r.y, r.width, r.height,
+                            ));
+                            next_continuous_update = Instant::now();
+                            dirty = None;
+                            pending = None;
+                        } else {
+                            // Acknowledge, so the client knows no more updates are coming.
+                            continuous = None;
+                            EndOfContinuousUpdates.write_to(s).await?;
+                            debug!("Tx [{:?}]: EndOfContinuousUpdates", addr);
+                        }
                     }
                 },
                 Err(e) => {
-                    error!("[{:?}] error reading client message: {}", addr, e);
-                    return;
+                    // Reads that fail partway through a message are I/O errors, not protocol
+                    // errors, even though they carry the field being parsed.
+                    if let Some(e) = e
+                        .downcast_ref::<ProtoError>()
+                        .and_then(ProtoError::io_error)
+                    {
+                        return Err(ServerError::Io(e));
+                    }
+
+                    return match e.downcast::<io::Error>() {
+                        Ok(e) => Err(ServerError::Io(e)),
+                        Err(e) => Ok(DisconnectReason::ProtocolError(e.to_string())),
+                    };
+                }
+            }
+
+            let now = Instant::now();
+            if !input.is_empty() && now >= next_input_delivery {
+                self.deliver_input(&mut input).await;
+                if let Some(interval) = self.config.input_coalesce_interval {
+                    next_input_delivery = now + interval;
                 }
             }
         }
@@ -224,11 +1438,2404 @@ impl<S: Server> VncServer<S> {
         let listener = TcpListener::bind(self.config.addr).await.unwrap();
 
         loop {
-            let (mut s, a) = listener.accept().await.unwrap();
-            let server = self.clone();
+            let (s, a) = listener.accept().await.unwrap();
+            self.spawn_conn(s, a);
+        }
+    }
+
+    /// Handles a newly accepted connection in its own task.
+    fn spawn_conn<T: AsyncRead + AsyncWrite + Unpin + Send + 'static>(&self, s: T, a: SocketAddr) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            if server.config.count_bytes {
+                let s = Counted::new(s);
+                server.server.session_stats(a, s.stats()).await;
+                VncServer::handle_conn(&server, buffered_reader(s), a).await;
+            } else {
+                VncServer::handle_conn(&server, buffered_reader(s), a).await;
+            }
+        });
+    }
+}
+
+/// The address given for clients connected over a Unix domain socket, which don't have one.
+/// Sessions are identified by address in logs and backend callbacks, so these all share it.
+#[cfg(unix)]
+pub const UNIX_PEER_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Binds a Unix domain socket at `path` and handles the connections made to it, as
+/// `VncServer::start` does for TCP. This suits servers that are reached through a local proxy.
+/// Sessions are given the address [`UNIX_PEER_ADDR`].
+///
+/// Only returns if the socket can't be bound. A socket file left at `path` by an earlier server
+/// must be removed first.
+#[cfg(unix)]
+pub async fn accept_unix<S: Server>(vnc: &VncServer<S>, path: impl AsRef<Path>) -> io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+
+    loop {
+        match listener.accept().await {
+            Ok((s, _)) => vnc.spawn_conn(s, UNIX_PEER_ADDR),
+            Err(e) => {
+                error!("could not accept connection: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Accepts connections on a listener and initializes them, allowing at most `max_connections`
+/// sessions at a time. Once the limit is reached, further connections wait in the listener's
+/// backlog until a session ends.
+///
+/// Each connection is initialized in its own task, so a client that is slow to complete the
+/// handshake doesn't hold up others.
+///
+/// ```no_run
+/// # use rfb::server::{Acceptor, Server, VncServer};
+/// # async fn serve<S: Server>(vnc: VncServer<S>) -> anyhow::Result<()> {
+/// let listener = tokio::net::TcpListener::bind("127.0.0.1:5900").await?;
+/// let mut acceptor = Acceptor::new(vnc, listener, 16);
+/// while let Some(session) = acceptor.accept().await {
+///     tokio::spawn(session.process(None));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct Acceptor<S: Server> {
+    sessions: mpsc::Receiver<AcceptedSession<S>>,
+    task: JoinHandle<()>,
+}
+
+impl<S: Server> Acceptor<S> {
+    pub fn new(vnc: VncServer<S>, listener: TcpListener, max_connections: usize) -> Self {
+        let permits = Arc::new(Semaphore::new(max_connections));
+        let (tx, sessions) = mpsc::channel(1);
+        let task = tokio::spawn(Self::accept_loop(vnc, listener, permits, tx));
+
+        Acceptor { sessions, task }
+    }
+
+    async fn accept_loop(
+        vnc: VncServer<S>,
+        listener: TcpListener,
+        permits: Arc<Semaphore>,
+        tx: mpsc::Sender<AcceptedSession<S>>,
+    ) {
+        loop {
+            // The semaphore is never closed.
+            let permit = permits.clone().acquire_owned().await.unwrap();
+
+            let (s, addr) = match listener.accept().await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("could not accept connection: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            info!("[{:?}] new connection", addr);
+
+            let vnc = vnc.clone();
+            let tx = tx.clone();
             tokio::spawn(async move {
-                VncServer::handle_conn(&server, &mut s, a).await;
+                let (stream, params) = match vnc.initialize(buffered_reader(s), addr).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        error!("[{:?}] could not complete handshake: {:?}", addr, e);
+                        return;
+                    }
+                };
+
+                let session = AcceptedSession {
+                    vnc,
+                    stream,
+                    addr,
+                    params,
+                    _permit: permit,
+                };
+                // If the acceptor has been dropped, so is the session.
+                let _ = tx.send(session).await;
+            });
+        }
+    }
+
+    /// Returns the next initialized session.
+    pub async fn accept(&mut self) -> Option<AcceptedSession<S>> {
+        self.sessions.recv().await
+    }
+}
+
+impl<S: Server> Drop for Acceptor<S> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A connection that has completed initialization. It holds one of its acceptor's connection
+/// slots until it is dropped.
+pub struct AcceptedSession<S: Server> {
+    vnc: VncServer<S>,
+    stream: BufReader<TcpStream>,
+    addr: SocketAddr,
+    params: SessionParams,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<S: Server> AcceptedSession<S> {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn params(&self) -> &SessionParams {
+        &self.params
+    }
+
+    /// Runs the session until it ends; see `VncServer::process`.
+    pub async fn process(
+        self,
+        cancel: Option<CancellationToken>,
+    ) -> Result<DisconnectReason, ServerError> {
+        self.vnc
+            .process(self.stream, self.addr, self.params, cancel)
+            .await
+    }
+}
+
+/// Waits until the token, if any, is cancelled.
+async fn cancelled(cancel: &Option<CancellationToken>) {
+    match cancel {
+        Some(token) => token.cancelled().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until data from the client is available to read, returning false if the client has
+/// closed the connection. This doesn't consume any data, so unlike reading a message, it can be
+/// safely cancelled.
+async fn wait_for_data<T: AsyncBufRead + Unpin>(s: &mut T) -> std::io::Result<bool> {
+    let buf = s.fill_buf().await?;
+    Ok(!buf.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encodings::{CopyRectEncoding, TightEncoding};
+    use crate::rfb::UnknownMessagePolicy;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+
+    #[derive(Clone)]
+    struct TestServer;
+
+    #[async_trait]
+    impl Server for TestServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            FramebufferUpdate::new(vec![])
+        }
+    }
+
+    fn test_config(version: ProtoVersion, sec_types: Vec<SecurityType>) -> VncServerConfig {
+        VncServerConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            version,
+            sec_types: SecurityTypes(sec_types),
+            name: "rfb-test".to_string(),
+            name_encoding: NameEncoding::Utf8,
+            empty_name_replacement: None,
+            max_desktop_size: None,
+            handshake_timeout: Duration::from_secs(5),
+            idle_timeout: None,
+            input_coalesce_interval: None,
+            relative_pointer: false,
+            suppress_key_repeat: false,
+            parse_config: ParseConfig::default(),
+            supported_encodings: vec![EncodingType::Raw],
+            count_bytes: false,
+            dirty_tracking: false,
+            security_policy: None,
+        }
+    }
+
+    fn test_server(version: ProtoVersion, sec_types: Vec<SecurityType>) -> VncServer<TestServer> {
+        test_server_with_config(test_config(version, sec_types))
+    }
+
+    fn test_server_with_config(config: VncServerConfig) -> VncServer<TestServer> {
+        VncServer::new(TestServer, config, test_data())
+    }
+
+    fn test_data() -> VncServerData {
+        VncServerData {
+            width: 64,
+            height: 48,
+            input_pixel_format: crate::pixel_formats::fourcc::fourcc_to_pixel_format(
+                crate::pixel_formats::fourcc::FOURCC_XR24,
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Returns a connected (server, client) pair over loopback, plus the client's address.
+    async fn tcp_pair() -> (TcpStream, TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, addr) = listener.accept().await.unwrap();
+        (server, client, addr)
+    }
+
+    /// A logger that captures formatted records so tests can assert on them.
+    struct CaptureLogger {
+        records: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let line = format!("{} {}", record.level(), record.args());
+            self.records.lock().unwrap().push(line);
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger {
+        records: std::sync::Mutex::new(Vec::new()),
+    };
+
+    fn captured_logs(addr: SocketAddr) -> Vec<String> {
+        let tag = format!("{:?}", addr);
+        LOGGER
+            .records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.contains(&tag))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_handshake_phase_logging() {
+        // Other tests may have installed the logger already.
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (mut s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+            c.read_u16().await.unwrap();
+            c.write_u8(1).await.unwrap();
+            c.read_u32().await.unwrap();
+            c.write_u8(1).await.unwrap();
+            c
+        });
+
+        vnc.rfb_handshake(&mut s, addr).await.unwrap();
+        vnc.rfb_initialization(&mut s, addr, ProtoVersion::Rfb38)
+            .await
+            .unwrap();
+        client.await.unwrap();
+
+        let logs = captured_logs(addr);
+        let has = |needle: &str| {
+            logs.iter()
+                .any(|l| l.starts_with("DEBUG") && l.contains(needle))
+        };
+        assert!(has("version negotiated=Rfb38"), "{:#?}", logs);
+        assert!(has("security type selected=None"), "{:#?}", logs);
+        assert!(has("client init shared=true"), "{:#?}", logs);
+    }
+
+    #[tokio::test]
+    async fn test_security_policy() {
+        fn policy(addr: &SocketAddr) -> SecurityTypes {
+            if addr.ip().is_loopback() {
+                SecurityTypes(vec![SecurityType::None])
+            } else {
+                SecurityTypes(vec![SecurityType::VncAuthentication])
+            }
+        }
+
+        let mut config = test_config(ProtoVersion::Rfb38, vec![]);
+        config.security_policy = Some(policy);
+        let vnc = test_server_with_config(config);
+
+        // The policy only sees the address, so a remote peer can be tested over loopback.
+        for (peer, expected) in [("127.0.0.1:5000", 1), ("192.0.2.1:5000", 2)] {
+            let (mut s, mut c, _) = tcp_pair().await;
+            let client = tokio::spawn(async move {
+                let mut version = [0u8; 12];
+                c.read_exact(&mut version).await.unwrap();
+                c.write_all(b"RFB 003.008\n").await.unwrap();
+                let mut types = [0u8; 2];
+                c.read_exact(&mut types).await.unwrap();
+                types
+            });
+
+            let addr: SocketAddr = peer.parse().unwrap();
+            let _ =
+                tokio::time::timeout(Duration::from_millis(100), vnc.rfb_handshake(&mut s, addr))
+                    .await;
+            assert_eq!(client.await.unwrap(), [1, expected]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rfb33_none_skips_security_result() {
+        let vnc = test_server(ProtoVersion::Rfb33, vec![SecurityType::None]);
+        let (mut s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            assert_eq!(&version, b"RFB 003.003\n");
+            c.write_all(b"RFB 003.003\n").await.unwrap();
+
+            // The server picks the security type; no list and no response from the client.
+            assert_eq!(c.read_u32().await.unwrap(), 1);
+
+            let mut rest = Vec::new();
+            c.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        vnc.rfb_handshake(&mut s, addr).await.unwrap();
+        drop(s);
+
+        // Nothing (in particular, no SecurityResult) follows the security type.
+        assert!(client.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rfb38_none_sends_security_result() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (mut s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+
+            assert_eq!(c.read_u8().await.unwrap(), 1);
+            assert_eq!(c.read_u8().await.unwrap(), 1);
+            c.write_u8(1).await.unwrap();
+
+            let mut rest = Vec::new();
+            c.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        vnc.rfb_handshake(&mut s, addr).await.unwrap();
+        drop(s);
+
+        assert_eq!(client.await.unwrap(), vec![0, 0, 0, 0]);
+    }
+
+    /// Writes a server message on one end of a loopback connection and returns the bytes that
+    /// arrive at the other end.
+    async fn written_bytes<M: WriteMessage>(msg: M) -> Vec<u8> {
+        let (mut s, mut c, _) = tcp_pair().await;
+        msg.write_to(&mut s).await.unwrap();
+        drop(s);
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+        buf
+    }
+
+    fn desktop_size_request(width: u16, height: u16) -> crate::rfb::SetDesktopSize {
+        crate::rfb::SetDesktopSize {
+            width,
+            height,
+            screens: vec![Screen {
+                id: 7,
+                x: 0,
+                y: 0,
+                width,
+                height,
+                flags: 0,
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_desktop_size_success() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.max_desktop_size = Some((1920, 1080));
+        let vnc = test_server_with_config(config);
+
+        let fbu = vnc
+            .handle_set_desktop_size(&desktop_size_request(800, 600))
+            .await;
+        let bytes = written_bytes(fbu).await;
+
+        #[rustfmt::skip]
+        let expected = vec![
+            0, 0, 0, 1,             // FramebufferUpdate, 1 rectangle
+            0, 1, 0, 0,             // reason: this client, status: success
+            0x03, 0x20, 0x02, 0x58, // 800x600
+            0xff, 0xff, 0xfe, 0xcc, // ExtendedDesktopSize (-308)
+            1, 0, 0, 0,             // 1 screen
+            0, 0, 0, 7,             // id
+            0, 0, 0, 0,             // x, y
+            0x03, 0x20, 0x02, 0x58, // 800x600
+            0, 0, 0, 0,             // flags
+        ];
+        assert_eq!(bytes, expected);
+
+        let data = vnc.data.lock().await;
+        assert_eq!((data.width, data.height), (800, 600));
+    }
+
+    #[tokio::test]
+    async fn test_set_desktop_size_prohibited() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+
+        let fbu = vnc
+            .handle_set_desktop_size(&desktop_size_request(800, 600))
+            .await;
+        let bytes = written_bytes(fbu).await;
+
+        // The reply carries the prohibited status and the unchanged size and layout.
+        assert_eq!(&bytes[4..8], &[0, 1, 0, 1]);
+        assert_eq!(&bytes[8..12], &[0, 64, 0, 48]);
+        assert_eq!(&bytes[24..32], &[0, 0, 0, 0, 0, 64, 0, 48]);
+
+        let data = vnc.data.lock().await;
+        assert_eq!((data.width, data.height), (64, 48));
+    }
+
+    #[tokio::test]
+    async fn test_send_full_update_after_init() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (mut s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+            c.read_u16().await.unwrap();
+            c.write_u8(1).await.unwrap();
+            c.read_u32().await.unwrap();
+            c.write_u8(1).await.unwrap();
+
+            let mut rest = Vec::new();
+            c.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        vnc.rfb_handshake(&mut s, addr).await.unwrap();
+        vnc.rfb_initialization(&mut s, addr, ProtoVersion::Rfb38)
+            .await
+            .unwrap();
+
+        let pixels = vec![0u8; 2 * 2 * 4];
+        let r = Rectangle::new(0, 0, 2, 2, Box::new(RawEncoding::new(pixels)));
+        let fbu = FramebufferUpdate::new(vec![r]);
+        vnc.send_full_update(&mut s, &fbu).await.unwrap();
+        drop(s);
+
+        // ServerInit (resolution, 16-byte pixel format, name) is followed directly by the
+        // update, without the client having sent a FramebufferUpdateRequest.
+        let rest = client.await.unwrap();
+        let name_len = "rfb-test".len();
+        let update = &rest[(4 + 16 + 4 + name_len)..];
+        assert_eq!(&update[0..4], &[0, 0, 0, 1]);
+        assert_eq!(update.len(), 4 + 12 + 16);
+    }
+
+    #[tokio::test]
+    async fn test_send_updates() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let frames = (1..=3u8).map(|n| {
+            let r = Rectangle::new(0, 0, 1, 1, Box::new(RawEncoding::new(vec![n; 4])));
+            FramebufferUpdate::new(vec![r])
+        });
+
+        let mut out = Vec::new();
+        let sent = vnc
+            .send_updates(&mut out, futures::stream::iter(frames))
+            .await
+            .unwrap();
+        assert_eq!(sent, 3);
+
+        // Three complete updates, back to back, each with its own frame's pixels.
+        let len = 4 + 12 + 4;
+        assert_eq!(out.len(), 3 * len);
+        for (n, update) in out.chunks(len).enumerate() {
+            assert_eq!(&update[0..4], &[0, 0, 0, 1]);
+            assert_eq!(&update[16..], &[n as u8 + 1; 4]);
+        }
+    }
+
+    /// Drives a 3.8 client through security type selection of VNC authentication, returning the
+    /// stream once the challenge has been received.
+    async fn start_vnc_auth(mut c: TcpStream) -> TcpStream {
+        let mut version = [0u8; 12];
+        c.read_exact(&mut version).await.unwrap();
+        c.write_all(b"RFB 003.008\n").await.unwrap();
+        assert_eq!(c.read_u8().await.unwrap(), 1);
+        assert_eq!(c.read_u8().await.unwrap(), 2);
+        c.write_u8(2).await.unwrap();
+
+        let mut challenge = [0u8; 16];
+        c.read_exact(&mut challenge).await.unwrap();
+        c
+    }
+
+    #[tokio::test]
+    async fn test_vnc_auth_refused() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::VncAuthentication]);
+        let (mut s, c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut c = start_vnc_auth(c).await;
+            c.write_all(&[0u8; 16]).await.unwrap();
+            let status = c.read_u32().await.unwrap();
+            let mut reason = vec![0u8; c.read_u32().await.unwrap() as usize];
+            c.read_exact(&mut reason).await.unwrap();
+            (status, String::from_utf8(reason).unwrap())
+        });
+
+        let err = vnc.rfb_handshake(&mut s, addr).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HandshakeError>(),
+            Some(HandshakeError::AuthenticationFailed)
+        ));
+        assert_eq!(
+            client.await.unwrap(),
+            (1, "authentication failed".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vnc_auth_response_timeout() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::VncAuthentication]);
+        config.handshake_timeout = Duration::from_millis(100);
+        let vnc = test_server_with_config(config);
+        let (mut s, c, addr) = tcp_pair().await;
+
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let mut c = start_vnc_auth(c).await;
+
+            // Send half of the response, then stall until the test is done.
+            c.write_all(&[0u8; 8]).await.unwrap();
+            let _ = done_rx.await;
+        });
+
+        let err = vnc.rfb_handshake(&mut s, addr).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<InitError>(),
+            Some(InitError::Timeout)
+        ));
+        let _ = done_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_vnc_auth_response_disconnect() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::VncAuthentication]);
+        let (mut s, c, addr) = tcp_pair().await;
+
+        tokio::spawn(async move {
+            let mut c = start_vnc_auth(c).await;
+            c.write_all(&[0u8; 8]).await.unwrap();
+        });
+
+        let err = vnc.rfb_handshake(&mut s, addr).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<InitError>(),
+            Some(InitError::ClientDisconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_no_security_types() {
+        for version in [ProtoVersion::Rfb33, ProtoVersion::Rfb38] {
+            let vnc = test_server(version, vec![]);
+            let (mut s, mut c, addr) = tcp_pair().await;
+
+            let client = tokio::spawn(async move {
+                let mut server_version = [0u8; 12];
+                c.read_exact(&mut server_version).await.unwrap();
+                c.write_all(&server_version).await.unwrap();
+
+                // A zero count (or, for 3.3, type), followed by the reason.
+                if version == ProtoVersion::Rfb33 {
+                    assert_eq!(c.read_u32().await.unwrap(), 0);
+                } else {
+                    assert_eq!(c.read_u8().await.unwrap(), 0);
+                }
+                let mut reason = vec![0u8; c.read_u32().await.unwrap() as usize];
+                c.read_exact(&mut reason).await.unwrap();
+                String::from_utf8(reason).unwrap()
             });
+
+            let err = vnc.rfb_handshake(&mut s, addr).await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<InitError>(),
+                Some(InitError::NoSecurityTypes)
+            ));
+            assert_eq!(client.await.unwrap(), "no security types are available");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_incremental_update_skipped() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let pf = vnc.data.lock().await.input_pixel_format.clone();
+
+        // TestServer always returns an empty update.
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(true, 0, 0, 64, 48);
+        let sent = vnc
+            .handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert!(!sent);
+        assert!(out.is_empty());
+
+        // A non-incremental request is still answered with an (empty) update.
+        let req = crate::rfb::FramebufferUpdateRequest::new(false, 0, 0, 64, 48);
+        let sent = vnc
+            .handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert!(sent);
+        assert_eq!(out, vec![0, 0, 0, 0]);
+    }
+
+    /// A backend whose framebuffer stays the same until `changed` is set.
+    #[derive(Clone, Default)]
+    struct ChangingServer {
+        changed: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    #[async_trait]
+    impl Server for ChangingServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            if !self.changed.load(std::sync::atomic::Ordering::SeqCst) {
+                return FramebufferUpdate::new(vec![]);
+            }
+            let r = Rectangle::new(0, 0, 1, 1, Box::new(RawEncoding::new(vec![1u8; 4])));
+            FramebufferUpdate::new(vec![r])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skipped_request_answered_later() {
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let backend = ChangingServer::default();
+        let changed = backend.changed.clone();
+        let vnc = VncServer::new(backend, config, test_data());
+
+        // Without continuous updates or refreshes, marking the framebuffer dirty answers the
+        // request.
+        let (s, mut c, addr) = tcp_pair().await;
+        let session = {
+            let vnc = vnc.clone();
+            let params = params.clone();
+            let interval = Duration::from_secs(3600);
+            tokio::spawn(async move {
+                vnc.run_with_refresh(buffered_reader(s), addr, params, interval, None)
+                    .await
+            })
+        };
+        c.write_all(&[3, 1, 0, 0, 0, 0, 0, 64, 0, 48])
+            .await
+            .unwrap();
+        let mut update = [0u8; 4 + 12 + 4];
+        let res = tokio::time::timeout(Duration::from_millis(100), c.read_u8()).await;
+        assert!(res.is_err(), "nothing has changed yet");
+
+        changed.store(true, std::sync::atomic::Ordering::SeqCst);
+        vnc.mark_dirty(Rect::new(0, 0, 1, 1));
+        tokio::time::timeout(Duration::from_secs(1), c.read_exact(&mut update))
+            .await
+            .expect("pending request should be answered")
+            .unwrap();
+        assert_eq!(&update[16..], &[1, 1, 1, 1]);
+        drop(c);
+        session.await.unwrap().unwrap();
+
+        // Otherwise, the request is answered on the next refresh.
+        changed.store(false, std::sync::atomic::Ordering::SeqCst);
+        let (s, mut c, addr) = tcp_pair().await;
+        let session = {
+            let vnc = vnc.clone();
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await })
+        };
+        c.write_all(&[3, 1, 0, 0, 0, 0, 0, 64, 0, 48])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        changed.store(true, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::timeout(Duration::from_secs(1), c.read_exact(&mut update))
+            .await
+            .expect("pending request should be answered")
+            .unwrap();
+        assert_eq!(&update[16..], &[1, 1, 1, 1]);
+        drop(c);
+        session.await.unwrap().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        // Any stream will do for a session, such as one end of a Unix socket pair.
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, mut c) = tokio::net::UnixStream::pair().unwrap();
+        let client = tokio::spawn(async move { client_handshake(&mut c).await });
+        let (_, params) = vnc
+            .initialize(buffered_reader(s), UNIX_PEER_ADDR)
+            .await
+            .unwrap();
+        assert_eq!(params.security_type, SecurityType::None);
+        client.await.unwrap();
+
+        // Connections to a bound socket are handled like those accepted by `start`.
+        let path = std::env::temp_dir().join(format!("rfb-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let server = {
+            let path = path.clone();
+            tokio::spawn(async move { accept_unix(&vnc, path).await })
+        };
+
+        let mut c = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(c) => break c,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        client_handshake(&mut c).await;
+
+        server.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_initialize_returns_stream() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+
+            let mut sec_types = [0u8; 2];
+            c.read_exact(&mut sec_types).await.unwrap();
+            c.write_u8(1).await.unwrap();
+            assert_eq!(c.read_u32().await.unwrap(), 0);
+
+            // ClientInit (shared), then read the ServerInit.
+            c.write_u8(1).await.unwrap();
+            let mut server_init = vec![0u8; 24 + "rfb-test".len()];
+            c.read_exact(&mut server_init).await.unwrap();
+
+            // The returned stream is still connected to us.
+            assert_eq!(c.read_u8().await.unwrap(), 0xaa);
+        });
+
+        let (mut s, params) = vnc.initialize(s, addr).await.unwrap();
+        assert_eq!(
+            params,
+            SessionParams {
+                version: ProtoVersion::Rfb38,
+                security_type: SecurityType::None,
+                shared: true,
+            }
+        );
+
+        s.write_u8(0xaa).await.unwrap();
+        client.await.unwrap();
+    }
+
+    /// A backend that returns a 1x1 update and counts how often it's called.
+    #[derive(Clone, Default)]
+    struct CountingServer {
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Server for CountingServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let r = Rectangle::new(0, 0, 1, 1, Box::new(RawEncoding::new(vec![1u8; 4])));
+            FramebufferUpdate::new(vec![r])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_frame_resent() {
+        let backend = CountingServer::default();
+        let calls = backend.calls.clone();
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(backend, config, test_data());
+        let pf = vnc.data.lock().await.input_pixel_format.clone();
+
+        let full = Rectangle::new(0, 0, 1, 1, Box::new(RawEncoding::new(vec![2u8; 4])));
+        vnc.set_full_frame(FramebufferUpdate::new(vec![full])).await;
+
+        // Incremental requests go to the backend.
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(true, 0, 0, 1, 1);
+        vnc.handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(&out[16..], &[1, 1, 1, 1]);
+
+        // A non-incremental request is answered with the full frame.
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(false, 0, 0, 1, 1);
+        vnc.handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(&out[16..], &[2, 2, 2, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_run_with_refresh() {
+        let backend = CountingServer::default();
+        let calls = backend.calls.clone();
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(backend, config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let interval = Duration::from_millis(20);
+        let session = tokio::spawn(async move {
+            vnc.run_with_refresh(buffered_reader(s), addr, params, interval, None)
+                .await
+        });
+
+        // Announce support for continuous updates, then enable them for the whole framebuffer.
+        c.write_all(&[2, 0, 0, 1]).await.unwrap();
+        c.write_i32(-313).await.unwrap();
+        assert_eq!(c.read_u8().await.unwrap(), 150);
+        c.write_all(&[150, 1, 0, 0, 0, 0, 0, 64, 0, 48])
+            .await
+            .unwrap();
+
+        // Updates keep arriving without any requests.
+        let start = Instant::now();
+        for _ in 0..3 {
+            let mut update = [0u8; 4 + 12 + 4];
+            tokio::time::timeout(Duration::from_secs(1), c.read_exact(&mut update))
+                .await
+                .expect("update should be pushed")
+                .unwrap();
+            assert_eq!(&update[..4], &[0, 0, 0, 1]);
+            assert_eq!(&update[16..], &[1, 1, 1, 1]);
+        }
+        assert!(start.elapsed() >= 2 * interval);
+        assert!(calls.load(std::sync::atomic::Ordering::SeqCst) >= 3);
+
+        drop(c);
+        assert_eq!(
+            session.await.unwrap().unwrap(),
+            DisconnectReason::ClientClosed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rfb33_client_with_rfb38_server() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            assert_eq!(&version, b"RFB 003.008\n");
+            c.write_all(b"RFB 003.003\n").await.unwrap();
+
+            // The server picks the security type, and there's no SecurityResult for None.
+            assert_eq!(c.read_u32().await.unwrap(), 1);
+
+            // ClientInit, then ServerInit: resolution, pixel format, and the name.
+            c.write_u8(0).await.unwrap();
+            let mut server_init = [0u8; 20];
+            c.read_exact(&mut server_init).await.unwrap();
+            let len = c.read_u32().await.unwrap();
+            let mut name = vec![0u8; len as usize];
+            c.read_exact(&mut name).await.unwrap();
+            assert_eq!(name, b"rfb-test");
+        });
+
+        let (_s, params) = vnc.initialize(s, addr).await.unwrap();
+        client.await.unwrap();
+
+        assert_eq!(params.version, ProtoVersion::Rfb33);
+        assert_eq!(params.security_type, SecurityType::None);
+        assert!(!params.shared);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_bytes() {
+        // Recorded from a session with a 3.8 client that chose None.
+        #[rustfmt::skip]
+        const GOLDEN: &[u8] = &[
+            // ProtocolVersion
+            b'R', b'F', b'B', b' ', b'0', b'0', b'3', b'.', b'0', b'0', b'8', b'\n',
+            // SecurityTypes: None
+            1, 1,
+            // SecurityResult: OK
+            0, 0, 0, 0,
+            // ServerInit: 64x48, 32bpp depth 24 little-endian true color, R << 16 | G << 8 | B
+            0, 64, 0, 48,
+            32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 16, 8, 0, 0, 0, 0,
+            0, 0, 0, 8, b'r', b'f', b'b', b'-', b't', b'e', b's', b't',
+        ];
+
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let bytes = vnc.handshake_bytes(ProtoVersion::Rfb38).await.unwrap();
+        assert_eq!(bytes, GOLDEN);
+
+        // The same bytes a real session sends.
+        let (s, mut c, addr) = tcp_pair().await;
+        let client = tokio::spawn(async move {
+            let mut received = vec![0u8; GOLDEN.len()];
+            c.read_exact(&mut received[..12]).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+            c.read_exact(&mut received[12..14]).await.unwrap();
+            c.write_u8(1).await.unwrap();
+            c.read_exact(&mut received[14..18]).await.unwrap();
+            c.write_u8(1).await.unwrap();
+            c.read_exact(&mut received[18..]).await.unwrap();
+            received
+        });
+        vnc.initialize(s, addr).await.unwrap();
+        assert_eq!(client.await.unwrap(), GOLDEN);
+
+        // 3.3 has no SecurityResult for None.
+        let bytes = vnc.handshake_bytes(ProtoVersion::Rfb33).await.unwrap();
+        assert_eq!(&bytes[..16], b"RFB 003.008\n\0\0\0\x01");
+        assert_eq!(&bytes[16..], &GOLDEN[18..]);
+
+        // VNC authentication needs a real client.
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::VncAuthentication]);
+        assert!(vnc.handshake_bytes(ProtoVersion::Rfb38).await.is_err());
+    }
+
+    /// A backend with a two-color palette.
+    #[derive(Clone)]
+    struct PaletteServer;
+
+    #[async_trait]
+    impl Server for PaletteServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            FramebufferUpdate::new(vec![])
+        }
+
+        async fn get_color_map(&self) -> Vec<ColorMapEntry> {
+            vec![
+                ColorMapEntry {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                },
+                ColorMapEntry {
+                    red: 0xffff,
+                    green: 0x8000,
+                    blue: 0x0001,
+                },
+            ]
         }
     }
+
+    #[tokio::test]
+    async fn test_color_map_sent_after_init() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let data = VncServerData {
+            input_pixel_format: PixelFormat {
+                bits_per_pixel: 8,
+                depth: 8,
+                big_endian: false,
+                color_spec: crate::rfb::ColorSpecification::ColorMap(crate::rfb::ColorMap {}),
+            },
+            ..test_data()
+        };
+        let vnc = VncServer::new(PaletteServer, config, data);
+        let (mut s, mut c, addr) = tcp_pair().await;
+
+        let client = tokio::spawn(async move {
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+            c.read_u16().await.unwrap();
+            c.write_u8(1).await.unwrap();
+            c.read_u32().await.unwrap();
+            c.write_u8(1).await.unwrap();
+
+            let mut rest = Vec::new();
+            c.read_to_end(&mut rest).await.unwrap();
+            rest
+        });
+
+        vnc.rfb_handshake(&mut s, addr).await.unwrap();
+        vnc.rfb_initialization(&mut s, addr, ProtoVersion::Rfb38)
+            .await
+            .unwrap();
+        drop(s);
+
+        let rest = client.await.unwrap();
+
+        // The ServerInit advertises a color-mapped format...
+        let server_init_len = 24 + "rfb-test".len();
+        assert_eq!(rest[4 + 3], 0);
+
+        // ... and is followed by the palette.
+        assert_eq!(
+            &rest[server_init_len..],
+            &[1, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0x80, 0x00, 0x00, 0x01]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_name() {
+        let data = test_data();
+
+        let server_named = |name: &str, replacement: Option<&str>| {
+            let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+            config.name = name.to_string();
+            config.empty_name_replacement = replacement.map(str::to_string);
+            test_server_with_config(config)
+        };
+
+        // By default, an empty name is sent with a zero length.
+        let server_init = server_named("", None).server_init(&data, ProtoVersion::Rfb38);
+        assert_eq!(server_init.name(), "");
+        let mut buf = Vec::new();
+        server_init.write_to(&mut buf).await.unwrap();
+        assert_eq!(&buf[20..], &[0, 0, 0, 0]);
+
+        let vnc = server_named("", Some(DEFAULT_NAME));
+        assert_eq!(vnc.server_init(&data, ProtoVersion::Rfb33).name(), "rfb");
+
+        // A name that isn't empty is never replaced.
+        let vnc = server_named("desktop", Some(DEFAULT_NAME));
+        assert_eq!(
+            vnc.server_init(&data, ProtoVersion::Rfb38).name(),
+            "desktop"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_color_map() {
+        let colors: Vec<_> = (0..5u16)
+            .map(|i| ColorMapEntry {
+                red: i,
+                green: 0x100 * i,
+                blue: 0xffff,
+            })
+            .collect();
+
+        // Servers without a color map can't update it.
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let mut out = Vec::new();
+        assert!(vnc.update_color_map(&mut out, 10, &colors).await.is_err());
+        assert!(out.is_empty());
+
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let data = VncServerData {
+            input_pixel_format: PixelFormat {
+                bits_per_pixel: 8,
+                depth: 8,
+                big_endian: false,
+                color_spec: crate::rfb::ColorSpecification::ColorMap(crate::rfb::ColorMap {}),
+            },
+            ..test_data()
+        };
+        let vnc = VncServer::new(PaletteServer, config, data);
+
+        // Only entries 10 through 14 are sent.
+        vnc.update_color_map(&mut out, 10, &colors).await.unwrap();
+        let mut expected = vec![1, 0, 0, 10, 0, 5];
+        for c in &colors {
+            expected.extend_from_slice(&c.red.to_be_bytes());
+            expected.extend_from_slice(&c.green.to_be_bytes());
+            expected.extend_from_slice(&c.blue.to_be_bytes());
+        }
+        assert_eq!(out, expected);
+
+        // The last entry is index 65535.
+        out.clear();
+        assert!(vnc.update_color_map(&mut out, 65531, &colors).await.is_ok());
+        assert!(vnc
+            .update_color_map(&mut out, 65532, &colors)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_process_client_closed() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+
+        drop(c);
+        let reason = vnc
+            .process(buffered_reader(s), addr, params, None)
+            .await
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::ClientClosed);
+    }
+
+    #[tokio::test]
+    async fn test_process_cancelled() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, _c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+
+        let token = CancellationToken::new();
+        let session = {
+            let token = token.clone();
+            tokio::spawn(async move {
+                vnc.process(buffered_reader(s), addr, params, Some(token))
+                    .await
+            })
+        };
+
+        // The client is still connected, but idle.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        token.cancel();
+
+        let reason = tokio::time::timeout(Duration::from_secs(1), session)
+            .await
+            .expect("session should stop promptly")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::BackendRequestedStop);
+    }
+
+    #[tokio::test]
+    async fn test_process_ignores_unknown_messages() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.parse_config.unknown_messages = UnknownMessagePolicy::Ignore;
+        let vnc = test_server_with_config(config);
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+
+        let session = {
+            let vnc = vnc.clone();
+            let params = params.clone();
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await })
+        };
+
+        // An xvp message and a ClientFence with a 2 byte payload, both skipped.
+        c.write_all(&[250, 0, 1, 2]).await.unwrap();
+        c.write_all(&[248, 0, 0, 0, 0, 0, 0, 0, 2, 0xaa, 0xbb])
+            .await
+            .unwrap();
+        // A key event after them is still parsed, so the session is in sync.
+        c.write_all(&[4, 1, 0, 0, 0, 0, 0, 0x61]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(c);
+
+        let reason = session.await.unwrap().unwrap();
+        assert_eq!(reason, DisconnectReason::ClientClosed);
+
+        // The Tight client message's layout varies, so it can't be skipped even when ignoring.
+        let (s, mut c, addr) = tcp_pair().await;
+        c.write_all(&[252, 0, 1, 2]).await.unwrap();
+        let reason = vnc
+            .process(buffered_reader(s), addr, params.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            reason,
+            DisconnectReason::ProtocolError("unknown client message type: 252".to_string())
+        );
+
+        // By default, the connection is closed.
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, mut c, addr) = tcp_pair().await;
+        c.write_all(&[250, 0, 1, 2]).await.unwrap();
+        let reason = vnc
+            .process(buffered_reader(s), addr, params, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            reason,
+            DisconnectReason::ProtocolError("unknown client message type: 250".to_string())
+        );
+    }
+
+    /// A backend that records the pointer events it receives.
+    #[derive(Clone, Default)]
+    struct PointerServer {
+        events: Arc<Mutex<Vec<crate::rfb::PointerEvent>>>,
+    }
+
+    #[async_trait]
+    impl Server for PointerServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            FramebufferUpdate::new(vec![])
+        }
+
+        async fn pointer_event(&self, pe: crate::rfb::PointerEvent) {
+            self.events.lock().await.push(pe);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relative_pointer() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.relative_pointer = true;
+        let backend = PointerServer::default();
+        let events = backend.events.clone();
+        let vnc = VncServer::new(backend, config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // Before the client negotiates the pseudo-encoding, positions are absolute.
+        c.write_all(&[5, 0, 0, 10, 0, 20]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // SetEncodings with Raw and Pointer Motion Change (-257).
+        c.write_all(&[2, 0, 0, 2, 0, 0, 0, 0, 0xff, 0xff, 0xfe, 0xff])
+            .await
+            .unwrap();
+
+        // The server switches the client to relative mode: one rectangle at x = 0.
+        let mut update = [0u8; 16];
+        c.read_exact(&mut update).await.unwrap();
+        assert_eq!(&update[..4], &[0, 0, 0, 1]);
+        assert_eq!(&update[4..6], &[0, 0]);
+        assert_eq!(&update[12..], &(-257i32).to_be_bytes());
+
+        // Moving 3 pixels right and 2 up.
+        c.write_all(&[5, 0, 0x80, 0x02, 0x7f, 0xfd]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(c);
+        session.await.unwrap().unwrap();
+
+        let events = events.lock().await;
+        let modes: Vec<_> = events
+            .iter()
+            .map(|e| (e.is_relative(), e.delta()))
+            .collect();
+        assert_eq!(modes, vec![(false, None), (true, Some((3, -2)))]);
+    }
+
+    #[derive(Clone, Default)]
+    struct KeyTimesServer {
+        times: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    #[async_trait]
+    impl Server for KeyTimesServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            FramebufferUpdate::new(vec![])
+        }
+
+        async fn key_event(&self, _ke: crate::rfb::KeyEvent) {
+            self.times.lock().await.push(Instant::now());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_input_delivery_rate() {
+        let interval = Duration::from_millis(100);
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.input_coalesce_interval = Some(interval);
+        let backend = KeyTimesServer::default();
+        let times = backend.times.clone();
+        let vnc = VncServer::new(backend, config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // The first key is delivered at once and the second when the interval is up. The third
+        // arrives after that delivery, so it waits for another interval rather than going out
+        // right away.
+        let key = [4, 1, 0, 0, 0, 0, 0, 0x61];
+        c.write_all(&key).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        c.write_all(&key).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        c.write_all(&key).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        drop(c);
+        session.await.unwrap().unwrap();
+
+        let times = times.lock().await;
+        assert_eq!(times.len(), 3);
+        for pair in times.windows(2) {
+            let spacing = pair[1] - pair[0];
+            assert!(
+                spacing >= interval - Duration::from_millis(5),
+                "{:?}",
+                spacing
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_idle_timeout() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.idle_timeout = Some(Duration::from_millis(200));
+        let vnc = test_server_with_config(config);
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+
+        let start = Instant::now();
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // A message resets the timer; after that, the client stalls without closing.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        c.write_all(&[3, 1, 0, 0, 0, 0, 0, 1, 0, 1]).await.unwrap();
+
+        let reason = tokio::time::timeout(Duration::from_secs(2), session)
+            .await
+            .expect("idle session should be closed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::IdleTimeout);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_process_idle_timeout_partial_message() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.idle_timeout = Some(Duration::from_millis(200));
+        let vnc = test_server_with_config(config);
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // The client sends the first byte of a FramebufferUpdateRequest, then stalls.
+        c.write_all(&[3]).await.unwrap();
+
+        let reason = tokio::time::timeout(Duration::from_secs(2), session)
+            .await
+            .expect("stalled session should be closed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(reason, DisconnectReason::IdleTimeout);
+    }
+
+    #[tokio::test]
+    async fn test_converter_rebuilt_on_format_change() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(CountingServer::default(), config, test_data());
+        let mut pf = vnc.data.lock().await.input_pixel_format.clone();
+        let mut converters = ConverterCache::default();
+
+        let req = crate::rfb::FramebufferUpdateRequest::new(true, 0, 0, 1, 1);
+        for _ in 0..10 {
+            let mut out = Vec::new();
+            vnc.handle_update_request(&mut out, &req, &pf, &mut converters, &[])
+                .await
+                .unwrap();
+        }
+        assert_eq!(converters.builds, 1);
+
+        // The client switches to the big-endian variant of the input format.
+        pf.big_endian = !pf.big_endian;
+        for _ in 0..10 {
+            let mut out = Vec::new();
+            vnc.handle_update_request(&mut out, &req, &pf, &mut converters, &[])
+                .await
+                .unwrap();
+        }
+        assert_eq!(converters.builds, 2);
+    }
+
+    /// A backend with a single pixel, whose bytes differ in every byte position.
+    #[derive(Clone)]
+    struct PixelServer;
+
+    #[async_trait]
+    impl Server for PixelServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            let pixel = vec![0x10, 0x20, 0x30, 0x00];
+            FramebufferUpdate::new(vec![Rectangle::new(
+                0,
+                0,
+                1,
+                1,
+                Box::new(RawEncoding::new(pixel)),
+            )])
+        }
+    }
+
+    /// A backend that ignores the client's encodings.
+    #[derive(Clone)]
+    struct TightServer;
+
+    /// A Tight rectangle and a CopyRect rectangle, neither of which a Raw-only client can decode.
+    fn tight_update() -> FramebufferUpdate {
+        let pf = test_data().input_pixel_format;
+        let pixels = [1u8, 2, 3, 0].repeat(4);
+        FramebufferUpdate::new(vec![
+            Rectangle::new(0, 0, 2, 2, Box::new(TightEncoding::new(pixels, &pf))),
+            Rectangle::new(2, 0, 2, 2, Box::new(CopyRectEncoding::new(0, 0))),
+        ])
+    }
+
+    /// What a Raw-only client is sent in place of `tight_update`.
+    fn tight_update_as_raw() -> Vec<u8> {
+        let mut expected = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 0];
+        expected.extend([1u8, 2, 3, 0].repeat(4));
+        expected
+    }
+
+    #[async_trait]
+    impl Server for TightServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            tight_update()
+        }
+
+        async fn get_initial_framebuffer_update(&self) -> Option<FramebufferUpdate> {
+            Some(tight_update())
+        }
+    }
+
+    /// A backend that records the encodings passed to it, and uses Tight when it can.
+    #[derive(Clone, Default)]
+    struct EncodingsServer {
+        seen: Arc<std::sync::Mutex<Vec<Vec<EncodingType>>>>,
+        changed: Arc<std::sync::Mutex<Vec<ClientEncodings>>>,
+    }
+
+    #[async_trait]
+    impl Server for EncodingsServer {
+        async fn get_framebuffer_update(
+            &self,
+            region: Rect,
+            encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            self.seen.lock().unwrap().push(encodings.to_vec());
+
+            let pf = test_data().input_pixel_format;
+            let pixels = [1u8, 2, 3, 0].repeat(region.width as usize * region.height as usize);
+            let data: Box<dyn crate::encodings::Encoding> =
+                if crate::encodings::select_encoding(encodings, &[EncodingType::Tight])
+                    == EncodingType::Tight
+                {
+                    Box::new(TightEncoding::new(pixels, &pf))
+                } else {
+                    Box::new(RawEncoding::new(pixels))
+                };
+            FramebufferUpdate::new(vec![Rectangle::new(
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+                data,
+            )])
+        }
+
+        async fn encodings_changed(&self, encodings: &ClientEncodings) {
+            self.changed.lock().unwrap().push(encodings.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_request_before_set_encodings() {
+        let backend = EncodingsServer::default();
+        let vnc = VncServer::new(
+            backend.clone(),
+            test_config(ProtoVersion::Rfb38, vec![SecurityType::None]),
+            test_data(),
+        );
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // A non-incremental 2x2 request, without a SetEncodings first.
+        c.write_all(&[3, 0, 0, 0, 0, 0, 0, 2, 0, 2]).await.unwrap();
+
+        let mut reply = [0u8; 4 + 12 + 16];
+        c.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], &[0, 0, 0, 1]);
+        assert_eq!(&reply[12..16], &0i32.to_be_bytes());
+        assert_eq!(&reply[16..], [1u8, 2, 3, 0].repeat(4).as_slice());
+
+        drop(c);
+        let reason = session.await.unwrap().unwrap();
+        assert_eq!(reason, DisconnectReason::ClientClosed);
+        assert_eq!(*backend.seen.lock().unwrap(), vec![vec![EncodingType::Raw]]);
+    }
+
+    #[tokio::test]
+    async fn test_empty_set_encodings() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.supported_encodings = vec![EncodingType::Raw, EncodingType::Tight];
+        let backend = EncodingsServer::default();
+        let vnc = VncServer::new(backend.clone(), config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // Tight, Cursor, ExtendedDesktopSize and ContinuousUpdates, with continuous updates
+        // enabled for a single pixel.
+        c.write_all(&[2, 0, 0, 4]).await.unwrap();
+        for e in [7, -239, -308, -313] {
+            c.write_i32(e).await.unwrap();
+        }
+        assert_eq!(c.read_u8().await.unwrap(), 150);
+        c.write_all(&[150, 1, 0, 0, 0, 0, 0, 1, 0, 1])
+            .await
+            .unwrap();
+
+        // Both Tight and Raw updates of a pixel are 20 bytes long.
+        let mut reply = [0u8; 4 + 12 + 4];
+        c.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[12..16], &7i32.to_be_bytes());
+
+        // An empty list turns continuous updates off. Updates already on their way still arrive.
+        c.write_all(&[2, 0, 0, 0]).await.unwrap();
+        let mut stopped = false;
+        for _ in 0..10 {
+            let res = tokio::time::timeout(Duration::from_millis(200), c.read_exact(&mut reply));
+            if res.await.is_err() {
+                stopped = true;
+                break;
+            }
+        }
+        assert!(stopped, "continuous updates should stop");
+
+        // They can't be enabled again until the client lists the pseudo-encoding again.
+        c.write_all(&[150, 1, 0, 0, 0, 0, 0, 1, 0, 1])
+            .await
+            .unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(200), c.read_u8()).await;
+        assert!(res.is_err());
+
+        // The next update is Raw.
+        c.write_all(&[3, 0, 0, 0, 0, 0, 0, 1, 0, 1]).await.unwrap();
+        c.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[12..16], &0i32.to_be_bytes());
+
+        drop(c);
+        session.await.unwrap().unwrap();
+        let seen = backend.seen.lock().unwrap();
+        assert_eq!(seen.last(), Some(&vec![EncodingType::Raw]));
+        assert_eq!(
+            seen.iter().filter(|e| *e == &[EncodingType::Raw]).count(),
+            1
+        );
+
+        let changed = backend.changed.lock().unwrap();
+        assert_eq!(changed.len(), 2);
+        assert!(changed[0].supports(EncodingType::CursorPseudo));
+        assert_eq!(changed[1].real, vec![EncodingType::Raw]);
+        assert!(!changed[1].supports(EncodingType::CursorPseudo));
+        assert!(!changed[1].supports(EncodingType::ExtendedDesktopSizePseudo));
+    }
+
+    #[tokio::test]
+    async fn test_only_unsupported_encodings() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.supported_encodings = vec![EncodingType::Tight];
+        let backend = EncodingsServer::default();
+        let vnc = VncServer::new(backend.clone(), config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // ZRLE, JPEG and an unknown encoding, none of which the server produces.
+        c.write_all(&[2, 0, 0, 3]).await.unwrap();
+        for e in [16, 21, 0x1234] {
+            c.write_i32(e).await.unwrap();
+        }
+
+        c.write_all(&[3, 0, 0, 0, 0, 0, 0, 1, 0, 1]).await.unwrap();
+        let mut reply = [0u8; 4 + 12 + 4];
+        c.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..4], &[0, 0, 0, 1]);
+        assert_eq!(&reply[12..16], &0i32.to_be_bytes());
+        assert_eq!(&reply[16..], &[1, 2, 3, 0]);
+
+        drop(c);
+        session.await.unwrap().unwrap();
+        assert_eq!(*backend.seen.lock().unwrap(), vec![vec![EncodingType::Raw]]);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_encoding_sent_as_raw() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(TightServer, config, test_data());
+        let pf = vnc.data.lock().await.input_pixel_format.clone();
+        let req = crate::rfb::FramebufferUpdateRequest::new(false, 0, 0, 64, 48);
+
+        // The client only supports Raw: the Tight rectangle is re-encoded, and the CopyRect
+        // rectangle, whose pixels the server doesn't have, is dropped.
+        let mut out = Vec::new();
+        vnc.handle_update_request(
+            &mut out,
+            &req,
+            &pf,
+            &mut ConverterCache::default(),
+            &[EncodingType::Raw],
+        )
+        .await
+        .unwrap();
+        assert_eq!(out, tight_update_as_raw());
+
+        // A client that supports both gets them as they are.
+        let mut out = Vec::new();
+        vnc.handle_update_request(
+            &mut out,
+            &req,
+            &pf,
+            &mut ConverterCache::default(),
+            &[EncodingType::Tight, EncodingType::CopyRect],
+        )
+        .await
+        .unwrap();
+        assert_eq!(&out[..4], &[0, 0, 0, 2]);
+        assert_eq!(&out[12..16], &7i32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_retained_and_initial_updates_sent_as_raw() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(TightServer, config, test_data());
+        let pf = vnc.data.lock().await.input_pixel_format.clone();
+
+        // A retained full frame is checked against the client's encodings too.
+        vnc.set_full_frame(tight_update()).await;
+        let req = crate::rfb::FramebufferUpdateRequest::new(false, 0, 0, 64, 48);
+        let mut out = Vec::new();
+        vnc.handle_update_request(
+            &mut out,
+            &req,
+            &pf,
+            &mut ConverterCache::default(),
+            &[EncodingType::Raw],
+        )
+        .await
+        .unwrap();
+        assert_eq!(out, tight_update_as_raw());
+
+        // So is the initial update, sent before the client has named any encodings.
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+        let mut initial = vec![0u8; tight_update_as_raw().len()];
+        c.read_exact(&mut initial).await.unwrap();
+        assert_eq!(initial, tight_update_as_raw());
+
+        drop(c);
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_set_pixel_format_rebuilds_converter() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(PixelServer, config, test_data());
+        let output_pf = vnc.data.lock().await.input_pixel_format.clone();
+        let mut converters = ConverterCache::default();
+        let req = crate::rfb::FramebufferUpdateRequest::new(true, 0, 0, 1, 1);
+
+        let mut out = Vec::new();
+        vnc.handle_update_request(&mut out, &req, &output_pf, &mut converters, &[])
+            .await
+            .unwrap();
+        assert_eq!(&out[out.len() - 4..], &[0x10, 0x20, 0x30, 0x00]);
+
+        // The backend now provides big-endian pixels, which are swapped for the client.
+        let mut input_pf = output_pf.clone();
+        input_pf.big_endian = !input_pf.big_endian;
+        vnc.set_pixel_format(input_pf.clone()).await;
+
+        let mut out = Vec::new();
+        vnc.handle_update_request(&mut out, &req, &output_pf, &mut converters, &[])
+            .await
+            .unwrap();
+        assert_eq!(&out[out.len() - 4..], &[0x00, 0x30, 0x20, 0x10]);
+        assert_eq!(converters.builds, 2);
+        assert_eq!(converters.converter.as_ref().unwrap().input(), &input_pf);
+    }
+
+    #[tokio::test]
+    async fn test_set_pixel_format_clears_full_frame() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(PixelServer, config, test_data());
+        let pf = vnc.data.lock().await.input_pixel_format.clone();
+        let frame = || {
+            let r = Rectangle::new(0, 0, 1, 1, Box::new(RawEncoding::new(vec![0; 4])));
+            FramebufferUpdate::new(vec![r])
+        };
+
+        // Setting the same format keeps the retained frame.
+        vnc.set_full_frame(frame()).await;
+        vnc.set_pixel_format(pf.clone()).await;
+        assert!(vnc.full_frame.lock().await.is_some());
+
+        // A new format discards it, as its pixels are in the old one.
+        let mut other = pf.clone();
+        other.big_endian = !other.big_endian;
+        vnc.set_pixel_format(other).await;
+        assert!(vnc.full_frame.lock().await.is_none());
+    }
+
+    /// A backend that always renders the whole framebuffer as one Raw rectangle, plus a small
+    /// rectangle in the bottom-right corner.
+    #[derive(Clone)]
+    struct FullScreenServer;
+
+    #[async_trait]
+    impl Server for FullScreenServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            let pixels = (0..200 * 200)
+                .flat_map(|i: u32| {
+                    let (x, y) = ((i % 200) as u8, (i / 200) as u8);
+                    [x, y, 0, 0]
+                })
+                .collect();
+            let full = Rectangle::new(0, 0, 200, 200, Box::new(RawEncoding::new(pixels)));
+            let corner =
+                Rectangle::new(180, 180, 20, 20, Box::new(RawEncoding::new(vec![0; 1600])));
+            FramebufferUpdate::new(vec![full, corner])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_clipped_to_region() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let mut data = test_data();
+        data.width = 200;
+        data.height = 200;
+        let pf = data.input_pixel_format.clone();
+        let vnc = VncServer::new(FullScreenServer, config, data);
+
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(true, 50, 40, 100, 100);
+        vnc.handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+
+        let registry = crate::encodings::EncodingRegistry::default();
+        let fbu = FramebufferUpdate::read_from(&mut out.as_slice(), &pf, &registry)
+            .await
+            .unwrap();
+
+        // The corner rectangle lies outside the region and is dropped.
+        assert_eq!(fbu.rectangles().len(), 1);
+        let r = &fbu.rectangles()[0];
+        assert_eq!(r.position(), crate::rfb::Position::new(50, 40));
+        assert_eq!(r.dimensions(), crate::rfb::Resolution::new(100, 100));
+
+        // Each pixel holds its own coordinates, so the first pixel is the region's corner.
+        let pixels = r.data().encode();
+        assert_eq!(pixels.len(), 100 * 100 * 4);
+        assert_eq!(&pixels[..4], &[50, 40, 0, 0]);
+        assert_eq!(&pixels[pixels.len() - 4..], &[149, 139, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_server_cut_text() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+
+        let sender = vnc.cut_text_sender();
+        let token = CancellationToken::new();
+        let session = {
+            let token = token.clone();
+            tokio::spawn(async move {
+                vnc.process(buffered_reader(s), addr, params, Some(token))
+                    .await
+            })
+        };
+
+        // Give the session a chance to subscribe before publishing.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        sender.send(CutText::new("hello".to_string())).unwrap();
+
+        let mut buf = [0u8; 13];
+        tokio::time::timeout(Duration::from_secs(1), c.read_exact(&mut buf))
+            .await
+            .expect("ServerCutText should be sent")
+            .unwrap();
+        assert_eq!(buf, [3, 0, 0, 0, 0, 0, 0, 5, b'h', b'e', b'l', b'l', b'o']);
+
+        token.cancel();
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_minimal_rfb33_session() {
+        let pf = test_data().input_pixel_format;
+        let vnc = VncServer::minimal(
+            CountingServer::default(),
+            "127.0.0.1:0".parse().unwrap(),
+            1,
+            1,
+            pf,
+        );
+        let (s, mut c, addr) = tcp_pair().await;
+        let session = tokio::spawn(async move { vnc.handle_conn(buffered_reader(s), addr).await });
+
+        let mut version = [0u8; 12];
+        c.read_exact(&mut version).await.unwrap();
+        assert_eq!(&version, b"RFB 003.003\n");
+        c.write_all(b"RFB 003.003\n").await.unwrap();
+
+        // The server picks None, and there's no SecurityResult for it in 3.3.
+        assert_eq!(c.read_u32().await.unwrap(), 1);
+
+        // ClientInit, then ServerInit.
+        c.write_u8(0).await.unwrap();
+        assert_eq!(c.read_u16().await.unwrap(), 1);
+        assert_eq!(c.read_u16().await.unwrap(), 1);
+        let mut pixel_format = [0u8; 16];
+        c.read_exact(&mut pixel_format).await.unwrap();
+        let len = c.read_u32().await.unwrap();
+        let mut name = vec![0u8; len as usize];
+        c.read_exact(&mut name).await.unwrap();
+        assert_eq!(name, b"rfb");
+
+        // A non-incremental FramebufferUpdateRequest for the whole screen.
+        c.write_all(&[3, 0, 0, 0, 0, 0, 0, 1, 0, 1]).await.unwrap();
+
+        // One 1x1 Raw rectangle.
+        let mut update = [0u8; 20];
+        c.read_exact(&mut update).await.unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0, 0, 0, 1,             // type, padding, 1 rectangle
+            0, 0, 0, 0, 0, 1, 0, 1, // x, y, width, height
+            0, 0, 0, 0,             // Raw
+            1, 1, 1, 1,             // pixel
+        ];
+        assert_eq!(update, expected);
+
+        drop(c);
+        tokio::time::timeout(Duration::from_secs(1), session)
+            .await
+            .expect("session should end when the client disconnects")
+            .unwrap();
+    }
+
+    /// A backend that records the pixel format reported by `format_negotiated`.
+    #[derive(Clone, Default)]
+    struct NegotiationServer {
+        negotiated: Arc<std::sync::Mutex<Vec<PixelFormat>>>,
+    }
+
+    #[async_trait]
+    impl Server for NegotiationServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            FramebufferUpdate::new(vec![])
+        }
+
+        async fn format_negotiated(&self, pf: &PixelFormat) {
+            self.negotiated.lock().unwrap().push(pf.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_format_negotiated() {
+        let backend = NegotiationServer::default();
+        let negotiated = backend.negotiated.clone();
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(backend, config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+        assert!(negotiated.lock().unwrap().is_empty());
+
+        // SetPixelFormat: big-endian xRGB.
+        #[rustfmt::skip]
+        c.write_all(&[
+            0, 0, 0, 0,
+            32, 24, 1, 1,
+            0, 255, 0, 255,
+            0, 255, 16, 8,
+            0, 0, 0, 0,
+        ])
+        .await
+        .unwrap();
+
+        // A FramebufferUpdateRequest, whose reply shows the SetPixelFormat was handled.
+        c.write_all(&[3, 0, 0, 0, 0, 0, 0, 1, 0, 1]).await.unwrap();
+        let mut reply = [0u8; 4];
+        c.read_exact(&mut reply).await.unwrap();
+
+        {
+            let negotiated = negotiated.lock().unwrap();
+            assert_eq!(negotiated.len(), 1);
+            assert!(negotiated[0].big_endian);
+        }
+
+        drop(c);
+        assert_eq!(
+            session.await.unwrap().unwrap(),
+            DisconnectReason::ClientClosed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_counted_handshake() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, c, addr) = tcp_pair().await;
+        let s = Counted::new(s);
+        let stats = s.stats();
+
+        // The client counts its own traffic too, which should mirror the server's.
+        let client = tokio::spawn(async move {
+            let mut c = Counted::new(c);
+            let mut version = [0u8; 12];
+            c.read_exact(&mut version).await.unwrap();
+            c.write_all(b"RFB 003.008\n").await.unwrap();
+
+            let n = c.read_u8().await.unwrap();
+            let mut types = vec![0u8; n as usize];
+            c.read_exact(&mut types).await.unwrap();
+            c.write_u8(1).await.unwrap();
+            assert_eq!(c.read_u32().await.unwrap(), 0);
+
+            c.write_u8(1).await.unwrap();
+            let mut server_init = [0u8; 20];
+            c.read_exact(&mut server_init).await.unwrap();
+            let len = c.read_u32().await.unwrap();
+            let mut name = vec![0u8; len as usize];
+            c.read_exact(&mut name).await.unwrap();
+
+            (c.read_bytes(), c.written_bytes())
+        });
+
+        let (s, _) = vnc.initialize(s, addr).await.unwrap();
+        let (client_read, client_written) = client.await.unwrap();
+
+        assert_eq!(stats.read_bytes(), 14);
+        assert_eq!(stats.written_bytes(), 12 + 2 + 4 + 24 + 8);
+        assert_eq!(stats.read_bytes(), client_written);
+        assert_eq!(stats.written_bytes(), client_read);
+        assert_eq!(s.written_bytes(), stats.written_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_continuous_updates_end_marker() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // SetEncodings: Raw and the ContinuousUpdates pseudo-encoding. The server announces its
+        // support with an EndOfContinuousUpdates.
+        c.write_all(&[2, 0, 0, 2]).await.unwrap();
+        c.write_i32(0).await.unwrap();
+        c.write_i32(-313).await.unwrap();
+        let mut msg = [0u8; 1];
+        c.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [150]);
+
+        // Enable, then disable, continuous updates. TestServer has nothing to send, so the only
+        // message is the end marker acknowledging the disable.
+        c.write_all(&[150, 1, 0, 0, 0, 0, 0, 64, 0, 48])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        c.write_all(&[150, 0, 0, 0, 0, 0, 0, 64, 0, 48])
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), c.read_exact(&mut msg))
+            .await
+            .expect("EndOfContinuousUpdates should be sent")
+            .unwrap();
+        assert_eq!(msg, [150]);
+
+        drop(c);
+        assert_eq!(
+            session.await.unwrap().unwrap(),
+            DisconnectReason::ClientClosed
+        );
+    }
+
+    /// A backend whose update has a rectangle that doesn't fit in the framebuffer.
+    #[derive(Clone)]
+    struct OutOfBoundsServer;
+
+    #[async_trait]
+    impl Server for OutOfBoundsServer {
+        async fn get_framebuffer_update(
+            &self,
+            _region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            let r = Rectangle::new(60, 0, 8, 1, Box::new(RawEncoding::new(vec![0; 32])));
+            FramebufferUpdate::new(vec![r])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_process_encoding_error() {
+        let config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let vnc = VncServer::new(OutOfBoundsServer, config, test_data());
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session =
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await });
+
+        // A request that extends past the 64x48 framebuffer is clipped to it, and so is the
+        // rectangle, rather than failing the session.
+        c.write_all(&[3, 0, 0, 0, 0, 0, 0, 100, 0, 100])
+            .await
+            .unwrap();
+        let mut msg = [0u8; 16 + 4 * 4];
+        c.read_exact(&mut msg).await.unwrap();
+        assert_eq!(&msg[..12], &[0, 0, 0, 1, 0, 60, 0, 0, 0, 4, 0, 1]);
+        drop(c);
+        assert_eq!(
+            session.await.unwrap().unwrap(),
+            DisconnectReason::ClientClosed
+        );
+
+        // An update that still doesn't fit when it's written is an encoding error.
+        let err = FramebufferUpdate::new(vec![Rectangle::new(
+            60,
+            0,
+            8,
+            1,
+            Box::new(RawEncoding::new(vec![0; 32])),
+        )])
+        .write_checked_to(&mut Vec::new(), 64, 48)
+        .await
+        .unwrap_err();
+        let err = ServerError::from(err);
+        assert!(
+            matches!(
+                err,
+                ServerError::Encoding(ProtoError::RectangleOutOfBounds { .. })
+            ),
+            "{:?}",
+            err
+        );
+    }
+
+    /// A backend that records the regions it's asked for, rendering each as a blank Raw
+    /// rectangle.
+    #[derive(Clone, Default)]
+    struct RegionServer {
+        requests: Arc<std::sync::Mutex<Vec<Rect>>>,
+    }
+
+    #[async_trait]
+    impl Server for RegionServer {
+        async fn get_framebuffer_update(
+            &self,
+            region: Rect,
+            _encodings: &[EncodingType],
+        ) -> FramebufferUpdate {
+            self.requests.lock().unwrap().push(region);
+            let pixels = vec![0; region.width as usize * region.height as usize * 4];
+            let r = Rectangle::new(
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+                Box::new(RawEncoding::new(pixels)),
+            );
+            FramebufferUpdate::new(vec![r])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_after_resize() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.max_desktop_size = Some((800, 600));
+        let backend = RegionServer::default();
+        let vnc = VncServer::new(backend.clone(), config, test_data());
+        let pf = vnc.data.lock().await.input_pixel_format.clone();
+
+        let full = Rectangle::new(
+            0,
+            0,
+            64,
+            48,
+            Box::new(RawEncoding::new(vec![0; 64 * 48 * 4])),
+        );
+        vnc.set_full_frame(FramebufferUpdate::new(vec![full])).await;
+        vnc.set_resolution(32, 16).await;
+
+        // A full update requested at the old size covers just the new size, from the backend
+        // rather than the retained frame.
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(false, 0, 0, 64, 48);
+        vnc.handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert_eq!(&out[..4], &[0, 0, 0, 1]);
+        assert_eq!(&out[4..12], &[0, 0, 0, 0, 0, 32, 0, 16]);
+        assert_eq!(out.len(), 16 + 32 * 16 * 4);
+        assert_eq!(
+            *backend.requests.lock().unwrap(),
+            vec![Rect::new(0, 0, 32, 16)]
+        );
+
+        // A request entirely outside the new size doesn't reach the backend.
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(true, 40, 20, 8, 8);
+        let sent = vnc
+            .handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert!(!sent);
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+
+        // The same applies after a client-requested resize.
+        let full = Rectangle::new(
+            0,
+            0,
+            32,
+            16,
+            Box::new(RawEncoding::new(vec![0; 32 * 16 * 4])),
+        );
+        vnc.set_full_frame(FramebufferUpdate::new(vec![full])).await;
+        vnc.handle_set_desktop_size(&desktop_size_request(100, 80))
+            .await;
+        assert!(vnc.full_frame.lock().await.is_none());
+
+        let mut out = Vec::new();
+        let req = crate::rfb::FramebufferUpdateRequest::new(false, 0, 0, 100, 80);
+        vnc.handle_update_request(&mut out, &req, &pf, &mut ConverterCache::default(), &[])
+            .await
+            .unwrap();
+        assert_eq!(&out[4..12], &[0, 0, 0, 0, 0, 100, 0, 80]);
+    }
+
+    #[tokio::test]
+    async fn test_continuous_updates_dirty_region() {
+        let mut config = test_config(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        config.dirty_tracking = true;
+        let mut data = test_data();
+        data.width = 400;
+        data.height = 400;
+        let backend = RegionServer::default();
+        let vnc = VncServer::new(backend.clone(), config, data);
+
+        let (s, mut c, addr) = tcp_pair().await;
+        let params = SessionParams {
+            version: ProtoVersion::Rfb38,
+            security_type: SecurityType::None,
+            shared: true,
+        };
+        let session = {
+            let vnc = vnc.clone();
+            tokio::spawn(async move { vnc.process(buffered_reader(s), addr, params, None).await })
+        };
+
+        // Announce support, then enable continuous updates for the top-left 200x200 corner.
+        c.write_all(&[2, 0, 0, 2]).await.unwrap();
+        c.write_i32(0).await.unwrap();
+        c.write_i32(-313).await.unwrap();
+        assert_eq!(c.read_u8().await.unwrap(), 150);
+        c.write_all(&[150, 1, 0, 0, 0, 0, 0, 200, 0, 200])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A change outside the corner isn't pushed, and the backend isn't asked about it.
+        vnc.mark_dirty(Rect::new(300, 300, 50, 50));
+        let mut msg = [0u8; 16];
+        let res = tokio::time::timeout(Duration::from_millis(200), c.read_exact(&mut msg)).await;
+        assert!(res.is_err(), "unexpected update");
+        assert!(backend.requests.lock().unwrap().is_empty());
+
+        // A change straddling its edge is pushed for just the part inside it.
+        vnc.mark_dirty(Rect::new(150, 150, 100, 100));
+        tokio::time::timeout(Duration::from_secs(1), c.read_exact(&mut msg))
+            .await
+            .expect("update should be pushed")
+            .unwrap();
+        assert_eq!(&msg[..4], &[0, 0, 0, 1]);
+        assert_eq!(&msg[4..12], &[0, 150, 0, 150, 0, 50, 0, 50]);
+        assert_eq!(
+            *backend.requests.lock().unwrap(),
+            vec![Rect::new(150, 150, 50, 50)]
+        );
+        let mut pixels = vec![0u8; 50 * 50 * 4];
+        c.read_exact(&mut pixels).await.unwrap();
+
+        drop(c);
+        session.await.unwrap().unwrap();
+    }
+
+    /// Completes the client side of a 3.8 handshake with no security.
+    async fn client_handshake<C: AsyncRead + AsyncWrite + Unpin>(c: &mut C) {
+        let mut version = [0u8; 12];
+        c.read_exact(&mut version).await.unwrap();
+        c.write_all(b"RFB 003.008\n").await.unwrap();
+
+        let n = c.read_u8().await.unwrap();
+        let mut types = vec![0u8; n as usize];
+        c.read_exact(&mut types).await.unwrap();
+        c.write_u8(1).await.unwrap();
+        assert_eq!(c.read_u32().await.unwrap(), 0);
+
+        c.write_u8(1).await.unwrap();
+        let mut server_init = [0u8; 20];
+        c.read_exact(&mut server_init).await.unwrap();
+        let len = c.read_u32().await.unwrap();
+        let mut name = vec![0u8; len as usize];
+        c.read_exact(&mut name).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_acceptor_max_connections() {
+        let vnc = test_server(ProtoVersion::Rfb38, vec![SecurityType::None]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut acceptor = Acceptor::new(vnc, listener, 1);
+
+        let mut c1 = TcpStream::connect(addr).await.unwrap();
+        client_handshake(&mut c1).await;
+        let session = acceptor.accept().await.unwrap();
+        assert_eq!(session.params().version, ProtoVersion::Rfb38);
+
+        // The second client is queued: it connects, but the server doesn't start the handshake.
+        let mut c2 = TcpStream::connect(addr).await.unwrap();
+        let mut version = [0u8; 12];
+        let res =
+            tokio::time::timeout(Duration::from_millis(200), c2.read_exact(&mut version)).await;
+        assert!(res.is_err(), "second connection should wait for a slot");
+
+        // Once the first session ends, the second is served.
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(1), c2.read_exact(&mut version))
+            .await
+            .expect("second connection should be served")
+            .unwrap();
+        assert_eq!(&version, b"RFB 003.008\n");
+    }
 }
@@ -0,0 +1,365 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2022 Oxide Computer Company
+
+//! Byte layout of fixed-size messages
+//!
+//! Messages whose size doesn't depend on their contents are encoded to and decoded from byte
+//! slices here, without allocating. This module only depends on `core`, so it's available when
+//! the crate is built without its default `std` feature, for users that handle the transport
+//! themselves.
+//!
+//! Each message includes its message type, if it has one. Decoding returns the number of bytes
+//! consumed and encoding the number written, which is always [`FixedMessage::LEN`].
+
+use core::fmt;
+
+/// Errors encoding or decoding a fixed-size message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireError {
+    /// The buffer is smaller than the message.
+    BufferTooShort { needed: usize, available: usize },
+
+    /// The buffer holds a different type of message.
+    WrongMessageType { expected: u8, actual: u8 },
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::BufferTooShort { needed, available } => write!(
+                f,
+                "buffer of {} bytes is too short for a {} byte message",
+                available, needed
+            ),
+            WireError::WrongMessageType { expected, actual } => {
+                write!(f, "expected message type {}, got {}", expected, actual)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for WireError {}
+
+/// A message with a fixed size on the wire.
+pub trait FixedMessage: Sized {
+    /// The size of the message in bytes.
+    const LEN: usize;
+
+    /// Decodes the message from the start of `buf`, returning it and the number of bytes it
+    /// took up.
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError>;
+
+    /// Encodes the message to the start of `buf`, returning the number of bytes written.
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError>;
+}
+
+/// Checks that `buf` can hold a message of `len` bytes, returning the part of it that does.
+fn fits(buf: &[u8], len: usize) -> Result<&[u8], WireError> {
+    buf.get(..len).ok_or(WireError::BufferTooShort {
+        needed: len,
+        available: buf.len(),
+    })
+}
+
+fn fits_mut(buf: &mut [u8], len: usize) -> Result<&mut [u8], WireError> {
+    let available = buf.len();
+    buf.get_mut(..len).ok_or(WireError::BufferTooShort {
+        needed: len,
+        available,
+    })
+}
+
+fn check_type(buf: &[u8], expected: u8) -> Result<(), WireError> {
+    match buf[0] {
+        t if t == expected => Ok(()),
+        actual => Err(WireError::WrongMessageType { expected, actual }),
+    }
+}
+
+fn get_u16(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn get_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn put(buf: &mut [u8], at: usize, bytes: &[u8]) {
+    buf[at..at + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Section 7.5.3: FramebufferUpdateRequest (client message type 3)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferUpdateRequest {
+    pub incremental: bool,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl FixedMessage for FramebufferUpdateRequest {
+    const LEN: usize = 10;
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        let buf = fits(buf, Self::LEN)?;
+        check_type(buf, 3)?;
+
+        let req = FramebufferUpdateRequest {
+            incremental: buf[1] != 0,
+            x: get_u16(buf, 2),
+            y: get_u16(buf, 4),
+            width: get_u16(buf, 6),
+            height: get_u16(buf, 8),
+        };
+        Ok((req, Self::LEN))
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let buf = fits_mut(buf, Self::LEN)?;
+        buf[0] = 3;
+        buf[1] = self.incremental as u8;
+        put(buf, 2, &self.x.to_be_bytes());
+        put(buf, 4, &self.y.to_be_bytes());
+        put(buf, 6, &self.width.to_be_bytes());
+        put(buf, 8, &self.height.to_be_bytes());
+        Ok(Self::LEN)
+    }
+}
+
+/// Section 7.5.4: KeyEvent (client message type 4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub down: bool,
+    pub keysym: u32,
+}
+
+impl FixedMessage for KeyEvent {
+    const LEN: usize = 8;
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        let buf = fits(buf, Self::LEN)?;
+        check_type(buf, 4)?;
+
+        // 2 bytes of padding
+        let ev = KeyEvent {
+            down: buf[1] != 0,
+            keysym: get_u32(buf, 4),
+        };
+        Ok((ev, Self::LEN))
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let buf = fits_mut(buf, Self::LEN)?;
+        buf[0] = 4;
+        buf[1] = self.down as u8;
+        put(buf, 2, &[0, 0]);
+        put(buf, 4, &self.keysym.to_be_bytes());
+        Ok(Self::LEN)
+    }
+}
+
+/// Section 7.5.5: PointerEvent (client message type 5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointerEvent {
+    pub button_mask: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
+impl FixedMessage for PointerEvent {
+    const LEN: usize = 6;
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        let buf = fits(buf, Self::LEN)?;
+        check_type(buf, 5)?;
+
+        let ev = PointerEvent {
+            button_mask: buf[1],
+            x: get_u16(buf, 2),
+            y: get_u16(buf, 4),
+        };
+        Ok((ev, Self::LEN))
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let buf = fits_mut(buf, Self::LEN)?;
+        buf[0] = 5;
+        buf[1] = self.button_mask;
+        put(buf, 2, &self.x.to_be_bytes());
+        put(buf, 4, &self.y.to_be_bytes());
+        Ok(Self::LEN)
+    }
+}
+
+/// Section 7.6.1: the header of a FramebufferUpdate (server message type 0), which is followed
+/// by its rectangles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferUpdateHeader {
+    /// The number of rectangles, or `u16::MAX` if the update ends with a LastRect rectangle.
+    pub rectangles: u16,
+}
+
+impl FixedMessage for FramebufferUpdateHeader {
+    const LEN: usize = 4;
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        let buf = fits(buf, Self::LEN)?;
+        check_type(buf, 0)?;
+
+        // 1 byte of padding
+        let header = FramebufferUpdateHeader {
+            rectangles: get_u16(buf, 2),
+        };
+        Ok((header, Self::LEN))
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let buf = fits_mut(buf, Self::LEN)?;
+        buf[0] = 0;
+        buf[1] = 0;
+        put(buf, 2, &self.rectangles.to_be_bytes());
+        Ok(Self::LEN)
+    }
+}
+
+/// Section 7.6.1: the header of a rectangle in a FramebufferUpdate, which is followed by its
+/// encoded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RectangleHeader {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub encoding: i32,
+}
+
+impl FixedMessage for RectangleHeader {
+    const LEN: usize = 12;
+
+    fn decode(buf: &[u8]) -> Result<(Self, usize), WireError> {
+        let buf = fits(buf, Self::LEN)?;
+
+        let header = RectangleHeader {
+            x: get_u16(buf, 0),
+            y: get_u16(buf, 2),
+            width: get_u16(buf, 4),
+            height: get_u16(buf, 6),
+            encoding: get_u32(buf, 8) as i32,
+        };
+        Ok((header, Self::LEN))
+    }
+
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let buf = fits_mut(buf, Self::LEN)?;
+        put(buf, 0, &self.x.to_be_bytes());
+        put(buf, 2, &self.y.to_be_bytes());
+        put(buf, 4, &self.width.to_be_bytes());
+        put(buf, 6, &self.height.to_be_bytes());
+        put(buf, 8, &self.encoding.to_be_bytes());
+        Ok(Self::LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FixedMessage, FramebufferUpdateRequest, KeyEvent, PointerEvent, RectangleHeader, WireError,
+    };
+
+    // These tests only use `core`, so they also run without the `std` feature.
+
+    #[test]
+    fn test_pointer_event() {
+        // At (300, 200) with the left button down, followed by the start of another message.
+        let buf = [5u8, 1, 0x01, 0x2c, 0, 200, 5, 0];
+        let (ev, len) = PointerEvent::decode(&buf).unwrap();
+        assert_eq!(len, 6);
+        assert_eq!(
+            ev,
+            PointerEvent {
+                button_mask: 1,
+                x: 300,
+                y: 200
+            }
+        );
+
+        let mut out = [0xffu8; 8];
+        assert_eq!(ev.encode(&mut out), Ok(6));
+        assert_eq!(out[..6], buf[..6]);
+        assert_eq!(out[6..], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_short_buffers() {
+        let buf = [5u8, 1, 0x01, 0x2c, 0];
+        assert_eq!(
+            PointerEvent::decode(&buf),
+            Err(WireError::BufferTooShort {
+                needed: 6,
+                available: 5
+            })
+        );
+
+        let mut out = [0u8; 11];
+        let header = RectangleHeader {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+            encoding: -223,
+        };
+        assert!(header.encode(&mut out).is_err());
+    }
+
+    #[test]
+    fn test_wrong_message_type() {
+        let buf = [4u8, 1, 0, 0, 0, 0, 0, 0x61];
+        assert_eq!(
+            PointerEvent::decode(&buf),
+            Err(WireError::WrongMessageType {
+                expected: 5,
+                actual: 4
+            })
+        );
+
+        let (ev, _) = KeyEvent::decode(&buf).unwrap();
+        assert_eq!(
+            ev,
+            KeyEvent {
+                down: true,
+                keysym: 0x61
+            }
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = [0u8; 12];
+
+        let req = FramebufferUpdateRequest {
+            incremental: true,
+            x: 1,
+            y: 2,
+            width: 640,
+            height: 480,
+        };
+        let n = req.encode(&mut buf).unwrap();
+        assert_eq!(FramebufferUpdateRequest::decode(&buf[..n]), Ok((req, n)));
+
+        let header = RectangleHeader {
+            x: 10,
+            y: 20,
+            width: 30,
+            height: 40,
+            encoding: -223,
+        };
+        let n = header.encode(&mut buf).unwrap();
+        assert_eq!(&buf[8..], &[0xff, 0xff, 0xff, 0x21]);
+        assert_eq!(RectangleHeader::decode(&buf[..n]), Ok((header, n)));
+    }
+}